@@ -0,0 +1,76 @@
+//! Parses a torrent with 50 000 pieces into an owned `Torrent` and into a borrowed
+//! `TorrentRef`.
+//!
+//! Besides the criterion timings, the bytes each parse allocates are printed once per
+//! variant.
+
+use bittorrent_starter_rust::torrent::Torrent;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const PIECES: usize = 50_000;
+
+/// Counts the bytes allocated, to compare what each parse costs besides time.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// A single-file torrent with `PIECES` pieces of 256 KiB.
+fn large_torrent() -> Vec<u8> {
+    let plength = 256 * 1024;
+    let mut info = Vec::new();
+    info.extend_from_slice(format!("d6:lengthi{}e4:name9:large.bin", PIECES * plength).as_bytes());
+    info.extend_from_slice(
+        format!("12:piece lengthi{plength}e6:pieces{}:", PIECES * 20).as_bytes(),
+    );
+    info.extend((0..PIECES * 20).map(|i| i as u8));
+    info.push(b'e');
+    let mut torrent = b"d8:announce30:http://127.0.0.1:6969/announce4:info".to_vec();
+    torrent.extend_from_slice(&info);
+    torrent.push(b'e');
+    torrent
+}
+
+fn allocated_by(parse: impl FnOnce()) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    parse();
+    ALLOCATED.load(Ordering::Relaxed) - before
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let bytes = large_torrent();
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("owned", |b| {
+        b.iter(|| serde_bencode::from_bytes::<Torrent>(&bytes).expect("valid torrent"))
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| Torrent::from_bytes_borrowed(&bytes).expect("valid torrent"))
+    });
+    group.finish();
+    let owned = allocated_by(|| {
+        serde_bencode::from_bytes::<Torrent>(&bytes).expect("valid torrent");
+    });
+    let borrowed = allocated_by(|| {
+        Torrent::from_bytes_borrowed(&bytes).expect("valid torrent");
+    });
+    eprintln!("owned: {owned} bytes allocated per parse, borrowed: {borrowed}");
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
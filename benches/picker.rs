@@ -0,0 +1,136 @@
+//! Drives each piece picker over a synthetic swarm, without any network code involved.
+//!
+//! Besides the criterion timings, the amount of duplicated work each strategy ends up
+//! scheduling is printed once per picker.
+
+use bittorrent_starter_rust::picker::{
+    Availability, PickContext, PiecePicker, Priority, RandomPicker, RarestFirst, Sequential,
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+
+const PIECES: usize = 10_000;
+const PEERS: usize = 200;
+/// Every this many picks, one peer leaves and a fresh one joins.
+const CHURN_EVERY: usize = 50;
+/// How many claims are outstanding at once; the oldest one completes when a new one is made.
+const IN_FLIGHT: usize = 2 * PEERS;
+
+struct Swarm {
+    rng: StdRng,
+    have: Vec<bool>,
+    in_flight: Vec<bool>,
+    claims: VecDeque<usize>,
+    priorities: Vec<Priority>,
+    peers: Vec<Vec<bool>>,
+    availability: Availability,
+    picks: usize,
+    duplicates: usize,
+}
+
+impl Swarm {
+    fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut availability = Availability::new(PIECES);
+        let peers: Vec<Vec<bool>> = (0..PEERS).map(|_| random_peer(&mut rng)).collect();
+        for peer in &peers {
+            availability.add_peer(peer);
+        }
+        let priorities = (0..PIECES)
+            .map(|_| match rng.gen_range(0..100) {
+                0..=4 => Priority::Skip,
+                5..=9 => Priority::High,
+                _ => Priority::Normal,
+            })
+            .collect();
+        Self {
+            rng,
+            have: vec![false; PIECES],
+            in_flight: vec![false; PIECES],
+            claims: VecDeque::with_capacity(IN_FLIGHT + 1),
+            priorities,
+            peers,
+            availability,
+            picks: 0,
+            duplicates: 0,
+        }
+    }
+
+    fn step(&mut self, picker: &mut dyn PiecePicker) {
+        self.picks += 1;
+        if self.picks.is_multiple_of(CHURN_EVERY) {
+            let leaving = self.rng.gen_range(0..PEERS);
+            let joining = random_peer(&mut self.rng);
+            self.availability.remove_peer(&self.peers[leaving]);
+            self.availability.add_peer(&joining);
+            self.peers[leaving] = joining;
+        }
+
+        let peer = self.rng.gen_range(0..PEERS);
+        let ctx = PickContext {
+            have: &self.have,
+            peer_has: &self.peers[peer],
+            availability: &self.availability,
+            in_flight: &self.in_flight,
+            priorities: &self.priorities,
+        };
+        match picker.pick(&ctx) {
+            Some(index) => {
+                if self.in_flight[index] {
+                    self.duplicates += 1;
+                }
+                self.in_flight[index] = true;
+                self.claims.push_back(index);
+                if self.claims.len() > IN_FLIGHT {
+                    let done = self.claims.pop_front().expect("claims is not empty");
+                    self.in_flight[done] = false;
+                    self.have[done] = true;
+                }
+            }
+            None => {
+                // the swarm is drained, start over
+                self.have.fill(false);
+                self.in_flight.fill(false);
+                self.claims.clear();
+            }
+        }
+    }
+}
+
+fn random_peer(rng: &mut StdRng) -> Vec<bool> {
+    // a mix of seeds and leechers at various stages
+    let density: f64 = if rng.gen_bool(0.2) { 1.0 } else { rng.gen() };
+    (0..PIECES).map(|_| rng.gen_bool(density)).collect()
+}
+
+fn pickers() -> Vec<(&'static str, Box<dyn PiecePicker>)> {
+    vec![
+        ("rarest_first", Box::new(RarestFirst)),
+        ("sequential", Box::new(Sequential)),
+        (
+            "random",
+            Box::new(RandomPicker::new(StdRng::seed_from_u64(7))),
+        ),
+    ]
+}
+
+fn bench_pickers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pick");
+    group.throughput(Throughput::Elements(1));
+    for (name, mut picker) in pickers() {
+        let mut swarm = Swarm::new(42);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| swarm.step(picker.as_mut()))
+        });
+        eprintln!(
+            "{name}: {} duplicate assignments over {} picks",
+            swarm.duplicates, swarm.picks
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_pickers);
+criterion_main!(benches);
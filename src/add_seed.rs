@@ -0,0 +1,339 @@
+//! Seeding data that is already on disk, e.g. downloaded with another client or from the
+//! publisher, under a new torrent.
+//!
+//! The torrent's files are looked up where the data is, hash-checked in place and the pieces
+//! that match recorded in a piece map for `seed`. Nothing is copied, moved or written to.
+//!
+//! `verify` runs the same check and only reports what it found, and `download` runs it on
+//! its output to pick up where an interrupted download stopped.
+
+use crate::files::{FileMapper, MappedFile};
+use crate::peer::Bitfield;
+use crate::resume_import::READ_CHUNK;
+use crate::sanitize;
+use crate::torrent::{Keys, Metainfo, Torrent};
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use tokio_util::sync::CancellationToken;
+
+/// How one file of the torrent compares to what is on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileCheck {
+    Matches,
+    Missing,
+    WrongSize {
+        actual: u64,
+    },
+    /// The size is right, but this many of the pieces the file is part of don't match.
+    Corrupt {
+        bad_pieces: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub length: usize,
+    pub check: FileCheck,
+}
+
+/// What a hash check of existing data found.
+#[derive(Debug, Clone)]
+pub struct DataCheck {
+    /// The pieces that match their hash.
+    pub have: Bitfield,
+    /// The pieces that couldn't be read, as a file they span is missing or of the wrong
+    /// size.
+    pub missing: Bitfield,
+    /// Every file but padding files, in torrent order.
+    pub files: Vec<FileReport>,
+}
+
+/// Where the files of `torrent` are when its data is at `data`.
+///
+/// A single-file torrent's data is the file itself, or a directory holding it. A
+/// multi-file torrent's data is a directory either holding the torrent's name folder or
+/// being it, whatever it is called. Names are matched as the sanitizer would write them and
+/// regardless of case, so data written by another client on another system is found too.
+pub fn locate(torrent: &Torrent, data: &Path) -> FileMapper {
+    let mut mapper = FileMapper::new(torrent, Path::new(""));
+    let name = torrent
+        .file_path(0)
+        .and_then(|path| {
+            path.components()
+                .next()
+                .map(|name| PathBuf::from(name.as_os_str()))
+        })
+        .unwrap_or_default();
+    match &torrent.info.keys {
+        Keys::SingleFile { .. } => {
+            let file = if data.is_dir() {
+                find(data, &name)
+            } else {
+                data.to_path_buf()
+            };
+            mapper.relocate(|_| file.clone());
+        }
+        Keys::MultiFile { .. } => {
+            let name_present = find(data, &name).is_dir();
+            mapper.relocate(|file| {
+                let relative = if name_present {
+                    &file.path
+                } else {
+                    file.path.strip_prefix(&name).unwrap_or(&file.path)
+                };
+                find(data, relative)
+            });
+        }
+    }
+    mapper
+}
+
+/// `dir` joined with `relative`, with every component that doesn't exist as written
+/// replaced by an entry of its directory that matches it but for case or sanitizing.
+fn find(dir: &Path, relative: &Path) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    let mut components = relative.components();
+    while let Some(component) = components.next() {
+        let Component::Normal(name) = component else {
+            continue;
+        };
+        if path.join(name).exists() {
+            path.push(name);
+            continue;
+        }
+        let wanted = name.to_string_lossy().to_lowercase();
+        let found = std::fs::read_dir(&path).ok().and_then(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name())
+                .find(|entry| {
+                    sanitize::path_component(&entry.to_string_lossy()).to_lowercase() == wanted
+                })
+        });
+        match found {
+            Some(entry) => path.push(entry),
+            None => {
+                // missing, which the size check reports
+                path.push(name);
+                path.push(components.as_path());
+                break;
+            }
+        }
+    }
+    path
+}
+
+/// Hashes every piece of `torrent` from the files `mapper` found, and compares every file's
+/// size.
+///
+/// This blocks on disk I/O, so it's meant for a blocking thread. It gives up soon after
+/// `cancel` fires, checking it between pieces and between [`READ_CHUNK`]s of a piece.
+pub fn check(
+    torrent: &Torrent,
+    mapper: &FileMapper,
+    cancel: &CancellationToken,
+) -> anyhow::Result<DataCheck> {
+    let mut checks = Vec::with_capacity(mapper.files().len());
+    let mut open = Vec::with_capacity(mapper.files().len());
+    for file in mapper.files() {
+        let (check, handle) = open_file(file)?;
+        checks.push(check);
+        open.push(handle);
+    }
+
+    let npieces = torrent.declared_pieces();
+    let mut have = Bitfield::new(npieces);
+    let mut missing = Bitfield::new(npieces);
+    let mut chunk = vec![0; READ_CHUNK.min(torrent.info.plength)];
+    for index in 0..npieces {
+        if cancel.is_cancelled() {
+            bail!("verification cancelled");
+        }
+        let slices = mapper.slices(index, torrent.piece_size(index));
+        // a file it spans is missing or ends too soon, which says nothing about the others
+        let readable = slices.iter().all(|slice| {
+            let file = &mapper.files()[slice.file];
+            file.padding
+                || open[slice.file]
+                    .as_ref()
+                    .is_some_and(|(_, size)| (slice.file_offset + slice.length) as u64 <= *size)
+        });
+        if !readable {
+            missing.set_piece(index);
+            continue;
+        }
+        let mut hasher = Sha1::new();
+        for slice in &slices {
+            let file = &mapper.files()[slice.file];
+            let mut handle = match &mut open[slice.file] {
+                Some((handle, _)) => {
+                    handle
+                        .seek(SeekFrom::Start(slice.file_offset as u64))
+                        .with_context(|| format!("read {}", file.path.display()))?;
+                    Some(handle)
+                }
+                // padding, which is all zeroes
+                None => None,
+            };
+            let mut remaining = slice.length;
+            while remaining > 0 {
+                if cancel.is_cancelled() {
+                    bail!("verification cancelled");
+                }
+                let chunk = &mut chunk[..remaining.min(READ_CHUNK)];
+                match &mut handle {
+                    Some(handle) => handle
+                        .read_exact(chunk)
+                        .with_context(|| format!("read {}", file.path.display()))?,
+                    None => chunk.fill(0),
+                }
+                hasher.update(&*chunk);
+                remaining -= chunk.len();
+            }
+        }
+        if hasher.finalize().as_slice() == torrent.piece_hash(index)? {
+            have.set_piece(index);
+            continue;
+        }
+        for slice in &slices {
+            if let FileCheck::Corrupt { bad_pieces } = &mut checks[slice.file] {
+                *bad_pieces += 1;
+            }
+        }
+    }
+
+    let files = mapper
+        .files()
+        .iter()
+        .zip(checks)
+        .filter(|(file, _)| !file.padding)
+        .map(|(file, check)| FileReport {
+            path: file.path.clone(),
+            length: file.length,
+            check: match check {
+                FileCheck::Corrupt { bad_pieces: 0 } => FileCheck::Matches,
+                check => check,
+            },
+        })
+        .collect();
+    Ok(DataCheck {
+        have,
+        missing,
+        files,
+    })
+}
+
+/// Checks what a download into the files of `mapper` already has, so only the other pieces
+/// are fetched.
+///
+/// A selected file longer than the torrent says is an error rather than something to cut
+/// short, as it's likely not a partial download of this torrent at all.
+pub fn resume(
+    torrent: &Torrent,
+    mapper: &FileMapper,
+    cancel: &CancellationToken,
+) -> anyhow::Result<DataCheck> {
+    for file in mapper.files() {
+        if file.padding || !file.selected {
+            continue;
+        }
+        match std::fs::metadata(&file.path) {
+            Ok(metadata) if metadata.is_file() && metadata.len() > file.length as u64 => bail!(
+                "{} is {} bytes, longer than the {} the torrent has",
+                file.path.display(),
+                metadata.len(),
+                file.length
+            ),
+            _ => {}
+        }
+    }
+    check(torrent, mapper, cancel)
+}
+
+/// Opens `file` for hashing, with its size on disk. Until its pieces are checked, a file of
+/// the right size counts as corrupt in none of them; one of the wrong size can still have
+/// the pieces that lie within it checked, as a partial download.
+fn open_file(file: &MappedFile) -> anyhow::Result<(FileCheck, Option<(File, u64)>)> {
+    if file.padding {
+        return Ok((FileCheck::Matches, None));
+    }
+    let metadata = match std::fs::metadata(&file.path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok((FileCheck::Missing, None)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok((FileCheck::Missing, None))
+        }
+        Err(err) => return Err(err).with_context(|| format!("inspect {}", file.path.display())),
+    };
+    let handle = File::open(&file.path).with_context(|| format!("open {}", file.path.display()))?;
+    let check = if metadata.len() == file.length as u64 {
+        FileCheck::Corrupt { bad_pieces: 0 }
+    } else {
+        FileCheck::WrongSize {
+            actual: metadata.len(),
+        }
+    };
+    Ok((check, Some((handle, metadata.len()))))
+}
+
+impl DataCheck {
+    /// The pieces that could be read but don't match their hash.
+    pub fn failed_pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        self.have
+            .missing_pieces()
+            .filter(|&index| !self.missing.has_piece(index))
+    }
+
+    /// The files that don't match the torrent.
+    pub fn mismatches(&self) -> usize {
+        self.files
+            .iter()
+            .filter(|file| file.check != FileCheck::Matches)
+            .count()
+    }
+}
+
+/// `pieces`, in ascending order, as a list of indices and ranges like `3, 7-9`.
+pub fn piece_list(pieces: impl IntoIterator<Item = usize>) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for index in pieces {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == index => *end = index,
+            _ => ranges.push((index, index)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Display for FileReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let path = self.path.display();
+        match self.check {
+            FileCheck::Matches => write!(f, "ok        {path}"),
+            FileCheck::Missing => write!(f, "missing   {path}"),
+            FileCheck::WrongSize { actual } => write!(
+                f,
+                "size      {path}: {actual} bytes instead of {}",
+                self.length
+            ),
+            FileCheck::Corrupt { bad_pieces } => {
+                write!(f, "content   {path}: {bad_pieces} piece(s) don't match")
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Inbound connections accepted per second on average.
+const ACCEPT_RATE: f64 = 20.0;
+
+/// Inbound connections accepted in a burst before [`ACCEPT_RATE`] applies.
+const ACCEPT_BURST: f64 = 40.0;
+
+/// Concurrent connections allowed from one IP address.
+pub const MAX_CONNECTIONS_PER_IP: usize = 3;
+
+/// How long an address that broke the protocol is turned away.
+const VIOLATION_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Decides which inbound connections the accept loop takes on.
+///
+/// Protects against a single address opening many connections or reconnecting in a tight
+/// loop. Everything is in memory and expires on its own; nothing here ever fails.
+#[derive(Debug)]
+pub struct Admission {
+    /// Tokens of the global accept rate limiter.
+    tokens: f64,
+    refilled_at: Instant,
+    connections: HashMap<IpAddr, usize>,
+    /// Addresses cooling down after a protocol violation, and until when.
+    cooldowns: HashMap<IpAddr, Instant>,
+    pub counters: AdmissionCounters,
+}
+
+/// How many connections were turned away and why.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AdmissionCounters {
+    pub rate_limited: u64,
+    pub over_ip_limit: u64,
+    pub cooling_down: u64,
+    pub violations: u64,
+}
+
+/// Why an inbound connection was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    RateLimited,
+    TooManyFromIp,
+    CoolingDown,
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Admission {
+    pub fn new() -> Self {
+        Self {
+            tokens: ACCEPT_BURST,
+            refilled_at: Instant::now(),
+            connections: HashMap::new(),
+            cooldowns: HashMap::new(),
+            counters: AdmissionCounters::default(),
+        }
+    }
+
+    /// Whether to serve a connection from `ip`, which then counts against its limit until
+    /// [`Admission::release`].
+    pub fn admit(&mut self, ip: IpAddr) -> Result<(), Rejection> {
+        let now = Instant::now();
+        self.cooldowns.retain(|_, until| *until > now);
+        if self.cooldowns.contains_key(&ip) {
+            self.counters.cooling_down += 1;
+            return Err(Rejection::CoolingDown);
+        }
+        if self.connections.get(&ip).copied().unwrap_or(0) >= MAX_CONNECTIONS_PER_IP {
+            self.counters.over_ip_limit += 1;
+            return Err(Rejection::TooManyFromIp);
+        }
+
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * ACCEPT_RATE).min(ACCEPT_BURST);
+        self.refilled_at = now;
+        if self.tokens < 1.0 {
+            self.counters.rate_limited += 1;
+            return Err(Rejection::RateLimited);
+        }
+        self.tokens -= 1.0;
+
+        *self.connections.entry(ip).or_default() += 1;
+        Ok(())
+    }
+
+    /// Records that a connection from `ip` ended, putting the address on cool-down if the
+    /// peer broke the protocol.
+    pub fn release(&mut self, ip: IpAddr, violated_protocol: bool) {
+        if let Some(count) = self.connections.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.connections.remove(&ip);
+            }
+        }
+        if violated_protocol {
+            self.counters.violations += 1;
+            self.cooldowns
+                .insert(ip, Instant::now() + VIOLATION_COOLDOWN);
+        }
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::RateLimited => write!(f, "accepting too many connections"),
+            Rejection::TooManyFromIp => write!(
+                f,
+                "already {MAX_CONNECTIONS_PER_IP} connections from this address"
+            ),
+            Rejection::CoolingDown => write!(f, "address recently broke the protocol"),
+        }
+    }
+}
+
+impl Display for AdmissionCounters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "refused {} rate limited, {} over the per-address limit, {} cooling down; \
+             {} protocol violation(s)",
+            self.rate_limited, self.over_ip_limit, self.cooling_down, self.violations
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn each_address_gets_a_few_connections_at_a_time() {
+        let mut admission = Admission::new();
+        for _ in 0..MAX_CONNECTIONS_PER_IP {
+            admission.admit(ip(1)).unwrap();
+        }
+        assert_eq!(admission.admit(ip(1)), Err(Rejection::TooManyFromIp));
+        // another address doesn't share the limit
+        admission.admit(ip(2)).unwrap();
+
+        admission.release(ip(1), false);
+        admission.admit(ip(1)).unwrap();
+        assert_eq!(admission.admit(ip(1)), Err(Rejection::TooManyFromIp));
+        assert_eq!(admission.counters.over_ip_limit, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_protocol_violation_turns_the_address_away_for_a_while() {
+        let mut admission = Admission::new();
+        admission.admit(ip(1)).unwrap();
+        admission.release(ip(1), true);
+        assert_eq!(admission.admit(ip(1)), Err(Rejection::CoolingDown));
+        admission.admit(ip(2)).unwrap();
+
+        tokio::time::advance(VIOLATION_COOLDOWN - Duration::from_secs(1)).await;
+        assert_eq!(admission.admit(ip(1)), Err(Rejection::CoolingDown));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        admission.admit(ip(1)).unwrap();
+        // expired entries don't linger
+        assert!(admission.cooldowns.is_empty());
+        assert_eq!(admission.counters.violations, 1);
+        assert_eq!(admission.counters.cooling_down, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accepting_is_limited_to_a_burst_then_a_steady_rate() {
+        let mut admission = Admission::new();
+        let mut accepted = 0;
+        for _ in 0..100 {
+            // reconnecting in a tight loop, so the per-address limit never applies
+            if admission.admit(ip(1)).is_ok() {
+                admission.release(ip(1), false);
+                accepted += 1;
+            }
+        }
+        assert_eq!(accepted, ACCEPT_BURST as usize);
+        assert_eq!(admission.admit(ip(2)), Err(Rejection::RateLimited));
+        assert_eq!(admission.counters.rate_limited, 61);
+
+        tokio::time::advance(Duration::from_secs_f64(1.0 / ACCEPT_RATE)).await;
+        admission.admit(ip(2)).unwrap();
+        assert_eq!(admission.admit(ip(2)), Err(Rejection::RateLimited));
+    }
+}
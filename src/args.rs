@@ -1,6 +1,21 @@
-use clap::{Parser, Subcommand};
-use std::net::SocketAddrV4;
+use bittorrent_starter_rust::dht;
+use bittorrent_starter_rust::endgame;
+use bittorrent_starter_rust::info_hash::InfoHash;
+use bittorrent_starter_rust::inventory::InventoryFormat;
+use bittorrent_starter_rust::limits::Limits;
+use bittorrent_starter_rust::netwatch;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::perms::Modes;
+use bittorrent_starter_rust::piece::{VerifyPolicy, DEFAULT_SAMPLE_FRACTION};
+use bittorrent_starter_rust::plan::DryRunAnnounce;
+use bittorrent_starter_rust::prealloc::Preallocation;
+use bittorrent_starter_rust::resume_import::ResumeFormat;
+use bittorrent_starter_rust::tracker_tls::TrackerTls;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::ffi::OsString;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration;
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -8,28 +23,348 @@ use std::path::PathBuf;
 pub struct Args {
     #[command(subcommand)]
     pub command: Command,
+    /// Dump every peer wire frame to this file, rotating it when it grows large.
+    #[arg(long, global = true)]
+    pub wire_log: Option<PathBuf>,
+    /// Contact trackers on loopback, private and link-local addresses, e.g. for testing.
+    #[arg(long = "allow-local-trackers", global = true)]
+    pub allow_local_trackers: bool,
+    /// Announce and handshake with this 20-byte peer id instead of a random one, e.g. for
+    /// reproducible tests.
+    #[arg(long = "peer-id", global = true, allow_hyphen_values = true)]
+    pub peer_id: Option<PeerId>,
+    /// Log whole info hashes rather than their first 8 hex digits.
+    #[arg(long = "log-full-ids", global = true)]
+    pub log_full_ids: bool,
+    /// Announce to this tracker instead of the torrent's own, e.g. a local mock tracker;
+    /// for `create`, the tracker the new torrent names.
+    #[arg(long, global = true)]
+    pub announce: Option<reqwest::Url>,
+    /// Connect to this peer instead of asking the tracker for peers; may be repeated.
+    #[arg(long = "peer", global = true)]
+    pub peers: Vec<SocketAddr>,
+    /// Log more on stderr: what peers and trackers do with `-v`, every block with `-vv`;
+    /// `RUST_LOG` takes precedence. `handshake` also lists the peer's extensions.
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Don't show how far a download got.
+    #[arg(long = "no-progress", global = true)]
+    pub no_progress: bool,
+    /// Keep state between sessions here, such as the peers worth trying again.
+    #[arg(long = "state-dir", global = true)]
+    pub state_dir: Option<PathBuf>,
+    /// Forget cached peers not seen for this long.
+    #[arg(
+        long = "peer-cache-max-age",
+        global = true,
+        default_value = "7days",
+        value_parser = humantime::parse_duration
+    )]
+    pub peer_cache_max_age: Duration,
+    #[command(flatten)]
+    pub limits: Limits,
+    #[command(flatten)]
+    pub tracker_tls: TrackerTls,
+    #[command(flatten)]
+    pub modes: Modes,
 }
 
 #[derive(Debug, Subcommand)]
 #[clap(rename_all = "snake_case")]
 pub enum Command {
     Decode {
-        msg: String,
+        /// Print the value as JSON.
+        #[arg(long)]
+        json: bool,
+        /// The bencoded value; on Unix its bytes are taken as they are, UTF-8 or not.
+        msg: OsString,
     },
     Info {
+        /// Print the torrent as JSON.
+        #[arg(long)]
+        json: bool,
         path: PathBuf,
     },
+    /// Hash-check data on disk against a torrent and list the pieces that don't match.
+    Verify {
+        /// The file of a single-file torrent, or the directory of a multi-file one, with or
+        /// without the torrent's name as a folder in it.
+        #[arg(long)]
+        data: PathBuf,
+        path: PathBuf,
+    },
+    /// Build a .torrent of a file, or of a directory and the files in it, that names the
+    /// tracker given with `--announce`.
+    Create {
+        /// Where to write the .torrent.
+        #[arg(short)]
+        output: PathBuf,
+        /// The size of the pieces, a power of two, e.g. `256KiB` or `1MiB`.
+        #[arg(
+            long = "piece-length",
+            default_value_t = bittorrent_starter_rust::create::DEFAULT_PIECE_LENGTH as u64,
+            value_parser = bittorrent_starter_rust::bench::parse_size
+        )]
+        piece_length: u64,
+        input: PathBuf,
+    },
     Peers {
         path: PathBuf,
     },
+    /// Find the peers of a torrent on the DHT, without asking a tracker.
+    DhtPeers {
+        /// Hex or base32.
+        info_hash: InfoHash,
+        /// A node to join the DHT through; may be repeated.
+        #[arg(long, default_value = dht::DEFAULT_BOOTSTRAP)]
+        bootstrap: Vec<String>,
+    },
+    /// Print the info hash, name and trackers of a magnet link.
+    MagnetParse {
+        link: String,
+    },
+    /// Fetch the metadata of a magnet link from its peers and print it like `info` does.
+    ///
+    /// Peers come from the link's trackers, or from --peer.
+    MagnetInfo {
+        link: String,
+    },
+    /// Ask trackers for seeder, leecher and snatch counts.
+    Scrape {
+        /// Torrent files, or hex info hashes together with --tracker.
+        #[arg(required = true)]
+        targets: Vec<String>,
+        /// The announce URL to scrape bare info hashes at.
+        #[arg(long)]
+        tracker: Option<String>,
+        /// Keep polling instead of scraping once.
+        #[arg(long)]
+        watch: bool,
+        /// How long to wait between polls, e.g. `30s` or `5m`.
+        #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+        interval: Duration,
+        /// Also append every row to this CSV file.
+        #[arg(long)]
+        csv: Option<PathBuf>,
+        /// Print a JSON object per torrent and poll instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check data that is already on disk against a torrent and record what matches for
+    /// `seed`, without copying or moving any of it.
+    AddSeed {
+        /// The file of a single-file torrent, or the directory of a multi-file one, with or
+        /// without the torrent's name as a folder in it.
+        #[arg(long)]
+        data: PathBuf,
+        /// The piece map to record the matching pieces in, for `seed --pieces`.
+        #[arg(long)]
+        pieces: PathBuf,
+        /// Record the pieces that match even if some files don't.
+        #[arg(long)]
+        partial: bool,
+        path: PathBuf,
+    },
+    /// Ask a sample of the swarm which pieces they have, to see whether the torrent can be
+    /// completed.
+    Availability {
+        /// How many of the tracker's peers to ask.
+        #[arg(long, default_value_t = 20)]
+        sample: usize,
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+        path: PathBuf,
+    },
+    /// List the torrent files in a directory with their info hash, size and trackers.
+    Inventory {
+        /// Also list the torrents in subdirectories.
+        #[arg(long, short)]
+        recursive: bool,
+        #[arg(long, value_enum, default_value_t = InventoryFormat::Csv)]
+        format: InventoryFormat,
+        dir: PathBuf,
+    },
+    /// Handshake with a peer and report how long each step took.
     Handshake {
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+        path: PathBuf,
+        peer_ip: SocketAddr,
+    },
+    /// Check a torrent file for encoding problems and suspicious metadata.
+    ///
+    /// Exits with 2 if any error was found, 1 if only warnings were found and 0 otherwise.
+    Lint {
+        /// Also check that the torrent can be used in private tracker compliance mode.
+        #[arg(long)]
+        private: bool,
+        path: PathBuf,
+    },
+    /// Show which piece and block hold a byte of the torrent's data, e.g. one a media player
+    /// reported as corrupt.
+    Locate {
+        /// The byte offset, into all files one after another unless --file is given.
+        #[arg(long)]
+        offset: usize,
+        /// Count the offset from the start of this file instead, by its index in the torrent.
+        #[arg(long)]
+        file: Option<usize>,
+        path: PathBuf,
+    },
+    /// Serve the pieces of downloaded data to other peers.
+    Seed {
+        /// The file of a single-file torrent, or the directory of a multi-file one, with or
+        /// without the torrent's name as a folder in it. Named after the torrent in the
+        /// current directory by default.
+        #[arg(long)]
+        data: Option<PathBuf>,
+        /// A piece map listing the pieces that are present, all pieces are assumed otherwise.
+        #[arg(long)]
+        pieces: Option<PathBuf>,
+        /// The port to accept peers on.
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// Take the pieces and data location from another client's resume file instead.
+        ///
+        /// With --pieces, the imported pieces are saved to that piece map.
+        #[arg(long = "import-resume", requires = "format")]
+        import_resume: Option<PathBuf>,
+        /// The format of the resume file to import.
+        #[arg(long, value_enum)]
+        format: Option<ResumeFormat>,
+        /// Hash a sample of the imported pieces before trusting them.
+        #[arg(long = "verify-imported", requires = "import_resume")]
+        verify_imported: bool,
+        /// Print what would happen, announcing at most once, without connecting to peers or
+        /// writing anything.
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+        /// How a dry run announces to the tracker.
+        #[arg(
+            long = "dry-run-announce",
+            value_enum,
+            default_value_t = DryRunAnnounce::Peers,
+            requires = "dry_run"
+        )]
+        dry_run_announce: DryRunAnnounce,
+        /// Stop after uploading this many times what was downloaded, or the torrent's size
+        /// if nothing was.
+        #[arg(long = "seed-ratio", value_parser = bittorrent_starter_rust::seed_goal::parse_ratio)]
+        seed_ratio: Option<f64>,
+        /// Stop after seeding this long, e.g. `48h`.
+        #[arg(long = "seed-time", value_parser = humantime::parse_duration)]
+        seed_time: Option<Duration>,
+        /// Take the network as changed, and reconnect, once this many peer connections
+        /// break within --rebind-window. SIGHUP reconnects right away.
+        #[arg(long = "rebind-after", default_value_t = netwatch::DEFAULT_FAILURE_BURST)]
+        rebind_after: usize,
+        /// How close together those breaks have to be, e.g. `10s`.
+        #[arg(
+            long = "rebind-window",
+            value_parser = humantime::parse_duration,
+            default_value = "10s"
+        )]
+        rebind_window: Duration,
+        path: PathBuf,
+    },
+    /// Measure raw peer wire throughput between two instances, without disk or hashing.
+    Bench {
+        /// Serve synthetic blocks to benchmark clients.
+        #[arg(long, required_unless_present = "connect", conflicts_with = "connect")]
+        listen: bool,
+        /// The port to listen on.
+        #[arg(long, default_value_t = 6881)]
+        port: u16,
+        /// Download synthetic blocks from the benchmark listener at this address.
+        #[arg(long)]
+        connect: Option<SocketAddr>,
+        /// How much data to download, e.g. `512MiB` or `1GiB`.
+        #[arg(long, default_value = "1GiB", value_parser = bittorrent_starter_rust::bench::parse_size)]
+        size: u64,
+    },
+    /// Download a whole torrent from one peer at a time, checking every piece.
+    Download {
+        /// The file to write a single-file torrent to, or the directory to create the
+        /// directory of a multi-file torrent in.
+        #[arg(short)]
+        output: PathBuf,
+        /// Print the final summary as JSON.
+        #[arg(long)]
+        json: bool,
+        /// Download only these files, by their position in the torrent's file list from 0,
+        /// e.g. `0,2`; all of them by default.
+        #[arg(long, value_delimiter = ',')]
+        files: Vec<usize>,
+        /// Which piece to fetch next.
+        #[arg(long, value_enum, default_value_t = Pick::RarestFirst)]
+        pick: Pick,
+        /// Fetch this many pieces from the first missing one on before any other, to play
+        /// the output while it downloads; `--pick` applies beyond them.
+        #[arg(long)]
+        readahead: Option<usize>,
+        /// Also write the data to stdout in order as it comes in, for a player to read; the
+        /// read-ahead window then follows how fast stdout drains, and the summary goes to
+        /// stderr.
+        #[arg(long, conflicts_with = "files")]
+        stream: bool,
+        /// Once this many blocks or fewer are left, ask every peer that has one for it and
+        /// cancel the slower copies; 0 turns this off.
+        #[arg(long = "endgame-threshold", default_value_t = endgame::DEFAULT_THRESHOLD)]
+        endgame_threshold: usize,
+        /// Run this for every file once it's complete, with `{}` replaced by its path, e.g.
+        /// `unrar x {}`; it's split on whitespace and run without a shell.
+        #[arg(long = "exec-on-file-complete", value_name = "CMD")]
+        exec_on_file_complete: Option<String>,
         path: PathBuf,
-        peer_ip: SocketAddrV4,
     },
     DownloadPiece {
         #[arg(short)]
         output: PathBuf,
+        /// Print the final summary as JSON.
+        #[arg(long)]
+        json: bool,
+        /// Which pieces to hash-check; only relax this between machines you trust.
+        #[arg(long = "verify-policy", value_enum, default_value_t = VerifyPolicy::Full)]
+        verify_policy: VerifyPolicy,
+        /// The share of pieces `--verify-policy sampled` checks.
+        #[arg(long = "sample-fraction", default_value_t = DEFAULT_SAMPLE_FRACTION)]
+        sample_fraction: f64,
+        /// Hash the written output once more, even if the policy skipped the piece.
+        #[arg(long = "final-check")]
+        final_check: bool,
+        /// Record the piece in this piece map, for `seed --pieces`.
+        #[arg(long)]
+        pieces: Option<PathBuf>,
+        /// How to allocate the output before the data arrives; by default as recorded in
+        /// the piece map, or sparse.
+        #[arg(long, value_enum)]
+        preallocation: Option<Preallocation>,
+        /// Print what would happen, announcing at most once, without connecting to peers or
+        /// writing anything.
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+        /// How a dry run announces to the tracker.
+        #[arg(
+            long = "dry-run-announce",
+            value_enum,
+            default_value_t = DryRunAnnounce::Peers,
+            requires = "dry_run"
+        )]
+        dry_run_announce: DryRunAnnounce,
         path: PathBuf,
         piece_index: usize,
     },
 }
+
+/// The piece orders `download --pick` offers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Pick {
+    /// The piece the fewest peers have.
+    RarestFirst,
+    /// Pieces in index order.
+    Sequential,
+    /// Pieces at random.
+    Random,
+}
@@ -0,0 +1,132 @@
+//! What a torrent's swarm has, as far as a sample of its peers tells, to judge whether it
+//! can be completed at all.
+
+use crate::peer::Bitfield;
+use crate::picker::Availability;
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// How long one peer gets to connect, handshake and tell us its pieces.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Once a peer announced something, how long a pause ends its announcements.
+const QUIET_PERIOD: Duration = Duration::from_secs(2);
+
+/// How pieces are spread over the peers that told us what they have.
+#[derive(Debug, Clone, Serialize)]
+pub struct AvailabilityReport {
+    /// Peers we tried to ask.
+    pub peers_tried: usize,
+    /// Peers that announced their pieces.
+    pub peers_reported: usize,
+    pub pieces: usize,
+    /// Element `n` is the number of pieces `n` of the peers have.
+    pub histogram: Vec<usize>,
+    pub distributed_copies: f64,
+}
+
+impl AvailabilityReport {
+    pub fn new(availability: &Availability, peers_tried: usize, peers_reported: usize) -> Self {
+        Self {
+            peers_tried,
+            peers_reported,
+            pieces: availability.len(),
+            histogram: availability.histogram(),
+            distributed_copies: availability.distributed_copies(),
+        }
+    }
+
+    /// Pieces with exactly `sources` sources, or with at least that many if `or_more`.
+    fn pieces_with(&self, sources: usize, or_more: bool) -> usize {
+        if or_more {
+            self.histogram.iter().skip(sources).sum()
+        } else {
+            self.histogram.get(sources).copied().unwrap_or(0)
+        }
+    }
+}
+
+/// Reads what a peer announces right after the handshake: a bitfield, `have_all` or
+/// `have_none`, and any `have`s, until it goes quiet for a moment.
+///
+/// Returns `None` if the peer announced nothing at all before `deadline`.
+pub async fn peer_pieces(
+    stream: &mut TcpStream,
+    npieces: usize,
+    max_frame: usize,
+    deadline: Instant,
+) -> anyhow::Result<Option<Vec<bool>>> {
+    let mut has: Option<Vec<bool>> = None;
+    loop {
+        let wait_until = match has {
+            Some(_) => deadline.min(Instant::now() + QUIET_PERIOD),
+            None => deadline,
+        };
+        let frame = match tokio::time::timeout_at(wait_until, read_frame(stream, max_frame)).await {
+            Ok(Ok(frame)) => frame,
+            // a peer that hung up after announcing still told us what it has
+            Ok(Err(_)) if has.is_some() => return Ok(has),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Ok(has),
+        };
+        let Some((&id, payload)) = frame.split_first() else {
+            // keep-alive
+            continue;
+        };
+        let pieces = has.get_or_insert_with(|| vec![false; npieces]);
+        match id {
+            // have
+            4 => {
+                let index = <[u8; 4]>::try_from(payload)
+                    .map(|index| u32::from_be_bytes(index) as usize)
+                    .ok()
+                    .filter(|&index| index < npieces)
+                    .context("invalid have message")?;
+                pieces[index] = true;
+            }
+            // bitfield
+            5 => {
+                let bitfield = Bitfield::from_bytes(payload, npieces)
+                    .map_err(|err| anyhow::anyhow!("invalid bitfield: {err}"))?;
+                for (index, has) in pieces.iter_mut().enumerate() {
+                    *has |= bitfield.has_piece(index);
+                }
+            }
+            // have_all and have_none, from the fast extension
+            14 => pieces.fill(true),
+            15 => pieces.fill(false),
+            // anything else says nothing about pieces, e.g. unchoke or extended
+            _ => {}
+        }
+    }
+}
+
+async fn read_frame(stream: &mut TcpStream, max_frame: usize) -> anyhow::Result<Vec<u8>> {
+    let length = stream.read_u32().await.context("read frame length")? as usize;
+    if length > max_frame {
+        bail!("frame of {length} bytes is larger than {max_frame}");
+    }
+    let mut frame = vec![0; length];
+    stream.read_exact(&mut frame).await.context("read frame")?;
+    Ok(frame)
+}
+
+impl Display for AvailabilityReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Peers: {} of {} reported their pieces",
+            self.peers_reported, self.peers_tried
+        )?;
+        writeln!(f, "Pieces: {}", self.pieces)?;
+        writeln!(f, "  no source: {}", self.pieces_with(0, false))?;
+        writeln!(f, "  1 source: {}", self.pieces_with(1, false))?;
+        writeln!(f, "  2+ sources: {}", self.pieces_with(2, true))?;
+        writeln!(f, "Distributed copies: {:.3}", self.distributed_copies)
+    }
+}
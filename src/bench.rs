@@ -0,0 +1,282 @@
+use crate::limits::Limits;
+use crate::peer::{write_deadline, Handshake, MessageFramer, MessagePayload, MessageRequest};
+use crate::peer_id::PeerId;
+use crate::stats::HumanBytes;
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use cpu_time::ProcessTime;
+use futures_util::{SinkExt, StreamExt};
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The info hash both ends of a benchmark agree on; there is no torrent behind it.
+const BENCH_INFO_HASH: [u8; 20] = *b"rbittorrent-bench!!!";
+
+/// The piece length the synthetic requests are laid out in.
+const PIECE_LENGTH: u64 = 1 << 18;
+
+/// How many requests the connecting side keeps in flight.
+const PIPELINE_DEPTH: usize = 32;
+
+/// Throughput of one benchmark connection, as seen by one end.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Block bytes sent or received, excluding message headers.
+    pub bytes: u64,
+    /// Messages sent and received.
+    pub messages: u64,
+    pub elapsed: Duration,
+    pub cpu: Duration,
+}
+
+/// Parses sizes like `512MiB`, `1GiB`, `500k` or `1000000`; single-letter units are binary.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("`{s}` does not start with a number"))?;
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "KiB" | "k" | "K" => 1 << 10,
+        "MiB" | "m" | "M" => 1 << 20,
+        "GiB" | "g" | "G" => 1 << 30,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        unit => return Err(format!("unknown size unit `{unit}`")),
+    };
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("`{s}` is too large"))
+}
+
+/// Serves synthetic blocks to every benchmark client that connects, forever.
+///
+/// Blocks come from one preallocated buffer, so nothing but the handshake, the framer and
+/// the socket is measured.
+pub async fn listen(listener: TcpListener, limits: Limits) -> anyhow::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await.context("accept peer")?;
+        tokio::spawn(async move {
+            match serve(stream, addr, &limits).await {
+                Ok(report) => eprintln!("peer {addr}: {report}"),
+                Err(err) => eprintln!("peer {addr}: {err:#}"),
+            }
+        });
+    }
+}
+
+async fn serve(mut stream: TcpStream, addr: SocketAddr, limits: &Limits) -> anyhow::Result<Report> {
+    let mut theirs = [0; Handshake::LEN];
+    stream
+        .read_exact(&mut theirs)
+        .await
+        .context("read handshake")?;
+    let handshake = Handshake::from_bytes(&theirs).map_err(anyhow::Error::msg)?;
+    if handshake.info_hash != BENCH_INFO_HASH {
+        bail!("peer is not a benchmark client");
+    }
+    let reply = Handshake::new(BENCH_INFO_HASH, PeerId::generate().0, false);
+    write_deadline(stream.write_all(&reply.to_bytes()))
+        .await
+        .context("write handshake")?;
+
+    let block = vec![0xa5; limits.max_request_length as usize];
+    let mut stream = tokio_util::codec::Framed::new(stream, MessageFramer::new(addr, limits));
+    let start = (Instant::now(), ProcessTime::now());
+    let mut bytes = 0;
+    let mut messages = 0;
+    while let Some(message) = stream.next().await {
+        let message = message.context("peer message was invalid")?;
+        messages += 1;
+        match message {
+            MessagePayload::Interested => {
+                write_deadline(stream.send(MessagePayload::Unchoke))
+                    .await
+                    .context("send unchoke")?;
+            }
+            MessagePayload::Request(request) => {
+                let length = request.length() as usize;
+                if length > block.len() {
+                    bail!("peer requested a {length} byte block");
+                }
+                let piece = MessagePayload::Piece {
+                    index: request.index(),
+                    begin: request.begin(),
+                    block: Bytes::copy_from_slice(&block[..length]),
+                };
+                write_deadline(stream.send(piece))
+                    .await
+                    .context("send piece")?;
+                bytes += length as u64;
+            }
+            _ => continue,
+        }
+        messages += 1;
+    }
+    Ok(Report {
+        bytes,
+        messages,
+        elapsed: start.0.elapsed(),
+        cpu: start.1.elapsed(),
+    })
+}
+
+/// Downloads `size` bytes of synthetic blocks from a benchmark listener at `addr`.
+///
+/// Received blocks are checked for their position and dropped, never hashed or written.
+pub async fn connect(addr: SocketAddr, size: u64, limits: &Limits) -> anyhow::Result<Report> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("connect to {addr}"))?;
+    let ours = Handshake::new(BENCH_INFO_HASH, PeerId::generate().0, false);
+    write_deadline(stream.write_all(&ours.to_bytes()))
+        .await
+        .context("write handshake")?;
+    let mut theirs = [0; Handshake::LEN];
+    stream
+        .read_exact(&mut theirs)
+        .await
+        .context("read handshake")?;
+    let handshake = Handshake::from_bytes(&theirs).map_err(anyhow::Error::msg)?;
+    if handshake.info_hash != BENCH_INFO_HASH {
+        bail!("peer is not a benchmark listener");
+    }
+
+    let mut stream = tokio_util::codec::Framed::new(stream, MessageFramer::new(addr, limits));
+    let start = (Instant::now(), ProcessTime::now());
+    write_deadline(stream.send(MessagePayload::Interested))
+        .await
+        .context("send interested")?;
+    let mut messages = 1;
+
+    let block_size = limits.block_size as u64;
+    let mut requested = 0;
+    let mut received = 0;
+    let mut unchoked = false;
+    while received < size {
+        while unchoked
+            && requested < size
+            && requested - received < PIPELINE_DEPTH as u64 * block_size
+        {
+            let length = block_size.min(size - requested);
+            let request = MessageRequest::new(
+                (requested / PIECE_LENGTH) as u32,
+                (requested % PIECE_LENGTH) as u32,
+                length as u32,
+            );
+            write_deadline(stream.feed(MessagePayload::Request(request)))
+                .await
+                .context("send request")?;
+            messages += 1;
+            requested += length;
+        }
+        write_deadline(stream.flush())
+            .await
+            .context("send requests")?;
+
+        let Some(message) = stream.next().await else {
+            bail!("listener hung up after {received} bytes");
+        };
+        let message = message.context("peer message was invalid")?;
+        messages += 1;
+        match message {
+            MessagePayload::Unchoke => unchoked = true,
+            MessagePayload::Choke => bail!("listener choked us"),
+            MessagePayload::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let (index, begin) = (index as u64, begin as u64);
+                if index * PIECE_LENGTH + begin != received {
+                    bail!("got block {index}/{begin} out of order");
+                }
+                received += block.len() as u64;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Report {
+        bytes: received,
+        messages,
+        elapsed: start.0.elapsed(),
+        cpu: start.1.elapsed(),
+    })
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "{} in {:.2}s, {}/s, {:.0} msg/s, {:.2}s CPU ({:.0}%)",
+            HumanBytes(self.bytes),
+            secs,
+            HumanBytes((self.bytes as f64 / secs) as u64),
+            self.messages as f64 / secs,
+            self.cpu.as_secs_f64(),
+            100.0 * self.cpu.as_secs_f64() / secs
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_parse_with_binary_and_decimal_units() {
+        assert_eq!(parse_size("1000000"), Ok(1_000_000));
+        assert_eq!(parse_size("512MiB"), Ok(512 << 20));
+        assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+        assert_eq!(parse_size(" 500k "), Ok(500 << 10));
+        assert_eq!(parse_size("2 MB"), Ok(2_000_000));
+        assert!(parse_size("GiB").is_err());
+        assert!(parse_size("1TiB").is_err());
+        assert!(parse_size("99999999999999GiB").is_err());
+    }
+
+    #[tokio::test]
+    async fn a_short_loopback_run_transfers_exactly_the_requested_size() {
+        let limits = Limits::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(listen(listener, limits));
+        // not a multiple of the block size, so the last request is a short one
+        let size = (4 << 20) + 1234;
+        let report = connect(addr, size, &limits).await.unwrap();
+        assert_eq!(report.bytes, size);
+        let requests = size.div_ceil(limits.block_size as u64);
+        // interested, unchoke, and a request and a piece per block
+        assert_eq!(report.messages, 2 + 2 * requests);
+        assert!(report.to_string().starts_with("4.00 MiB in "), "{report}");
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn a_peer_of_a_real_torrent_is_not_benchmarked() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut theirs = [0; Handshake::LEN];
+            stream.read_exact(&mut theirs).await.unwrap();
+            let reply = Handshake::new([1; 20], PeerId::generate().0, false);
+            stream.write_all(&reply.to_bytes()).await.unwrap();
+        });
+        let err = connect(addr, 1 << 20, &Limits::default())
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("not a benchmark listener"),
+            "{err}"
+        );
+    }
+}
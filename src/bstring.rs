@@ -0,0 +1,143 @@
+use crate::sanitize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Debug, Display, Formatter};
+
+/// A bencode byte string that is meant to be text, but isn't guaranteed to be UTF-8.
+///
+/// Older torrents often carry names in legacy encodings such as CP1251 or Shift-JIS.
+/// The raw bytes are kept as-is, so re-encoding the info dict still yields the original
+/// info hash, and are only converted to text for display or at the filesystem boundary.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct BencodeString(pub Vec<u8>);
+
+impl BencodeString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    /// Decodes the string as text, see [`decode`].
+    pub fn decode(&self, encoding: Option<&str>) -> String {
+        decode(&self.0, encoding)
+    }
+
+    /// The string as a single, safe file or directory name.
+    pub fn to_path_component(&self, encoding: Option<&str>) -> String {
+        sanitize::path_component(&self.decode(encoding))
+    }
+}
+
+/// Decodes a byte string from a torrent as text, using the torrent's `encoding` key as a
+/// hint.
+///
+/// Valid UTF-8 always wins; otherwise the named encoding is used if it is known,
+/// and invalid sequences are replaced with U+FFFD.
+pub fn decode(bytes: &[u8], encoding: Option<&str>) -> String {
+    if let Ok(s) = std::str::from_utf8(bytes) {
+        return s.to_string();
+    }
+    match encoding.and_then(|label| encoding_rs::Encoding::for_label(label.as_bytes())) {
+        Some(encoding) => encoding.decode(bytes).0.into_owned(),
+        None => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+impl From<&str> for BencodeString {
+    fn from(s: &str) -> Self {
+        Self(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for BencodeString {
+    fn from(s: String) -> Self {
+        Self(s.into_bytes())
+    }
+}
+
+impl Display for BencodeString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&String::from_utf8_lossy(&self.0), f)
+    }
+}
+
+impl Debug for BencodeString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&String::from_utf8_lossy(&self.0), f)
+    }
+}
+
+impl<'de> Deserialize<'de> for BencodeString {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        serde_bytes::ByteBuf::deserialize(deserializer).map(|bytes| Self(bytes.into_vec()))
+    }
+}
+
+impl Serialize for BencodeString {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `表示.txt` in Shift-JIS; the second byte of `表` is `\`.
+    const SHIFT_JIS: &[u8] = b"\x95\x5c\x8e\xa6.txt";
+
+    #[test]
+    fn utf8_is_taken_as_is_whatever_the_encoding_says() {
+        let name = BencodeString::from("Ünïcode.txt");
+        assert_eq!(name.as_str(), Some("Ünïcode.txt"));
+        assert_eq!(name.decode(Some("Shift_JIS")), "Ünïcode.txt");
+    }
+
+    #[test]
+    fn legacy_encodings_are_decoded_by_their_label() {
+        let name = BencodeString(SHIFT_JIS.to_vec());
+        assert_eq!(name.as_str(), None);
+        assert_eq!(name.decode(Some("Shift_JIS")), "表示.txt");
+        assert_eq!(name.decode(Some("sjis")), "表示.txt");
+        let cp1251 = BencodeString(b"\xcf\xf0\xe8\xe2\xe5\xf2".to_vec());
+        assert_eq!(cp1251.decode(Some("windows-1251")), "Привет");
+    }
+
+    #[test]
+    fn without_a_known_encoding_invalid_bytes_become_replacement_characters() {
+        let name = BencodeString(SHIFT_JIS.to_vec());
+        for encoding in [None, Some("no-such-encoding")] {
+            let decoded = name.decode(encoding);
+            assert!(decoded.contains('\u{fffd}'), "{decoded}");
+            assert!(decoded.ends_with(".txt"));
+        }
+        assert_eq!(name.to_string(), name.decode(None));
+    }
+
+    #[test]
+    fn path_components_are_sanitized_after_decoding() {
+        let name = BencodeString(SHIFT_JIS.to_vec());
+        assert_eq!(name.to_path_component(Some("Shift_JIS")), "表示.txt");
+        // read as anything but Shift-JIS, the name holds a separator
+        let lossy = name.to_path_component(None);
+        assert!(!lossy.contains('\\'), "{lossy}");
+        assert_eq!(BencodeString::from("..").to_path_component(None), "_");
+    }
+
+    #[test]
+    fn the_raw_bytes_survive_a_bencode_round_trip() {
+        let name = BencodeString(SHIFT_JIS.to_vec());
+        let encoded = serde_bencode::to_bytes(&name).unwrap();
+        assert_eq!(encoded, [b"8:".as_slice(), SHIFT_JIS].concat());
+        let decoded: BencodeString = serde_bencode::from_bytes(&encoded).unwrap();
+        assert_eq!(decoded, name);
+    }
+}
@@ -0,0 +1,172 @@
+//! Which interested peers we upload to: the few that did best over the last round, plus
+//! one picked at random now and then, so that a newcomer gets a chance to show what it can
+//! do.
+
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Peers unchoked for their rate at once, unless configured otherwise.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// How often the unchoked peers are chosen anew.
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the optimistic unchoke stays with one peer.
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A connected peer as the choker sees it.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub addr: SocketAddr,
+    /// Whether the peer wants to download from us at all.
+    pub interested: bool,
+    /// Bytes per second over the last round; what a seed sends the peer, as nothing comes
+    /// back.
+    pub rate: f64,
+    /// Whether we choke the peer now; ties in rate go to peers that aren't, so nobody is
+    /// choked for nothing.
+    pub choked: bool,
+}
+
+/// Picks the peers we upload to, as BEP 3 suggests.
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    /// The optimistically unchoked peer and since when.
+    optimistic: Option<(SocketAddr, Instant)>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            optimistic: None,
+        }
+    }
+
+    /// The peers to have unchoked at `now`: the `slots` interested ones with the highest
+    /// rate, and one other interested peer, kept for [`OPTIMISTIC_INTERVAL`] before another
+    /// is picked at random.
+    pub fn unchoked(&mut self, now: Instant, candidates: &[Candidate]) -> HashSet<SocketAddr> {
+        let mut interested: Vec<_> = candidates
+            .iter()
+            .filter(|candidate| candidate.interested)
+            .collect();
+        interested.sort_by(|a, b| b.rate.total_cmp(&a.rate).then(a.choked.cmp(&b.choked)));
+        let mut unchoked: HashSet<_> = interested
+            .iter()
+            .take(self.slots)
+            .map(|candidate| candidate.addr)
+            .collect();
+        let others: Vec<_> = interested
+            .iter()
+            .map(|candidate| candidate.addr)
+            .filter(|addr| !unchoked.contains(addr))
+            .collect();
+        // a peer that earned a regular slot, lost interest or left frees the optimistic one
+        self.optimistic = self
+            .optimistic
+            .filter(|(addr, since)| {
+                now.duration_since(*since) < OPTIMISTIC_INTERVAL && others.contains(addr)
+            })
+            .or_else(|| {
+                let addr = others.choose(&mut rand::thread_rng())?;
+                Some((*addr, now))
+            });
+        unchoked.extend(self.optimistic.map(|(addr, _)| addr));
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    /// Interested, choked peers 1 to `rates.len()` with the given rates.
+    fn candidates(rates: &[f64]) -> Vec<Candidate> {
+        rates
+            .iter()
+            .zip(1..)
+            .map(|(&rate, n)| Candidate {
+                addr: addr(n),
+                interested: true,
+                rate,
+                choked: true,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_fastest_peers_get_the_slots_and_one_more_is_optimistic() {
+        let mut choker = Choker::new(2);
+        let peers = candidates(&[10.0, 500.0, 0.0, 300.0, 20.0]);
+        let unchoked = choker.unchoked(Instant::now(), &peers);
+        assert_eq!(unchoked.len(), 3);
+        assert!(unchoked.contains(&addr(2)) && unchoked.contains(&addr(4)));
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let mut choker = Choker::new(2);
+        let mut peers = candidates(&[900.0, 5.0, 1.0]);
+        peers[0].interested = false;
+        let unchoked = choker.unchoked(Instant::now(), &peers);
+        assert_eq!(unchoked, HashSet::from([addr(2), addr(3)]));
+    }
+
+    #[test]
+    fn ties_in_rate_go_to_the_peers_already_unchoked() {
+        let mut choker = Choker::new(1);
+        let mut peers = candidates(&[0.0, 0.0]);
+        peers[1].choked = false;
+        let unchoked = choker.unchoked(Instant::now(), &peers[..]);
+        assert!(unchoked.contains(&addr(2)));
+        assert_eq!(unchoked.len(), 2);
+    }
+
+    #[test]
+    fn the_optimistic_unchoke_stays_put_until_its_interval_is_up() {
+        let mut choker = Choker::new(1);
+        let peers = candidates(&[100.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        let start = Instant::now();
+        let optimistic = |unchoked: HashSet<SocketAddr>| {
+            let others: Vec<_> = unchoked.into_iter().filter(|&a| a != addr(1)).collect();
+            assert_eq!(others.len(), 1);
+            others[0]
+        };
+        let first = optimistic(choker.unchoked(start, &peers));
+        for round in 1..3 {
+            let now = start + RECHOKE_INTERVAL * round;
+            assert_eq!(optimistic(choker.unchoked(now, &peers)), first);
+        }
+        // a new pick can land on the same peer again, but not every time
+        let rotated = (1..=16).any(|round| {
+            let now = start + OPTIMISTIC_INTERVAL * round;
+            optimistic(choker.unchoked(now, &peers)) != first
+        });
+        assert!(rotated, "the optimistic unchoke never moved on");
+    }
+
+    #[test]
+    fn an_optimistic_peer_that_earns_a_slot_frees_the_optimistic_one() {
+        let mut choker = Choker::new(1);
+        let now = Instant::now();
+        let mut peers = candidates(&[100.0, 0.0]);
+        assert_eq!(
+            choker.unchoked(now, &peers),
+            HashSet::from([addr(1), addr(2)])
+        );
+        peers[1].rate = 200.0;
+        peers.extend(candidates(&[0.0, 0.0, 0.0]).into_iter().skip(2));
+        let unchoked = choker.unchoked(now + RECHOKE_INTERVAL, &peers);
+        assert!(unchoked.contains(&addr(2)));
+        assert!(unchoked.contains(&addr(1)) || unchoked.contains(&addr(3)));
+        assert_eq!(unchoked.len(), 2);
+    }
+}
@@ -0,0 +1,1276 @@
+//! Announcing to trackers and downloading from peers, the sequence of steps the protocol
+//! takes put together: handshake, bitfield, interest and unchoke, then block requests.
+
+use crate::add_seed;
+use crate::download::{self, PeerStream};
+use crate::endgame::Endgame;
+use crate::files::{DataReader, DataWriter, FileCompleted, FileMapper, FileProgress};
+use crate::handshake::HandshakeReport;
+use crate::info_hash::InfoHash;
+use crate::layout;
+use crate::magnet::MagnetLink;
+use crate::metadata;
+use crate::peer::{self, write_deadline, Handshake, MessageFramer};
+use crate::peer_cache::{PeerCacheConfig, PeerCacheFile};
+use crate::peer_pool::PeerPool;
+use crate::peer_session::{self, PeerSession};
+use crate::picker::{RandomPicker, RarestFirst, ReadAhead, Sequential};
+use crate::rate_limit::RateLimiter;
+use crate::redact;
+use crate::torrent::{Info, Metainfo, Torrent};
+use crate::tracker::{self, AnnounceSchedule, Event, SwarmNeed, TrackerRequest};
+use crate::tracker_policy::TrackerPolicy;
+use crate::tracker_tls::TrackerTls;
+use anyhow::{bail, ensure, Context};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::{self, JoinSet};
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+pub use crate::limits::Limits;
+pub use crate::peer_id::PeerId;
+pub use crate::picker::{Availability, PickContext, PiecePicker, Priority};
+pub use crate::piece::PieceAssembler;
+pub use crate::stats::{DrainRate, TransferStats, Transferred};
+pub use crate::tracker::{TrackerClient, TrackerResponse};
+
+/// The most peers we connect to at once.
+pub const MAX_PEERS: usize = 50;
+
+/// The port we tell the tracker we accept peers on.
+pub const LISTEN_PORT: u16 = 6881;
+
+/// How long an interrupted verification may take to wind down before we stop waiting.
+const VERIFY_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// How long to wait for a cached peer, which may well be gone since.
+pub const CACHED_PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What we tell trackers is left of a magnet link's data before its size is known; not 0,
+/// which would make us a seed the tracker gives no seeds to.
+const MAGNET_LEFT: usize = 16 << 10;
+
+/// Reads and parses the torrent file at `path`.
+///
+/// With `announce`, that tracker replaces the torrent's own.
+pub fn read_torrent(path: &Path, announce: Option<&reqwest::Url>) -> anyhow::Result<Torrent> {
+    let torrent_f = std::fs::read(path).context("read torrent file")?;
+    let mut torrent = Torrent::from_bytes(&torrent_f)?;
+    if let Some(announce) = announce {
+        torrent.announce = announce.to_string();
+        torrent.announce_list = None;
+    }
+    Ok(torrent)
+}
+
+/// Hashes the pieces of `data` on a blocking thread, stopping early once `cancel` is.
+pub async fn check_data<T: Send + 'static>(
+    torrent: &Torrent,
+    data: &Path,
+    cancel: &CancellationToken,
+    check: impl FnOnce(&Torrent, &Path, &CancellationToken) -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    let mut worker = tokio::task::spawn_blocking({
+        let (torrent, data, cancel) = (torrent.clone(), data.to_path_buf(), cancel.clone());
+        move || check(&torrent, &data, &cancel)
+    });
+    tokio::select! {
+        result = &mut worker => result.context("verification worker panicked")?,
+        () = cancel.cancelled() => {
+            // a worker stuck in a slow read is left to finish on its own
+            let _ = tokio::time::timeout(VERIFY_GRACE_PERIOD, worker).await;
+            bail!("verification interrupted");
+        }
+    }
+}
+
+/// Connects to `peer` and exchanges handshakes for the torrent known by `identity`.
+///
+/// Fails if the peer answers for another torrent.
+pub async fn handshake(
+    identity: &InfoHash,
+    peer_id: PeerId,
+    peer: &SocketAddr,
+) -> anyhow::Result<(Handshake, TcpStream, HandshakeReport)> {
+    let started = Instant::now();
+    let mut tcp_stream = TcpStream::connect(peer)
+        .await
+        .with_context(|| format!("connect to peer: {peer}"))?;
+    let connected = Instant::now();
+
+    let ours = Handshake::new(identity.wire(), peer_id.0, true);
+    write_deadline(tcp_stream.write_all(&ours.to_bytes()))
+        .await
+        .context("write handshake")?;
+    let mut theirs = [0; Handshake::LEN];
+    tcp_stream
+        .read_exact(&mut theirs)
+        .await
+        .context("read handshake")?;
+    let handshake = Handshake::from_bytes(&theirs).map_err(anyhow::Error::msg)?;
+    if !identity.matches_wire(&handshake.info_hash) {
+        bail!("answered for another torrent");
+    }
+    let report = HandshakeReport::new(*peer, &handshake, connected - started, connected.elapsed());
+    Ok((handshake, tcp_stream, report))
+}
+
+/// A peer that unchoked us, ready to be asked for pieces.
+pub struct PeerConnection {
+    pub addr: SocketAddr,
+    pub stream: PeerStream,
+    /// What the peer told us so far, such as the pieces it has.
+    pub session: PeerSession,
+}
+
+/// Where to download a torrent from and what of it.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// Positions in the torrent's file list from 0; all of the files if empty.
+    pub files: Vec<usize>,
+    /// Peers to download from instead of the tracker's, which then hears nothing from us.
+    pub peers: Vec<SocketAddr>,
+    /// Which piece to fetch next.
+    pub pick: PickOrder,
+    /// How many pieces from the first missing one on are fetched before any other, so the
+    /// output can be read in order while it downloads; beyond them `pick` applies.
+    pub readahead: Option<usize>,
+    /// How fast whoever reads the output in order gets through it; the read-ahead window
+    /// grows while the download barely keeps ahead and shrinks while it easily does, see
+    /// [`ReadAhead::adjust`].
+    pub drain: Option<DrainRate>,
+    /// Hand out the output in order as it comes in, see [`DownloadEvent::InOrder`]; the
+    /// files selected must be all of them.
+    pub in_order: bool,
+    /// Once this many blocks or fewer are left, each is requested from every peer that has
+    /// it and the slower copies are cancelled, see [`endgame`](crate::endgame); 0 never.
+    pub endgame_threshold: usize,
+    /// Dial the peers that served earlier downloads of the torrent first and those that
+    /// sent bad data never, remembering how this one went, see [`PeerCacheFile`].
+    pub peer_cache: Option<PeerCacheConfig>,
+}
+
+/// Which piece a download fetches next, out of those the peer has.
+#[derive(Clone, Default)]
+pub enum PickOrder {
+    /// The one the fewest connected peers have.
+    #[default]
+    RarestFirst,
+    /// The first one by index.
+    Sequential,
+    /// Any of them.
+    Random,
+    /// Whichever the picker this makes says; every download gets a fresh one.
+    Custom(Arc<dyn Fn() -> Box<dyn PiecePicker + Send> + Send + Sync>),
+}
+
+impl PickOrder {
+    /// A picker of this order for a new download.
+    pub fn picker(&self) -> Box<dyn PiecePicker + Send> {
+        match self {
+            PickOrder::RarestFirst => Box::new(RarestFirst),
+            PickOrder::Sequential => Box::new(Sequential),
+            PickOrder::Random => Box::new(RandomPicker::new(StdRng::from_entropy())),
+            PickOrder::Custom(make) => make(),
+        }
+    }
+}
+
+impl Debug for PickOrder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PickOrder::RarestFirst => f.write_str("RarestFirst"),
+            PickOrder::Sequential => f.write_str("Sequential"),
+            PickOrder::Random => f.write_str("Random"),
+            PickOrder::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Something that happened during a download, as passed to its callback.
+#[derive(Debug)]
+pub enum DownloadEvent<'a> {
+    /// A piece was verified and written.
+    Piece(Progress<'a>),
+    /// Every peer we knew of was gone with pieces left, so the tracker was asked for more
+    /// ahead of its interval; it told us of `new_peers` we didn't know yet.
+    Reannounced { new_peers: usize },
+    /// Every piece of a file was verified and written, so it can be used before the rest
+    /// of the download is in. Follows the [`DownloadEvent::Piece`] that completed it.
+    FileCompleted(FileCompleted),
+    /// The output from where the last of these ended, as far as the pieces in reach, with
+    /// [`DownloadOptions::in_order`]; one per piece, from the first on.
+    InOrder(&'a [u8]),
+}
+
+/// How far a download got, as passed to its callback after each piece.
+#[derive(Debug)]
+pub struct Progress<'a> {
+    /// The pieces in, out of `wanted`.
+    pub done: usize,
+    pub wanted: usize,
+    pub stats: &'a TransferStats,
+    /// How far along each file is.
+    pub files: &'a FileProgress,
+    /// The peers connected.
+    pub peers: usize,
+    /// The pieces read ahead of the first missing one, with [`DownloadOptions::readahead`].
+    pub readahead: Option<usize>,
+}
+
+/// A finished download.
+#[derive(Debug)]
+pub struct DownloadOutcome {
+    pub stats: TransferStats,
+    /// Peers connected to, of the `tried` ones.
+    pub connected: usize,
+    pub tried: usize,
+    /// Peers dropped for sending a piece that failed its hash check.
+    pub banned: usize,
+}
+
+/// Announces to trackers and downloads from peers, within the same limits throughout.
+#[derive(Debug, Clone)]
+pub struct Client {
+    trackers: TrackerClient,
+    limits: Limits,
+    /// Shared by every connection we download from.
+    download_rate: RateLimiter,
+}
+
+impl Client {
+    pub fn new(trackers: TrackerClient, limits: Limits) -> Self {
+        Self {
+            trackers,
+            limits,
+            download_rate: RateLimiter::new(limits.max_down),
+        }
+    }
+
+    /// A client with a random peer id, the default limits, and trackers on public
+    /// addresses only.
+    pub fn with_defaults() -> anyhow::Result<Self> {
+        let trackers = TrackerClient::new(
+            TrackerPolicy::default(),
+            &TrackerTls::default(),
+            PeerId::generate(),
+        )?;
+        Ok(Self::new(trackers, Limits::default()))
+    }
+
+    pub fn trackers(&self) -> &TrackerClient {
+        &self.trackers
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.trackers.peer_id()
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Announces what we `transferred` of `torrent` and have left to go, and reports our
+    /// place in the swarm.
+    ///
+    /// ```no_run
+    /// # async fn run() -> anyhow::Result<()> {
+    /// use bittorrent_starter_rust::client::{self, Client, Transferred};
+    /// use bittorrent_starter_rust::tracker::SwarmNeed;
+    ///
+    /// let client = Client::with_defaults()?;
+    /// let torrent = client::read_torrent("sample.torrent".as_ref(), None)?;
+    /// let need = SwarmNeed {
+    ///     connected: 0,
+    ///     max_connections: client::MAX_PEERS,
+    ///     seeding: false,
+    ///     paused: false,
+    /// };
+    /// let left = Transferred::starting(torrent.info.keys.total_length());
+    /// let response = client
+    ///     .announce(&torrent, client::LISTEN_PORT, left, need, None)
+    ///     .await?;
+    /// for peer in response.all_peers() {
+    ///     println!("{peer}");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn announce(
+        &self,
+        torrent: &Torrent,
+        port: u16,
+        transferred: Transferred,
+        need: SwarmNeed,
+        event: Option<Event>,
+    ) -> anyhow::Result<TrackerResponse> {
+        let numwant = tracker::numwant(need, tracker::MAX_NUMWANT);
+        info!(
+            "announce with numwant={numwant}{}, {} of {} peer(s) connected",
+            event
+                .map(|event| format!(" event={event}"))
+                .unwrap_or_default(),
+            need.connected,
+            need.max_connections
+        );
+        let request = TrackerRequest {
+            numwant: Some(numwant),
+            event,
+            ..self.tracker_request(torrent, port, transferred)
+        };
+        let (_, response) = self.trackers.announce_tiers(torrent, &request).await?;
+        info!("{}", response.position(&request));
+        Ok(response)
+    }
+
+    /// Tells the tracker we are leaving the swarm, after we `transferred` what we did.
+    pub async fn announce_stopped(
+        &self,
+        torrent: &Torrent,
+        port: u16,
+        transferred: Transferred,
+    ) -> anyhow::Result<()> {
+        info!("announce with event={}", Event::Stopped);
+        let request = TrackerRequest {
+            numwant: Some(0),
+            event: Some(Event::Stopped),
+            ..self.tracker_request(torrent, port, transferred)
+        };
+        self.trackers.announce_tiers(torrent, &request).await?;
+        Ok(())
+    }
+
+    /// An announce of `torrent` after we `transferred` what we did, without an event or a
+    /// peer count; the tracker id is up to the tracker it goes to.
+    fn tracker_request(
+        &self,
+        torrent: &Torrent,
+        port: u16,
+        transferred: Transferred,
+    ) -> TrackerRequest {
+        TrackerRequest {
+            uploaded: transferred.uploaded as usize,
+            downloaded: transferred.downloaded,
+            ..TrackerRequest::new(
+                torrent.identity().wire(),
+                self.peer_id(),
+                port,
+                transferred.left,
+            )
+        }
+    }
+
+    /// Tells the tracker how far a download got, with `connected` peers downloaded from
+    /// right now, which only gets reported if it fails.
+    async fn announce_progress(
+        &self,
+        torrent: &Torrent,
+        stats: &TransferStats,
+        schedule: &mut AnnounceSchedule,
+        connected: usize,
+        event: Option<Event>,
+    ) {
+        let need = SwarmNeed {
+            connected,
+            max_connections: MAX_PEERS,
+            seeding: stats.left() == 0,
+            paused: false,
+        };
+        let announced = self
+            .announce(torrent, LISTEN_PORT, stats.transferred(), need, event)
+            .await;
+        match announced {
+            Ok(response) => schedule.announced(&response),
+            Err(err) => {
+                warn!("announce failed: {err:#}");
+                schedule.failed();
+            }
+        }
+    }
+
+    /// Asks the tracker for more peers ahead of its interval, as `pool` has run dry, if
+    /// `schedule` allows for it; waits for the tracker's minimum interval if need be.
+    ///
+    /// Returns how many peers the pool gained, or `None` without announcing at all.
+    async fn reannounce_early(
+        &self,
+        torrent: &Torrent,
+        stats: &TransferStats,
+        schedule: &mut AnnounceSchedule,
+        pool: &mut PeerPool,
+    ) -> Option<usize> {
+        let at = schedule.request_early(0, stats.left() > 0)?;
+        tokio::time::sleep_until(at).await;
+        let need = SwarmNeed {
+            connected: 0,
+            max_connections: MAX_PEERS,
+            seeding: false,
+            paused: false,
+        };
+        let announced = self
+            .announce(torrent, LISTEN_PORT, stats.transferred(), need, None)
+            .await;
+        match announced {
+            Ok(response) => {
+                schedule.announced(&response);
+                Some(pool.add(response.all_peers()))
+            }
+            Err(err) => {
+                warn!("early announce failed: {err:#}");
+                schedule.failed();
+                Some(0)
+            }
+        }
+    }
+
+    /// The peers `given`, or else those the tracker knows.
+    pub async fn find_peers(
+        &self,
+        torrent: &Torrent,
+        transferred: Transferred,
+        need: SwarmNeed,
+        given: &[SocketAddr],
+        event: Option<Event>,
+    ) -> anyhow::Result<TrackerResponse> {
+        if given.is_empty() {
+            return self
+                .announce(torrent, LISTEN_PORT, transferred, need, event)
+                .await;
+        }
+        info!("not announcing, {} peer(s) given", given.len());
+        Ok(TrackerResponse {
+            interval: 0,
+            peers: peer::Peers(given.to_vec()),
+            peers6: None,
+            warning_message: None,
+            min_interval: None,
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+        })
+    }
+
+    /// Asks the trackers of a magnet link for peers, one after the other until one answers.
+    pub async fn announce_magnet(&self, magnet: &MagnetLink) -> anyhow::Result<TrackerResponse> {
+        if magnet.trackers.is_empty() {
+            bail!("the magnet link names no trackers, give peers with --peer");
+        }
+        let request = TrackerRequest::new(
+            magnet.info_hash.wire(),
+            self.peer_id(),
+            LISTEN_PORT,
+            MAGNET_LEFT,
+        );
+        for url in &magnet.trackers {
+            match self.trackers.announce(&request, url).await {
+                Ok(response) => return Ok(response),
+                Err(err) => warn!("tracker {}: {err:#}", redact::url(url)),
+            }
+        }
+        bail!("none of the magnet link's trackers answered")
+    }
+
+    /// Fetches the info dict of the torrent known by `identity` from the first of `peers`
+    /// that sends one matching it.
+    pub async fn fetch_metadata(
+        &self,
+        identity: &InfoHash,
+        peers: &[SocketAddr],
+    ) -> anyhow::Result<Info> {
+        for &peer in peers {
+            let fetched = async {
+                let (handshake, tcp_stream, _) = handshake(identity, self.peer_id(), &peer).await?;
+                let mut stream = Framed::new(tcp_stream, MessageFramer::new(peer, &self.limits));
+                let mut stats = TransferStats::new(0);
+                // whether the torrent is private isn't known before its metadata is
+                let extensions = peer_session::DOWNLOAD_EXTENSIONS;
+                let session = peer_session::establish(
+                    &mut stream,
+                    peer,
+                    &handshake,
+                    extensions,
+                    None,
+                    &mut stats,
+                )
+                .await?;
+                metadata::fetch(&mut stream, &session, identity, &mut stats).await
+            };
+            match fetched.await {
+                Ok(info) => {
+                    info!("got the metadata from {peer}");
+                    return Ok(info);
+                }
+                Err(err) => info!("peer {peer}: {err:#}"),
+            }
+        }
+        bail!("none of the {} peer(s) sent the metadata", peers.len())
+    }
+
+    /// Takes a connection to `peer` on from the `handshake` to being unchoked, for `torrent`.
+    pub async fn open(
+        &self,
+        peer: SocketAddr,
+        handshake: &Handshake,
+        tcp_stream: TcpStream,
+        torrent: &Torrent,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<PeerConnection> {
+        let npieces = torrent.declared_pieces();
+        let framer = MessageFramer::new(peer, &self.limits.for_pieces(npieces));
+        let mut stream = Framed::new(tcp_stream, framer);
+        stats.record_wire(2 * Handshake::LEN);
+        let extensions = if torrent.is_private() {
+            peer_session::PRIVATE_DOWNLOAD_EXTENSIONS
+        } else {
+            peer_session::DOWNLOAD_EXTENSIONS
+        };
+        let mut session = peer_session::establish(
+            &mut stream,
+            peer,
+            handshake,
+            extensions,
+            Some(npieces),
+            stats,
+        )
+        .await?;
+        download::unchoked(&mut stream, &mut session, stats).await?;
+        Ok(PeerConnection {
+            addr: peer,
+            stream,
+            session,
+        })
+    }
+
+    /// Connects to `peer` for `torrent` and waits for it to unchoke us.
+    pub async fn connect(
+        &self,
+        torrent: &Torrent,
+        peer: SocketAddr,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<PeerConnection> {
+        let identity = torrent.identity();
+        async {
+            let (handshake, tcp_stream, _) = handshake(&identity, self.peer_id(), &peer).await?;
+            self.open(peer, &handshake, tcp_stream, torrent, stats)
+                .await
+        }
+        .instrument(info_span!("peer", %peer))
+        .await
+    }
+
+    /// Connects to the next peer of `pool` that answers for `torrent` and unchokes us.
+    async fn connect_next(
+        &self,
+        pool: &mut PeerPool,
+        torrent: &Torrent,
+        stats: &mut TransferStats,
+        mut cache: Option<&mut PeerCacheFile>,
+    ) -> anyhow::Result<PeerConnection> {
+        while let Some(peer) = pool.next_to_dial() {
+            match self.connect(torrent, peer, stats).await {
+                Ok(connection) => {
+                    info!("downloading from {peer}");
+                    return Ok(connection);
+                }
+                Err(err) => {
+                    info!("peer {peer}: {err:#}");
+                    if let Some(cache) = cache.as_deref_mut() {
+                        cache.failed(peer);
+                    }
+                }
+            }
+        }
+        bail!("none of the peers we know of answered")
+    }
+
+    /// Downloads piece `index` of `torrent` over `connection`, without checking its hash.
+    ///
+    /// ```no_run
+    /// # async fn run() -> anyhow::Result<()> {
+    /// use bittorrent_starter_rust::client::{self, Client, TransferStats};
+    /// use bittorrent_starter_rust::torrent::Metainfo;
+    ///
+    /// let client = Client::with_defaults()?;
+    /// let torrent = client::read_torrent("sample.torrent".as_ref(), None)?;
+    /// let mut stats = TransferStats::new(torrent.info.plength);
+    /// let peer = "127.0.0.1:6881".parse()?;
+    /// let mut connection = client.connect(&torrent, peer, &mut stats).await?;
+    /// let piece = client
+    ///     .download_piece(&torrent, &mut connection, 0, &mut stats)
+    ///     .await?
+    ///     .finish(torrent.piece_hash(0)?, true, &mut stats)?;
+    /// println!("piece 0 is {} bytes", piece.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn download_piece(
+        &self,
+        torrent: &Torrent,
+        connection: &mut PeerConnection,
+        index: usize,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<PieceAssembler> {
+        let npieces = torrent.declared_pieces();
+        ensure!(
+            index < npieces,
+            "piece {index} is out of range, the torrent has {npieces}"
+        );
+        let PeerConnection {
+            addr,
+            stream,
+            session,
+        } = connection;
+        if !session.has_piece(index) {
+            bail!("{addr} doesn't have piece {index}");
+        }
+        let piece_size = torrent.piece_size(index);
+        let block_max = self.limits.block_size;
+        let nblocks = layout::block_count(piece_size, block_max);
+        debug!("{nblocks} blocks of at most {block_max} to reach {piece_size}");
+        let assembler = download::fetch_piece(
+            stream,
+            index,
+            piece_size,
+            &self.limits,
+            &self.download_rate,
+            session,
+            stats,
+        )
+        .instrument(info_span!("piece", peer = %addr, index))
+        .await?;
+        ensure!(assembler.is_complete(), "piece {index} is missing blocks");
+        Ok(assembler)
+    }
+
+    /// Downloads `torrent` to `output`, a file for a single-file torrent or the directory
+    /// to create the files of a multi-file one in, telling `on_event` after each piece and
+    /// file and whatever else happens on the way.
+    ///
+    /// Pieces already in the output are kept. The tracker hears that we started, how far
+    /// we got now and then, and that we stopped, unless peers are given in `options`; once
+    /// every peer it gave us is gone, it's asked for more ahead of its interval.
+    /// Each piece is picked and fetched on a task of its own: a peer whose task panics is
+    /// dialed again after a backoff, see [`PeerPool::panicked`].
+    /// Once `cancel` is, the download stops with an error.
+    ///
+    /// ```no_run
+    /// # async fn run() -> anyhow::Result<()> {
+    /// use bittorrent_starter_rust::client::{self, Client, DownloadEvent, DownloadOptions};
+    /// use tokio_util::sync::CancellationToken;
+    ///
+    /// let client = Client::with_defaults()?;
+    /// let torrent = client::read_torrent("sample.torrent".as_ref(), None)?;
+    /// let outcome = client
+    ///     .download(
+    ///         &torrent,
+    ///         "sample.txt".as_ref(),
+    ///         &DownloadOptions::default(),
+    ///         |event| {
+    ///             if let DownloadEvent::Piece(progress) = event {
+    ///                 println!("{} of {} pieces", progress.done, progress.wanted);
+    ///             }
+    ///         },
+    ///         &CancellationToken::new(),
+    ///     )
+    ///     .await?;
+    /// println!("from {} peer(s)", outcome.connected);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn download(
+        &self,
+        torrent: &Torrent,
+        output: &Path,
+        options: &DownloadOptions,
+        mut on_event: impl FnMut(DownloadEvent<'_>),
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<DownloadOutcome> {
+        torrent.validate()?;
+        let npieces = torrent.declared_pieces();
+        let mut mapper = FileMapper::new(torrent, output);
+        if !options.files.is_empty() {
+            ensure!(
+                !options.in_order,
+                "the output is only handed out in order in full"
+            );
+            mapper.select(&options.files)?;
+        }
+        let wanted: Vec<usize> = (0..npieces)
+            .map(|index| mapper.selected_bytes(index, torrent.piece_size(index)))
+            .collect();
+        let mut stats = TransferStats::selective(wanted.clone());
+        // what an earlier, interrupted run left in the output needn't be fetched again
+        let resumed = check_data(torrent, output, cancel, {
+            let mapper = mapper.clone();
+            move |torrent, _, cancel| add_seed::resume(torrent, &mapper, cancel)
+        })
+        .await?
+        .have;
+        let mut files = FileProgress::new(&mapper, npieces);
+        let mut recovered = 0;
+        for index in resumed.pieces().filter(|&index| wanted[index] > 0) {
+            stats.record_verified(index, torrent.piece_size(index));
+            // files that were complete before are not news
+            files.piece_verified(index);
+            recovered += 1;
+        }
+        if recovered > 0 {
+            info!("resuming, {recovered} piece(s) already in the output");
+        }
+        let need = SwarmNeed {
+            connected: 0,
+            max_connections: MAX_PEERS,
+            seeding: false,
+            paused: false,
+        };
+        let response = self
+            .find_peers(
+                torrent,
+                stats.transferred(),
+                need,
+                &options.peers,
+                Some(Event::Started),
+            )
+            .await?;
+        // peers given stand in for the tracker
+        let mut schedule = options.peers.is_empty().then(|| {
+            let mut schedule = AnnounceSchedule::new(torrent.is_private());
+            schedule.announced(&response);
+            schedule
+        });
+        let npieces_wanted = wanted.iter().filter(|&&bytes| bytes > 0).count();
+        // the tracker hears that we left however the transfer ends
+        let transfer = async {
+            let mut cache = options
+                .peer_cache
+                .as_ref()
+                .map(|config| PeerCacheFile::open(config, &torrent.info_hash()));
+            // the peers that served us before come first
+            let mut pool = PeerPool::new(cache.iter().flat_map(PeerCacheFile::dial_order));
+            pool.add(unbanned(response.all_peers(), cache.as_ref()));
+            // the peers that served us this time, each counts once
+            let mut served = HashSet::new();
+            let mut connections = 0;
+            let mut banned = 0;
+            let mut current = None;
+            let mut in_order = options.in_order.then(|| InOrder::new(mapper.clone()));
+            let mut writer = DataWriter::create(mapper).await?;
+            let mut remaining: Vec<usize> = (0..npieces)
+                .filter(|&index| wanted[index] > 0 && !resumed.has_piece(index))
+                .collect();
+            // each turn of a peer runs on a task of its own, taking the picker along
+            let mut picker = Some(options.pick.picker());
+            let mut turns = JoinSet::new();
+            let claims = Claims::new(npieces);
+            let shared = Arc::new(torrent.clone());
+            let mut have: Vec<bool> = (0..npieces).map(|index| resumed.has_piece(index)).collect();
+            let mut priorities: Vec<_> = wanted
+                .iter()
+                .map(|&bytes| {
+                    if bytes > 0 {
+                        Priority::Normal
+                    } else {
+                        Priority::Skip
+                    }
+                })
+                .collect();
+            let mut readahead = options
+                .readahead
+                .map(|window| ReadAhead::new(npieces, window));
+            let block_size = self.limits.block_size;
+            let mut endgame: Option<Endgame> = None;
+            if let Some(in_order) = &mut in_order {
+                // what an earlier run left in the output can be read right away
+                while let Some(data) = in_order.next(torrent, &have).await? {
+                    on_event(DownloadEvent::InOrder(&data));
+                }
+            }
+            // a peer that fails is dropped and the piece asked of the next one
+            while !remaining.is_empty() {
+                let blocks_left: usize = remaining
+                    .iter()
+                    .map(|&index| layout::block_count(torrent.piece_size(index), block_size))
+                    .sum();
+                let endgame_due = blocks_left <= options.endgame_threshold
+                    && (current.is_some() || pool.has_untried());
+                if endgame.is_none() && endgame_due {
+                    // every peer we can get races for the last blocks
+                    let mut joined: Vec<_> = current.take().into_iter().collect();
+                    while joined.len() < MAX_PEERS && pool.has_untried() {
+                        match self
+                            .connect_next(&mut pool, torrent, &mut stats, cache.as_mut())
+                            .await
+                        {
+                            Ok(connection) => {
+                                connections += 1;
+                                joined.push(connection);
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    if !joined.is_empty() {
+                        info!(
+                            "endgame: {blocks_left} block(s) left, asking all of {} peer(s)",
+                            joined.len()
+                        );
+                        let threshold = options.endgame_threshold;
+                        endgame = Some(Endgame::new(
+                            torrent,
+                            &remaining,
+                            joined,
+                            self.limits,
+                            threshold,
+                        ));
+                    }
+                }
+                let (index, data) = if let Some(tail) = &mut endgame {
+                    match tail
+                        .next_piece(torrent, &self.download_rate, &mut stats)
+                        .await
+                    {
+                        Ok(piece) => piece,
+                        Err(err) => {
+                            info!("{err:#}");
+                            endgame = None;
+                            continue;
+                        }
+                    }
+                } else {
+                    if current.is_none() {
+                        let connected = loop {
+                            let dry = match self
+                                .connect_next(&mut pool, torrent, &mut stats, cache.as_mut())
+                                .await
+                            {
+                                Ok(connected) => break connected,
+                                Err(dry) => dry,
+                            };
+                            if let Some(at) = pool.next_restart() {
+                                tokio::time::sleep_until(at).await;
+                                continue;
+                            }
+                            let Some(schedule) = &mut schedule else {
+                                return Err(dry);
+                            };
+                            let reannounced = self
+                                .reannounce_early(torrent, &stats, schedule, &mut pool)
+                                .await;
+                            match reannounced {
+                                Some(new_peers) => {
+                                    on_event(DownloadEvent::Reannounced { new_peers });
+                                    if new_peers == 0 {
+                                        return Err(dry);
+                                    }
+                                }
+                                None => return Err(dry),
+                            }
+                        };
+                        connections += 1;
+                        current = Some(connected);
+                    }
+                    let connection = current.take().expect("connected above");
+                    let peer = connection.addr;
+                    if let Some(readahead) = &mut readahead {
+                        // whoever reads the output in order is held up by the first missing piece
+                        let position = (0..npieces)
+                            .find(|&index| !have[index] && priorities[index] != Priority::Skip)
+                            .unwrap_or(npieces);
+                        readahead.advance(position);
+                        if let Some(drain) =
+                            options.drain.as_ref().and_then(DrainRate::bytes_per_sec)
+                        {
+                            readahead.adjust(stats.progress().rate, drain);
+                        }
+                        readahead.apply(&mut priorities);
+                    }
+                    let turn = Turn {
+                        client: self.clone(),
+                        torrent: Arc::clone(&shared),
+                        have: have.clone(),
+                        priorities: priorities.clone(),
+                        remaining: remaining.clone(),
+                        // the peer may be downloading what we need itself
+                        wait: !pool.has_untried(),
+                        claims: claims.clone(),
+                    };
+                    let picking = picker.take().expect("back from the last turn");
+                    let run = turn.run(connection, picking, stats.clone());
+                    turns.spawn(run.instrument(info_span!("peer", %peer)));
+                    let joined = turns.join_next_with_id().await.expect("a turn was spawned");
+                    let ended = match joined {
+                        Ok((id, ended)) => {
+                            claims.release(id);
+                            ended
+                        }
+                        Err(err) => {
+                            // the piece it was after is up for grabs again
+                            let released = claims.release(err.id());
+                            let what = if err.is_panic() {
+                                "panicked"
+                            } else {
+                                "was cancelled"
+                            };
+                            match released {
+                                Some(index) => {
+                                    warn!("peer {peer}: task {what} fetching piece {index}")
+                                }
+                                None => warn!("peer {peer}: task {what}"),
+                            }
+                            // the picker went down with it
+                            picker = Some(options.pick.picker());
+                            match pool.panicked(peer) {
+                                Some(backoff) => info!("dialing {peer} again in {backoff:?}"),
+                                None => warn!("giving up on {peer}, its task keeps failing"),
+                            }
+                            continue;
+                        }
+                    };
+                    picker = Some(ended.picker);
+                    stats = ended.stats;
+                    let mut connection = ended.connection;
+                    let pex_peers = connection.session.take_pex_peers();
+                    let dht_port = connection.session.take_dht_port();
+                    // a private torrent's peers come from its trackers alone (BEP 27)
+                    if !torrent.is_private() {
+                        let learned = pool.add(unbanned(pex_peers, cache.as_ref()));
+                        if learned > 0 {
+                            info!("learned {learned} peer(s) from {peer} over PEX");
+                        }
+                        // the DHT isn't consulted during downloads yet, `dht_peers` can use it
+                        if let Some(port) = dht_port {
+                            let node = SocketAddr::new(peer.ip(), port);
+                            info!("{peer} runs a DHT node at {node}");
+                        }
+                    }
+                    match ended.fetched {
+                        Fetched::Piece { index, data } => {
+                            if let Some(cache) = &mut cache {
+                                if served.insert(peer) {
+                                    cache.succeeded(peer);
+                                }
+                            }
+                            current = Some(connection);
+                            (index, data)
+                        }
+                        Fetched::Useless => {
+                            info!("peer {peer}: has none of the pieces we still need");
+                            continue;
+                        }
+                        Fetched::Failed { err, corrupt } => {
+                            info!("peer {peer}: {err:#}");
+                            if corrupt {
+                                banned += 1;
+                                if let Some(cache) = &mut cache {
+                                    cache.ban(peer);
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                };
+                remaining.retain(|&left| left != index);
+                have[index] = true;
+                writer.write_piece(index, &data).await?;
+                info!(index, "piece verified");
+                if let Some(tail) = &mut endgame {
+                    tail.broadcast_have(index, &mut stats).await;
+                } else if let Some(connection) = &mut current {
+                    let PeerConnection {
+                        addr,
+                        stream,
+                        session,
+                    } = connection;
+                    let sent = download::send_have(stream, session, index, &mut stats).await;
+                    if let Err(err) = sent {
+                        info!("peer {addr}: {err:#}");
+                        current = None;
+                    }
+                }
+                let completed = files.piece_verified(index);
+                on_event(DownloadEvent::Piece(Progress {
+                    done: npieces_wanted - remaining.len(),
+                    wanted: npieces_wanted,
+                    stats: &stats,
+                    files: &files,
+                    peers: endgame
+                        .as_ref()
+                        .map_or(usize::from(current.is_some()), Endgame::peers),
+                    readahead: readahead.as_ref().map(ReadAhead::window),
+                }));
+                for file in completed {
+                    on_event(DownloadEvent::FileCompleted(file));
+                }
+                if let Some(in_order) = &mut in_order {
+                    in_order.held.insert(index, data);
+                    while let Some(data) = in_order.next(torrent, &have).await? {
+                        on_event(DownloadEvent::InOrder(&data));
+                    }
+                }
+                if let Some(schedule) = &mut schedule {
+                    // finishing the selection is announced right after the loop
+                    if stats.left() > 0 && Instant::now() >= schedule.next_announce() {
+                        let connected = connected_now(endgame.as_ref(), current.as_ref());
+                        self.announce_progress(torrent, &stats, schedule, connected, None)
+                            .await;
+                    }
+                }
+            }
+            writer.finish().await?;
+            let connected = connected_now(endgame.as_ref(), current.as_ref());
+            anyhow::Ok((pool.tried(), connections, banned, connected))
+        };
+        let transfer = tokio::select! {
+            transfer = transfer => transfer,
+            () = cancel.cancelled() => Err(anyhow::anyhow!("download interrupted")),
+        };
+        if let Some(schedule) = &mut schedule {
+            if let Ok((.., connected)) = transfer {
+                let event = (stats.left() == 0).then_some(Event::Completed);
+                self.announce_progress(torrent, &stats, schedule, connected, event)
+                    .await;
+            }
+            if let Err(err) = self
+                .announce_stopped(torrent, LISTEN_PORT, stats.transferred())
+                .await
+            {
+                warn!("stopped announce failed: {err:#}");
+            }
+        }
+        let (tried, connected, banned, _) = transfer?;
+        Ok(DownloadOutcome {
+            stats,
+            connected,
+            tried,
+            banned,
+        })
+    }
+}
+
+/// The `peers` not banned in `cache`.
+fn unbanned(
+    peers: Vec<SocketAddr>,
+    cache: Option<&PeerCacheFile>,
+) -> impl Iterator<Item = SocketAddr> + '_ {
+    let banned = move |peer: SocketAddr| cache.is_some_and(|cache| cache.is_banned(peer));
+    peers.into_iter().filter(move |&peer| !banned(peer))
+}
+
+/// The peers a download is getting blocks from: all of the endgame's, or the one `current`.
+fn connected_now(endgame: Option<&Endgame>, current: Option<&PeerConnection>) -> usize {
+    endgame.map_or(usize::from(current.is_some()), Endgame::peers)
+}
+
+/// The output of a download handed out in order, as far as the pieces verified reach.
+struct InOrder {
+    /// The first piece not handed out yet.
+    next: usize,
+    /// Pieces verified in this session past `next`.
+    held: BTreeMap<usize, Vec<u8>>,
+    mapper: FileMapper,
+    /// Reads the pieces that were in the output before the download started, once needed.
+    reader: Option<DataReader>,
+}
+
+impl InOrder {
+    fn new(mapper: FileMapper) -> Self {
+        Self {
+            next: 0,
+            held: BTreeMap::new(),
+            mapper,
+            reader: None,
+        }
+    }
+
+    /// The data of the next piece, if we `have` it.
+    async fn next(&mut self, torrent: &Torrent, have: &[bool]) -> anyhow::Result<Option<Vec<u8>>> {
+        let index = self.next;
+        if !have.get(index).copied().unwrap_or(false) {
+            return Ok(None);
+        }
+        let data = match self.held.remove(&index) {
+            Some(data) => data,
+            None => {
+                let reader = match &mut self.reader {
+                    Some(reader) => reader,
+                    None => self
+                        .reader
+                        .insert(DataReader::open(self.mapper.clone()).await?),
+                };
+                reader
+                    .read_block(index, 0, torrent.piece_size(index))
+                    .await?
+            }
+        };
+        self.next += 1;
+        Ok(Some(data))
+    }
+}
+
+/// The pieces the peer tasks of a download are after, so that one task leaves another's
+/// piece be and the piece of a task that died is given back.
+#[derive(Debug, Clone)]
+struct Claims(Arc<Mutex<ClaimTable>>);
+
+#[derive(Debug)]
+struct ClaimTable {
+    in_flight: Vec<bool>,
+    by_task: HashMap<task::Id, usize>,
+}
+
+impl Claims {
+    fn new(npieces: usize) -> Self {
+        Self(Arc::new(Mutex::new(ClaimTable {
+            in_flight: vec![false; npieces],
+            by_task: HashMap::new(),
+        })))
+    }
+
+    fn table(&self) -> MutexGuard<'_, ClaimTable> {
+        // the table is consistent between any two statements, so poisoning is moot
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Which pieces are claimed.
+    fn in_flight(&self) -> Vec<bool> {
+        self.table().in_flight.clone()
+    }
+
+    /// Piece `index` is being fetched by the task this is called on.
+    fn claim(&self, index: usize) {
+        let mut table = self.table();
+        table.in_flight[index] = true;
+        table.by_task.insert(task::id(), index);
+    }
+
+    /// The task `id` is done with its piece, which it returns, however it ended.
+    fn release(&self, id: task::Id) -> Option<usize> {
+        let mut table = self.table();
+        let index = table.by_task.remove(&id)?;
+        table.in_flight[index] = false;
+        Some(index)
+    }
+}
+
+/// One peer fetching one piece on a task of its own, so that a panic in it costs the
+/// download nothing but that peer.
+struct Turn {
+    client: Client,
+    torrent: Arc<Torrent>,
+    have: Vec<bool>,
+    priorities: Vec<Priority>,
+    remaining: Vec<usize>,
+    /// Whether to wait for the peer to get a piece we need if it has none.
+    wait: bool,
+    claims: Claims,
+}
+
+/// What a [`Turn`] hands back.
+struct TurnEnded {
+    connection: PeerConnection,
+    picker: Box<dyn PiecePicker + Send>,
+    stats: TransferStats,
+    fetched: Fetched,
+}
+
+enum Fetched {
+    Piece {
+        index: usize,
+        data: Vec<u8>,
+    },
+    /// The peer has none of the pieces we still need.
+    Useless,
+    Failed {
+        err: anyhow::Error,
+        /// The piece failed its hash check, which the peer is to blame for.
+        corrupt: bool,
+    },
+}
+
+impl Turn {
+    /// Picks a piece with `picker` out of those the peer of `connection` has, and fetches
+    /// and checks it.
+    async fn run(
+        self,
+        mut connection: PeerConnection,
+        mut picker: Box<dyn PiecePicker + Send>,
+        mut stats: TransferStats,
+    ) -> TurnEnded {
+        let fetched = self.fetch(&mut connection, &mut picker, &mut stats).await;
+        TurnEnded {
+            connection,
+            picker,
+            stats,
+            fetched,
+        }
+    }
+
+    async fn fetch(
+        &self,
+        connection: &mut PeerConnection,
+        picker: &mut Box<dyn PiecePicker + Send>,
+        stats: &mut TransferStats,
+    ) -> Fetched {
+        let failed = |err| Fetched::Failed {
+            err,
+            corrupt: false,
+        };
+        let npieces = self.have.len();
+        let session = &mut connection.session;
+        let peer_has: Vec<bool> = (0..npieces).map(|index| session.has_piece(index)).collect();
+        let mut availability = Availability::new(npieces);
+        availability.add_peer(&peer_has);
+        let picked = picker.pick(&PickContext {
+            have: &self.have,
+            peer_has: &peer_has,
+            availability: &availability,
+            in_flight: &self.claims.in_flight(),
+            priorities: &self.priorities,
+        });
+        let remaining = &self.remaining;
+        let at = match picked.and_then(|index| remaining.iter().position(|&at| at == index)) {
+            Some(at) => at,
+            None if !self.wait => return Fetched::Useless,
+            None => {
+                info!("waiting for {} to get a piece we need", connection.addr);
+                let stream = &mut connection.stream;
+                match download::announced(stream, remaining, session, stats).await {
+                    Ok(at) => at,
+                    Err(err) => return failed(err),
+                }
+            }
+        };
+        let index = remaining[at];
+        self.claims.claim(index);
+        let expected_hash = match self.torrent.piece_hash(index) {
+            Ok(hash) => hash,
+            Err(err) => return failed(err.into()),
+        };
+        let assembler = match self
+            .client
+            .download_piece(&self.torrent, connection, index, stats)
+            .await
+        {
+            Ok(assembler) => assembler,
+            Err(err) => return failed(err),
+        };
+        // only a peer whose data fails the hash check is to blame for it
+        match assembler.finish(expected_hash, true, stats) {
+            Ok(data) => Fetched::Piece { index, data },
+            Err(err) => Fetched::Failed {
+                err: err.context(format!("piece {index} is corrupt")),
+                corrupt: true,
+            },
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use crate::hashes::Hashes;
+use crate::torrent::{Info, Keys, Torrent, TorrentFile};
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// The `created by` value of torrents we build.
+const CREATED_BY: &str = concat!("rbittorrent/", env!("CARGO_PKG_VERSION"));
+
+/// The piece length `create` uses unless told otherwise.
+pub const DEFAULT_PIECE_LENGTH: usize = 256 << 10;
+
+/// Builds the metainfo of a torrent from the layout and content of its files.
+///
+/// The result only depends on the inputs: bencode dict keys are always emitted in sorted
+/// order and the creation date is whatever [`TorrentBuilder::creation_date`] was given,
+/// so building the same content twice yields byte-identical torrents.
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    name: String,
+    piece_length: usize,
+    announce: String,
+    announce_list: Option<Vec<Vec<String>>>,
+    creation_date: Option<i64>,
+    private: bool,
+    pad_files: bool,
+    layout: Layout,
+}
+
+#[derive(Debug, Clone)]
+enum Layout {
+    SingleFile { length: usize },
+    MultiFile { files: Vec<(Vec<String>, usize)> },
+}
+
+impl TorrentBuilder {
+    /// A single-file torrent of `length` bytes.
+    pub fn single_file(name: &str, length: usize, piece_length: usize) -> Self {
+        Self::new(name, piece_length, Layout::SingleFile { length })
+    }
+
+    /// A multi-file torrent, whose files are given as (path, length) pairs in order.
+    pub fn multi_file(name: &str, files: Vec<(Vec<String>, usize)>, piece_length: usize) -> Self {
+        Self::new(name, piece_length, Layout::MultiFile { files })
+    }
+
+    fn new(name: &str, piece_length: usize, layout: Layout) -> Self {
+        Self {
+            name: name.to_string(),
+            piece_length,
+            announce: String::new(),
+            announce_list: None,
+            creation_date: None,
+            private: false,
+            pad_files: false,
+            layout,
+        }
+    }
+
+    pub fn announce(mut self, url: &str) -> Self {
+        self.announce = url.to_string();
+        self
+    }
+
+    pub fn announce_list(mut self, tiers: Vec<Vec<String>>) -> Self {
+        self.announce_list = Some(tiers);
+        self
+    }
+
+    pub fn creation_date(mut self, secs_since_epoch: i64) -> Self {
+        self.creation_date = Some(secs_since_epoch);
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Insert BEP 47 padding files so that every file starts on a piece boundary.
+    pub fn pad_files(mut self, pad_files: bool) -> Self {
+        self.pad_files = pad_files;
+        self
+    }
+
+    /// The total length of the files' content, without padding.
+    pub fn content_length(&self) -> usize {
+        match &self.layout {
+            Layout::SingleFile { length } => *length,
+            Layout::MultiFile { files } => files.iter().map(|(_, length)| length).sum(),
+        }
+    }
+
+    /// Hashes `content`, the concatenation of all files in order, into the finished torrent.
+    pub fn build(self, mut content: impl Read) -> anyhow::Result<Torrent> {
+        if self.piece_length == 0 {
+            bail!("piece length must not be zero");
+        }
+
+        // (length, is padding) of every span of the piece stream
+        let mut spans = Vec::new();
+        let keys = match &self.layout {
+            Layout::SingleFile { length } => {
+                spans.push((*length, false));
+                Keys::SingleFile { length: *length }
+            }
+            Layout::MultiFile { files } => {
+                let mut entries = Vec::new();
+                let mut offset = 0;
+                for (i, (path, length)) in files.iter().enumerate() {
+                    if path.is_empty() {
+                        bail!("file {i} has an empty path");
+                    }
+                    let path = path.iter().map(|c| c.as_str().into()).collect();
+                    entries.push(TorrentFile::new(*length, path, None));
+                    spans.push((*length, false));
+                    offset += length;
+                    let misalignment = offset % self.piece_length;
+                    if self.pad_files && misalignment != 0 && i != files.len() - 1 {
+                        let pad = self.piece_length - misalignment;
+                        entries.push(TorrentFile::new(
+                            pad,
+                            vec![".pad".into(), pad.to_string().into()],
+                            Some("p".to_string()),
+                        ));
+                        spans.push((pad, true));
+                        offset += pad;
+                    }
+                }
+                Keys::MultiFile { files: entries }
+            }
+        };
+
+        let mut hashes = Vec::new();
+        let mut piece = Vec::with_capacity(self.piece_length);
+        for (length, is_padding) in spans {
+            let mut remaining = length;
+            while remaining > 0 {
+                let take = remaining.min(self.piece_length - piece.len());
+                let start = piece.len();
+                piece.resize(start + take, 0);
+                if !is_padding {
+                    content
+                        .read_exact(&mut piece[start..])
+                        .context("read torrent content")?;
+                }
+                remaining -= take;
+                if piece.len() == self.piece_length {
+                    hashes.push(Sha1::digest(&piece).into());
+                    piece.clear();
+                }
+            }
+        }
+        if !piece.is_empty() {
+            hashes.push(Sha1::digest(&piece).into());
+        }
+
+        let info = Info {
+            name: self.name.into(),
+            plength: self.piece_length,
+            pieces: Hashes(hashes),
+            private: self.private.then_some(1),
+            keys,
+        };
+        let mut torrent = Torrent::new(self.announce, info);
+        torrent.announce_list = self.announce_list;
+        torrent.creation_date = self.creation_date;
+        torrent.created_by = Some(CREATED_BY.to_string());
+        Ok(torrent)
+    }
+}
+
+/// Builds a torrent of `input`, a file or a directory, with pieces of `piece_length`.
+///
+/// A directory's files are taken in path order, so the same tree always gives the same
+/// info hash. Empty directories are left out, as a torrent can't describe them.
+pub fn from_path(input: &Path, announce: &str, piece_length: usize) -> anyhow::Result<Torrent> {
+    if !piece_length.is_power_of_two() {
+        bail!("the piece length must be a power of two, not {piece_length}");
+    }
+    let input = input
+        .canonicalize()
+        .with_context(|| format!("find {}", input.display()))?;
+    let name = utf8_name(&input)?;
+    let metadata =
+        std::fs::metadata(&input).with_context(|| format!("inspect {}", input.display()))?;
+    let (builder, paths) = if metadata.is_dir() {
+        let mut found = Vec::new();
+        list_files(&input, &mut Vec::new(), &mut found)?;
+        if found.is_empty() {
+            bail!("{} has no files in it", input.display());
+        }
+        let (paths, files) = found.into_iter().unzip();
+        (TorrentBuilder::multi_file(name, files, piece_length), paths)
+    } else {
+        let length = metadata.len() as usize;
+        (
+            TorrentBuilder::single_file(name, length, piece_length),
+            vec![input.clone()],
+        )
+    };
+    if builder.content_length() == 0 {
+        bail!("{} is empty, there is nothing to share", input.display());
+    }
+    builder.announce(announce).build(ConcatFiles {
+        paths: paths.into(),
+        current: None,
+    })
+}
+
+/// Adds the files below `dir` to `found`, in path order, with their path below the
+/// torrent's directory, of which `prefix` is the part down to `dir`.
+fn list_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    found: &mut Vec<(PathBuf, (Vec<String>, usize))>,
+) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("list {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("list {}", dir.display()))?;
+    entries.sort();
+    for path in entries {
+        prefix.push(utf8_name(&path)?.to_string());
+        let metadata =
+            std::fs::metadata(&path).with_context(|| format!("inspect {}", path.display()))?;
+        if metadata.is_dir() {
+            list_files(&path, prefix, found)?;
+        } else {
+            found.push((path, (prefix.clone(), metadata.len() as usize)));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// The last component of `path`, which the torrent has to hold as text.
+fn utf8_name(path: &Path) -> anyhow::Result<&str> {
+    path.file_name()
+        .with_context(|| format!("{} has no name", path.display()))?
+        .to_str()
+        .with_context(|| format!("the name of {} isn't UTF-8", path.display()))
+}
+
+/// The content of files one after the other, each opened once the one before is read.
+struct ConcatFiles {
+    paths: VecDeque<PathBuf>,
+    current: Option<File>,
+}
+
+impl Read for ConcatFiles {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(file) = &mut self.current {
+                let read = file.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+            }
+            let Some(path) = self.paths.pop_front() else {
+                return Ok(0);
+            };
+            self.current = Some(File::open(path)?);
+        }
+    }
+}
+
+/// Deterministic, non-repeating-looking content of `len` bytes, for generating test torrents.
+///
+/// Different `seed`s give different content, so files of a multi-file fixture are distinguishable.
+pub fn fixture_data(len: usize, seed: u64) -> Vec<u8> {
+    // xorshift64*, good enough to make every piece hash unique
+    let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Metainfo;
+
+    fn album() -> TorrentBuilder {
+        let files = vec![
+            (vec!["a.txt".to_string()], 1000),
+            (vec!["sub".to_string(), "b.bin".to_string()], 40_000),
+            (vec!["c.bin".to_string()], 7),
+        ];
+        TorrentBuilder::multi_file("album", files, 16384)
+    }
+
+    #[test]
+    fn the_same_content_builds_byte_identical_torrents() {
+        let data = fixture_data(41_007, 1);
+        let build = || {
+            let torrent = album()
+                .announce("http://t.example/announce")
+                .announce_list(vec![vec!["http://t.example/announce".to_string()]])
+                .creation_date(1_700_000_000)
+                .private(true)
+                .build(data.as_slice())
+                .unwrap();
+            serde_bencode::to_bytes(&torrent).unwrap()
+        };
+        let bytes = build();
+        assert_eq!(bytes, build());
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.creation_date, Some(1_700_000_000));
+        assert!(torrent.is_private());
+    }
+
+    #[test]
+    fn piece_hashes_cover_the_content_across_file_boundaries() {
+        let data = fixture_data(41_007, 1);
+        let torrent = album().build(data.as_slice()).unwrap();
+        assert_eq!(torrent.declared_pieces(), 3);
+        for (index, piece) in data.chunks(16384).enumerate() {
+            assert_eq!(
+                torrent.piece_hash(index).unwrap(),
+                &crate::piece::sha1(piece)
+            );
+        }
+    }
+
+    #[test]
+    fn padding_aligns_every_file_but_the_last_to_a_piece() {
+        let data = fixture_data(41_007, 1);
+        let torrent = album().pad_files(true).build(data.as_slice()).unwrap();
+        let Keys::MultiFile { files } = &torrent.info.keys else {
+            panic!("not multi-file");
+        };
+        let lengths: Vec<_> = files
+            .iter()
+            .map(|file| (file.length, file.is_padding()))
+            .collect();
+        assert_eq!(
+            lengths,
+            [
+                (1000, false),
+                (15_384, true),
+                (40_000, false),
+                (9152, true),
+                (7, false)
+            ]
+        );
+        assert_eq!(torrent.declared_pieces(), 5);
+        // the last piece holds nothing but the last file
+        assert_eq!(
+            torrent.piece_hash(4).unwrap(),
+            &crate::piece::sha1(&data[41_000..])
+        );
+    }
+
+    #[test]
+    fn content_shorter_than_the_layout_is_an_error() {
+        let err = album().build(&[0u8; 100][..]).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("read torrent content"),
+            "{err:#}"
+        );
+        assert!(TorrentBuilder::single_file("x", 1, 0)
+            .build(&[0u8][..])
+            .is_err());
+    }
+
+    #[test]
+    fn fixture_data_depends_on_the_seed_only() {
+        assert_eq!(fixture_data(64, 3), fixture_data(64, 3));
+        assert_ne!(fixture_data(64, 3), fixture_data(64, 4));
+        assert_eq!(fixture_data(64, 3)[..10], fixture_data(10, 3));
+    }
+}
@@ -2,118 +2,174 @@ use anyhow::{anyhow, Context};
 use serde_bencode::value::Value as BencodeValue;
 use std::collections::HashMap;
 
-pub fn decode_cmd(encoded_value: &str) -> anyhow::Result<BencodeValue> {
+pub fn decode_cmd(encoded_value: &[u8]) -> anyhow::Result<BencodeValue> {
     let (value, rest) = decode_bencoded_value(encoded_value)?;
     if rest.is_empty() {
         Ok(value)
     } else {
-        Err(anyhow!("still have decode str: {}", rest))
+        Err(anyhow!("still have decode bytes: {}", rest.escape_ascii()))
     }
 }
 
 /// # Arguments
 ///
-/// * `encoded_value`: bencoded string, may be very long
+/// * `encoded_value`: bencoded bytes, may be very long and needn't be UTF-8
 ///
-/// returns: Result of a pair (json Value, rest of input string)
-fn decode_bencoded_value(encoded_value: &str) -> anyhow::Result<(BencodeValue, &str)> {
-    let first_char = encoded_value
-        .chars()
-        .next()
-        .context("encoded_value exhausted!")?;
-    // let mut peeker = encoded_value.chars().peekable();
-    // if peeker.peek().unwrap() == &'i' {}
-    match first_char {
-        'i' => decode_bencoded_int(encoded_value),
-        '0'..='9' => decode_bencoded_string(encoded_value),
-        'l' => {
+/// returns: Result of a pair (bencode value, rest of input bytes)
+fn decode_bencoded_value(encoded_value: &[u8]) -> anyhow::Result<(BencodeValue, &[u8])> {
+    let first_byte = *encoded_value.first().context("encoded_value exhausted!")?;
+    match first_byte {
+        b'i' => decode_bencoded_int(encoded_value),
+        b'0'..=b'9' => decode_bencoded_string(encoded_value),
+        b'l' => {
             let mut values = Vec::new();
             let mut remainder = &encoded_value[1..];
-            while remainder.chars().next() != Some('e') {
+            while !remainder.starts_with(b"e") {
                 let (value, rest) = decode_bencoded_value(remainder)?;
                 values.push(value);
                 remainder = rest;
             }
-            let remainder = remainder
-                .strip_prefix('e')
-                .with_context(|| format!("Can't strip prefix `e` of str: {}", remainder))?;
-            Ok((BencodeValue::List(values), remainder))
+            Ok((BencodeValue::List(values), &remainder[1..]))
         }
-        'd' => {
+        b'd' => {
             let mut map = HashMap::new();
             let mut remainder = &encoded_value[1..];
-            while remainder.chars().next() != Some('e') {
+            while !remainder.starts_with(b"e") {
                 let decoded = decode_bencoded_value(remainder)?;
                 if let (BencodeValue::Bytes(key), rest) = decoded {
                     let (value, rest) = decode_bencoded_value(rest).with_context(|| {
-                        format!(
-                            "Can't decoded when parsed value of map, str: {}\nprev key is: {:?}",
-                            rest, key
-                        )
+                        format!("Can't decode the value of map key {}", key.escape_ascii())
                     })?;
                     map.insert(key, value);
                     remainder = rest;
                 } else {
-                    return Err(anyhow!("decoded value: {:?} is not string", decoded.0));
+                    return Err(anyhow!(
+                        "dict keys must be byte strings, not {:?}",
+                        decoded.0
+                    ));
                 }
             }
-            let remainder = remainder
-                .strip_prefix('e')
-                .with_context(|| format!("Can't strip prefix `e` of str: {}", remainder))?;
-            Ok((BencodeValue::Dict(map), remainder))
+            Ok((BencodeValue::Dict(map), &remainder[1..]))
         }
-        _ => Err(anyhow!("Encounter an invalid char: {}", encoded_value)),
+        _ => Err(anyhow!(
+            "Encounter an invalid byte: {}",
+            encoded_value.escape_ascii()
+        )),
     }
 }
 
+/// Splits `bytes` at the first `delimiter`, leaving it out of both halves.
+fn split_once(bytes: &[u8], delimiter: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|&b| b == delimiter)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
+
 // Example: "5:hello" -> "hello"
-fn decode_bencoded_string(encoded_string: &str) -> anyhow::Result<(BencodeValue, &str)> {
-    let (len, rest) = encoded_string
-        .split_once(':')
-        .with_context(|| format!("Can't split_once encoded_value: {} by `:`", encoded_string))
-        .and_then(|(len_str, rest)| {
-            let len = len_str.parse::<usize>().with_context(|| {
-                format!(
-                    "Can't parse str: {} before `:` delimiter which should be a usize",
-                    &len_str
-                )
-            })?;
-            if len > rest.len() {
-                Err(anyhow!(
-                    "Parsed len {} is bigger than rest.len {}",
-                    len,
-                    rest.len()
-                ))
-            } else {
-                Ok((len, rest))
-            }
+fn decode_bencoded_string(encoded_string: &[u8]) -> anyhow::Result<(BencodeValue, &[u8])> {
+    let (len_str, rest) = split_once(encoded_string, b':').with_context(|| {
+        format!(
+            "Can't split_once encoded_value: {} by `:`",
+            encoded_string.escape_ascii()
+        )
+    })?;
+    let len = std::str::from_utf8(len_str)
+        .ok()
+        .and_then(|len| len.parse::<usize>().ok())
+        .with_context(|| {
+            format!(
+                "Can't parse {} before `:` delimiter which should be a usize",
+                len_str.escape_ascii()
+            )
         })?;
-    Ok((BencodeValue::Bytes((&rest[..len]).into()), &rest[len..]))
+    if len > rest.len() {
+        return Err(anyhow!(
+            "Parsed len {} is bigger than rest.len {}",
+            len,
+            rest.len()
+        ));
+    }
+    let (string, rest) = rest.split_at(len);
+    Ok((BencodeValue::Bytes(string.to_vec()), rest))
 }
 
 // Example: "i42e" -> 42
 // Example: "i0e" -> 0
 // Example: "i-1e" -> -1
-fn decode_bencoded_int(encoded_int: &str) -> anyhow::Result<(BencodeValue, &str)> {
-    encoded_int
-        .strip_prefix('i')
-        .with_context(|| format!("str: {} can't strip prefix `i`", encoded_int))?
-        .split_once('e')
-        .with_context(|| format!("Can't split_once encoded_value: {} by `:`", encoded_int))
-        .and_then(|(int_str, rest)| {
-            if int_str.strip_prefix("-0").is_some() {
-                return Err(anyhow!("i-0*e is invalid"));
-            }
-            let int = int_str.parse::<i64>().with_context(|| {
-                format!(
-                    "Can't parse str : {} before `:` delimiter which should be a usize",
-                    &int_str
-                )
-            })?;
-            let int_str = int_str.strip_prefix('-').or(Some(int_str)).unwrap();
-            if int_str.starts_with('0') && int != 0 {
-                return Err(anyhow!("i(-)0*e is invalid"));
-            }
-            Ok((BencodeValue::Int(int), rest))
-        })
+fn decode_bencoded_int(encoded_int: &[u8]) -> anyhow::Result<(BencodeValue, &[u8])> {
+    let (int_str, rest) = split_once(&encoded_int[1..], b'e').with_context(|| {
+        format!(
+            "Can't split_once encoded_value: {} by `e`",
+            encoded_int.escape_ascii()
+        )
+    })?;
+    // the digits are ASCII, so anything that isn't UTF-8 isn't an integer either
+    let int_str = std::str::from_utf8(int_str)
+        .with_context(|| format!("{} is not an integer", int_str.escape_ascii()))?;
+    if int_str.starts_with("-0") {
+        return Err(anyhow!("i-0*e is invalid"));
+    }
+    let int = int_str.parse::<i64>().with_context(|| {
+        format!(
+            "Can't parse str : {} before `e` delimiter which should be an i64",
+            int_str
+        )
+    })?;
+    let digits = int_str.strip_prefix('-').unwrap_or(int_str);
+    if digits.starts_with('0') && digits.len() > 1 {
+        return Err(anyhow!("i(-)0*e is invalid"));
+    }
+    Ok((BencodeValue::Int(int), rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(value: &[u8]) -> BencodeValue {
+        BencodeValue::Bytes(value.to_vec())
+    }
+
+    #[test]
+    fn byte_strings_needn_t_be_utf8() {
+        assert_eq!(
+            decode_cmd(b"4:\x00\xff\x00\xff").unwrap(),
+            bytes(b"\x00\xff\x00\xff")
+        );
+        assert_eq!(decode_cmd(b"0:").unwrap(), bytes(b""));
+        assert_eq!(
+            decode_cmd(b"l1:\xffi7e2:a\x00e").unwrap(),
+            BencodeValue::List(vec![bytes(b"\xff"), BencodeValue::Int(7), bytes(b"a\x00")])
+        );
+        let BencodeValue::Dict(dict) =
+            decode_cmd(b"d2:\xff\x00i1e6:pieces3:\x00\x01\xffe").unwrap()
+        else {
+            panic!("not a dict");
+        };
+        assert_eq!(dict[&b"\xff\x00"[..]], BencodeValue::Int(1));
+        assert_eq!(dict[&b"pieces"[..]], bytes(b"\x00\x01\xff"));
+    }
+
+    #[test]
+    fn a_string_longer_than_the_input_is_invalid() {
+        assert!(decode_cmd(b"5:\x00\xff\x00\xff").is_err());
+        assert!(decode_cmd(b"\xff:").is_err());
+        assert!(decode_cmd(b"5hello").is_err());
+    }
+
+    #[test]
+    fn integers_are_canonical() {
+        assert_eq!(decode_cmd(b"i0e").unwrap(), BencodeValue::Int(0));
+        assert_eq!(decode_cmd(b"i-42e").unwrap(), BencodeValue::Int(-42));
+        for invalid in [&b"i-0e"[..], b"i03e", b"i-03e", b"ie", b"i4\xffe", b"i12"] {
+            assert!(decode_cmd(invalid).is_err(), "{}", invalid.escape_ascii());
+        }
+    }
+
+    #[test]
+    fn trailing_and_unterminated_input_is_invalid() {
+        assert!(decode_cmd(b"i1ei2e").is_err());
+        assert!(decode_cmd(b"li1e").is_err());
+        assert!(decode_cmd(b"di1ei2ee").is_err());
+        assert!(decode_cmd(b"").is_err());
+    }
 }
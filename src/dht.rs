@@ -0,0 +1,471 @@
+//! A read-only DHT client (BEP 5) that finds the peers of a torrent without a tracker.
+//!
+//! Queries are KRPC: bencoded dicts over UDP, matched to their responses by transaction id.
+//! We mark them read-only (BEP 43), so other nodes don't put us in their routing tables
+//! and query us; there is no routing table of our own beyond a single lookup either.
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value as BencodeValue;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// The node everybody bootstraps from.
+pub const DEFAULT_BOOTSTRAP: &str = "router.bittorrent.com:6881";
+
+/// How long a node gets to answer a query.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Queries in flight at once during a lookup, Kademlia's alpha.
+const ALPHA: usize = 3;
+
+/// The closest nodes a lookup keeps querying until all of them answered or failed,
+/// Kademlia's k.
+const K: usize = 8;
+
+/// The most queries one lookup sends, which bounds it however far away the target is.
+const MAX_QUERIES: usize = 200;
+
+/// The largest datagram we read; KRPC messages fit in well under this.
+const MAX_DATAGRAM: usize = 1500;
+
+/// A node or torrent id: a point in the DHT's 160-bit keyspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(pub [u8; 20]);
+
+/// Another node of the DHT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+/// A KRPC query, with the arguments of all the queries we send.
+#[derive(Debug, Serialize)]
+struct Query {
+    t: ByteBuf,
+    y: &'static str,
+    q: &'static str,
+    a: QueryArgs,
+    /// Read-only (BEP 43): don't add us to routing tables.
+    ro: u8,
+}
+
+#[derive(Debug, Serialize)]
+struct QueryArgs {
+    id: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+}
+
+/// A KRPC message we received: a response, an error, or a query we ignore.
+#[derive(Debug, Deserialize)]
+struct Reply {
+    t: ByteBuf,
+    y: ByteBuf,
+    #[serde(default)]
+    r: Option<ReplyBody>,
+    /// `[code, message]`.
+    #[serde(default)]
+    e: Option<Vec<BencodeValue>>,
+}
+
+/// The keys of the responses to all the queries we send.
+#[derive(Debug, Deserialize)]
+struct ReplyBody {
+    id: ByteBuf,
+    /// Compact node infos: 20 bytes of id, then 6 of address.
+    #[serde(default)]
+    nodes: Option<ByteBuf>,
+    /// Compact peer infos, from `get_peers` when the node knows peers of the torrent.
+    #[serde(default)]
+    values: Option<Vec<ByteBuf>>,
+}
+
+/// What to look up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lookup {
+    /// The nodes closest to the target.
+    FindNode,
+    /// The peers of the torrent with the target as info hash.
+    GetPeers,
+}
+
+/// What a lookup found.
+#[derive(Debug, Default)]
+pub struct LookupResult {
+    /// The closest nodes that answered, closest first.
+    pub closest: Vec<Node>,
+    /// Peers of the torrent, for a `get_peers` lookup.
+    pub peers: Vec<SocketAddr>,
+    /// Queries sent.
+    pub queried: usize,
+    /// Queries that got a response.
+    pub answered: usize,
+}
+
+/// A query waiting for its response.
+struct Pending {
+    node: Node,
+    deadline: Instant,
+}
+
+pub struct Dht {
+    socket: UdpSocket,
+    id: NodeId,
+    next_transaction: u16,
+}
+
+impl NodeId {
+    pub fn generate() -> Self {
+        let mut id = [0; 20];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut id);
+        Self(id)
+    }
+
+    /// The XOR distance to `other`, which orders as a big-endian number.
+    fn distance(&self, other: &NodeId) -> [u8; 20] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl Dht {
+    /// A client with a fresh node id on an unused UDP port.
+    pub async fn bind() -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .await
+            .context("bind DHT socket")?;
+        Ok(Self {
+            socket,
+            id: NodeId::generate(),
+            next_transaction: 0,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Asks the node at `addr` for its id.
+    pub async fn ping(&mut self, addr: SocketAddrV4) -> anyhow::Result<NodeId> {
+        let transaction = self.send(addr, "ping", QueryArgs::plain(self.id)).await?;
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (from, reply) = tokio::time::timeout(remaining, self.recv())
+                .await
+                .with_context(|| format!("node {addr} didn't answer the ping"))??;
+            if from == addr && reply.t.as_ref() == transaction {
+                return reply.into_body()?.id();
+            }
+        }
+    }
+
+    /// Pings the bootstrap nodes and looks up the nodes closest to our own id, which
+    /// gives a lookup somewhere to start from.
+    pub async fn bootstrap(&mut self, routers: &[String]) -> anyhow::Result<Vec<Node>> {
+        let mut start = Vec::new();
+        for router in routers {
+            let addrs = match tokio::net::lookup_host(router.as_str()).await {
+                Ok(addrs) => addrs,
+                Err(err) => {
+                    warn!("dht: can't resolve bootstrap node {router}: {err}");
+                    continue;
+                }
+            };
+            for addr in addrs {
+                // KRPC node infos are IPv4 only
+                let SocketAddr::V4(addr) = addr else {
+                    continue;
+                };
+                match self.ping(addr).await {
+                    Ok(id) => start.push(Node { id, addr }),
+                    Err(err) => debug!("dht: {err:#}"),
+                }
+            }
+        }
+        if start.is_empty() {
+            bail!("none of the bootstrap nodes answered");
+        }
+        let found = self.find_node(self.id, &start).await?;
+        info!(
+            "dht bootstrapped, {} of {} node(s) answered",
+            found.answered, found.queried
+        );
+        Ok(found.closest)
+    }
+
+    /// Looks up the nodes closest to `target`, starting from `start`.
+    pub async fn find_node(
+        &mut self,
+        target: NodeId,
+        start: &[Node],
+    ) -> anyhow::Result<LookupResult> {
+        self.lookup(target, Lookup::FindNode, start).await
+    }
+
+    /// Looks up the peers of the torrent with `info_hash`, starting from `start`, and
+    /// collects the peers every node on the way knows.
+    pub async fn get_peers(
+        &mut self,
+        info_hash: [u8; 20],
+        start: &[Node],
+    ) -> anyhow::Result<LookupResult> {
+        self.lookup(NodeId(info_hash), Lookup::GetPeers, start)
+            .await
+    }
+
+    /// An iterative Kademlia lookup: queries the closest nodes known, [`ALPHA`] at a time,
+    /// until the [`K`] closest have all answered or failed.
+    async fn lookup(
+        &mut self,
+        target: NodeId,
+        kind: Lookup,
+        start: &[Node],
+    ) -> anyhow::Result<LookupResult> {
+        // by distance, so the first entries are the closest
+        let mut candidates: BTreeMap<[u8; 20], Node> = start
+            .iter()
+            .map(|node| (target.distance(&node.id), *node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut answered = BTreeMap::new();
+        let mut pending: HashMap<Vec<u8>, Pending> = HashMap::new();
+        let mut peers = Vec::new();
+        let mut seen_peers = HashSet::new();
+        let mut result = LookupResult::default();
+        loop {
+            while pending.len() < ALPHA && result.queried < MAX_QUERIES {
+                // nodes that failed are gone from the candidates, so these are the closest
+                // that may still answer
+                let next = candidates
+                    .values()
+                    .take(K)
+                    .find(|node| !queried.contains(&node.addr))
+                    .copied();
+                let Some(node) = next else {
+                    break;
+                };
+                queried.insert(node.addr);
+                let args = match kind {
+                    Lookup::FindNode => QueryArgs::find_node(self.id, target),
+                    Lookup::GetPeers => QueryArgs::get_peers(self.id, target),
+                };
+                let query = match kind {
+                    Lookup::FindNode => "find_node",
+                    Lookup::GetPeers => "get_peers",
+                };
+                match self.send(node.addr, query, args).await {
+                    Ok(transaction) => {
+                        result.queried += 1;
+                        pending.insert(
+                            transaction,
+                            Pending {
+                                node,
+                                deadline: Instant::now() + QUERY_TIMEOUT,
+                            },
+                        );
+                    }
+                    Err(err) => debug!("dht: node {}: {err:#}", node.addr),
+                }
+            }
+            let Some(deadline) = pending.values().map(|pending| pending.deadline).min() else {
+                break;
+            };
+            let received = tokio::time::timeout_at(deadline, self.recv()).await;
+            let (from, reply) = match received {
+                Ok(Ok(received)) => received,
+                Ok(Err(err)) => {
+                    debug!("dht: {err:#}");
+                    continue;
+                }
+                Err(_) => {
+                    // the nodes that timed out are dropped from the candidates
+                    let now = Instant::now();
+                    pending.retain(|_, pending| {
+                        let expired = pending.deadline <= now;
+                        if expired {
+                            candidates.remove(&target.distance(&pending.node.id));
+                        }
+                        !expired
+                    });
+                    continue;
+                }
+            };
+            let Some(query) = pending.remove(reply.t.as_ref()) else {
+                // late, or a query of a node that didn't take the hint that we're read-only
+                continue;
+            };
+            if query.node.addr != from {
+                pending.insert(reply.t.to_vec(), query);
+                continue;
+            }
+            let body = match reply.into_body() {
+                Ok(body) => body,
+                Err(err) => {
+                    debug!("dht: node {from}: {err:#}");
+                    candidates.remove(&target.distance(&query.node.id));
+                    continue;
+                }
+            };
+            result.answered += 1;
+            answered.insert(target.distance(&query.node.id), query.node);
+            for node in body.nodes() {
+                candidates.entry(target.distance(&node.id)).or_insert(node);
+            }
+            for peer in body.peers() {
+                if seen_peers.insert(peer) {
+                    peers.push(peer);
+                }
+            }
+        }
+        result.closest = answered.into_values().take(K).collect();
+        result.peers = peers;
+        Ok(result)
+    }
+
+    /// Sends query `q` to `addr`, returning its transaction id.
+    async fn send(
+        &mut self,
+        addr: SocketAddrV4,
+        q: &'static str,
+        a: QueryArgs,
+    ) -> anyhow::Result<Vec<u8>> {
+        let transaction = self.next_transaction.to_be_bytes().to_vec();
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        let query = Query {
+            t: ByteBuf::from(transaction.clone()),
+            y: "q",
+            q,
+            a,
+            ro: 1,
+        };
+        let datagram = serde_bencode::to_bytes(&query).context("encode KRPC query")?;
+        self.socket
+            .send_to(&datagram, addr)
+            .await
+            .with_context(|| format!("send {q} query"))?;
+        Ok(transaction)
+    }
+
+    /// The next KRPC message from an IPv4 node; datagrams that aren't one are skipped.
+    async fn recv(&self) -> anyhow::Result<(SocketAddrV4, Reply)> {
+        let mut buf = [0; MAX_DATAGRAM];
+        loop {
+            let (len, from) = self
+                .socket
+                .recv_from(&mut buf)
+                .await
+                .context("receive from DHT socket")?;
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            if let Ok(reply) = serde_bencode::from_bytes(&buf[..len]) {
+                return Ok((from, reply));
+            }
+        }
+    }
+}
+
+impl QueryArgs {
+    fn plain(id: NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.0.to_vec()),
+            target: None,
+            info_hash: None,
+        }
+    }
+
+    fn find_node(id: NodeId, target: NodeId) -> Self {
+        Self {
+            target: Some(ByteBuf::from(target.0.to_vec())),
+            ..Self::plain(id)
+        }
+    }
+
+    fn get_peers(id: NodeId, info_hash: NodeId) -> Self {
+        Self {
+            info_hash: Some(ByteBuf::from(info_hash.0.to_vec())),
+            ..Self::plain(id)
+        }
+    }
+}
+
+impl Reply {
+    /// The response, or an error for a KRPC error or a message that is neither.
+    fn into_body(self) -> anyhow::Result<ReplyBody> {
+        match (self.y.as_ref(), self.r, self.e) {
+            (b"r", Some(body), _) => Ok(body),
+            (b"e", _, Some(error)) => {
+                let describe = |value: Option<&BencodeValue>| match value {
+                    Some(BencodeValue::Int(code)) => code.to_string(),
+                    Some(BencodeValue::Bytes(message)) => {
+                        String::from_utf8_lossy(message).into_owned()
+                    }
+                    _ => "?".to_string(),
+                };
+                bail!(
+                    "error {}: {}",
+                    describe(error.first()),
+                    describe(error.get(1))
+                )
+            }
+            _ => bail!("not a KRPC response"),
+        }
+    }
+}
+
+impl ReplyBody {
+    fn id(&self) -> anyhow::Result<NodeId> {
+        let id = <[u8; 20]>::try_from(self.id.as_ref())
+            .map_err(|_| anyhow::anyhow!("node id of {} bytes", self.id.len()))?;
+        Ok(NodeId(id))
+    }
+
+    /// The nodes in `nodes`, ignoring trailing bytes that don't form a whole node.
+    fn nodes(&self) -> Vec<Node> {
+        let Some(nodes) = &self.nodes else {
+            return Vec::new();
+        };
+        nodes
+            .chunks_exact(26)
+            .map(|node| Node {
+                id: NodeId(node[..20].try_into().expect("20 bytes")),
+                addr: compact_addr(&node[20..]),
+            })
+            .filter(|node| node.addr.port() != 0)
+            .collect()
+    }
+
+    /// The peers in `values`, skipping entries that aren't 6 bytes.
+    fn peers(&self) -> Vec<SocketAddr> {
+        self.values
+            .iter()
+            .flatten()
+            .filter(|value| value.len() == 6)
+            .map(|value| SocketAddr::V4(compact_addr(value)))
+            .collect()
+    }
+}
+
+/// A 6-byte compact IPv4 address and port.
+fn compact_addr(compact: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(compact[0], compact[1], compact[2], compact[3]),
+        u16::from_be_bytes([compact[4], compact[5]]),
+    )
+}
@@ -0,0 +1,176 @@
+//! Fetching pieces from a single peer, with several block requests in flight.
+
+use crate::layout;
+use crate::limits::Limits;
+use crate::peer::{write_deadline, MessageFramer, MessagePayload, MessageRequest, IDLE_TIMEOUT};
+use crate::peer_session::PeerSession;
+use crate::piece::PieceAssembler;
+use crate::rate_limit::RateLimiter;
+use crate::request_window::RequestWindow;
+use crate::stats::TransferStats;
+use anyhow::{bail, Context};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+use tracing::debug;
+
+pub type PeerStream = Framed<TcpStream, MessageFramer>;
+
+/// Tells the peer we are interested and waits until it unchokes us, unless it already has.
+///
+/// Whatever the peer announces in the meantime, a bitfield or `have`s, is kept track of in
+/// `session`.
+pub async fn unchoked(
+    stream: &mut PeerStream,
+    session: &mut PeerSession,
+    stats: &mut TransferStats,
+) -> anyhow::Result<()> {
+    write_deadline(stream.send(MessagePayload::Interested))
+        .await
+        .context("send interested message")?;
+    while session.choked {
+        let message = next_message(stream).await?;
+        stats.record_wire(4 + message.len());
+        session.observe(&message)?;
+    }
+    Ok(())
+}
+
+/// Tells the peer we have piece `index` now, unless `session` shows it has the piece
+/// itself: on a large swarm most peers do, and the message would be pure overhead. Those
+/// left out are counted in `stats`.
+pub async fn send_have(
+    stream: &mut PeerStream,
+    session: &PeerSession,
+    index: usize,
+    stats: &mut TransferStats,
+) -> anyhow::Result<()> {
+    if session.has_piece(index) {
+        stats.record_have_suppressed();
+        return Ok(());
+    }
+    write_deadline(stream.send(MessagePayload::Have(index as u32)))
+        .await
+        .context("send have")
+}
+
+/// Reads messages until the peer has one of `pieces`, e.g. because it announces finishing
+/// it with a `have` while downloading the torrent itself, and returns its position.
+pub async fn announced(
+    stream: &mut PeerStream,
+    pieces: &[usize],
+    session: &mut PeerSession,
+    stats: &mut TransferStats,
+) -> anyhow::Result<usize> {
+    loop {
+        if let Some(at) = pieces.iter().position(|&index| session.has_piece(index)) {
+            return Ok(at);
+        }
+        let message = next_message(stream).await?;
+        stats.record_wire(4 + message.len());
+        session.observe(&message)?;
+    }
+}
+
+/// Requests piece `index` of `piece_size` bytes block by block, within the download cap
+/// `rate`, and collects the blocks.
+///
+/// Up to the pipeline depth of `limits` requests are kept outstanding, so the connection doesn't idle for a round
+/// trip between blocks, or fewer if the peer's `reqq` says it queues fewer; blocks are
+/// matched to their requests by offset, in whatever order they arrive. The piece isn't
+/// hash-checked yet, that's up to [`PieceAssembler::finish`].
+pub async fn fetch_piece(
+    stream: &mut PeerStream,
+    index: usize,
+    piece_size: usize,
+    limits: &Limits,
+    rate: &RateLimiter,
+    session: &mut PeerSession,
+    stats: &mut TransferStats,
+) -> anyhow::Result<PieceAssembler> {
+    let block_max = limits.block_size;
+    let mut assembler = PieceAssembler::new(index, piece_size, block_max);
+    let mut window = RequestWindow::new(limits.pipeline_depth, session.reqq());
+    let mut blocks = layout::block_layout(piece_size, block_max);
+    // (begin, length) of the blocks asked for but not received yet
+    let mut outstanding = Vec::with_capacity(window.limit());
+    loop {
+        while window.has_room() {
+            let Some((begin, block_size)) = blocks.next() else {
+                break;
+            };
+            // the block is only asked for once the download cap has room for it
+            rate.acquire(block_size as usize).await;
+            let request = MessageRequest::new(index as u32, begin, block_size);
+            write_deadline(stream.send(MessagePayload::Request(request)))
+                .await
+                .with_context(|| format!("request block {begin} of piece {index}"))?;
+            debug!(begin, length = block_size, "requested block");
+            outstanding.push((begin, block_size));
+            window.sent();
+        }
+        if outstanding.is_empty() {
+            return Ok(assembler);
+        }
+        let message = next_message(stream).await?;
+        stats.record_wire(4 + message.len());
+        session.observe(&message)?;
+        let (piece_index, begin, block) = match message {
+            MessagePayload::Piece {
+                index,
+                begin,
+                block,
+            } => (index, begin, block),
+            MessagePayload::Choke => bail!("peer choked us during piece {index}"),
+            // e.g. a have for a piece it just finished, or peers over PEX
+            _ => continue,
+        };
+        let Some(at) = outstanding
+            .iter()
+            .position(|&(requested, _)| piece_index == index as u32 && requested == begin)
+        else {
+            bail!("got block {begin} of piece {piece_index}, which we didn't ask for");
+        };
+        let (_, block_size) = outstanding.swap_remove(at);
+        debug!(begin, length = block.len(), "received block");
+        if block.len() != block_size as usize {
+            bail!(
+                "block {begin} of piece {index} is {} bytes instead of {block_size}",
+                block.len()
+            );
+        }
+        assembler
+            .add_block(begin as usize, &block, stats)
+            .with_context(|| format!("store block {begin} of piece {index}"))?;
+        window.answered();
+    }
+}
+
+/// The next message from the peer.
+///
+/// While we wait, the peer gets a keep-alive whenever we have written nothing to it for
+/// [`KEEP_ALIVE_INTERVAL`](crate::peer::KEEP_ALIVE_INTERVAL). Keep-alives from the peer
+/// are returned like any other message, which is how they count as the peer being alive.
+async fn next_message(stream: &mut PeerStream) -> anyhow::Result<MessagePayload> {
+    let deadline = Instant::now() + IDLE_TIMEOUT;
+    loop {
+        let wait = stream
+            .codec()
+            .until_keep_alive()
+            .min(deadline.saturating_duration_since(Instant::now()));
+        match tokio::time::timeout(wait, stream.next()).await {
+            Ok(message) => {
+                return message
+                    .context("peer closed the connection")?
+                    .context("peer message was invalid")
+            }
+            Err(_) if Instant::now() >= deadline => {
+                bail!("peer sent nothing for {}s", IDLE_TIMEOUT.as_secs())
+            }
+            Err(_) => write_deadline(stream.send(MessagePayload::KeepAlive))
+                .await
+                .context("send keep-alive")?,
+        }
+    }
+}
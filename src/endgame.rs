@@ -0,0 +1,527 @@
+//! Endgame mode: once only a few blocks are left, each is requested from every connected
+//! peer that has it, so the tail of a download doesn't wait on the slowest peer, and the
+//! copies that lose the race are cancelled.
+
+use crate::client::PeerConnection;
+use crate::download::{self, PeerStream};
+use crate::layout;
+use crate::limits::Limits;
+use crate::peer::{write_deadline, MessagePayload, MessageRequest, IDLE_TIMEOUT};
+use crate::piece::PieceAssembler;
+use crate::rate_limit::RateLimiter;
+use crate::request_window::RequestWindow;
+use crate::stats::TransferStats;
+use crate::torrent::{Metainfo, Torrent};
+use anyhow::{anyhow, ensure, Context};
+use futures_util::future::select_all;
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// Endgame starts once this many blocks or fewer are left, unless configured otherwise.
+pub const DEFAULT_THRESHOLD: usize = 20;
+
+/// A block of a piece, as requested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Block {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+/// What a block that arrived means for the download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Arrival {
+    /// The first copy; the other peers it is requested from should get a cancel.
+    First { cancel: Vec<SocketAddr> },
+    /// Another copy of a block we already have, sent before the peer saw our cancel.
+    Duplicate,
+    /// A block that was never needed.
+    Unrequested,
+}
+
+/// The blocks a download still needs, and which peers each one is requested from.
+#[derive(Debug, Clone)]
+pub struct BlockRequests {
+    /// At most this many blocks left is the endgame.
+    threshold: usize,
+    /// The blocks not received yet, with the peers they are outstanding with.
+    pending: HashMap<Block, Vec<SocketAddr>>,
+    /// The blocks received, so that late copies are told apart from bogus ones.
+    done: HashSet<Block>,
+}
+
+impl Block {
+    /// The blocks of piece `index` of `piece_size` bytes, `block_size` each but the last.
+    pub fn of_piece(
+        index: u32,
+        piece_size: usize,
+        block_size: usize,
+    ) -> impl Iterator<Item = Self> {
+        layout::block_layout(piece_size, block_size).map(move |(begin, length)| Self {
+            index,
+            begin,
+            length,
+        })
+    }
+
+    pub fn request(&self) -> MessageRequest {
+        MessageRequest::new(self.index, self.begin, self.length)
+    }
+}
+
+impl BlockRequests {
+    /// Keeps track of `blocks`, with the endgame starting once `threshold` of them are left.
+    pub fn new(blocks: impl IntoIterator<Item = Block>, threshold: usize) -> Self {
+        Self {
+            threshold,
+            pending: blocks
+                .into_iter()
+                .map(|block| (block, Vec::new()))
+                .collect(),
+            done: HashSet::new(),
+        }
+    }
+
+    /// How many blocks haven't arrived yet.
+    pub fn left(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn in_endgame(&self) -> bool {
+        self.pending.len() <= self.threshold
+    }
+
+    /// Picks the next block to request from `peer`, which has the pieces `has` accepts, and
+    /// records the request.
+    ///
+    /// Before the endgame that is a block nobody is asked for. In the endgame it is any block
+    /// `peer` isn't asked for yet, those asked of the fewest peers first.
+    pub fn next_for(&mut self, peer: SocketAddr, has: impl Fn(u32) -> bool) -> Option<Block> {
+        let endgame = self.in_endgame();
+        let (&block, requested) = self
+            .pending
+            .iter_mut()
+            .filter(|(block, requested)| {
+                has(block.index)
+                    && if endgame {
+                        !requested.contains(&peer)
+                    } else {
+                        requested.is_empty()
+                    }
+            })
+            .min_by_key(|(&block, requested)| (requested.len(), block))?;
+        requested.push(peer);
+        Some(block)
+    }
+
+    /// Records that `block` arrived from `peer`.
+    pub fn received(&mut self, peer: SocketAddr, block: Block) -> Arrival {
+        if let Some(requested) = self.pending.remove(&block) {
+            self.done.insert(block);
+            let cancel = requested
+                .into_iter()
+                .filter(|&other| other != peer)
+                .collect();
+            return Arrival::First { cancel };
+        }
+        if self.done.contains(&block) {
+            Arrival::Duplicate
+        } else {
+            Arrival::Unrequested
+        }
+    }
+
+    /// Forgets what `peer` was asked for, as it disconnected or choked us; blocks nobody
+    /// else is asked for can be picked again.
+    pub fn peer_gone(&mut self, peer: SocketAddr) {
+        for requested in self.pending.values_mut() {
+            requested.retain(|&other| other != peer);
+        }
+    }
+
+    /// Needs `blocks` again, e.g. those of a piece that failed its hash check.
+    pub fn requeue(&mut self, blocks: impl IntoIterator<Item = Block>) {
+        for block in blocks {
+            self.done.remove(&block);
+            self.pending.entry(block).or_default();
+        }
+    }
+}
+
+/// Tells the peer we no longer want `block`, which another peer sent first.
+pub async fn send_cancel(stream: &mut PeerStream, block: Block) -> anyhow::Result<()> {
+    write_deadline(stream.send(MessagePayload::Cancel(block.request())))
+        .await
+        .with_context(|| format!("cancel block {} of piece {}", block.begin, block.index))
+}
+
+/// The tail of a download, fetched from every connected peer at once.
+pub struct Endgame {
+    requests: BlockRequests,
+    /// The pieces being assembled, by index.
+    pieces: HashMap<u32, PieceAssembler>,
+    peers: Vec<Participant>,
+    limits: Limits,
+}
+
+/// A peer taking part in the endgame.
+struct Participant {
+    connection: PeerConnection,
+    window: RequestWindow,
+    /// The blocks asked of it that neither arrived nor were cancelled yet.
+    outstanding: Vec<Block>,
+    last_heard: Instant,
+}
+
+impl Endgame {
+    /// Fetches the `pieces` of `torrent` left over `connections`, each block from every one
+    /// of them that has it, once at most `threshold` blocks are left.
+    pub fn new(
+        torrent: &Torrent,
+        pieces: &[usize],
+        connections: Vec<PeerConnection>,
+        limits: Limits,
+        threshold: usize,
+    ) -> Self {
+        let blocks = pieces.iter().flat_map(|&index| {
+            Block::of_piece(index as u32, torrent.piece_size(index), limits.block_size)
+        });
+        let pieces = pieces
+            .iter()
+            .map(|&index| {
+                let assembler =
+                    PieceAssembler::new(index, torrent.piece_size(index), limits.block_size);
+                (index as u32, assembler)
+            })
+            .collect();
+        let peers = connections
+            .into_iter()
+            .map(|connection| Participant {
+                window: RequestWindow::new(limits.pipeline_depth, connection.session.reqq()),
+                connection,
+                outstanding: Vec::new(),
+                last_heard: Instant::now(),
+            })
+            .collect();
+        Self {
+            requests: BlockRequests::new(blocks, threshold),
+            pieces,
+            peers,
+            limits,
+        }
+    }
+
+    /// The peers still taking part.
+    pub fn peers(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Fetches blocks until a piece is complete and matches its hash, and hands out its
+    /// index and data, within the download cap `rate`.
+    ///
+    /// Peers that fail are dropped; once none are left, this fails.
+    pub async fn next_piece(
+        &mut self,
+        torrent: &Torrent,
+        rate: &RateLimiter,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<(usize, Vec<u8>)> {
+        loop {
+            self.request_more(rate).await;
+            ensure!(!self.peers.is_empty(), "every peer left during the endgame");
+            let now = Instant::now();
+            let wait = self
+                .peers
+                .iter()
+                .map(|peer| {
+                    let idle = (peer.last_heard + IDLE_TIMEOUT).saturating_duration_since(now);
+                    peer.connection.stream.codec().until_keep_alive().min(idle)
+                })
+                .min()
+                .unwrap_or_default();
+            let next = select_all(
+                self.peers
+                    .iter_mut()
+                    .map(|peer| peer.connection.stream.next()),
+            )
+            .map(|(message, at, _)| (message, at));
+            let Ok((message, at)) = tokio::time::timeout(wait, next).await else {
+                self.keep_alive().await;
+                continue;
+            };
+            let message = match message {
+                Some(Ok(message)) => message,
+                Some(Err(err)) => {
+                    self.drop_peer(at, anyhow!(err).context("peer message was invalid"));
+                    continue;
+                }
+                None => {
+                    self.drop_peer(at, anyhow!("peer closed the connection"));
+                    continue;
+                }
+            };
+            match self.handle(at, message, stats).await {
+                Ok(Some(index)) => {
+                    if let Some(data) = self.finish(torrent, index, stats) {
+                        return Ok((index as usize, data));
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => self.drop_peer(at, err),
+            }
+        }
+    }
+
+    /// Fills the request window of every peer with blocks it has that it isn't asked for.
+    async fn request_more(&mut self, rate: &RateLimiter) {
+        let mut at = 0;
+        while at < self.peers.len() {
+            let peer = &mut self.peers[at];
+            let session = &peer.connection.session;
+            let mut sent = Ok(());
+            while peer.window.has_room() {
+                let has = |index: u32| session.has_piece(index as usize);
+                let Some(block) = self.requests.next_for(peer.connection.addr, has) else {
+                    break;
+                };
+                rate.acquire(block.length as usize).await;
+                let request = MessagePayload::Request(block.request());
+                sent = write_deadline(peer.connection.stream.send(request))
+                    .await
+                    .with_context(|| {
+                        format!("request block {} of piece {}", block.begin, block.index)
+                    });
+                if sent.is_err() {
+                    break;
+                }
+                debug!(peer = %peer.connection.addr, ?block, "requested block");
+                peer.outstanding.push(block);
+                peer.window.sent();
+            }
+            match sent {
+                Ok(()) => at += 1,
+                Err(err) => self.drop_peer(at, err),
+            }
+        }
+    }
+
+    /// Takes in `message` from the peer at `at`, and tells which piece it completed, if any.
+    async fn handle(
+        &mut self,
+        at: usize,
+        message: MessagePayload,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<Option<u32>> {
+        let peer = &mut self.peers[at];
+        let addr = peer.connection.addr;
+        stats.record_wire(4 + message.len());
+        peer.last_heard = Instant::now();
+        peer.connection.session.observe(&message)?;
+        let (index, begin, data) = match message {
+            MessagePayload::Piece {
+                index,
+                begin,
+                block,
+            } => (index, begin, block),
+            MessagePayload::Choke => anyhow::bail!("peer choked us during the endgame"),
+            _ => return Ok(None),
+        };
+        let block = Block {
+            index,
+            begin,
+            length: data.len() as u32,
+        };
+        if let Some(asked) = peer.outstanding.iter().position(|&asked| asked == block) {
+            peer.outstanding.swap_remove(asked);
+            peer.window.answered();
+        }
+        let cancel = match self.requests.received(addr, block) {
+            Arrival::First { cancel } => cancel,
+            Arrival::Duplicate => {
+                debug!(peer = %addr, ?block, "duplicate block");
+                stats.record_duplicate(data.len());
+                return Ok(None);
+            }
+            Arrival::Unrequested => {
+                anyhow::bail!("got block {begin} of piece {index}, which we didn't ask for")
+            }
+        };
+        let assembler = self
+            .pieces
+            .get_mut(&index)
+            .context("block of a piece we don't need")?;
+        assembler
+            .add_block(begin as usize, &data, stats)
+            .with_context(|| format!("store block {begin} of piece {index}"))?;
+        let complete = assembler.is_complete();
+        for other in cancel {
+            let Some(at) = self.peers.iter().position(|p| p.connection.addr == other) else {
+                continue;
+            };
+            let peer = &mut self.peers[at];
+            if let Some(asked) = peer.outstanding.iter().position(|&asked| asked == block) {
+                peer.outstanding.swap_remove(asked);
+                peer.window.answered();
+            }
+            debug!(peer = %other, ?block, "cancelling block");
+            if let Err(err) = send_cancel(&mut peer.connection.stream, block).await {
+                self.drop_peer(at, err);
+            }
+        }
+        Ok(complete.then_some(index))
+    }
+
+    /// Tells the peers taking part that we have piece `index` now, see
+    /// [`download::send_have`]; those that can't be told are dropped.
+    pub async fn broadcast_have(&mut self, index: usize, stats: &mut TransferStats) {
+        let mut at = 0;
+        while at < self.peers.len() {
+            let connection = &mut self.peers[at].connection;
+            let sent =
+                download::send_have(&mut connection.stream, &connection.session, index, stats)
+                    .await;
+            match sent {
+                Ok(()) => at += 1,
+                Err(err) => self.drop_peer(at, err),
+            }
+        }
+    }
+
+    /// Checks complete piece `index` against its hash; one that fails is fetched again.
+    fn finish(
+        &mut self,
+        torrent: &Torrent,
+        index: u32,
+        stats: &mut TransferStats,
+    ) -> Option<Vec<u8>> {
+        let assembler = self.pieces.remove(&index)?;
+        let piece_size = torrent.piece_size(index as usize);
+        let checked = torrent
+            .piece_hash(index as usize)
+            .map_err(anyhow::Error::from)
+            .and_then(|hash| assembler.finish(hash, true, stats));
+        match checked {
+            Ok(data) => Some(data),
+            Err(err) => {
+                info!("piece {index} is corrupt: {err:#}");
+                let block_size = self.limits.block_size;
+                self.requests
+                    .requeue(Block::of_piece(index, piece_size, block_size));
+                let assembler = PieceAssembler::new(index as usize, piece_size, block_size);
+                self.pieces.insert(index, assembler);
+                None
+            }
+        }
+    }
+
+    /// Sends a keep-alive to the peers we haven't written to in a while, and drops those we
+    /// haven't heard from for [`IDLE_TIMEOUT`].
+    async fn keep_alive(&mut self) {
+        let mut at = 0;
+        while at < self.peers.len() {
+            let peer = &mut self.peers[at];
+            let checked = if peer.last_heard.elapsed() >= IDLE_TIMEOUT {
+                Err(anyhow!("peer sent nothing for {}s", IDLE_TIMEOUT.as_secs()))
+            } else if peer.connection.stream.codec().until_keep_alive().is_zero() {
+                write_deadline(peer.connection.stream.send(MessagePayload::KeepAlive))
+                    .await
+                    .context("send keep-alive")
+            } else {
+                Ok(())
+            };
+            match checked {
+                Ok(()) => at += 1,
+                Err(err) => self.drop_peer(at, err),
+            }
+        }
+    }
+
+    /// Lets go of the peer at `at`; the blocks asked of it are left to the others.
+    fn drop_peer(&mut self, at: usize, err: anyhow::Error) {
+        let peer = self.peers.swap_remove(at);
+        info!("peer {}: {err:#}", peer.connection.addr);
+        self.requests.peer_gone(peer.connection.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, n], 6881))
+    }
+
+    fn blocks(nblocks: u32) -> Vec<Block> {
+        Block::of_piece(0, nblocks as usize * 16384, 16384).collect()
+    }
+
+    #[test]
+    fn the_endgame_starts_at_the_threshold() {
+        let mut requests = BlockRequests::new(blocks(3), 2);
+        assert!(!requests.in_endgame());
+        let first = requests.next_for(peer(1), |_| true).unwrap();
+        requests.received(peer(1), first);
+        assert_eq!(requests.left(), 2);
+        assert!(requests.in_endgame());
+    }
+
+    #[test]
+    fn before_the_endgame_a_block_is_asked_of_one_peer() {
+        let mut requests = BlockRequests::new(blocks(2), 0);
+        let a = requests.next_for(peer(1), |_| true).unwrap();
+        let b = requests.next_for(peer(2), |_| true).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(requests.next_for(peer(3), |_| true), None);
+    }
+
+    #[test]
+    fn in_the_endgame_every_peer_that_has_a_block_is_asked_for_it() {
+        let mut requests = BlockRequests::new(blocks(2), 20);
+        let mut asked = |n| {
+            let mut got: Vec<_> =
+                std::iter::from_fn(|| requests.next_for(peer(n), |_| true)).collect();
+            got.sort();
+            got
+        };
+        assert_eq!(asked(1), blocks(2));
+        assert_eq!(asked(2), blocks(2));
+        assert_eq!(requests.next_for(peer(3), |index| index != 0), None);
+    }
+
+    #[test]
+    fn the_first_copy_cancels_the_others_and_later_ones_are_duplicates() {
+        let mut requests = BlockRequests::new(blocks(1), 20);
+        let block = requests.next_for(peer(1), |_| true).unwrap();
+        requests.next_for(peer(2), |_| true).unwrap();
+        requests.next_for(peer(3), |_| true).unwrap();
+        assert_eq!(
+            requests.received(peer(2), block),
+            Arrival::First {
+                cancel: vec![peer(1), peer(3)]
+            }
+        );
+        assert_eq!(requests.received(peer(1), block), Arrival::Duplicate);
+        assert_eq!(requests.left(), 0);
+        let bogus = Block { length: 1, ..block };
+        assert_eq!(requests.received(peer(1), bogus), Arrival::Unrequested);
+    }
+
+    #[test]
+    fn a_gone_peer_is_not_cancelled_and_requeued_blocks_are_needed_again() {
+        let mut requests = BlockRequests::new(blocks(1), 20);
+        let block = requests.next_for(peer(1), |_| true).unwrap();
+        requests.next_for(peer(2), |_| true).unwrap();
+        requests.peer_gone(peer(1));
+        assert_eq!(
+            requests.received(peer(2), block),
+            Arrival::First { cancel: vec![] }
+        );
+        requests.requeue([block]);
+        assert_eq!(requests.left(), 1);
+        assert_eq!(requests.next_for(peer(2), |_| true), Some(block));
+    }
+}
@@ -0,0 +1,451 @@
+//! Typed forms of the bencoded dicts that extension messages (BEP 10) carry.
+//!
+//! Parsing is strict about types but forgiving about content: unknown keys are ignored,
+//! as other clients add their own freely, while a known key of the wrong type rejects the
+//! whole message.
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+/// The size of every metadata piece but the last, fixed by BEP 9.
+pub const METADATA_PIECE_SIZE: usize = 16 << 10;
+
+/// The most peers one `ut_pex` message adds or drops; libtorrent ignores the excess.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// A bencoded dict carried in a message payload.
+pub trait BencodeDict: Serialize + DeserializeOwned {
+    fn from_bencode(payload: &[u8]) -> anyhow::Result<Self> {
+        let (dict, rest) = Self::from_bencode_prefix(payload)?;
+        if !rest.is_empty() {
+            anyhow::bail!("{} bytes after the dict", rest.len());
+        }
+        Ok(dict)
+    }
+
+    /// Parses the dict at the start of `payload`, returning what follows it, like the
+    /// metadata piece after a `ut_metadata` data message.
+    fn from_bencode_prefix(payload: &[u8]) -> anyhow::Result<(Self, &[u8])> {
+        let mut rest = payload;
+        let dict = Self::deserialize(&mut serde_bencode::Deserializer::new(&mut rest))
+            .context("parse extension message")?;
+        Ok((dict, rest))
+    }
+
+    fn to_bencode(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("extension messages always encode")
+    }
+}
+
+/// The payload of the extended handshake, message 20/0.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names and the message ids the sender wants them sent with; 0 disables one.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// The sender's listen port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// How many outstanding requests the sender queues before dropping them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
+    /// The size of the info dict, for `ut_metadata`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u32>,
+    /// Our address as the sender sees it.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "compact_ip")]
+    pub yourip: Option<IpAddr>,
+}
+
+/// A `ut_metadata` (BEP 9) message, without the metadata piece a data message carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UtMetadataMsg {
+    /// 0 request, 1 data, 2 reject.
+    pub msg_type: u8,
+    pub piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<u32>,
+}
+
+/// A `ut_pex` message, with peers in compact form.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UtPexMsg {
+    #[serde(default)]
+    pub added: ByteBuf,
+    /// One flags byte per peer in `added`.
+    #[serde(default, rename = "added.f")]
+    pub added_f: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default)]
+    pub added6: ByteBuf,
+    #[serde(default, rename = "added6.f")]
+    pub added6_f: ByteBuf,
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+impl BencodeDict for ExtendedHandshake {}
+impl BencodeDict for UtMetadataMsg {}
+impl BencodeDict for UtPexMsg {}
+
+impl ExtendedHandshake {
+    /// The message id the sender wants `extension` sent with, if it supports it.
+    pub fn id_of(&self, extension: &str) -> Option<u8> {
+        self.m.get(extension).copied().filter(|&id| id != 0)
+    }
+}
+
+impl UtMetadataMsg {
+    pub const REQUEST: u8 = 0;
+    pub const DATA: u8 = 1;
+    pub const REJECT: u8 = 2;
+
+    pub fn request(piece: u32) -> Self {
+        Self {
+            msg_type: Self::REQUEST,
+            piece,
+            total_size: None,
+        }
+    }
+
+    pub fn reject(piece: u32) -> Self {
+        Self {
+            msg_type: Self::REJECT,
+            piece,
+            total_size: None,
+        }
+    }
+}
+
+/// The number of [`METADATA_PIECE_SIZE`] pieces an info dict of `size` bytes takes.
+pub fn metadata_piece_count(size: usize) -> usize {
+    size.div_ceil(METADATA_PIECE_SIZE)
+}
+
+/// The payload answering a request for metadata piece `piece` of `info`: the data message
+/// followed by the piece, or a reject if there is no such piece.
+pub fn metadata_response(info: &[u8], piece: u32) -> Vec<u8> {
+    let start = piece as usize * METADATA_PIECE_SIZE;
+    if start >= info.len() {
+        return UtMetadataMsg::reject(piece).to_bencode();
+    }
+    let end = info.len().min(start + METADATA_PIECE_SIZE);
+    let mut payload = UtMetadataMsg {
+        msg_type: UtMetadataMsg::DATA,
+        piece,
+        total_size: Some(info.len() as u32),
+    }
+    .to_bencode();
+    payload.extend_from_slice(&info[start..end]);
+    payload
+}
+
+impl UtPexMsg {
+    /// `ut_pex` messages announcing `added` and `dropped`, split so that none adds or drops
+    /// more than [`MAX_PEX_PEERS`].
+    pub fn batches(added: &[SocketAddr], dropped: &[SocketAddr]) -> Vec<Self> {
+        let batches = added
+            .len()
+            .max(dropped.len())
+            .div_ceil(MAX_PEX_PEERS)
+            .max(1);
+        (0..batches)
+            .map(|batch| {
+                let range = |peers: &[SocketAddr]| {
+                    let start = (batch * MAX_PEX_PEERS).min(peers.len());
+                    start..(start + MAX_PEX_PEERS).min(peers.len())
+                };
+                let mut msg = Self::default();
+                for addr in &added[range(added)] {
+                    match addr {
+                        SocketAddr::V4(_) => {
+                            msg.added.extend(compact_peer(addr));
+                            msg.added_f.push(0);
+                        }
+                        SocketAddr::V6(_) => {
+                            msg.added6.extend(compact_peer(addr));
+                            msg.added6_f.push(0);
+                        }
+                    }
+                }
+                for addr in &dropped[range(dropped)] {
+                    match addr {
+                        SocketAddr::V4(_) => msg.dropped.extend(compact_peer(addr)),
+                        SocketAddr::V6(_) => msg.dropped6.extend(compact_peer(addr)),
+                    }
+                }
+                msg
+            })
+            .collect()
+    }
+
+    /// The peers that joined, IPv4 and IPv6 alike.
+    pub fn added_peers(&self) -> Vec<SocketAddr> {
+        compact_peers(&self.added, &self.added6)
+    }
+
+    pub fn dropped_peers(&self) -> Vec<SocketAddr> {
+        compact_peers(&self.dropped, &self.dropped6)
+    }
+}
+
+/// Peers from their compact forms, ignoring trailing bytes that don't form a whole peer.
+fn compact_peers(v4: &[u8], v6: &[u8]) -> Vec<SocketAddr> {
+    let v4 = v4.chunks_exact(6).map(|peer| {
+        let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+        SocketAddr::V4(SocketAddrV4::new(
+            ip,
+            u16::from_be_bytes([peer[4], peer[5]]),
+        ))
+    });
+    let v6 = v6.chunks_exact(18).map(|peer| {
+        let ip: [u8; 16] = peer[..16].try_into().expect("16 bytes");
+        SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::from(ip),
+            u16::from_be_bytes([peer[16], peer[17]]),
+            0,
+            0,
+        ))
+    });
+    v4.chain(v6).collect()
+}
+
+/// `addr` in compact form: the address bytes, then the port in network byte order.
+fn compact_peer(addr: &SocketAddr) -> Vec<u8> {
+    let mut compact = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    compact.extend(addr.port().to_be_bytes());
+    compact
+}
+
+/// An IP address as its 4 or 16 raw bytes.
+mod compact_ip {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(ip: &Option<IpAddr>, serializer: S) -> Result<S::Ok, S::Error> {
+        let bytes = match ip {
+            Some(IpAddr::V4(ip)) => ip.octets().to_vec(),
+            Some(IpAddr::V6(ip)) => ip.octets().to_vec(),
+            None => Vec::new(),
+        };
+        serde_bytes::serialize(&bytes, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<IpAddr>, D::Error> {
+        let bytes: ByteBuf = serde_bytes::deserialize(deserializer)?;
+        match bytes.len() {
+            4 => Ok(Some(IpAddr::from(
+                <[u8; 4]>::try_from(bytes.as_slice()).expect("4 bytes"),
+            ))),
+            16 => Ok(Some(IpAddr::from(
+                <[u8; 16]>::try_from(bytes.as_slice()).expect("16 bytes"),
+            ))),
+            len => Err(serde::de::Error::invalid_length(len, &"4 or 16 bytes")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An extended handshake as libtorrent (qBittorrent 4.6) sends it, keys it has and we
+    /// don't included.
+    const LIBTORRENT_HANDSHAKE: &[u8] = b"d12:complete_agoi-1e1:md11:lt_donthavei7e10:share_modei8e11:upload_onlyi3e12:ut_holepunchi4e11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei31235e1:pi6881e4:reqqi500e11:upload_onlyi0e1:v17:qBittorrent/4.6.26:yourip4:\xc0\xa8\x01\x02e";
+
+    /// An extended handshake as Transmission 4.0 sends it, with an IPv6 `yourip`.
+    const TRANSMISSION_HANDSHAKE: &[u8] = b"d1:ei1e4:ipv616:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x011:md11:ut_metadatai3e6:ut_pexi1ee13:metadata_sizei5205e1:pi51413e4:reqqi512e11:upload_onlyi1e1:v18:Transmission 4.0.56:yourip16:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x02e";
+
+    #[test]
+    fn libtorrent_handshake() {
+        let handshake = ExtendedHandshake::from_bencode(LIBTORRENT_HANDSHAKE).unwrap();
+        assert_eq!(handshake.id_of("ut_metadata"), Some(2));
+        assert_eq!(handshake.id_of("ut_pex"), Some(1));
+        assert_eq!(handshake.id_of("lt_donthave"), Some(7));
+        assert_eq!(handshake.id_of("ut_holepunch"), Some(4));
+        assert_eq!(handshake.m.len(), 6);
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.v.as_deref(), Some("qBittorrent/4.6.2"));
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.metadata_size, Some(31235));
+        assert_eq!(handshake.yourip, Some(IpAddr::from([192, 168, 1, 2])));
+
+        // what we don't know of is dropped, the rest survives the round trip
+        let encoded = handshake.to_bencode();
+        assert_eq!(
+            ExtendedHandshake::from_bencode(&encoded).unwrap(),
+            handshake
+        );
+        assert_eq!(encoded, b"d1:md11:lt_donthavei7e10:share_modei8e11:upload_onlyi3e12:ut_holepunchi4e11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei31235e1:pi6881e4:reqqi500e1:v17:qBittorrent/4.6.26:yourip4:\xc0\xa8\x01\x02e");
+    }
+
+    #[test]
+    fn transmission_handshake() {
+        let handshake = ExtendedHandshake::from_bencode(TRANSMISSION_HANDSHAKE).unwrap();
+        assert_eq!(handshake.id_of("ut_metadata"), Some(3));
+        assert_eq!(handshake.id_of("lt_donthave"), None);
+        assert_eq!(handshake.p, Some(51413));
+        assert_eq!(handshake.v.as_deref(), Some("Transmission 4.0.5"));
+        assert_eq!(handshake.reqq, Some(512));
+        assert_eq!(handshake.metadata_size, Some(5205));
+        assert_eq!(
+            handshake.yourip,
+            Some("2001:db8::2".parse::<IpAddr>().unwrap())
+        );
+        let encoded = handshake.to_bencode();
+        assert_eq!(
+            ExtendedHandshake::from_bencode(&encoded).unwrap(),
+            handshake
+        );
+    }
+
+    #[test]
+    fn a_disabled_extension_has_no_id() {
+        let handshake = ExtendedHandshake::from_bencode(b"d1:md6:ut_pexi0eee").unwrap();
+        assert_eq!(handshake.id_of("ut_pex"), None);
+        // a handshake may be nothing but an update
+        assert_eq!(
+            ExtendedHandshake::from_bencode(b"de").unwrap(),
+            ExtendedHandshake::default()
+        );
+    }
+
+    #[test]
+    fn known_keys_of_the_wrong_type_reject_the_message() {
+        for payload in [
+            &b"d4:reqq3:abce"[..],
+            b"d1:mi1ee",
+            b"d1:md6:ut_pex3:oneee",
+            b"d1:pi70000ee",
+            b"d6:yourip5:abcdee",
+            b"d1:v3:abc",
+            b"li1ee",
+        ] {
+            assert!(
+                ExtendedHandshake::from_bencode(payload).is_err(),
+                "{}",
+                String::from_utf8_lossy(payload)
+            );
+        }
+        assert!(ExtendedHandshake::from_bencode(b"de garbage").is_err());
+    }
+
+    #[test]
+    fn a_metadata_data_message_is_followed_by_its_piece() {
+        let mut payload = b"d8:msg_typei1e5:piecei0e10:total_sizei34256ee".to_vec();
+        payload.extend_from_slice(&[0xab; 100]);
+        let (msg, piece) = UtMetadataMsg::from_bencode_prefix(&payload).unwrap();
+        assert_eq!(
+            msg,
+            UtMetadataMsg {
+                msg_type: UtMetadataMsg::DATA,
+                piece: 0,
+                total_size: Some(34256),
+            }
+        );
+        assert_eq!(piece, [0xab; 100]);
+        assert!(UtMetadataMsg::from_bencode(&payload).is_err());
+
+        assert_eq!(
+            UtMetadataMsg::request(2).to_bencode(),
+            b"d8:msg_typei0e5:piecei2ee"
+        );
+        assert_eq!(
+            UtMetadataMsg::reject(1).to_bencode(),
+            b"d8:msg_typei2e5:piecei1ee"
+        );
+    }
+
+    #[test]
+    fn metadata_is_cut_into_16_kib_pieces() {
+        assert_eq!(metadata_piece_count(1), 1);
+        assert_eq!(metadata_piece_count(METADATA_PIECE_SIZE), 1);
+        assert_eq!(metadata_piece_count(METADATA_PIECE_SIZE + 1), 2);
+        assert_eq!(metadata_piece_count(3 * METADATA_PIECE_SIZE), 3);
+    }
+
+    #[test]
+    fn metadata_responses_carry_one_piece_each() {
+        let info: Vec<u8> = (0..2 * METADATA_PIECE_SIZE + 100)
+            .map(|i| i as u8)
+            .collect();
+        let mut reassembled = Vec::new();
+        for piece in 0..3 {
+            let payload = metadata_response(&info, piece);
+            let (msg, data) = UtMetadataMsg::from_bencode_prefix(&payload).unwrap();
+            assert_eq!(
+                msg,
+                UtMetadataMsg {
+                    msg_type: UtMetadataMsg::DATA,
+                    piece,
+                    total_size: Some(info.len() as u32),
+                }
+            );
+            let expected = if piece < 2 { METADATA_PIECE_SIZE } else { 100 };
+            assert_eq!(data.len(), expected);
+            reassembled.extend_from_slice(data);
+        }
+        assert_eq!(reassembled, info);
+
+        assert_eq!(
+            metadata_response(&info, 3),
+            UtMetadataMsg::reject(3).to_bencode()
+        );
+        // an exact multiple has no empty piece after it
+        let exact = &info[..METADATA_PIECE_SIZE];
+        let payload = metadata_response(exact, 0);
+        let (_, data) = UtMetadataMsg::from_bencode_prefix(&payload).unwrap();
+        assert_eq!(data, exact);
+        assert_eq!(
+            metadata_response(exact, 1),
+            UtMetadataMsg::reject(1).to_bencode()
+        );
+    }
+
+    #[test]
+    fn pex_peers_of_both_families() {
+        let payload = b"d5:added12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe27:added.f2:\x10\x006:added618:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe18:added6.f1:\x007:dropped6:\x0a\x00\x00\x03\x00\x50e";
+        let msg = UtPexMsg::from_bencode(payload).unwrap();
+        let peers = |peers: &[&str]| -> Vec<SocketAddr> {
+            peers.iter().map(|peer| peer.parse().unwrap()).collect()
+        };
+        assert_eq!(
+            msg.added_peers(),
+            peers(&["10.0.0.1:6881", "10.0.0.2:6882", "[2001:db8::1]:6881"])
+        );
+        assert_eq!(msg.dropped_peers(), peers(&["10.0.0.3:80"]));
+        assert_eq!(msg.added_f.as_slice(), [0x10, 0]);
+        assert_eq!(UtPexMsg::from_bencode(&msg.to_bencode()).unwrap(), msg);
+    }
+
+    #[test]
+    fn pex_batches_stay_within_the_peer_limit() {
+        let added: Vec<SocketAddr> = (0..120)
+            .map(|port| SocketAddr::from(([10, 0, 0, 1], port)))
+            .collect();
+        let dropped = [SocketAddr::from(([10, 0, 0, 2], 1))];
+        let batches = UtPexMsg::batches(&added, &dropped);
+        assert_eq!(batches.len(), 3);
+        let sent: Vec<_> = batches.iter().flat_map(UtPexMsg::added_peers).collect();
+        assert_eq!(sent, added);
+        assert!(batches
+            .iter()
+            .all(|batch| batch.added_peers().len() <= MAX_PEX_PEERS));
+        assert_eq!(batches[0].dropped_peers(), dropped);
+        assert!(batches[1].dropped_peers().is_empty());
+        // nothing to say is still one message
+        assert_eq!(UtPexMsg::batches(&[], &[]), [UtPexMsg::default()]);
+    }
+}
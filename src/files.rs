@@ -0,0 +1,518 @@
+use crate::perms;
+use crate::torrent::{Keys, Torrent};
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Tracks which files of a torrent are fully verified, so post-processing can start on
+/// the finished files of a multi-file download before the rest arrives.
+///
+/// A file is complete once every piece overlapping it has verified. Pieces that straddle
+/// two files count toward both.
+#[derive(Debug, Clone)]
+pub struct FileProgress {
+    files: Vec<FileSpan>,
+    plength: usize,
+    /// Which pieces have verified, so a piece verifying twice doesn't count twice.
+    verified: Vec<bool>,
+}
+
+/// Where one file lies in the stream of pieces.
+#[derive(Debug, Clone)]
+struct FileSpan {
+    path: PathBuf,
+    offset: usize,
+    length: usize,
+    /// Padding files (BEP 47) are never reported.
+    padding: bool,
+    verified_bytes: usize,
+}
+
+/// A file became fully available.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileCompleted {
+    pub index: usize,
+    pub path: PathBuf,
+}
+
+/// How far along one file is.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileStatus {
+    pub index: usize,
+    pub path: PathBuf,
+    pub length: usize,
+    pub percent: f64,
+}
+
+impl FileProgress {
+    /// Progress of the files `mapper` places, out of `npieces` pieces, none of which have
+    /// verified yet.
+    ///
+    /// Paths are where the mapper puts the files, e.g. `output/name/dir/file`.
+    pub fn new(mapper: &FileMapper, npieces: usize) -> Self {
+        let files = mapper
+            .files()
+            .iter()
+            .map(|file| FileSpan {
+                path: file.path.clone(),
+                offset: file.offset,
+                length: file.length,
+                padding: file.padding,
+                verified_bytes: 0,
+            })
+            .collect();
+        Self {
+            files,
+            plength: mapper.plength,
+            verified: vec![false; npieces],
+        }
+    }
+
+    /// Records that piece `index` verified, returning the files that became complete
+    /// because of it, in file order.
+    pub fn piece_verified(&mut self, index: usize) -> Vec<FileCompleted> {
+        if self.verified.get(index).copied().unwrap_or(true) {
+            return Vec::new();
+        }
+        self.verified[index] = true;
+        let start = index * self.plength;
+        let end = start + self.plength;
+        let first = self
+            .files
+            .partition_point(|file| file.offset + file.length <= start);
+        let mut completed = Vec::new();
+        for (file_index, file) in self.files.iter_mut().enumerate().skip(first) {
+            if file.offset >= end {
+                break;
+            }
+            let overlap = (file.offset + file.length).min(end) - file.offset.max(start);
+            if overlap == 0 {
+                // empty files have nothing to wait for and are never reported
+                continue;
+            }
+            file.verified_bytes += overlap;
+            if file.verified_bytes == file.length && !file.padding {
+                completed.push(FileCompleted {
+                    index: file_index,
+                    path: file.path.clone(),
+                });
+            }
+        }
+        completed
+    }
+
+    /// How far along every file is, padding files excluded.
+    pub fn status(&self) -> Vec<FileStatus> {
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| !file.padding)
+            .map(|(index, file)| FileStatus {
+                index,
+                path: file.path.clone(),
+                length: file.length,
+                percent: if file.length == 0 {
+                    100.0
+                } else {
+                    100.0 * file.verified_bytes as f64 / file.length as f64
+                },
+            })
+            .collect()
+    }
+}
+
+impl FileCompleted {
+    /// Starts `template` for the completed file, e.g. `unpack {}`, without waiting for it.
+    ///
+    /// The template is split on whitespace and every `{}` in it is replaced by the file's
+    /// path. There is no shell involved, so file names from the torrent can't inject
+    /// commands.
+    pub fn exec(&self, template: &str) -> std::io::Result<Child> {
+        let path = self.path.to_string_lossy();
+        let mut args = template
+            .split_whitespace()
+            .map(|arg| arg.replace("{}", &path));
+        let program = args.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty command")
+        })?;
+        Command::new(program).args(args).spawn()
+    }
+}
+
+impl Display for FileCompleted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "event: file_completed index={} path={}",
+            self.index,
+            self.path.display()
+        )
+    }
+}
+
+/// Where the bytes of each piece go on disk.
+///
+/// A torrent's data is its files concatenated in order, so a piece may end in one file and
+/// continue in the next ones, and a file shorter than a piece may lie entirely within one.
+#[derive(Debug, Clone)]
+pub struct FileMapper {
+    files: Vec<MappedFile>,
+    plength: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct MappedFile {
+    pub path: PathBuf,
+    /// Where the file starts in the torrent's data.
+    pub offset: usize,
+    pub length: usize,
+    /// Padding files (BEP 47) are never written.
+    pub padding: bool,
+    /// Whether the file is downloaded at all.
+    pub selected: bool,
+}
+
+/// The part of a piece that belongs to one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file: usize,
+    pub file_offset: usize,
+    pub piece_offset: usize,
+    pub length: usize,
+}
+
+impl FileMapper {
+    /// The files of `torrent` when downloading to `output`: a single-file torrent is
+    /// written to `output` itself, a multi-file one to `output/name/...`.
+    pub fn new(torrent: &Torrent, output: &Path) -> Self {
+        let files = match &torrent.info.keys {
+            Keys::SingleFile { length } => vec![MappedFile {
+                path: output.to_path_buf(),
+                offset: 0,
+                length: *length,
+                padding: false,
+                selected: true,
+            }],
+            Keys::MultiFile { files: entries } => {
+                let mut offset = 0;
+                let mut files = Vec::with_capacity(entries.len());
+                for (index, entry) in entries.iter().enumerate() {
+                    files.push(MappedFile {
+                        path: output
+                            .join(torrent.file_path(index).expect("index of an existing file")),
+                        offset,
+                        length: entry.length,
+                        padding: entry.is_padding(),
+                        selected: true,
+                    });
+                    offset += entry.length;
+                }
+                files
+            }
+        };
+        Self {
+            files,
+            plength: torrent.info.plength,
+        }
+    }
+
+    pub fn files(&self) -> &[MappedFile] {
+        &self.files
+    }
+
+    /// Moves every file to `path(file)`, e.g. to where it was found on disk.
+    pub fn relocate(&mut self, mut path: impl FnMut(&MappedFile) -> PathBuf) {
+        for file in &mut self.files {
+            file.path = path(file);
+        }
+    }
+
+    /// Downloads only the files at `indices`, in the order of the torrent's file list.
+    pub fn select(&mut self, indices: &[usize]) -> anyhow::Result<()> {
+        if let Some(index) = indices.iter().find(|&&index| index >= self.files.len()) {
+            bail!(
+                "there is no file {index}, the torrent has {} file(s)",
+                self.files.len()
+            );
+        }
+        for (index, file) in self.files.iter_mut().enumerate() {
+            file.selected = indices.contains(&index);
+        }
+        Ok(())
+    }
+
+    /// The bytes of piece `index` of `piece_size` bytes that lie within selected files.
+    pub fn selected_bytes(&self, index: usize, piece_size: usize) -> usize {
+        self.slices(index, piece_size)
+            .iter()
+            .filter(|slice| self.files[slice.file].selected)
+            .map(|slice| slice.length)
+            .sum()
+    }
+
+    /// The files the `length` bytes of piece `index` from `begin` on spread over, in order,
+    /// with `piece_offset`s counted from `begin`.
+    pub fn block_slices(&self, index: usize, begin: usize, length: usize) -> Vec<FileSlice> {
+        self.slices(index, begin + length)
+            .into_iter()
+            .filter(|slice| slice.piece_offset + slice.length > begin)
+            .map(|slice| {
+                let skip = begin.saturating_sub(slice.piece_offset);
+                FileSlice {
+                    file: slice.file,
+                    file_offset: slice.file_offset + skip,
+                    piece_offset: slice.piece_offset + skip - begin,
+                    length: slice.length - skip,
+                }
+            })
+            .collect()
+    }
+
+    /// The files piece `index` of `piece_size` bytes spreads over, in order. Empty files
+    /// hold no part of any piece.
+    pub fn slices(&self, index: usize, piece_size: usize) -> Vec<FileSlice> {
+        let start = index * self.plength;
+        let end = start + piece_size;
+        let first = self
+            .files
+            .partition_point(|file| file.offset + file.length <= start);
+        self.files
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take_while(|(_, file)| file.offset < end)
+            .filter(|(_, file)| file.length > 0)
+            .map(|(file_index, file)| {
+                let from = file.offset.max(start);
+                let to = (file.offset + file.length).min(end);
+                FileSlice {
+                    file: file_index,
+                    file_offset: from - file.offset,
+                    piece_offset: from - start,
+                    length: to - from,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reads blocks out of the files of a torrent, to seed them.
+#[derive(Debug)]
+pub struct DataReader {
+    mapper: FileMapper,
+    /// `None` for padding files, which read as zeros.
+    files: Vec<Option<tokio::fs::File>>,
+}
+
+impl DataReader {
+    /// Opens every file of `mapper` but the padding files.
+    pub async fn open(mapper: FileMapper) -> anyhow::Result<Self> {
+        let mut files = Vec::with_capacity(mapper.files.len());
+        for file in &mapper.files {
+            if file.padding {
+                files.push(None);
+                continue;
+            }
+            let opened = tokio::fs::File::open(&file.path)
+                .await
+                .with_context(|| format!("open {}", file.path.display()))?;
+            files.push(Some(opened));
+        }
+        Ok(Self { mapper, files })
+    }
+
+    /// Reads the `length` bytes of piece `index` from `begin` on, from the files they
+    /// spread over.
+    pub async fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut block = vec![0; length];
+        for slice in self.mapper.block_slices(index, begin, length) {
+            let Some(file) = &mut self.files[slice.file] else {
+                continue;
+            };
+            let path = &self.mapper.files[slice.file].path;
+            let read = async {
+                file.seek(SeekFrom::Start(slice.file_offset as u64)).await?;
+                file.read_exact(&mut block[slice.piece_offset..][..slice.length])
+                    .await
+            };
+            read.await
+                .with_context(|| format!("read piece {index} from {}", path.display()))?;
+        }
+        Ok(block)
+    }
+}
+
+/// Writes verified pieces into the files of a torrent.
+pub struct DataWriter {
+    mapper: FileMapper,
+    /// `None` for padding files and files that aren't selected.
+    files: Vec<Option<tokio::fs::File>>,
+}
+
+impl DataWriter {
+    /// Creates every selected file of `mapper` and the directories they are in, each with
+    /// its final length.
+    ///
+    /// Files that are already there keep what they hold, so a download can resume into
+    /// them; see [`add_seed::resume`](crate::add_seed::resume).
+    pub async fn create(mapper: FileMapper) -> anyhow::Result<Self> {
+        let mut files = Vec::with_capacity(mapper.files.len());
+        for file in &mapper.files {
+            if file.padding || !file.selected {
+                files.push(None);
+                continue;
+            }
+            if let Some(dir) = file.path.parent() {
+                perms::create_dir_all(dir)
+                    .with_context(|| format!("create directory {}", dir.display()))?;
+            }
+            let created = perms::create_or_open(&file.path)
+                .with_context(|| format!("open output {}", file.path.display()))?;
+            let existing = created
+                .metadata()
+                .with_context(|| format!("inspect output {}", file.path.display()))?
+                .len();
+            if existing > file.length as u64 {
+                bail!(
+                    "output {} is {existing} bytes, longer than the {} the torrent has",
+                    file.path.display(),
+                    file.length
+                );
+            }
+            created
+                .set_len(file.length as u64)
+                .with_context(|| format!("size output {}", file.path.display()))?;
+            files.push(Some(tokio::fs::File::from_std(created)));
+        }
+        Ok(Self { mapper, files })
+    }
+
+    /// Writes piece `index` into the selected files it spreads over.
+    pub async fn write_piece(&mut self, index: usize, data: &[u8]) -> anyhow::Result<()> {
+        for slice in self.mapper.slices(index, data.len()) {
+            let Some(file) = &mut self.files[slice.file] else {
+                continue;
+            };
+            let path = &self.mapper.files[slice.file].path;
+            let write = async {
+                file.seek(SeekFrom::Start(slice.file_offset as u64)).await?;
+                file.write_all(&data[slice.piece_offset..][..slice.length])
+                    .await
+            };
+            write
+                .await
+                .with_context(|| format!("write piece {index} to {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Flushes every file.
+    pub async fn finish(self) -> anyhow::Result<()> {
+        for (file, mapped) in self.files.into_iter().zip(&self.mapper.files) {
+            if let Some(mut file) = file {
+                file.flush()
+                    .await
+                    .with_context(|| format!("write {}", mapped.path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{fixture_data, TorrentBuilder};
+
+    fn progress(files: &[usize], plength: usize, pad_files: bool) -> FileProgress {
+        let entries: Vec<_> = files
+            .iter()
+            .enumerate()
+            .map(|(index, &length)| (vec![format!("{index}")], length))
+            .collect();
+        let len = files.iter().sum();
+        let torrent = TorrentBuilder::multi_file("t", entries, plength)
+            .pad_files(pad_files)
+            .build(fixture_data(len, 1).as_slice())
+            .unwrap();
+        let mapper = FileMapper::new(&torrent, Path::new("out"));
+        FileProgress::new(&mapper, torrent.info.pieces.0.len())
+    }
+
+    fn indices(completed: Vec<FileCompleted>) -> Vec<usize> {
+        completed.into_iter().map(|file| file.index).collect()
+    }
+
+    #[test]
+    fn a_piece_shared_by_files_counts_toward_each() {
+        // one piece holds all three files
+        let mut files = progress(&[10, 0, 20], 64, false);
+        assert_eq!(indices(files.piece_verified(0)), [0, 2]);
+        let status = files.status();
+        assert!(
+            status.iter().all(|file| file.percent == 100.0),
+            "{status:?}"
+        );
+        assert_eq!(status[0].path, Path::new("out/t/0"));
+    }
+
+    #[test]
+    fn files_complete_whatever_order_their_pieces_come_in() {
+        let mut files = progress(&[100, 50], 40, false);
+        // piece 1 is 40..80, all in file 0
+        assert!(files.piece_verified(1).is_empty());
+        assert_eq!(files.status()[0].percent, 40.0);
+        // piece 3 is 120..150, the tail of file 1
+        assert!(files.piece_verified(3).is_empty());
+        assert!(files.piece_verified(0).is_empty());
+        assert_eq!(indices(files.piece_verified(2)), [0, 1]);
+        // again, or out of range, is no news
+        assert!(files.piece_verified(2).is_empty());
+        assert!(files.piece_verified(9).is_empty());
+    }
+
+    #[test]
+    fn a_block_is_cut_where_its_files_meet() {
+        let torrent = TorrentBuilder::multi_file(
+            "t",
+            vec![(vec!["a".into()], 100), (vec!["b".into()], 50)],
+            64,
+        )
+        .build(fixture_data(150, 1).as_slice())
+        .unwrap();
+        let mapper = FileMapper::new(&torrent, Path::new("out"));
+        // piece 1 is 64..128, bytes 20..50 of it 84..114, across the end of a at 100
+        let slice = |file, file_offset, piece_offset, length| FileSlice {
+            file,
+            file_offset,
+            piece_offset,
+            length,
+        };
+        assert_eq!(
+            mapper.block_slices(1, 20, 30),
+            [slice(0, 84, 0, 16), slice(1, 0, 16, 14)]
+        );
+        assert_eq!(mapper.block_slices(1, 40, 10), [slice(1, 4, 0, 10)]);
+        assert_eq!(mapper.block_slices(2, 0, 22), [slice(1, 28, 0, 22)]);
+    }
+
+    #[test]
+    fn padding_is_neither_reported_nor_shown() {
+        let mut files = progress(&[10, 100], 64, true);
+        let status = files.status();
+        assert_eq!(status.len(), 2, "{status:?}");
+        assert_eq!(status[1].index, 2);
+        assert_eq!(indices(files.piece_verified(0)), [0]);
+        assert!(files.piece_verified(1).is_empty());
+        assert_eq!(indices(files.piece_verified(2)), [2]);
+    }
+}
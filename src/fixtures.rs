@@ -0,0 +1,122 @@
+//! Realistic values for tests, built in memory instead of shipped as binary fixtures.
+//!
+//! Only compiled with the `test-util` feature and for our own unit tests. These helpers are
+//! not covered by semver:
+//! they may change whenever our own tests need them to.
+
+use crate::create::{fixture_data, TorrentBuilder};
+use crate::peer::{Handshake, Peers, Peers6};
+use crate::torrent::Torrent;
+use crate::tracker::TrackerResponse;
+use std::net::SocketAddr;
+
+impl Torrent {
+    /// A single-file torrent of `len` bytes whose piece hashes match [`Torrent::fixture_data`].
+    pub fn fixture_single_file(len: usize, plength: usize) -> Self {
+        TorrentBuilder::single_file("fixture.bin", len, plength)
+            .announce("http://127.0.0.1:6969/announce")
+            .creation_date(0)
+            .build(Self::fixture_data(len).as_slice())
+            .expect("fixture content has the declared length")
+    }
+
+    /// Like [`Torrent::fixture_single_file`], but with the last piece hash cut off, as in a
+    /// subtly corrupt torrent.
+    pub fn fixture_truncated_pieces(len: usize, plength: usize) -> Self {
+        let mut torrent = Self::fixture_single_file(len, plength);
+        torrent.info.pieces.0.pop();
+        torrent
+    }
+
+    /// The content of the torrents built by the `fixture_*` constructors.
+    pub fn fixture_data(len: usize) -> Vec<u8> {
+        fixture_data(len, 0)
+    }
+}
+
+impl TrackerResponse {
+    /// A response handing out `peers`, which survives a bencode round trip unchanged.
+    ///
+    /// IPv6 peers go in `peers6`, unless there are none.
+    pub fn fixture(peers: &[SocketAddr]) -> Self {
+        let mut peers4 = Vec::new();
+        let mut peers6 = Vec::new();
+        for &peer in peers {
+            match peer {
+                SocketAddr::V4(_) => peers4.push(peer),
+                SocketAddr::V6(peer) => peers6.push(peer),
+            }
+        }
+        let response = Self {
+            interval: 1800,
+            peers: Peers(peers4),
+            peers6: (!peers6.is_empty()).then_some(Peers6(peers6)),
+            warning_message: None,
+            min_interval: None,
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+        };
+        let encoded = serde_bencode::to_bytes(&response).expect("tracker response encodes");
+        serde_bencode::from_bytes(&encoded).expect("compact peers decode")
+    }
+}
+
+impl Handshake {
+    /// The handshake a peer sends for `Torrent::fixture_single_file(100_000, 1 << 15)`.
+    pub fn fixture() -> Self {
+        let torrent = Torrent::fixture_single_file(100_000, 1 << 15);
+        Handshake::new(torrent.info_hash(), *b"-RB0000-fixturepeer!", false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::piece::sha1;
+    use crate::torrent::Metainfo;
+
+    #[test]
+    fn fixture_torrents_hash_their_fixture_data() {
+        let torrent = Torrent::fixture_single_file(100_000, 1 << 15);
+        let data = Torrent::fixture_data(100_000);
+        torrent.validate().unwrap();
+        assert_eq!(torrent.declared_pieces(), 4);
+        for (index, piece) in data.chunks(1 << 15).enumerate() {
+            assert_eq!(torrent.piece_hash(index).unwrap(), &sha1(piece));
+        }
+        // and survive a round trip through their encoding
+        let bytes = serde_bencode::to_bytes(&torrent).unwrap();
+        let parsed = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.info_hash(), torrent.info_hash());
+    }
+
+    #[test]
+    fn a_truncated_fixture_misses_its_last_hash() {
+        let torrent = Torrent::fixture_truncated_pieces(100_000, 1 << 15);
+        assert_eq!(torrent.info.pieces.0.len(), 3);
+        assert!(torrent.validate().is_err());
+    }
+
+    #[test]
+    fn tracker_fixtures_split_peers_by_family() {
+        let peers: Vec<SocketAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:6882".parse().unwrap(),
+            "10.0.0.2:1".parse().unwrap(),
+        ];
+        let response = TrackerResponse::fixture(&peers);
+        assert_eq!(response.peers.0, [peers[0], peers[2]]);
+        assert_eq!(response.peers6.as_ref().unwrap().0.len(), 1);
+        assert_eq!(response.all_peers().len(), 3);
+        assert!(TrackerResponse::fixture(&peers[..1]).peers6.is_none());
+    }
+
+    #[test]
+    fn the_handshake_fixture_is_for_the_fixture_torrent() {
+        let handshake = Handshake::fixture();
+        let torrent = Torrent::fixture_single_file(100_000, 1 << 15);
+        assert_eq!(handshake.info_hash, torrent.info_hash());
+        assert_eq!(Handshake::from_bytes(&handshake.to_bytes()), Ok(handshake));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::peer::Handshake;
+use anyhow::Context;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+/// How long to wait for the first message after the handshake before reporting none.
+pub const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a handshake with one peer went and how long each step took, for diagnosing slow
+/// peers.
+#[derive(Debug, Clone, Serialize)]
+pub struct HandshakeReport {
+    pub peer: SocketAddr,
+    /// Hex-encoded.
+    pub peer_id: String,
+    /// Establishing the TCP connection.
+    pub connect_ms: f64,
+    /// From sending our handshake to having read the peer's.
+    pub handshake_ms: f64,
+    /// The first message after the handshake, if it was waited for and came.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_message: Option<FirstMessage>,
+    /// The extensions the peer advertises in its reserved bytes.
+    pub extensions: Vec<&'static str>,
+}
+
+/// The first message a peer sent after the handshake.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirstMessage {
+    /// e.g. `bitfield` or `have_all`.
+    pub name: &'static str,
+    /// The length of the message, without its length prefix.
+    pub length: u32,
+    /// Since the handshake completed.
+    pub after_ms: f64,
+}
+
+impl HandshakeReport {
+    pub fn new(peer: SocketAddr, handshake: &Handshake, connect: Duration, rtt: Duration) -> Self {
+        Self {
+            peer,
+            peer_id: hex::encode(handshake.peer_id),
+            connect_ms: millis(connect),
+            handshake_ms: millis(rtt),
+            first_message: None,
+            extensions: extensions(&handshake.reserved),
+        }
+    }
+}
+
+/// Waits up to `timeout` for the first message on `stream`, right after the handshake.
+///
+/// Only the length prefix and the id are read, so this works for messages we don't speak
+/// too, but it leaves the stream in the middle of a message.
+pub async fn first_message(
+    stream: &mut TcpStream,
+    timeout: Duration,
+) -> anyhow::Result<Option<FirstMessage>> {
+    let started = Instant::now();
+    let read = async {
+        let length = stream.read_u32().await?;
+        let id = if length == 0 {
+            None
+        } else {
+            Some(stream.read_u8().await?)
+        };
+        std::io::Result::Ok((length, id))
+    };
+    let (length, id) = match tokio::time::timeout(timeout, read).await {
+        Ok(result) => result.context("read first message")?,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(FirstMessage {
+        name: id.map_or("keep_alive", message_name),
+        length,
+        after_ms: millis(started.elapsed()),
+    }))
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// The name of message `id`, including those of extensions we don't implement.
+fn message_name(id: u8) -> &'static str {
+    match id {
+        0 => "choke",
+        1 => "unchoke",
+        2 => "interested",
+        3 => "not_interested",
+        4 => "have",
+        5 => "bitfield",
+        6 => "request",
+        7 => "piece",
+        8 => "cancel",
+        9 => "port",
+        13 => "suggest_piece",
+        14 => "have_all",
+        15 => "have_none",
+        16 => "reject_request",
+        17 => "allowed_fast",
+        20 => "extended",
+        _ => "unknown",
+    }
+}
+
+/// The extensions set in the reserved bytes of a handshake.
+fn extensions(reserved: &[u8; 8]) -> Vec<&'static str> {
+    let mut extensions = Vec::new();
+    if reserved[5] & 0x10 != 0 {
+        extensions.push("extension_protocol");
+    }
+    if reserved[7] & 0x04 != 0 {
+        extensions.push("fast");
+    }
+    if reserved[7] & 0x01 != 0 {
+        extensions.push("dht");
+    }
+    extensions
+}
+
+impl Display for HandshakeReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Peer ID: {}", self.peer_id)?;
+        writeln!(f, "Connect: {:.1} ms", self.connect_ms)?;
+        writeln!(f, "Handshake: {:.1} ms", self.handshake_ms)?;
+        match &self.first_message {
+            Some(message) => writeln!(
+                f,
+                "First message: {} ({} bytes) after {:.1} ms",
+                message.name, message.length, message.after_ms
+            ),
+            None => writeln!(f, "First message: none"),
+        }
+    }
+}
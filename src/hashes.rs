@@ -1,11 +1,35 @@
+use crate::redact;
 use serde::de::{Error, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::Formatter;
 
 const SIZE: usize = 20;
 
-#[derive(Debug, Clone)]
-pub struct Hashes(pub(crate) Vec<[u8; 20]>);
+#[derive(Clone)]
+pub struct Hashes(pub Vec<[u8; 20]>);
+
+/// Only the count and the first hash, a torrent has thousands.
+impl std::fmt::Debug for Hashes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut hashes = f.debug_struct("Hashes");
+        hashes.field("count", &self.0.len());
+        if let Some(first) = self.0.first() {
+            hashes.field("first", &redact::hash(first));
+        }
+        hashes.finish()
+    }
+}
+
+/// A piece past the end of the piece hashes, in a torrent whose length says it exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "torrent metadata inconsistent: {declared} pieces declared by length/piece-length but only \
+     {present} hashes present"
+)]
+pub struct MissingHashes {
+    pub declared: usize,
+    pub present: usize,
+}
 
 struct HashStrVisitor;
 
@@ -19,7 +43,7 @@ impl<'de> Visitor<'de> for HashStrVisitor {
     where
         E: Error,
     {
-        if v.len() % SIZE != 0 {
+        if !v.len().is_multiple_of(SIZE) {
             Err(E::custom(format!("length is {}", v.len())))
         } else {
             // TODO: use array_chunks when stable
@@ -0,0 +1,228 @@
+//! Which torrent a swarm is about, for v1, v2 (BEP 52) and hybrid torrents alike.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// The identity of a torrent: the SHA-1 of a v1 info dict, the SHA-256 of a v2 one, or
+/// both for a hybrid torrent, which has one info dict valid as either.
+///
+/// `==` compares the hashes themselves. Whether two identities name the same torrent,
+/// e.g. a hybrid and the v1 hash a peer or magnet link gave us, is [`matches`]; a map
+/// keyed by torrent should be keyed by each of its [`keys`].
+///
+/// [`matches`]: InfoHash::matches
+/// [`keys`]: InfoHash::keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InfoHash {
+    V1([u8; 20]),
+    V2([u8; 32]),
+    Hybrid { v1: [u8; 20], v2: [u8; 32] },
+}
+
+/// A string that is none of the forms an info hash is written in.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("`{0}` is not an info hash: expected 40 or 64 hex digits, or 32 base32 characters")]
+pub struct InvalidInfoHash(String);
+
+impl InfoHash {
+    pub fn v1(&self) -> Option<&[u8; 20]> {
+        match self {
+            InfoHash::V1(v1) | InfoHash::Hybrid { v1, .. } => Some(v1),
+            InfoHash::V2(_) => None,
+        }
+    }
+
+    pub fn v2(&self) -> Option<&[u8; 32]> {
+        match self {
+            InfoHash::V2(v2) | InfoHash::Hybrid { v2, .. } => Some(v2),
+            InfoHash::V1(_) => None,
+        }
+    }
+
+    /// The 20 bytes that stand for the torrent in handshakes and tracker announces.
+    ///
+    /// A v2-only torrent uses its SHA-256 truncated to 20 bytes (BEP 52), a hybrid one its
+    /// v1 hash, so that v1-only peers can join the swarm.
+    pub fn wire(&self) -> [u8; 20] {
+        match self {
+            InfoHash::V1(v1) | InfoHash::Hybrid { v1, .. } => *v1,
+            InfoHash::V2(v2) => v2[..20].try_into().expect("32 bytes hold 20"),
+        }
+    }
+
+    /// Whether `wire`, as received in a handshake, stands for this torrent. A hybrid
+    /// torrent is known by either hash.
+    pub fn matches_wire(&self, wire: &[u8; 20]) -> bool {
+        self.wire() == *wire || self.v2().is_some_and(|v2| v2[..20] == wire[..])
+    }
+
+    /// Whether both name the same torrent, i.e. share a v1 or a v2 hash.
+    pub fn matches(&self, other: &InfoHash) -> bool {
+        let v1 = self.v1().is_some_and(|v1| other.v1() == Some(v1));
+        let v2 = self.v2().is_some_and(|v2| other.v2() == Some(v2));
+        v1 || v2
+    }
+
+    /// The single-hash identities a torrent is known by: one, or two for a hybrid.
+    pub fn keys(&self) -> Vec<InfoHash> {
+        match *self {
+            InfoHash::Hybrid { v1, v2 } => vec![InfoHash::V1(v1), InfoHash::V2(v2)],
+            single => vec![single],
+        }
+    }
+
+    /// The hash in unpadded base32, as in older magnet links; the v1 hash of a hybrid.
+    pub fn base32(&self) -> String {
+        match self {
+            InfoHash::V1(v1) | InfoHash::Hybrid { v1, .. } => base32_encode(v1),
+            InfoHash::V2(v2) => base32_encode(v2),
+        }
+    }
+}
+
+/// Hex, with a hybrid torrent's v2 hash after its v1 hash.
+impl Display for InfoHash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InfoHash::V1(v1) => f.write_str(&hex::encode(v1)),
+            InfoHash::V2(v2) => f.write_str(&hex::encode(v2)),
+            InfoHash::Hybrid { v1, v2 } => {
+                write!(f, "{} (v2 {})", hex::encode(v1), hex::encode(v2))
+            }
+        }
+    }
+}
+
+/// Parses a v1 hash from 40 hex digits or 32 base32 characters, or a v2 hash from 64 hex
+/// digits. A hybrid torrent is named by either of its hashes.
+impl FromStr for InfoHash {
+    type Err = InvalidInfoHash;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidInfoHash(s.to_string());
+        match s.len() {
+            40 => {
+                let mut v1 = [0; 20];
+                hex::decode_to_slice(s, &mut v1).map_err(|_| invalid())?;
+                Ok(InfoHash::V1(v1))
+            }
+            64 => {
+                let mut v2 = [0; 32];
+                hex::decode_to_slice(s, &mut v2).map_err(|_| invalid())?;
+                Ok(InfoHash::V2(v2))
+            }
+            32 => base32_decode(s)
+                .and_then(|v1| v1.try_into().ok())
+                .map(InfoHash::V1)
+                .ok_or_else(invalid),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+/// Decodes unpadded base32, in either case.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the hashes of the reference hybrid torrent of BEP 52, "bittorrent-v1-v2-hybrid-test"
+    const V1: &str = "631a31dd0a46257d5078c0dee4e66e26f73e42ac";
+    const V2: &str = "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb";
+
+    fn hybrid() -> InfoHash {
+        let (InfoHash::V1(v1), InfoHash::V2(v2)) = (V1.parse().unwrap(), V2.parse().unwrap())
+        else {
+            panic!("40 and 64 hex digits are v1 and v2 hashes");
+        };
+        InfoHash::Hybrid { v1, v2 }
+    }
+
+    #[test]
+    fn hashes_parse_from_hex_and_base32() {
+        let v1: InfoHash = V1.parse().unwrap();
+        assert_eq!(v1.to_string(), V1);
+        assert_eq!(v1.base32(), "MMNDDXIKIYSX2UDYYDPOJZTOE33T4QVM");
+        assert_eq!("MMNDDXIKIYSX2UDYYDPOJZTOE33T4QVM".parse(), Ok(v1));
+        assert_eq!("mmnddxikiysx2udyydpojztoe33t4qvm".parse(), Ok(v1));
+        assert_eq!(V1.to_uppercase().parse(), Ok(v1));
+
+        let v2: InfoHash = V2.parse().unwrap();
+        assert_eq!(v2.to_string(), V2);
+        assert_eq!(
+            v2.base32(),
+            "3DOTFLETGV6DNBKWV45MDWK4TV3L2DP7N6UYGPWNVQ6VGE2O7K5Q"
+        );
+
+        for invalid in ["", &V1[1..], &format!("{V1}0"), &V1.replace('a', "g")] {
+            assert_eq!(
+                invalid.parse::<InfoHash>(),
+                Err(InvalidInfoHash(invalid.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn a_hybrid_shows_and_goes_on_the_wire_as_its_v1_hash() {
+        let hybrid = hybrid();
+        assert_eq!(hybrid.to_string(), format!("{V1} (v2 {V2})"));
+        assert_eq!(hex::encode(hybrid.wire()), V1);
+        assert_eq!(hybrid.base32(), "MMNDDXIKIYSX2UDYYDPOJZTOE33T4QVM");
+
+        let v2: InfoHash = V2.parse().unwrap();
+        assert_eq!(hex::encode(v2.wire()), V2[..40]);
+    }
+
+    #[test]
+    fn a_hybrid_is_known_by_either_hash() {
+        let hybrid = hybrid();
+        let v1: InfoHash = V1.parse().unwrap();
+        let v2: InfoHash = V2.parse().unwrap();
+        assert!(hybrid.matches(&v1) && v1.matches(&hybrid));
+        assert!(hybrid.matches(&v2) && v2.matches(&hybrid));
+        assert!(!v1.matches(&v2));
+        assert_ne!(hybrid, v1);
+        assert_eq!(hybrid.keys(), [v1, v2]);
+        assert_eq!(v1.keys(), [v1]);
+
+        assert!(hybrid.matches_wire(&v1.wire()));
+        assert!(hybrid.matches_wire(&v2.wire()));
+        assert!(v2.matches_wire(&v2.wire()));
+        assert!(!v1.matches_wire(&v2.wire()));
+    }
+}
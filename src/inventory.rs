@@ -0,0 +1,164 @@
+//! A listing of a directory of torrent files, for archival tooling.
+//!
+//! Rows are written as each torrent is read, so a directory of ten thousand torrents never
+//! has to fit in memory, and a torrent that can't be read is reported without stopping the
+//! rest.
+
+use crate::scrape::csv_field;
+use crate::torrent::{Metainfo, Torrent};
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum InventoryFormat {
+    #[default]
+    Csv,
+    /// A JSON array with one object per torrent.
+    Json,
+}
+
+/// One torrent of an inventory.
+#[derive(Debug, Clone, Serialize)]
+pub struct InventoryEntry {
+    pub path: PathBuf,
+    /// Hex-encoded.
+    pub info_hash: String,
+    pub name: String,
+    pub total_size: usize,
+    pub piece_length: usize,
+    pub pieces: usize,
+    pub files: usize,
+    pub private: bool,
+    /// RFC 3339, in UTC.
+    pub creation_date: Option<String>,
+    /// Every announce URL, the main one first.
+    pub trackers: Vec<String>,
+}
+
+/// What went into an inventory.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InventoryCounts {
+    pub listed: usize,
+    pub failed: usize,
+}
+
+impl InventoryEntry {
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::read(path).context("read torrent file")?;
+        // a directory of large torrents would otherwise copy every piece hash
+        let torrent = Torrent::from_bytes_borrowed(&file).context("parse torrent file")?;
+        let mut trackers = vec![torrent.announce.to_string()];
+        for url in torrent.announce_list.iter().flatten().flatten() {
+            if !trackers.iter().any(|tracker| tracker == url) {
+                trackers.push(url.to_string());
+            }
+        }
+        let file_lengths = torrent.file_lengths();
+        Ok(Self {
+            path: path.to_path_buf(),
+            info_hash: hex::encode(torrent.info_hash()),
+            name: torrent.name(),
+            total_size: file_lengths.iter().sum(),
+            piece_length: torrent.info.plength,
+            pieces: torrent.info.pieces.len(),
+            files: file_lengths.len(),
+            private: torrent.is_private(),
+            creation_date: torrent
+                .creation_date
+                .and_then(|date| u64::try_from(date).ok())
+                .map(|date| {
+                    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(date))
+                        .to_string()
+                }),
+            trackers,
+        })
+    }
+}
+
+/// Writes the inventory of the torrent files in `dir`, and with `recursive` in its
+/// subdirectories, to `out`, in file name order.
+///
+/// Torrents that can't be read are reported on stderr and left out.
+pub fn write(
+    dir: &Path,
+    recursive: bool,
+    format: InventoryFormat,
+    out: &mut impl Write,
+) -> anyhow::Result<InventoryCounts> {
+    let mut counts = InventoryCounts::default();
+    match format {
+        InventoryFormat::Csv => writeln!(
+            out,
+            "path,info_hash,name,total_size,piece_length,pieces,files,private,creation_date,\
+             trackers"
+        )?,
+        InventoryFormat::Json => write!(out, "[")?,
+    }
+    walk(dir, recursive, &mut |path| {
+        let entry = match InventoryEntry::read(path) {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("{}: {err:#}", path.display());
+                counts.failed += 1;
+                return Ok(());
+            }
+        };
+        match format {
+            InventoryFormat::Csv => writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{}",
+                csv_field(&entry.path.display().to_string()),
+                entry.info_hash,
+                csv_field(&entry.name),
+                entry.total_size,
+                entry.piece_length,
+                entry.pieces,
+                entry.files,
+                entry.private,
+                entry.creation_date.as_deref().unwrap_or_default(),
+                csv_field(&entry.trackers.join(" "))
+            )?,
+            InventoryFormat::Json => {
+                let separator = if counts.listed == 0 { "" } else { "," };
+                writeln!(out, "{separator}")?;
+                serde_json::to_writer(&mut *out, &entry).context("serialize inventory entry")?;
+            }
+        }
+        counts.listed += 1;
+        Ok(())
+    })?;
+    if format == InventoryFormat::Json {
+        writeln!(out, "\n]")?;
+    }
+    out.flush()?;
+    Ok(counts)
+}
+
+/// Calls `visit` with every `.torrent` file in `dir`, in file name order.
+fn walk(
+    dir: &Path,
+    recursive: bool,
+    visit: &mut impl FnMut(&Path) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("list {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("list {}", dir.display()))?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                walk(&path, recursive, visit)?;
+            }
+        } else if path.extension().is_some_and(|ext| ext == "torrent") {
+            visit(&path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+//! How a torrent's data splits into pieces, and pieces into the blocks we request.
+
+/// The size of piece `index` of `total` bytes of data split into pieces of `plength`.
+///
+/// Every piece is `plength` long except the last, which holds whatever is left; there are
+/// no pieces past the end, so those are empty.
+pub fn piece_size(total: usize, plength: usize, index: usize) -> usize {
+    total.saturating_sub(index * plength).min(plength)
+}
+
+/// The number of blocks of at most `block_size` a piece of `piece_size` bytes takes.
+pub fn block_count(piece_size: usize, block_size: usize) -> usize {
+    piece_size.div_ceil(block_size)
+}
+
+/// The blocks a piece of `piece_size` bytes is requested in, as `(begin, length)`.
+///
+/// Every block is `block_size` long except the last, which may be shorter. An empty piece
+/// has no blocks. Panics if `block_size` is zero.
+pub fn block_layout(piece_size: usize, block_size: usize) -> impl Iterator<Item = (u32, u32)> {
+    (0..piece_size)
+        .step_by(block_size)
+        .map(move |begin| (begin as u32, block_size.min(piece_size - begin) as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 1 << 14;
+
+    fn blocks(piece_size: usize) -> Vec<(u32, u32)> {
+        block_layout(piece_size, BLOCK).collect()
+    }
+
+    #[test]
+    fn a_piece_smaller_than_a_block_is_one_short_block() {
+        assert_eq!(block_count(100, BLOCK), 1);
+        assert_eq!(blocks(100), [(0, 100)]);
+        // a torrent shorter than its piece length has just that piece
+        assert_eq!(piece_size(100, 1 << 18, 0), 100);
+        assert_eq!(piece_size(100, 1 << 18, 1), 0);
+    }
+
+    #[test]
+    fn exact_multiples_have_no_short_piece_or_block() {
+        let total = 4 * 2 * BLOCK;
+        let sizes: Vec<_> = (0..5)
+            .map(|index| piece_size(total, 2 * BLOCK, index))
+            .collect();
+        assert_eq!(sizes, [2 * BLOCK, 2 * BLOCK, 2 * BLOCK, 2 * BLOCK, 0]);
+        assert_eq!(block_count(2 * BLOCK, BLOCK), 2);
+        assert_eq!(
+            blocks(2 * BLOCK),
+            [(0, BLOCK as u32), (BLOCK as u32, BLOCK as u32)]
+        );
+    }
+
+    #[test]
+    fn a_zero_length_torrent_has_nothing_to_request() {
+        assert_eq!(piece_size(0, BLOCK, 0), 0);
+        assert_eq!(block_count(0, BLOCK), 0);
+        assert!(blocks(0).is_empty());
+    }
+
+    #[test]
+    fn the_final_block_of_the_final_piece_holds_the_rest() {
+        // three full pieces of 2.5 blocks, then 1 block and 7 bytes
+        let plength = 2 * BLOCK + BLOCK / 2;
+        let total = 3 * plength + BLOCK + 7;
+        assert_eq!(piece_size(total, plength, 2), plength);
+        let last = piece_size(total, plength, 3);
+        assert_eq!(last, BLOCK + 7);
+        assert_eq!(block_count(last, BLOCK), 2);
+        assert_eq!(blocks(last), [(0, BLOCK as u32), (BLOCK as u32, 7)]);
+        // a piece length that isn't a multiple of the block size ends every piece short
+        assert_eq!(
+            blocks(plength).last(),
+            Some(&(2 * BLOCK as u32, BLOCK as u32 / 2))
+        );
+        let covered: usize = (0..4)
+            .flat_map(|index| blocks(piece_size(total, plength, index)))
+            .map(|(_, length)| length as usize)
+            .sum();
+        assert_eq!(covered, total);
+    }
+}
@@ -0,0 +1,109 @@
+//! A BitTorrent client: reading torrents, announcing to trackers, and downloading pieces
+//! from peers over the peer wire protocol.
+//!
+//! [`client::Client`] ties the pieces together; [`torrent`], [`tracker`], [`peer`] and
+//! [`hashes`] are the parts it is built from. The hidden modules belong to the
+//! command-line tool and may change at any time.
+
+#[doc(hidden)]
+pub mod add_seed;
+#[doc(hidden)]
+pub mod admission;
+#[doc(hidden)]
+pub mod availability;
+#[doc(hidden)]
+pub mod bench;
+#[doc(hidden)]
+pub mod bstring;
+#[doc(hidden)]
+pub mod choker;
+pub mod client;
+#[doc(hidden)]
+pub mod create;
+#[doc(hidden)]
+pub mod de;
+#[doc(hidden)]
+pub mod dht;
+#[doc(hidden)]
+pub mod download;
+#[doc(hidden)]
+pub mod endgame;
+#[doc(hidden)]
+pub mod extension;
+#[doc(hidden)]
+pub mod files;
+#[cfg(any(test, feature = "test-util"))]
+#[doc(hidden)]
+pub mod fixtures;
+#[doc(hidden)]
+pub mod handshake;
+pub mod hashes;
+#[doc(hidden)]
+pub mod info_hash;
+#[doc(hidden)]
+pub mod inventory;
+#[doc(hidden)]
+pub mod layout;
+#[doc(hidden)]
+pub mod limits;
+#[doc(hidden)]
+pub mod lint;
+#[doc(hidden)]
+pub mod magnet;
+#[doc(hidden)]
+pub mod metadata;
+#[doc(hidden)]
+pub mod netwatch;
+pub mod peer;
+#[doc(hidden)]
+pub mod peer_cache;
+#[doc(hidden)]
+pub mod peer_id;
+#[doc(hidden)]
+pub mod peer_pool;
+#[doc(hidden)]
+pub mod peer_session;
+#[doc(hidden)]
+pub mod perms;
+#[doc(hidden)]
+pub mod picker;
+#[doc(hidden)]
+pub mod piece;
+#[doc(hidden)]
+pub mod plan;
+#[doc(hidden)]
+pub mod prealloc;
+#[doc(hidden)]
+pub mod rate_limit;
+#[doc(hidden)]
+pub mod redact;
+#[doc(hidden)]
+pub mod request_window;
+#[doc(hidden)]
+pub mod resume_import;
+#[doc(hidden)]
+pub mod sanitize;
+#[doc(hidden)]
+pub mod scrape;
+#[doc(hidden)]
+pub mod seed;
+#[doc(hidden)]
+pub mod seed_goal;
+#[doc(hidden)]
+pub mod sidecar;
+#[doc(hidden)]
+pub mod stats;
+pub mod torrent;
+#[doc(hidden)]
+pub mod torrent_ref;
+pub mod tracker;
+#[doc(hidden)]
+pub mod tracker_policy;
+#[doc(hidden)]
+pub mod tracker_tls;
+#[doc(hidden)]
+pub mod value;
+#[doc(hidden)]
+pub mod wire_log;
+#[doc(hidden)]
+pub mod ws_tracker;
@@ -0,0 +1,174 @@
+use crate::choker::DEFAULT_UPLOAD_SLOTS;
+use anyhow::bail;
+
+/// Bytes of a `Piece` frame besides its block: the tag, `index` and `begin`.
+const PIECE_FRAME_OVERHEAD: usize = 1 + 4 + 4;
+
+/// The default largest frame either way, which fits the bitfield of a torrent of 16M
+/// pieces.
+const DEFAULT_MAX_FRAME: usize = 2 << 20;
+
+/// The protocol sizes we work with, tunable from the command line for unusual setups like
+/// tiny embedded targets or torrents with giant pieces.
+///
+/// Build one with [`Limits::default`] or from the command line, then call
+/// [`Limits::validate`] before handing it out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::Args)]
+pub struct Limits {
+    /// Bytes we request per block; other clients refuse anything above 16 KiB.
+    #[arg(long = "block-size", global = true, default_value_t = 1 << 14)]
+    pub block_size: usize,
+    /// The largest frame we accept from a peer, which bounds the memory one peer can make
+    /// us buffer; raised to fit the torrent's bitfield.
+    #[arg(long = "max-inbound-frame", global = true, default_value_t = DEFAULT_MAX_FRAME)]
+    pub max_inbound_frame: usize,
+    /// The largest frame we send; raised to fit the torrent's bitfield.
+    #[arg(long = "max-outbound-frame", global = true, default_value_t = DEFAULT_MAX_FRAME)]
+    pub max_outbound_frame: usize,
+    /// The largest block we serve when seeding.
+    #[arg(long = "max-request-length", global = true, default_value_t = 1 << 14)]
+    pub max_request_length: u32,
+    /// Requests a peer may have queued with us when seeding; any beyond that are dropped.
+    #[arg(long = "max-queued-requests", global = true, default_value_t = 64)]
+    pub max_queued_requests: usize,
+    /// Peers we upload to at once when seeding, picked by rate; one more is unchoked
+    /// optimistically.
+    #[arg(long = "upload-slots", global = true, default_value_t = DEFAULT_UPLOAD_SLOTS)]
+    pub upload_slots: usize,
+    /// Bytes per second we download at most, over all peers, like `500k` or `2m`; 0 is
+    /// no limit.
+    #[arg(long = "max-down", global = true, default_value_t = 0, value_parser = crate::bench::parse_size)]
+    pub max_down: u64,
+    /// Bytes per second we upload at most, over all peers; 0 is no limit.
+    #[arg(long = "max-up", global = true, default_value_t = 0, value_parser = crate::bench::parse_size)]
+    pub max_up: u64,
+    /// Block requests we keep outstanding with a peer when downloading; peers that don't
+    /// tell us their queue length get at most 8.
+    #[arg(long = "pipeline-depth", global = true, default_value_t = 5)]
+    pub pipeline_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            block_size: 1 << 14,
+            max_inbound_frame: DEFAULT_MAX_FRAME,
+            max_outbound_frame: DEFAULT_MAX_FRAME,
+            max_request_length: 1 << 14,
+            max_queued_requests: 64,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            max_down: 0,
+            max_up: 0,
+            pipeline_depth: 5,
+        }
+    }
+}
+
+impl Limits {
+    /// These limits for a torrent of `npieces` pieces, with frames raised to fit its
+    /// bitfield either way so that a large torrent doesn't cost us every peer.
+    pub fn for_pieces(self, npieces: usize) -> Self {
+        let bitfield_frame = 1 + npieces.div_ceil(8);
+        Self {
+            max_inbound_frame: self.max_inbound_frame.max(bitfield_frame),
+            max_outbound_frame: self.max_outbound_frame.max(bitfield_frame),
+            ..self
+        }
+    }
+
+    /// Rejects combinations that can't work, such as requesting blocks we'd refuse to
+    /// receive.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.block_size == 0 || self.max_request_length == 0 {
+            bail!("block size and request length must not be zero");
+        }
+        if self.pipeline_depth == 0 {
+            bail!("the pipeline depth must not be zero");
+        }
+        if self.block_size + PIECE_FRAME_OVERHEAD > self.max_inbound_frame {
+            bail!(
+                "blocks of {} bytes don't fit in inbound frames of at most {} bytes",
+                self.block_size,
+                self.max_inbound_frame
+            );
+        }
+        if self.max_request_length as usize + PIECE_FRAME_OVERHEAD > self.max_outbound_frame {
+            bail!(
+                "blocks of {} bytes don't fit in outbound frames of at most {} bytes",
+                self.max_request_length,
+                self.max_outbound_frame
+            );
+        }
+        if u32::try_from(self.max_outbound_frame).is_err() {
+            bail!("outbound frames can be at most {} bytes", u32::MAX);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_defaults_are_consistent() {
+        Limits::default().validate().unwrap();
+    }
+
+    #[test]
+    fn blocks_must_fit_the_frames_they_travel_in() {
+        let small_frames = Limits {
+            block_size: 1 << 14,
+            max_inbound_frame: 1 << 14,
+            ..Limits::default()
+        };
+        let err = small_frames.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "blocks of 16384 bytes don't fit in inbound frames of at most 16384 bytes"
+        );
+        Limits {
+            max_inbound_frame: (1 << 14) + PIECE_FRAME_OVERHEAD,
+            ..small_frames
+        }
+        .validate()
+        .unwrap();
+
+        let err = Limits {
+            max_request_length: 1 << 22,
+            ..Limits::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert!(err.to_string().contains("outbound frames"), "{err}");
+
+        for zero in [
+            Limits {
+                block_size: 0,
+                ..Limits::default()
+            },
+            Limits {
+                pipeline_depth: 0,
+                ..Limits::default()
+            },
+        ] {
+            assert!(zero.validate().is_err(), "{zero:?}");
+        }
+    }
+
+    #[test]
+    fn frames_grow_to_fit_the_bitfield_and_never_shrink() {
+        let tiny = Limits {
+            block_size: 1024,
+            max_inbound_frame: 2048,
+            max_outbound_frame: 2048,
+            max_request_length: 1024,
+            ..Limits::default()
+        };
+        assert_eq!(tiny.for_pieces(100), tiny);
+        let huge = tiny.for_pieces(1 << 20);
+        assert_eq!(huge.max_inbound_frame, 1 + (1 << 17));
+        assert_eq!(huge.max_outbound_frame, 1 + (1 << 17));
+        assert_eq!(huge.block_size, 1024);
+    }
+}
@@ -0,0 +1,955 @@
+use crate::sanitize;
+use crate::torrent::{Metainfo, Torrent};
+use serde_bencode::value::Value as BencodeValue;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+/// Torrents with more pieces than this make every bitfield and hash check needlessly expensive.
+const MAX_REASONABLE_PIECES: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The torrent works, but is unusual or may confuse some clients.
+    Warning,
+    /// The torrent is broken or unsafe to use.
+    Error,
+}
+
+/// A single problem found in a torrent file.
+#[derive(Debug, Clone)]
+pub struct LintFinding {
+    pub severity: Severity,
+    /// Stable identifier of the rule that produced the finding, e.g. `bencode/key-order`.
+    pub code: &'static str,
+    pub message: String,
+    /// Where in the torrent the problem is, as a dotted key path such as `info.files[2].path`.
+    pub location: String,
+}
+
+/// Runs every lint rule over the raw bytes of a torrent file.
+///
+/// With `private`, also checks that the torrent can be used in private tracker compliance
+/// mode. Findings are ordered by severity, most severe first.
+pub fn lint(bytes: &[u8], private: bool) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+
+    let mut scanner = Scanner {
+        bytes,
+        pos: 0,
+        findings: &mut findings,
+    };
+    let structure_ok = scanner.scan_value("").is_some();
+    if structure_ok && scanner.pos != bytes.len() {
+        scanner.push(
+            Severity::Error,
+            "bencode/trailing-data",
+            "",
+            format!(
+                "{} bytes after the end of the root dict",
+                bytes.len() - scanner.pos
+            ),
+        );
+    }
+
+    if structure_ok {
+        match serde_bencode::from_bytes::<BencodeValue>(bytes) {
+            Ok(BencodeValue::Dict(root)) => lint_metainfo(&root, &mut findings),
+            Ok(_) => findings.push(finding(
+                Severity::Error,
+                "torrent/root",
+                "",
+                "the root value is not a dict".to_string(),
+            )),
+            Err(err) => findings.push(finding(
+                Severity::Error,
+                "bencode/syntax",
+                "",
+                err.to_string(),
+            )),
+        }
+        match serde_bencode::from_bytes::<Torrent>(bytes) {
+            Ok(torrent) => {
+                if let Err(err) = torrent.validate() {
+                    findings.push(finding(
+                        Severity::Error,
+                        "torrent/inconsistent",
+                        "info",
+                        format!("{err:#}"),
+                    ));
+                }
+                if private {
+                    lint_private(&torrent, &mut findings);
+                }
+            }
+            Err(err) => findings.push(finding(
+                Severity::Error,
+                "torrent/parse",
+                "",
+                format!("not a usable torrent: {err}"),
+            )),
+        }
+    }
+
+    findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+    findings
+}
+
+fn finding(severity: Severity, code: &'static str, location: &str, message: String) -> LintFinding {
+    LintFinding {
+        severity,
+        code,
+        message,
+        location: location.to_string(),
+    }
+}
+
+/// Walks the raw bencode, checking the encoding rules serde_bencode silently tolerates.
+struct Scanner<'a, 'f> {
+    bytes: &'a [u8],
+    pos: usize,
+    findings: &'f mut Vec<LintFinding>,
+}
+
+impl<'a> Scanner<'a, '_> {
+    fn push(&mut self, severity: Severity, code: &'static str, location: &str, message: String) {
+        self.findings
+            .push(finding(severity, code, location, message));
+    }
+
+    fn syntax_error<T>(&mut self, location: &str, message: String) -> Option<T> {
+        let message = format!("{message} at byte {}", self.pos);
+        self.push(Severity::Error, "bencode/syntax", location, message);
+        None
+    }
+
+    /// Scans one value, returning `None` after reporting a syntax error.
+    fn scan_value(&mut self, location: &str) -> Option<()> {
+        match self.bytes.get(self.pos) {
+            Some(b'i') => {
+                self.pos += 1;
+                self.scan_int(location, b'e').map(|_| ())
+            }
+            Some(b'0'..=b'9') => self.scan_bytes(location).map(|_| ()),
+            Some(b'l') => {
+                self.pos += 1;
+                let mut index = 0;
+                while self.bytes.get(self.pos) != Some(&b'e') {
+                    self.scan_value(&format!("{location}[{index}]"))?;
+                    index += 1;
+                }
+                self.pos += 1;
+                Some(())
+            }
+            Some(b'd') => {
+                self.pos += 1;
+                let mut prev_key: Option<&[u8]> = None;
+                let mut seen = HashSet::new();
+                while self.bytes.get(self.pos) != Some(&b'e') {
+                    let key = match self.bytes.get(self.pos) {
+                        Some(b'0'..=b'9') => self.scan_bytes(location)?,
+                        _ => return self.syntax_error(location, "dict key is not a string".into()),
+                    };
+                    let key_str = String::from_utf8_lossy(key);
+                    let child = if location.is_empty() {
+                        key_str.to_string()
+                    } else {
+                        format!("{location}.{key_str}")
+                    };
+                    if !seen.insert(key) {
+                        self.push(
+                            Severity::Error,
+                            "bencode/duplicate-key",
+                            location,
+                            format!("duplicate key `{key_str}`"),
+                        );
+                    } else if prev_key.is_some_and(|prev| prev > key) {
+                        self.push(
+                            Severity::Warning,
+                            "bencode/key-order",
+                            location,
+                            format!("key `{key_str}` is not in sorted order"),
+                        );
+                    }
+                    prev_key = Some(key);
+                    self.scan_value(&child)?;
+                }
+                self.pos += 1;
+                Some(())
+            }
+            Some(&other) => self.syntax_error(
+                location,
+                format!("unexpected byte 0x{other:02x} at the start of a value"),
+            ),
+            None => self.syntax_error(location, "unexpected end of input".into()),
+        }
+    }
+
+    /// Scans the digits of an integer up to `terminator`, which is consumed.
+    fn scan_int(&mut self, location: &str, terminator: u8) -> Option<i64> {
+        let start = self.pos;
+        while self.bytes.get(self.pos).is_some_and(|&b| b != terminator) {
+            self.pos += 1;
+        }
+        if self.pos >= self.bytes.len() {
+            return self.syntax_error(location, "unterminated integer".into());
+        }
+        let digits = &self.bytes[start..self.pos];
+        self.pos += 1;
+        let Some(value) = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse::<i64>().ok())
+        else {
+            return self.syntax_error(
+                location,
+                format!("invalid integer `{}`", String::from_utf8_lossy(digits)),
+            );
+        };
+        let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
+        if digits.starts_with(b"+")
+            || digits == b"-0"
+            || (unsigned.len() > 1 && unsigned[0] == b'0')
+        {
+            self.push(
+                Severity::Error,
+                "bencode/non-canonical-int",
+                location,
+                format!(
+                    "integer `{}` is not canonical",
+                    String::from_utf8_lossy(digits)
+                ),
+            );
+        }
+        Some(value)
+    }
+
+    fn scan_bytes(&mut self, location: &str) -> Option<&'a [u8]> {
+        let len = self.scan_int(location, b':')?;
+        if len < 0 {
+            return self.syntax_error(location, "negative string length".into());
+        }
+        let len = len as usize;
+        if self.bytes.len() - self.pos < len {
+            return self.syntax_error(
+                location,
+                format!("string of length {len} runs past the end"),
+            );
+        }
+        let value = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Some(value)
+    }
+}
+
+fn lint_metainfo(root: &HashMap<Vec<u8>, BencodeValue>, findings: &mut Vec<LintFinding>) {
+    let mut push = |severity, code, location: &str, message| {
+        findings.push(finding(severity, code, location, message))
+    };
+
+    for key in ["created by", "creation date"] {
+        if !root.contains_key(key.as_bytes()) {
+            push(
+                Severity::Warning,
+                "torrent/missing-recommended",
+                "",
+                format!("recommended key `{key}` is missing"),
+            );
+        }
+    }
+
+    match root.get(&b"announce"[..]) {
+        Some(BencodeValue::Bytes(url)) => {
+            if let Some(problem) = announce_url_problem(url) {
+                push(Severity::Error, "tracker/url", "announce", problem);
+            }
+        }
+        Some(_) => push(
+            Severity::Error,
+            "tracker/url",
+            "announce",
+            "announce is not a string".into(),
+        ),
+        None if !root.contains_key(&b"announce-list"[..]) => push(
+            Severity::Warning,
+            "tracker/missing",
+            "",
+            "no announce or announce-list, the torrent is trackerless".into(),
+        ),
+        None => {}
+    }
+    if let Some(BencodeValue::List(tiers)) = root.get(&b"announce-list"[..]) {
+        for (t, tier) in tiers.iter().enumerate() {
+            let BencodeValue::List(urls) = tier else {
+                push(
+                    Severity::Error,
+                    "tracker/url",
+                    &format!("announce-list[{t}]"),
+                    "tier is not a list".into(),
+                );
+                continue;
+            };
+            for (u, url) in urls.iter().enumerate() {
+                let location = format!("announce-list[{t}][{u}]");
+                match url {
+                    BencodeValue::Bytes(url) => {
+                        if let Some(problem) = announce_url_problem(url) {
+                            push(Severity::Error, "tracker/url", &location, problem);
+                        }
+                    }
+                    _ => push(
+                        Severity::Error,
+                        "tracker/url",
+                        &location,
+                        "url is not a string".into(),
+                    ),
+                }
+            }
+        }
+    }
+
+    let Some(BencodeValue::Dict(info)) = root.get(&b"info"[..]) else {
+        push(
+            Severity::Error,
+            "torrent/missing-info",
+            "",
+            "the info dict is missing".into(),
+        );
+        return;
+    };
+
+    let plength = match info.get(&b"piece length"[..]) {
+        Some(BencodeValue::Int(plength)) if *plength > 0 => {
+            if !(*plength as u64).is_power_of_two() {
+                push(
+                    Severity::Warning,
+                    "torrent/piece-length",
+                    "info.piece length",
+                    format!("{plength} is not a power of two"),
+                );
+            }
+            Some(*plength as u64)
+        }
+        _ => {
+            push(
+                Severity::Error,
+                "torrent/piece-length",
+                "info.piece length",
+                "piece length must be a positive integer".into(),
+            );
+            None
+        }
+    };
+
+    let meta_version = info.get(&b"meta version"[..]);
+    let has_v1 = info.contains_key(&b"pieces"[..]);
+    let has_v2 = info.contains_key(&b"file tree"[..]);
+    match meta_version {
+        Some(BencodeValue::Int(2)) => {
+            if !has_v2 {
+                push(
+                    Severity::Error,
+                    "torrent/v2",
+                    "info",
+                    "meta version 2 without a file tree".into(),
+                );
+            }
+            if !root.contains_key(&b"piece layers"[..]) {
+                push(
+                    Severity::Error,
+                    "torrent/v2",
+                    "",
+                    "meta version 2 without piece layers".into(),
+                );
+            }
+            if has_v1 {
+                push(
+                    Severity::Warning,
+                    "torrent/hybrid",
+                    "info",
+                    "hybrid v1/v2 torrent, only the v1 part is used by this client".into(),
+                );
+            } else {
+                push(
+                    Severity::Error,
+                    "torrent/v2",
+                    "info",
+                    "v2-only torrents are not supported".into(),
+                );
+            }
+        }
+        Some(other) => push(
+            Severity::Error,
+            "torrent/v2",
+            "info.meta version",
+            format!("unknown meta version {other:?}"),
+        ),
+        None if has_v2 => push(
+            Severity::Error,
+            "torrent/v2",
+            "info.file tree",
+            "file tree without meta version".into(),
+        ),
+        None => {}
+    }
+
+    if let Some(BencodeValue::Bytes(name)) = info.get(&b"name"[..]) {
+        if let Some(problem) = path_component_problem(name) {
+            push(Severity::Error, "path/suspicious", "info.name", problem);
+        }
+    }
+
+    let total_length = match (info.get(&b"length"[..]), info.get(&b"files"[..])) {
+        (Some(BencodeValue::Int(length)), None) => (*length).max(0) as u64,
+        (None, Some(BencodeValue::List(files))) => lint_files(files, plength, &mut push),
+        _ => return,
+    };
+    if let Some(plength) = plength {
+        let npieces = total_length.div_ceil(plength) as usize;
+        if npieces > MAX_REASONABLE_PIECES {
+            push(
+                Severity::Warning,
+                "torrent/piece-count",
+                "info.piece length",
+                format!("{npieces} pieces, consider a larger piece length"),
+            );
+        }
+    }
+}
+
+/// Checks the entries of a multi-file torrent, returning their total length.
+fn lint_files(
+    files: &[BencodeValue],
+    plength: Option<u64>,
+    push: &mut impl FnMut(Severity, &'static str, &str, String),
+) -> u64 {
+    if files.is_empty() {
+        push(
+            Severity::Error,
+            "path/empty",
+            "info.files",
+            "multi-file torrent without files".into(),
+        );
+    }
+    let mut offset = 0u64;
+    for (i, file) in files.iter().enumerate() {
+        let location = format!("info.files[{i}]");
+        let BencodeValue::Dict(file) = file else {
+            push(
+                Severity::Error,
+                "torrent/file",
+                &location,
+                "file entry is not a dict".into(),
+            );
+            continue;
+        };
+        let length = match file.get(&b"length"[..]) {
+            Some(BencodeValue::Int(length)) if *length >= 0 => *length as u64,
+            _ => {
+                push(
+                    Severity::Error,
+                    "torrent/file",
+                    &location,
+                    "length must be a non-negative integer".into(),
+                );
+                0
+            }
+        };
+        match file.get(&b"path"[..]) {
+            Some(BencodeValue::List(path)) if !path.is_empty() => {
+                for (c, component) in path.iter().enumerate() {
+                    let problem = match component {
+                        BencodeValue::Bytes(component) => path_component_problem(component),
+                        _ => Some("path component is not a string".into()),
+                    };
+                    if let Some(problem) = problem {
+                        push(
+                            Severity::Error,
+                            "path/suspicious",
+                            &format!("{location}.path[{c}]"),
+                            problem,
+                        );
+                    }
+                }
+            }
+            _ => push(
+                Severity::Error,
+                "path/empty",
+                &format!("{location}.path"),
+                "path must be a non-empty list".into(),
+            ),
+        }
+
+        let is_padding = matches!(
+            file.get(&b"attr"[..]),
+            Some(BencodeValue::Bytes(attr)) if attr.contains(&b'p')
+        );
+        if is_padding {
+            if i == files.len() - 1 {
+                push(
+                    Severity::Warning,
+                    "padding/trailing",
+                    &location,
+                    "padding file at the end of the torrent".into(),
+                );
+            }
+            if let Some(plength) = plength {
+                if !(offset + length).is_multiple_of(plength) {
+                    push(
+                        Severity::Warning,
+                        "padding/alignment",
+                        &location,
+                        format!(
+                            "padding file ends at {} which is not a multiple of the piece length",
+                            offset + length
+                        ),
+                    );
+                }
+            }
+        }
+        offset += length;
+    }
+    offset
+}
+
+/// Checks what private tracker compliance mode relies on.
+///
+/// That mode gets peers from the tracker only, announces with a stable key and echoes the
+/// tracker id, so what's left to check is the torrent itself.
+fn lint_private(torrent: &Torrent, findings: &mut Vec<LintFinding>) {
+    match torrent.info.private {
+        Some(1) => {}
+        Some(private) => findings.push(finding(
+            Severity::Error,
+            "private/flag",
+            "info.private",
+            format!("private is {private}, only 1 makes the torrent private"),
+        )),
+        None => findings.push(finding(
+            Severity::Warning,
+            "private/flag",
+            "info",
+            "the torrent is not private, compliance mode won't be enabled for it".into(),
+        )),
+    }
+    let trackers: Vec<&str> = std::iter::once(torrent.announce.as_str())
+        .chain(
+            torrent
+                .announce_list
+                .iter()
+                .flatten()
+                .flatten()
+                .map(String::as_str),
+        )
+        .filter(|url| !url.is_empty())
+        .collect();
+    if trackers.is_empty() {
+        findings.push(finding(
+            Severity::Error,
+            "private/no-tracker",
+            "announce",
+            "without DHT and PEX, a private torrent without trackers never finds peers".into(),
+        ));
+    }
+    for url in trackers {
+        let Ok(parsed) = reqwest::Url::parse(url) else {
+            continue;
+        };
+        match parsed.scheme() {
+            "http" | "ws" => findings.push(finding(
+                Severity::Warning,
+                "private/plaintext",
+                "announce",
+                format!("`{url}` sends the passkey and our stats unencrypted"),
+            )),
+            "udp" => findings.push(finding(
+                Severity::Error,
+                "private/unsupported-tracker",
+                "announce",
+                format!("`{url}` is a UDP tracker, which this client can't announce to"),
+            )),
+            _ => {}
+        }
+    }
+}
+
+fn announce_url_problem(url: &[u8]) -> Option<String> {
+    let Ok(url) = std::str::from_utf8(url) else {
+        return Some("announce url is not valid UTF-8".into());
+    };
+    let parsed = match reqwest::Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(err) => return Some(format!("`{url}` is not a valid url: {err}")),
+    };
+    if !matches!(parsed.scheme(), "http" | "https" | "udp" | "ws" | "wss") {
+        return Some(format!("`{}` is not a tracker url scheme", parsed.scheme()));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Some(format!("`{url}` has no host"));
+    }
+    None
+}
+
+fn path_component_problem(component: &[u8]) -> Option<String> {
+    let shown = String::from_utf8_lossy(component);
+    if component.is_empty() {
+        return Some("empty path component".into());
+    }
+    if component == b"." || component == b".." {
+        return Some(format!(
+            "path component `{shown}` escapes the download directory"
+        ));
+    }
+    if component.iter().any(|&b| b == b'/' || b == b'\\' || b == 0) {
+        return Some(format!(
+            "path component `{shown}` contains a separator or NUL"
+        ));
+    }
+    if sanitize::is_reserved(&shown) {
+        return Some(format!("`{shown}` is a reserved file name on Windows"));
+    }
+    None
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+impl Display for LintFinding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<7} [{}]", self.severity, self.code)?;
+        if !self.location.is_empty() {
+            write!(f, " {}:", self.location)?;
+        }
+        write!(f, " {}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(value: &str) -> String {
+        format!("{}:{value}", value.len())
+    }
+
+    /// A single-file info dict of 100 bytes with `extra` appended after `pieces`.
+    fn info(name: &str, extra: &str) -> String {
+        format!(
+            "d{}i100e{}{}{}i16384e{}{}{extra}e",
+            s("length"),
+            s("name"),
+            s(name),
+            s("piece length"),
+            s("pieces"),
+            s(&"a".repeat(20))
+        )
+    }
+
+    /// A torrent with every recommended key, announcing to `announce` if there is one.
+    fn metainfo(announce: Option<&str>, info: &str) -> Vec<u8> {
+        let announce = announce.map_or(String::new(), |url| format!("{}{}", s("announce"), s(url)));
+        format!(
+            "d{announce}{}{}{}i0e{}{info}e",
+            s("created by"),
+            s("test"),
+            s("creation date"),
+            s("info")
+        )
+        .into_bytes()
+    }
+
+    fn clean() -> Vec<u8> {
+        metainfo(
+            Some("http://tracker.example/announce"),
+            &info("file.bin", ""),
+        )
+    }
+
+    /// The code and location of every finding of `severity`.
+    fn found(bytes: &[u8], private: bool, severity: Severity) -> Vec<(&'static str, String)> {
+        lint(bytes, private)
+            .into_iter()
+            .filter(|finding| finding.severity == severity)
+            .map(|finding| (finding.code, finding.location))
+            .collect()
+    }
+
+    fn has(bytes: &[u8], severity: Severity, code: &str, location: &str) -> bool {
+        found(bytes, false, severity)
+            .iter()
+            .any(|(c, l)| *c == code && l == location)
+    }
+
+    #[test]
+    fn a_clean_torrent_has_no_findings() {
+        assert!(
+            lint(&clean(), false).is_empty(),
+            "{:?}",
+            lint(&clean(), false)
+        );
+    }
+
+    #[test]
+    fn keys_out_of_order_are_a_warning() {
+        let info = format!(
+            "d{}{}{}i100e{}i16384e{}{}e",
+            s("name"),
+            s("f"),
+            s("length"),
+            s("piece length"),
+            s("pieces"),
+            s(&"a".repeat(20))
+        );
+        let bytes = metainfo(Some("http://t.example/a"), &info);
+        assert!(has(&bytes, Severity::Warning, "bencode/key-order", "info"));
+    }
+
+    #[test]
+    fn duplicate_keys_are_an_error() {
+        let info = info("f", &format!("{}{}", s("name"), s("g")));
+        let bytes = metainfo(Some("http://t.example/a"), &info);
+        assert!(has(
+            &bytes,
+            Severity::Error,
+            "bencode/duplicate-key",
+            "info"
+        ));
+    }
+
+    #[test]
+    fn non_canonical_integers_are_an_error() {
+        let bytes = String::from_utf8(clean()).unwrap().replace("i0e", "i00e");
+        assert!(has(
+            bytes.as_bytes(),
+            Severity::Error,
+            "bencode/non-canonical-int",
+            "creation date"
+        ));
+    }
+
+    #[test]
+    fn broken_bencode_is_a_syntax_error_or_trailing_data() {
+        let mut truncated = clean();
+        truncated.pop();
+        assert!(has(&truncated, Severity::Error, "bencode/syntax", ""));
+        let mut trailing = clean();
+        trailing.extend_from_slice(b"xyz");
+        assert!(has(&trailing, Severity::Error, "bencode/trailing-data", ""));
+    }
+
+    #[test]
+    fn missing_recommended_keys_are_a_warning() {
+        let bytes = format!("d{}d{}e", s("info"), &info("f", "")[1..]);
+        let found = found(bytes.as_bytes(), false, Severity::Warning);
+        let missing = found
+            .iter()
+            .filter(|(code, _)| *code == "torrent/missing-recommended")
+            .count();
+        assert_eq!(missing, 2);
+        assert!(found.iter().any(|(code, _)| *code == "tracker/missing"));
+    }
+
+    #[test]
+    fn unusable_announce_urls_are_an_error() {
+        for url in ["ftp://t.example/a", "not a url", "http://"] {
+            let bytes = metainfo(Some(url), &info("f", ""));
+            assert!(
+                has(&bytes, Severity::Error, "tracker/url", "announce"),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_missing_info_dict_is_an_error() {
+        let bytes = format!("d{}{}e", s("announce"), s("http://t.example/a"));
+        assert!(has(
+            bytes.as_bytes(),
+            Severity::Error,
+            "torrent/missing-info",
+            ""
+        ));
+    }
+
+    #[test]
+    fn piece_lengths_that_are_no_power_of_two_are_a_warning() {
+        let bytes = String::from_utf8(clean())
+            .unwrap()
+            .replace("i16384e", "i10000e");
+        assert!(has(
+            bytes.as_bytes(),
+            Severity::Warning,
+            "torrent/piece-length",
+            "info.piece length"
+        ));
+        let bytes = String::from_utf8(clean())
+            .unwrap()
+            .replace("i16384e", "i0e");
+        assert!(has(
+            bytes.as_bytes(),
+            Severity::Error,
+            "torrent/piece-length",
+            "info.piece length"
+        ));
+    }
+
+    #[test]
+    fn absurd_piece_counts_are_a_warning() {
+        let bytes = String::from_utf8(clean())
+            .unwrap()
+            .replace("i100e", "i4294967296e")
+            .replace("i16384e", "i1024e");
+        assert!(has(
+            bytes.as_bytes(),
+            Severity::Warning,
+            "torrent/piece-count",
+            "info.piece length"
+        ));
+        // the piece hashes don't cover that much data either
+        assert!(has(
+            bytes.as_bytes(),
+            Severity::Error,
+            "torrent/inconsistent",
+            "info"
+        ));
+    }
+
+    #[test]
+    fn names_escaping_the_download_directory_are_an_error() {
+        let bytes = metainfo(Some("http://t.example/a"), &info("..", ""));
+        assert!(has(&bytes, Severity::Error, "path/suspicious", "info.name"));
+        let bytes = metainfo(Some("http://t.example/a"), &info("CON.txt", ""));
+        assert!(has(&bytes, Severity::Error, "path/suspicious", "info.name"));
+    }
+
+    /// A multi-file info dict of `files`, each `(length, path, attr)`.
+    fn multi_file(files: &[(u64, &[&str], &str)]) -> String {
+        let files: String = files
+            .iter()
+            .map(|(length, path, attr)| {
+                let attr = if attr.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}{}", s("attr"), s(attr))
+                };
+                let path: String = path.iter().map(|component| s(component)).collect();
+                format!("d{attr}{}i{length}e{}l{path}ee", s("length"), s("path"))
+            })
+            .collect();
+        format!(
+            "d{}l{files}e{}{}{}i4e{}{}e",
+            s("files"),
+            s("name"),
+            s("dir"),
+            s("piece length"),
+            s("pieces"),
+            s(&"a".repeat(20))
+        )
+    }
+
+    #[test]
+    fn suspicious_and_empty_file_paths_are_an_error() {
+        let info = multi_file(&[(1, &["a", "..", "b"], ""), (1, &[], ""), (2, &["c/d"], "")]);
+        let bytes = metainfo(Some("http://t.example/a"), &info);
+        assert!(has(
+            &bytes,
+            Severity::Error,
+            "path/suspicious",
+            "info.files[0].path[1]"
+        ));
+        assert!(has(
+            &bytes,
+            Severity::Error,
+            "path/empty",
+            "info.files[1].path"
+        ));
+        assert!(has(
+            &bytes,
+            Severity::Error,
+            "path/suspicious",
+            "info.files[2].path[0]"
+        ));
+    }
+
+    #[test]
+    fn misplaced_padding_files_are_a_warning() {
+        let info = multi_file(&[
+            (1, &["a"], ""),
+            (2, &[".pad", "2"], "p"),
+            (1, &[".pad", "1"], "p"),
+        ]);
+        let bytes = metainfo(Some("http://t.example/a"), &info);
+        assert!(has(
+            &bytes,
+            Severity::Warning,
+            "padding/alignment",
+            "info.files[1]"
+        ));
+        assert!(has(
+            &bytes,
+            Severity::Warning,
+            "padding/trailing",
+            "info.files[2]"
+        ));
+    }
+
+    #[test]
+    fn v2_torrents_are_flagged() {
+        let hybrid = info("f", &format!("{}i2e", s("meta version")));
+        let found = found(
+            &metainfo(Some("http://t.example/a"), &hybrid),
+            false,
+            Severity::Error,
+        );
+        assert!(found.contains(&("torrent/v2", "info".to_string())));
+        assert!(found.contains(&("torrent/v2", String::new())));
+    }
+
+    #[test]
+    fn private_mode_checks_the_flag_and_the_trackers() {
+        let public = metainfo(Some("udp://t.example:80"), &info("f", ""));
+        let warnings = found(&public, true, Severity::Warning);
+        assert!(warnings.contains(&("private/flag", "info".to_string())));
+        let errors = found(&public, true, Severity::Error);
+        assert!(errors.contains(&("private/unsupported-tracker", "announce".to_string())));
+
+        let private = metainfo(
+            Some("http://t.example/a"),
+            &info("f", &format!("{}i1e", s("private"))),
+        );
+        assert_eq!(
+            found(&private, true, Severity::Warning),
+            [("private/plaintext", "announce".to_string())]
+        );
+        assert!(found(&private, false, Severity::Warning).is_empty());
+    }
+
+    #[test]
+    fn errors_come_before_warnings() {
+        let bytes = metainfo(Some("ftp://t.example/a"), &info("f", ""))
+            .into_iter()
+            .chain(*b"x")
+            .collect::<Vec<_>>();
+        let findings = lint(&bytes, true);
+        assert!(findings.is_sorted_by_key(|finding| std::cmp::Reverse(finding.severity)));
+        assert_eq!(findings.first().unwrap().severity, Severity::Error);
+    }
+
+    #[test]
+    fn findings_show_severity_code_and_location() {
+        let finding = finding(
+            Severity::Warning,
+            "torrent/piece-length",
+            "info.piece length",
+            "10000 is not a power of two".into(),
+        );
+        assert_eq!(
+            finding.to_string(),
+            "warning [torrent/piece-length] info.piece length: 10000 is not a power of two"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Magnet links (BEP 9): a torrent named by its info hash, with a name and trackers to
+//! find the swarm by, but without the metadata itself.
+
+use crate::info_hash::{InfoHash, InvalidInfoHash};
+use crate::tracker;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// The multihash prefix of a SHA-256 of 32 bytes, which `urn:btmh:` hashes start with.
+const SHA256_MULTIHASH: &str = "1220";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    /// The `dn` to show until the metadata arrives, if any.
+    pub name: Option<String>,
+    /// The `tr` announce URLs, in the order given.
+    pub trackers: Vec<reqwest::Url>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MagnetError {
+    #[error("not a magnet link, which starts with `magnet:?`")]
+    NotMagnet,
+    #[error("the magnet link has no `xt` info hash")]
+    MissingTopic,
+    #[error("`xt={0}` is neither a urn:btih: v1 hash nor a urn:btmh: SHA-256 one")]
+    UnsupportedTopic(String),
+    #[error("`xt` gives two different {0} hashes")]
+    ConflictingTopics(&'static str),
+    #[error("bad `xt` hash")]
+    InvalidHash(#[from] InvalidInfoHash),
+    #[error("bad `tr` tracker `{url}`")]
+    InvalidTracker {
+        url: String,
+        #[source]
+        source: url::ParseError,
+    },
+}
+
+impl FromStr for MagnetLink {
+    type Err = MagnetError;
+
+    /// Takes a v1 `urn:btih:` hash in hex or base32, a v2 `urn:btmh:` one, or both for a
+    /// hybrid torrent. Parameters we don't know are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = s.strip_prefix("magnet:?").ok_or(MagnetError::NotMagnet)?;
+        let (mut v1, mut v2) = (None, None);
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "xt" => {
+                    let hash = parse_topic(&value)?;
+                    let (slot, kind) = match hash {
+                        InfoHash::V2(_) => (&mut v2, "v2"),
+                        _ => (&mut v1, "v1"),
+                    };
+                    if slot.is_some_and(|known| known != hash) {
+                        return Err(MagnetError::ConflictingTopics(kind));
+                    }
+                    *slot = Some(hash);
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => {
+                    let url = reqwest::Url::parse(&value).map_err(|source| {
+                        MagnetError::InvalidTracker {
+                            url: value.to_string(),
+                            source,
+                        }
+                    })?;
+                    trackers.push(url);
+                }
+                _ => {}
+            }
+        }
+        let info_hash = match (v1, v2) {
+            (Some(InfoHash::V1(v1)), Some(InfoHash::V2(v2))) => InfoHash::Hybrid { v1, v2 },
+            (Some(hash), None) | (None, Some(hash)) => hash,
+            _ => return Err(MagnetError::MissingTopic),
+        };
+        Ok(Self {
+            info_hash,
+            name,
+            trackers,
+        })
+    }
+}
+
+/// The info hash of an `xt` parameter.
+fn parse_topic(topic: &str) -> Result<InfoHash, MagnetError> {
+    let unsupported = || MagnetError::UnsupportedTopic(topic.to_string());
+    if let Some(hash) = topic.strip_prefix("urn:btih:") {
+        match hash.parse()? {
+            hash @ InfoHash::V1(_) => Ok(hash),
+            _ => Err(unsupported()),
+        }
+    } else if let Some(hash) = topic.strip_prefix("urn:btmh:") {
+        let hash = hash
+            .strip_prefix(SHA256_MULTIHASH)
+            .ok_or_else(unsupported)?;
+        match hash.parse()? {
+            hash @ InfoHash::V2(_) => Ok(hash),
+            _ => Err(unsupported()),
+        }
+    } else {
+        Err(unsupported())
+    }
+}
+
+/// The link itself, with a v1 hash in hex, so parsing it gives back the same link.
+impl Display for MagnetLink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("magnet:?")?;
+        let mut separator = "";
+        if let Some(v1) = self.info_hash.v1() {
+            write!(f, "xt=urn:btih:{}", hex::encode(v1))?;
+            separator = "&";
+        }
+        if let Some(v2) = self.info_hash.v2() {
+            write!(
+                f,
+                "{separator}xt=urn:btmh:{SHA256_MULTIHASH}{}",
+                hex::encode(v2)
+            )?;
+        }
+        if let Some(name) = &self.name {
+            write!(f, "&dn={}", tracker::urlencode_bytes(name.as_bytes()))?;
+        }
+        for tracker in &self.trackers {
+            write!(
+                f,
+                "&tr={}",
+                tracker::urlencode_bytes(tracker.as_str().as_bytes())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HYBRID: &str = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&xt=urn:btmh:1220d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb&dn=bittorrent-v1-v2-hybrid-test";
+
+    #[test]
+    fn both_topics_make_a_hybrid() {
+        let magnet: MagnetLink = HYBRID.parse().unwrap();
+        let InfoHash::Hybrid { v1, v2 } = magnet.info_hash else {
+            panic!("{:?}", magnet.info_hash);
+        };
+        assert_eq!(hex::encode(v1), "631a31dd0a46257d5078c0dee4e66e26f73e42ac");
+        assert_eq!(
+            hex::encode(v2),
+            "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb"
+        );
+        assert_eq!(magnet.name.as_deref(), Some("bittorrent-v1-v2-hybrid-test"));
+        assert_eq!(magnet.to_string(), HYBRID);
+    }
+
+    #[test]
+    fn a_single_topic_in_any_form() {
+        let hex: MagnetLink = "magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac"
+            .parse()
+            .unwrap();
+        let base32: MagnetLink = "magnet:?xt=urn:btih:MMNDDXIKIYSX2UDYYDPOJZTOE33T4QVM"
+            .parse()
+            .unwrap();
+        assert_eq!(hex, base32);
+        assert!(matches!(hex.info_hash, InfoHash::V1(_)));
+
+        let v2: MagnetLink = "magnet:?xt=urn:btmh:1220d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb&tr=http%3A%2F%2Ftracker.example%2Fannounce"
+            .parse()
+            .unwrap();
+        assert!(matches!(v2.info_hash, InfoHash::V2(_)));
+        assert_eq!(v2.trackers[0].as_str(), "http://tracker.example/announce");
+    }
+
+    #[test]
+    fn bad_links_say_what_is_wrong() {
+        let err = |link: &str| link.parse::<MagnetLink>().unwrap_err().to_string();
+        assert_eq!(
+            err("http://example.com"),
+            "not a magnet link, which starts with `magnet:?`"
+        );
+        assert_eq!(err("magnet:?dn=x"), "the magnet link has no `xt` info hash");
+        assert_eq!(
+            err("magnet:?xt=urn:sha1:631a31dd0a46257d5078c0dee4e66e26f73e42ac"),
+            "`xt=urn:sha1:631a31dd0a46257d5078c0dee4e66e26f73e42ac` is neither a urn:btih: v1 \
+             hash nor a urn:btmh: SHA-256 one"
+        );
+        // a v2 hash is only valid as a multihash
+        assert!(err(
+            "magnet:?xt=urn:btih:d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb"
+        )
+        .contains("neither"));
+        assert_eq!(
+            err("magnet:?xt=urn:btih:631a31dd0a46257d5078c0dee4e66e26f73e42ac&xt=urn:btih:0000000000000000000000000000000000000000"),
+            "`xt` gives two different v1 hashes"
+        );
+        assert_eq!(err("magnet:?xt=urn:btih:xyz"), "bad `xt` hash");
+    }
+}
@@ -1,275 +1,961 @@
-#![feature(generic_const_exprs)]
-
 use anyhow::Context;
-use clap::Parser;
-use futures_util::{SinkExt, StreamExt};
-use sha1::{Digest, Sha1};
-use std::net::SocketAddrV4;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+use bittorrent_starter_rust::availability::{self, AvailabilityReport};
+use bittorrent_starter_rust::client::{
+    self, Client, DownloadEvent, DownloadOptions, PickOrder, LISTEN_PORT, MAX_PEERS,
 };
-
-use crate::common::AsBytes;
-use crate::peer::{Message, MessageFramer, MessagePiece, MessageRequest, MessageTag};
-use crate::{
-    args::{Args, Command},
-    peer::Handshake,
-    torrent::Torrent,
-    tracker::TrackerRequest,
-    tracker::TrackerResponse,
+use bittorrent_starter_rust::dht::Dht;
+use bittorrent_starter_rust::info_hash::InfoHash;
+use bittorrent_starter_rust::magnet::MagnetLink;
+use bittorrent_starter_rust::netwatch::FailureBurst;
+use bittorrent_starter_rust::peer_cache::{PeerCacheConfig, PeerCacheFile};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::plan::{DownloadPiecePlan, DryRunAnnounce, SeedPlan};
+use bittorrent_starter_rust::scrape::{self, ScrapeTarget};
+use bittorrent_starter_rust::seed::{self, PieceMap, PieceMapWriter, Seeder};
+use bittorrent_starter_rust::seed_goal::{GoalTracker, SeedGoal, GOAL_CHECK_INTERVAL};
+use bittorrent_starter_rust::stats::{
+    DrainRate, HumanBytes, PeerCounts, TransferStats, Transferred,
 };
+use bittorrent_starter_rust::torrent::{Keys, Metainfo, Torrent, TorrentSummary};
+use bittorrent_starter_rust::tracker::{AnnounceSchedule, SwarmNeed, TrackerClient};
+use bittorrent_starter_rust::tracker_policy::TrackerPolicy;
+use bittorrent_starter_rust::value::BenCode;
+use bittorrent_starter_rust::{
+    add_seed, bench, create, de, handshake, inventory, lint, perms, picker, piece, prealloc,
+    redact, sidecar, wire_log,
+};
+use clap::Parser;
+use std::collections::VecDeque;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
 
-pub(crate) mod args;
-pub(crate) mod common;
-pub(crate) mod de;
-pub(crate) mod hashes;
-pub(crate) mod peer;
-pub(crate) mod torrent;
-pub(crate) mod tracker;
+use crate::args::{Args, Command, Pick};
+use crate::progress::ProgressReporter;
+use crate::stream::StdoutStream;
 
-const PEER_ID: &str = "00112233445566778899";
-const PEER_ID_BYTES: [u8; 20] = *b"00112233445566778899";
+mod args;
+mod progress;
+mod stream;
 
-const PIECE_BLOCK_MAX: usize = 1 << 14;
+/// Prints what `info` shows of a torrent, known by `identity`.
+fn print_info(torrent: &Torrent, identity: &InfoHash) {
+    debug!("{torrent:?}");
+    println!("Tracker URL: {}", torrent.announce);
+    match torrent.info.name.as_str() {
+        Some(name) => println!("Name: {name}"),
+        None => println!(
+            "Name: {} (not UTF-8, raw bytes: {})",
+            torrent.info.name.decode(torrent.encoding.as_deref()),
+            hex::encode(torrent.info.name.as_bytes())
+        ),
+    }
+    println!("Length: {}", torrent.info.keys.total_length());
+    println!("Info Hash: {identity}");
+    println!("Piece Length: {}", torrent.info.plength);
+    println!("Piece Hashes:");
 
-async fn get_tracker_info(
+    for hash in &torrent.info.pieces.0 {
+        println!("{}", hex::encode(hash));
+    }
+}
+
+/// The announce of a dry run: reports what the tracker says without acting on it.
+async fn announce_dry_run(
+    client: &Client,
     torrent: &Torrent,
-    self_peer_id: &str,
-) -> anyhow::Result<TrackerResponse> {
-    let request = TrackerRequest {
-        info_hash: torrent.info_hash()?,
-        peer_id: String::from(self_peer_id),
-        port: 6881,
-        uploaded: 0,
-        downloaded: 0,
-        left: torrent.info.keys.length(),
-        compact: 1,
+    port: u16,
+    left: usize,
+    need: SwarmNeed,
+    mode: DryRunAnnounce,
+) -> anyhow::Result<()> {
+    let need = match mode {
+        DryRunAnnounce::Peers => need,
+        // numwant is 0 when paused
+        DryRunAnnounce::Quiet => SwarmNeed {
+            paused: true,
+            ..need
+        },
+        DryRunAnnounce::Skip => {
+            println!("Peers: not announced");
+            return Ok(());
+        }
     };
-
-    let mut tracker_url =
-        reqwest::Url::parse(&torrent.announce).context("parse tracker announce url")?;
-    let mut url_params =
-        serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-
-    let hexed_info_hash_str = &request.info_hash.map(|byte| hex::encode(&[byte])).join("%");
-
-    url_params.push_str(format!("&info_hash=%{}", hexed_info_hash_str).as_str());
-
-    tracker_url.set_query(Some(&url_params));
-    eprintln!("get_tracker_info by url:\n{}", tracker_url);
-
-    let response = reqwest::get(tracker_url).await.context("fetch tracker")?;
-    let response = response.bytes().await.context("fetch tracker response")?;
-    let response: TrackerResponse =
-        serde_bencode::from_bytes(&response).context("parse tracker response")?;
-    Ok(response)
+    let transferred = Transferred::starting(left);
+    let response = client
+        .announce(torrent, port, transferred, need, None)
+        .await?;
+    let peers = response.all_peers();
+    println!("Peers: {} available", peers.len());
+    for peer in &peers {
+        println!("  {peer}");
+    }
+    Ok(())
 }
 
-async fn make_handshake(
-    torrent: &Torrent,
-    peer_ip: &SocketAddrV4,
-) -> anyhow::Result<(Handshake, TcpStream)> {
-    let mut tcp_stream: TcpStream = tokio::net::TcpStream::connect(peer_ip)
-        .await
-        .with_context(|| format!("connect to peer: {}", peer_ip))?;
+/// A token cancelled on ctrl-c, for the work about to start.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancel.cancel();
+            }
+        }
+    });
+    cancel
+}
 
-    let mut handshake = Handshake::new(torrent.info_hash()?, PEER_ID_BYTES);
-    {
-        let handshake_bytes = handshake.as_bytes_mut();
-        tcp_stream
-            .write_all(handshake_bytes)
-            .await
-            .context("write handshake")?;
-        tcp_stream
-            .read_exact(handshake_bytes)
-            .await
-            .context("read handshake")?;
-    }
-    Ok((handshake, tcp_stream))
+/// What gets logged: `RUST_LOG` if it is set, otherwise warnings and whatever `verbose`
+/// adds for this crate.
+fn log_filter(verbose: u8) -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        let level = match verbose {
+            0 => "warn",
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        };
+        EnvFilter::new(format!("{}={level},warn", env!("CARGO_CRATE_NAME")))
+    })
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    tracing_subscriber::fmt()
+        .with_env_filter(log_filter(args.verbose))
+        .with_writer(progress::log_writer)
+        .with_ansi(std::io::stderr().is_terminal())
+        .init();
+    let limits = args.limits;
+    limits.validate()?;
+    let trackers = TrackerClient::new(
+        TrackerPolicy {
+            allow_local: args.allow_local_trackers,
+        },
+        &args.tracker_tls,
+        args.peer_id.unwrap_or_else(PeerId::generate),
+    )?;
+    let client = Client::new(trackers, limits);
+    let peer_cache = args.state_dir.clone().map(|state_dir| PeerCacheConfig {
+        state_dir,
+        max_age: args.peer_cache_max_age,
+    });
+    redact::set_full_ids(args.log_full_ids);
+    perms::set_modes(args.modes);
+    if let Some(path) = &args.wire_log {
+        wire_log::init(path)?;
+    }
     match args.command {
-        Command::Decode { msg } => {
-            let decoded_value = de::decode_cmd(&msg)?;
-            println!("{:?}", decoded_value);
+        Command::Decode { json, msg } => {
+            let decoded_value = BenCode::from(de::decode_cmd(msg.as_encoded_bytes())?);
+            if json {
+                println!("{}", decoded_value.to_json());
+            } else {
+                println!("{decoded_value}");
+            }
         }
-        Command::Info { path } => {
-            let torrent_f = std::fs::read(path).context("read torrent file")?;
-            let torrent: Torrent =
-                serde_bencode::from_bytes(&torrent_f).context("parse torrent file")?;
-
-            eprintln!("{torrent:?}");
-            println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.info.keys.length());
-            println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
-            println!("Piece Length: {}", torrent.info.plength);
-            println!("Piece Hashes:");
-
-            for hash in torrent.info.pieces.0 {
-                println!("{}", hex::encode(&hash));
+        Command::Info { json, path } => {
+            let torrent = client::read_torrent(&path, None)?;
+            let identity = torrent.identity();
+            if json {
+                let summary = TorrentSummary::new(&torrent, &identity);
+                println!(
+                    "{}",
+                    serde_json::to_string(&summary).context("serialize torrent summary")?
+                );
+            } else {
+                print_info(&torrent, &identity);
             }
         }
+        Command::Verify { data, path } => {
+            let torrent = client::read_torrent(&path, None)?;
+            torrent.validate()?;
+            let mapper = add_seed::locate(&torrent, &data);
+            let report = client::check_data(
+                &torrent,
+                &data,
+                &cancel_on_ctrl_c(),
+                move |torrent, _, cancel| add_seed::check(torrent, &mapper, cancel),
+            )
+            .await?;
+            for file in &report.files {
+                println!("{file}");
+            }
+            let npieces = report.have.len();
+            println!("Verified: {} of {npieces} pieces OK", report.have.count());
+            if report.missing.count() > 0 {
+                let missing = add_seed::piece_list(report.missing.pieces());
+                println!("Missing pieces: {missing}");
+            }
+            let failed: Vec<_> = report.failed_pieces().collect();
+            if !failed.is_empty() {
+                println!("Failed pieces: {}", add_seed::piece_list(failed));
+            }
+            let bad = npieces - report.have.count();
+            if bad > 0 {
+                anyhow::bail!("{bad} of {npieces} pieces don't match the torrent");
+            }
+        }
+        Command::Create {
+            output,
+            piece_length,
+            input,
+        } => {
+            let announce = args
+                .announce
+                .as_ref()
+                .context("give the tracker the torrent names with --announce")?;
+            let piece_length = usize::try_from(piece_length).context("piece length")?;
+            let torrent = create::from_path(&input, announce.as_str(), piece_length)?;
+            let encoded = serde_bencode::to_bytes(&torrent).context("encode torrent")?;
+            sidecar::write_atomic(&output, &encoded)?;
+            println!("Info Hash: {}", torrent.identity());
+        }
+        Command::DhtPeers {
+            info_hash,
+            bootstrap,
+        } => {
+            let mut dht = Dht::bind().await?;
+            info!("dht node id {}", dht.id());
+            let start = dht.bootstrap(&bootstrap).await?;
+            let found = dht.get_peers(info_hash.wire(), &start).await?;
+            info!(
+                "dht lookup done, {} of {} node(s) answered, {} peer(s) found",
+                found.answered,
+                found.queried,
+                found.peers.len()
+            );
+            for peer in found.peers {
+                println!("{peer}");
+            }
+        }
+        Command::MagnetParse { link } => {
+            let magnet: MagnetLink = link.parse().context("parse magnet link")?;
+            println!("Info Hash: {}", magnet.info_hash);
+            println!("Info Hash (base32): {}", magnet.info_hash.base32());
+            if let Some(name) = &magnet.name {
+                println!("Name: {name}");
+            }
+            println!("Trackers:");
+            for tracker in &magnet.trackers {
+                println!("  {tracker}");
+            }
+            println!("Link: {magnet}");
+        }
+        Command::MagnetInfo { link } => {
+            let magnet: MagnetLink = link.parse().context("parse magnet link")?;
+            let peers = if args.peers.is_empty() {
+                client.announce_magnet(&magnet).await?.all_peers()
+            } else {
+                args.peers.clone()
+            };
+            let info = client.fetch_metadata(&magnet.info_hash, &peers).await?;
+            let announce = magnet
+                .trackers
+                .first()
+                .map(ToString::to_string)
+                .unwrap_or_default();
+            let torrent = Torrent::new(announce, info);
+            print_info(&torrent, &magnet.info_hash);
+        }
         Command::Peers { path } => {
-            let torrent_f = std::fs::read(path).context("read torrent file")?;
-            let torrent: Torrent =
-                serde_bencode::from_bytes(&torrent_f).context("parse torrent file")?;
+            let torrent = client::read_torrent(&path, args.announce.as_ref())?;
 
-            let response = get_tracker_info(&torrent, PEER_ID).await?;
+            let response = client
+                .find_peers(
+                    &torrent,
+                    Transferred::starting(torrent.info.keys.total_length()),
+                    SwarmNeed {
+                        connected: 0,
+                        max_connections: MAX_PEERS,
+                        seeding: false,
+                        paused: false,
+                    },
+                    &args.peers,
+                    None,
+                )
+                .await?;
 
-            for peer in response.peers.0 {
+            for peer in response.all_peers() {
                 println!("{}", peer);
             }
+            if let Some(warning) = &response.warning_message {
+                println!("Tracker warning: {warning}");
+            }
+        }
+        Command::Scrape {
+            targets,
+            tracker,
+            watch,
+            interval,
+            csv,
+            json,
+        } => {
+            let targets = targets
+                .iter()
+                .map(|target| {
+                    let path = Path::new(target);
+                    if !path.exists() && target.len() == 40 {
+                        if let Ok(info_hash) = hex::decode(target) {
+                            let Some(tracker) = &tracker else {
+                                anyhow::bail!("scraping info hash {target} needs --tracker");
+                            };
+                            return Ok(ScrapeTarget {
+                                name: String::new(),
+                                info_hash: info_hash.try_into().expect("40 hex digits"),
+                                announce: tracker.clone(),
+                            });
+                        }
+                    }
+                    let torrent_f = std::fs::read(path)
+                        .with_context(|| format!("read torrent file {target}"))?;
+                    let torrent = Torrent::from_bytes(&torrent_f)?;
+                    Ok(ScrapeTarget {
+                        name: torrent.info.name.to_string(),
+                        info_hash: torrent.info_hash(),
+                        announce: args
+                            .announce
+                            .as_ref()
+                            .map_or(torrent.announce, reqwest::Url::to_string),
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            scrape::watch(
+                client.trackers(),
+                &targets,
+                watch.then_some(interval),
+                csv.as_deref(),
+                json,
+            )
+            .await?;
+        }
+        Command::AddSeed {
+            data,
+            pieces,
+            partial,
+            path,
+        } => {
+            let torrent = client::read_torrent(&path, None)?;
+            torrent.validate()?;
+            let mapper = add_seed::locate(&torrent, &data);
+            let report = client::check_data(
+                &torrent,
+                &data,
+                &cancel_on_ctrl_c(),
+                move |torrent, _, cancel| add_seed::check(torrent, &mapper, cancel),
+            )
+            .await?;
+            for file in &report.files {
+                println!("{file}");
+            }
+            println!(
+                "Matching: {} of {} pieces",
+                report.have.count(),
+                report.have.len()
+            );
+            let mismatches = report.mismatches();
+            if mismatches > 0 && !partial {
+                anyhow::bail!(
+                    "{mismatches} file(s) don't match the torrent, pass --partial to seed the \
+                     pieces that do"
+                );
+            }
+            PieceMap::from_bitfield(&torrent, &report.have)?.save(&pieces)?;
+            let left = report
+                .have
+                .missing_pieces()
+                .map(|index| torrent.piece_size(index))
+                .sum::<usize>();
+            println!("Recorded in {}, {left} bytes left", pieces.display());
+            let data = match &torrent.info.keys {
+                Keys::SingleFile { .. } => &report.files[0].path,
+                Keys::MultiFile { .. } => &data,
+            };
+            println!(
+                "Seed with: seed --data {} --pieces {} {}",
+                data.display(),
+                pieces.display(),
+                path.display()
+            );
         }
-        Command::Handshake { path, peer_ip } => {
-            println!("Handshake with peer_ip: {}", peer_ip);
+        Command::Availability { sample, json, path } => {
+            let torrent = client::read_torrent(&path, args.announce.as_ref())?;
+            let response = client
+                .find_peers(
+                    &torrent,
+                    Transferred::starting(torrent.info.keys.total_length()),
+                    SwarmNeed {
+                        connected: 0,
+                        max_connections: MAX_PEERS,
+                        seeding: false,
+                        paused: false,
+                    },
+                    &args.peers,
+                    None,
+                )
+                .await?;
 
-            let torrent_f = std::fs::read(path).context("read torrent file")?;
-            let torrent: Torrent =
-                serde_bencode::from_bytes(&torrent_f).context("parse torrent file")?;
-            let info_hash = torrent.info_hash()?;
+            let npieces = torrent.info.pieces.0.len();
+            let identity = torrent.identity();
+            let peer_id = client.peer_id();
+            let mut askers = tokio::task::JoinSet::new();
+            for peer in response.all_peers().into_iter().take(sample) {
+                askers.spawn(async move {
+                    let deadline = tokio::time::Instant::now() + availability::PEER_TIMEOUT;
+                    let asked = tokio::time::timeout_at(deadline, async {
+                        let (_, mut stream, _) =
+                            client::handshake(&identity, peer_id, &peer).await?;
+                        availability::peer_pieces(
+                            &mut stream,
+                            npieces,
+                            limits.for_pieces(npieces).max_inbound_frame,
+                            deadline,
+                        )
+                        .await
+                    })
+                    .await;
+                    (peer, asked)
+                });
+            }
+            let mut swarm = picker::Availability::new(npieces);
+            let (mut tried, mut reported) = (0, 0);
+            while let Some(joined) = askers.join_next().await {
+                tried += 1;
+                match joined.context("availability task")? {
+                    (_, Ok(Ok(Some(has)))) => {
+                        reported += 1;
+                        swarm.add_peer(&has);
+                    }
+                    (peer, Ok(Ok(None))) => info!("peer {peer}: announced no pieces"),
+                    (peer, Ok(Err(err))) => info!("peer {peer}: {err:#}"),
+                    (peer, Err(_)) => info!("peer {peer}: timed out"),
+                }
+            }
+            let report = AvailabilityReport::new(&swarm, tried, reported);
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string(&report).context("serialize availability report")?
+                );
+            } else {
+                print!("{report}");
+            }
+        }
+        Command::Inventory {
+            recursive,
+            format,
+            dir,
+        } => {
+            let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+            let counts = inventory::write(&dir, recursive, format, &mut out)?;
+            eprintln!(
+                "listed {} torrent(s), {} could not be read",
+                counts.listed, counts.failed
+            );
+        }
+        Command::Handshake {
+            json,
+            path,
+            peer_ip,
+        } => {
+            if !json {
+                println!("Handshake with peer_ip: {}", peer_ip);
+            }
 
-            let (handshake, _) = make_handshake(&torrent, &peer_ip).await?;
-            assert_eq!(handshake.length, 19);
-            assert_eq!(handshake.bittorrent, *b"BitTorrent protocol");
-            assert_eq!(handshake.info_hash, info_hash);
+            let torrent = client::read_torrent(&path, None)?;
+            let (_, mut stream, mut report) =
+                client::handshake(&torrent.identity(), client.peer_id(), &peer_ip).await?;
+            report.first_message =
+                handshake::first_message(&mut stream, handshake::FIRST_MESSAGE_TIMEOUT).await?;
 
-            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string(&report).context("serialize handshake report")?
+                );
+            } else {
+                print!("{report}");
+                if args.verbose > 0 {
+                    println!("Extensions: {}", report.extensions.join(", "));
+                }
+            }
         }
-        Command::DownloadPiece {
-            output,
+        Command::Lint { private, path } => {
+            let torrent_f = std::fs::read(path).context("read torrent file")?;
+            let findings = lint::lint(&torrent_f, private);
+            for finding in &findings {
+                println!("{finding}");
+            }
+            let errors = findings
+                .iter()
+                .filter(|finding| finding.severity == lint::Severity::Error)
+                .count();
+            let warnings = findings.len() - errors;
+            println!("{errors} error(s), {warnings} warning(s)");
+            let code = if errors > 0 {
+                2
+            } else if warnings > 0 {
+                1
+            } else {
+                0
+            };
+            std::process::exit(code);
+        }
+        Command::Locate { offset, file, path } => {
+            let torrent = client::read_torrent(&path, None)?;
+            torrent.validate()?;
+
+            let offset = match file {
+                Some(file) => torrent.file_offset_to_absolute(file, offset)?,
+                None => offset,
+            };
+            let location = torrent.locate(offset, limits.block_size)?;
+            println!("Offset: {}", location.offset);
+            println!(
+                "File: {} ({}) at offset {}",
+                location.file,
+                torrent
+                    .file_path(location.file)
+                    .expect("located in an existing file")
+                    .display(),
+                location.file_offset
+            );
+            println!(
+                "Piece: {} at offset {}",
+                location.piece, location.piece_offset
+            );
+            println!(
+                "Block: {} at offset {} ({} byte blocks)",
+                location.block, location.block_offset, limits.block_size
+            );
+        }
+        Command::Seed {
+            data,
+            pieces,
+            port,
+            import_resume,
+            format,
+            verify_imported,
+            dry_run,
+            dry_run_announce,
+            seed_ratio,
+            seed_time,
+            rebind_after,
+            rebind_window,
             path,
-            piece_index,
         } => {
-            let torrent_f = std::fs::read(path).context("read torrent file")?;
-            let torrent: Torrent =
-                serde_bencode::from_bytes(&torrent_f).context("parse torrent file")?;
-            eprintln!("torrent info: {:?}", &torrent.info);
-            assert!(piece_index < torrent.info.pieces.0.len());
-            let response = get_tracker_info(&torrent, PEER_ID).await?;
+            let goal = SeedGoal {
+                ratio: seed_ratio,
+                time: seed_time,
+            };
+            let torrent = client::read_torrent(&path, args.announce.as_ref())?;
+            let plan = SeedPlan::new(
+                &torrent,
+                data,
+                pieces,
+                import_resume.zip(format),
+                verify_imported,
+                port,
+            )?;
+            if dry_run {
+                print!("{plan}");
+                let left = plan
+                    .missing_bytes
+                    .unwrap_or_else(|| torrent.info.keys.total_length());
+                let need = SwarmNeed {
+                    connected: 0,
+                    max_connections: MAX_PEERS,
+                    seeding: true,
+                    paused: false,
+                };
+                announce_dry_run(&client, &torrent, port, left, need, dry_run_announce).await?;
+                return Ok(());
+            }
 
-            let to_connect_peer = response.peers.0[0];
-            let (handshake, tcp_stream) = make_handshake(&torrent, &to_connect_peer).await?;
-            assert_eq!(handshake.length, 19);
-            assert_eq!(handshake.bittorrent, *b"BitTorrent protocol");
-            assert_eq!(handshake.info_hash, torrent.info_hash()?);
+            let have = plan.check_pieces(&torrent, &cancel_on_ctrl_c()).await?;
+            info!(
+                "seeding {} of {} pieces",
+                have.count(),
+                torrent.info.pieces.0.len()
+            );
 
-            // let framer = MessageFramer {};
-            let mut stream = tokio_util::codec::Framed::new(tcp_stream, MessageFramer {});
-            let bitfield_msg = stream
-                .next()
-                .await
-                .expect("peer always sends a bitfields")
-                .context("peer message was invalid")?;
-            eprintln!("bitfield_msg: {:#?}", bitfield_msg);
-            assert_eq!(bitfield_msg.tag, MessageTag::Bitfield);
-            // assert!(bitfield_msg.payload.is_empty());
-            stream
-                .send(Message::new(MessageTag::Interested, Vec::new()))
-                .await
-                .context("send interested message")?;
+            let listener = seed::bind_listener(port).await?;
+            let burst = FailureBurst::new(rebind_after, rebind_window);
+            let seeder = Arc::new(Seeder::new(
+                torrent,
+                client.peer_id().0,
+                plan.data,
+                have,
+                limits,
+            )?);
 
-            let unchoke_msg = stream
-                .next()
-                .await
-                .expect("peer always sends a bitfields")
-                .context("peer message was invalid")?;
-            assert_eq!(unchoke_msg.tag, MessageTag::Unchoke);
-            assert!(unchoke_msg.payload.is_empty());
+            let announcer = Arc::clone(&seeder);
+            let announce_client = client.clone();
+            tokio::spawn(async move {
+                let mut schedule = AnnounceSchedule::new(announcer.torrent().is_private());
+                loop {
+                    tokio::select! {
+                        () = tokio::time::sleep_until(schedule.next_announce()) => {}
+                        // the tracker still hands out our old address
+                        () = announcer.network_changed() => {}
+                    }
+                    let need = SwarmNeed {
+                        connected: announcer.connected_peers(),
+                        max_connections: MAX_PEERS,
+                        seeding: true,
+                        paused: false,
+                    };
+                    match announce_client
+                        .announce(
+                            announcer.torrent(),
+                            port,
+                            announcer.transferred(),
+                            need,
+                            None,
+                        )
+                        .await
+                    {
+                        Ok(response) => schedule.announced(&response),
+                        Err(err) => {
+                            warn!("announce failed: {err:#}");
+                            schedule.failed();
+                        }
+                    }
+                }
+            });
 
-            let piece_size = if piece_index == torrent.info.pieces.0.len() - 1 {
-                let rem = torrent.info.keys.length() % torrent.info.plength;
-                if rem == 0 {
-                    torrent.info.plength
-                } else {
-                    rem
+            if !goal.is_set() {
+                seeder.serve(listener, burst).await?;
+                return Ok(());
+            }
+            let mut goals = GoalTracker::new(
+                goal,
+                seeder.torrent().info.keys.total_length() as u64,
+                Instant::now(),
+            );
+            let mut checks = tokio::time::interval(GOAL_CHECK_INTERVAL);
+            let serve = Arc::clone(&seeder).serve(listener, burst);
+            tokio::pin!(serve);
+            let reached = loop {
+                tokio::select! {
+                    served = &mut serve => return served,
+                    _ = checks.tick() => {
+                        // seeding doesn't download anything
+                        if let Some(reached) = goals.check(Instant::now(), seeder.uploaded(), 0) {
+                            break reached;
+                        }
+                    }
                 }
+            };
+            info!(
+                "seeding_goal_reached, {reached}, {} uploaded in all",
+                HumanBytes(seeder.uploaded())
+            );
+            // the piece map doesn't change while seeding, there is no state to flush
+            if let Err(err) = client
+                .announce_stopped(seeder.torrent(), port, seeder.transferred())
+                .await
+            {
+                warn!("stopped announce failed: {err:#}");
+            }
+        }
+        Command::Bench {
+            listen,
+            port,
+            connect,
+            size,
+        } => {
+            if listen {
+                let listener = seed::bind_listener(port).await?;
+                eprintln!("benchmark listening on port {port}");
+                bench::listen(listener, limits).await?;
+            } else if let Some(addr) = connect {
+                let report = bench::connect(addr, size, &limits).await?;
+                println!("{report}");
+            }
+        }
+        Command::Download {
+            output,
+            json,
+            files,
+            pick,
+            readahead,
+            stream,
+            endgame_threshold,
+            exec_on_file_complete,
+            path,
+        } => {
+            let torrent = client::read_torrent(&path, args.announce.as_ref())?;
+            let drain = stream.then(DrainRate::default);
+            let stdout = drain.clone().map(StdoutStream::start);
+            let options = DownloadOptions {
+                files,
+                peers: args.peers.clone(),
+                pick: match pick {
+                    Pick::RarestFirst => PickOrder::RarestFirst,
+                    Pick::Sequential => PickOrder::Sequential,
+                    Pick::Random => PickOrder::Random,
+                },
+                readahead,
+                drain,
+                in_order: stream,
+                endgame_threshold,
+                peer_cache,
+            };
+            let mut progress = None;
+            let outcome = client
+                .download(
+                    &torrent,
+                    &output,
+                    &options,
+                    |event| match event {
+                        DownloadEvent::Piece(done) => progress
+                            .get_or_insert_with(|| {
+                                ProgressReporter::new(!args.no_progress, done.wanted)
+                            })
+                            .update(done.done, done.stats, &done.files.status(), done.peers),
+                        DownloadEvent::Reannounced { new_peers } => {
+                            info!("ran out of peers, the tracker told us of {new_peers} more")
+                        }
+                        DownloadEvent::FileCompleted(file) => {
+                            info!("{file}");
+                            if let Some(command) = &exec_on_file_complete {
+                                if let Err(err) = file.exec(command) {
+                                    warn!("{command} for {}: {err}", file.path.display());
+                                }
+                            }
+                        }
+                        DownloadEvent::InOrder(data) => {
+                            if let Some(stdout) = &stdout {
+                                stdout.send(data);
+                            }
+                        }
+                    },
+                    &cancel_on_ctrl_c(),
+                )
+                .await;
+            if let Some(progress) = &progress {
+                progress.clear();
+            }
+            let outcome = outcome?;
+            if let Some(stdout) = stdout {
+                stdout.finish()?;
+            }
+            let summary = outcome.stats.summary(
+                torrent.info.name.decode(torrent.encoding.as_deref()),
+                Some(output),
+                PeerCounts {
+                    tried: outcome.tried,
+                    connected: outcome.connected,
+                    banned: outcome.banned,
+                },
+            );
+            let summary = if json {
+                serde_json::to_string(&summary).context("serialize summary")?
             } else {
-                torrent.info.plength
+                summary.to_string()
             };
-            let mut all_blocks: Vec<u8> = Vec::with_capacity(piece_size);
-            // piece_size / PIECE_BLOCK_MAX round up
-            let nblocks = (piece_size + (PIECE_BLOCK_MAX - 1)) / PIECE_BLOCK_MAX;
-            eprintln!("{nblocks} blocks of at most {PIECE_BLOCK_MAX} to reach {piece_size}");
-            // let
-            for block_idx in 0..nblocks {
-                let block_size = if block_idx == nblocks - 1 {
-                    let rem = piece_size % PIECE_BLOCK_MAX;
-                    if rem == 0 {
-                        PIECE_BLOCK_MAX
-                    } else {
-                        rem
-                    }
+            // stdout carries the data when streaming
+            if stream {
+                eprintln!("{summary}");
+            } else {
+                println!("{summary}");
+            }
+        }
+        Command::DownloadPiece {
+            output,
+            json,
+            verify_policy,
+            sample_fraction,
+            final_check,
+            pieces,
+            preallocation,
+            dry_run,
+            dry_run_announce,
+            path,
+            piece_index,
+        } => {
+            let torrent = client::read_torrent(&path, args.announce.as_ref())?;
+            debug!("torrent info: {:?}", torrent.info);
+            let plan = DownloadPiecePlan::new(
+                &torrent,
+                piece_index,
+                output,
+                pieces.as_deref(),
+                verify_policy,
+            )?;
+            let left = torrent.info.keys.total_length();
+            let need = SwarmNeed {
+                connected: 0,
+                max_connections: MAX_PEERS,
+                seeding: false,
+                paused: false,
+            };
+            if dry_run {
+                print!("{plan}");
+                announce_dry_run(&client, &torrent, LISTEN_PORT, left, need, dry_run_announce)
+                    .await?;
+                return Ok(());
+            }
+            // find out about a full disk before talking to anyone
+            let mut piece_map = pieces
+                .map(|pieces| PieceMapWriter::open(pieces, &torrent))
+                .transpose()?;
+            let preallocation = preallocation
+                .or(piece_map.as_ref().and_then(PieceMapWriter::preallocation))
+                .unwrap_or_default();
+            let preallocation =
+                prealloc::allocate_path(plan.output.clone(), plan.piece_size as u64, preallocation)
+                    .await?;
+            if let Some(piece_map) = &mut piece_map {
+                piece_map.preallocated(preallocation)?;
+            }
+
+            let mut peer_cache =
+                peer_cache.map(|config| PeerCacheFile::open(&config, &torrent.info_hash()));
+            let piece_size = plan.piece_size;
+            let mut stats = TransferStats::new(piece_size);
+            let npieces = torrent.info.pieces.0.len();
+            let verify = verify_policy.selection(npieces, sample_fraction, &mut rand::thread_rng())
+                [piece_index];
+            let expected_hash = torrent.piece_hash(piece_index)?;
+            // the cached peers first, the tracker is only asked once none of them served us
+            let mut candidates: VecDeque<_> = match &peer_cache {
+                Some(cache) => cache.dial_order().into(),
+                None => VecDeque::new(),
+            };
+            let mut cached = candidates.len();
+            let mut announced = false;
+            let mut dialed = Vec::new();
+            let mut peers = PeerCounts::default();
+            let all_blocks = loop {
+                let Some(peer) = candidates.pop_front() else {
+                    anyhow::ensure!(
+                        !announced,
+                        "none of the {} peer(s) tried sent piece {piece_index}",
+                        peers.tried
+                    );
+                    announced = true;
+                    let response = client
+                        .find_peers(
+                            &torrent,
+                            Transferred::starting(left),
+                            need,
+                            &args.peers,
+                            None,
+                        )
+                        .await?;
+                    let before = peers.tried;
+                    candidates.extend(response.all_peers().into_iter().filter(|&peer| {
+                        !dialed.contains(&peer)
+                            && peer_cache
+                                .as_ref()
+                                .is_none_or(|cache| !cache.is_banned(peer))
+                    }));
+                    anyhow::ensure!(
+                        !candidates.is_empty() || before > 0,
+                        "the tracker knows no peers that aren't banned"
+                    );
+                    continue;
+                };
+                peers.tried += 1;
+                dialed.push(peer);
+                let connecting = client.connect(&torrent, peer, &mut stats);
+                let connected = if cached > 0 {
+                    // a cached peer may well be gone since
+                    cached -= 1;
+                    tokio::time::timeout(client::CACHED_PEER_TIMEOUT, connecting)
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("no handshake in time")))
                 } else {
-                    PIECE_BLOCK_MAX
+                    connecting.await
                 };
-                eprintln!("block_size: {block_size} ");
-
-                let message_request = MessageRequest::new(
-                    piece_index as u32,
-                    (block_idx * PIECE_BLOCK_MAX) as u32,
-                    block_size as u32,
-                );
-                stream
-                    .send(Message::new(
-                        MessageTag::Request,
-                        Vec::from(message_request.as_bytes()),
-                    ))
-                    .await
-                    .with_context(|| format!("send request for block {block_idx}"))?;
-
-                let piece_msg = stream
-                    .next()
+                let mut connection = match connected {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        info!("peer {peer}: {err:#}");
+                        if let Some(cache) = &mut peer_cache {
+                            cache.failed(peer);
+                        }
+                        continue;
+                    }
+                };
+                peers.connected += 1;
+                let assembler = match client
+                    .download_piece(&torrent, &mut connection, piece_index, &mut stats)
                     .await
-                    .expect("peer should send a piece")
-                    .context("peer message was invalid")?;
-                assert_eq!(piece_msg.tag, MessageTag::Piece);
-                assert!(!piece_msg.payload.is_empty());
-                let a = &piece_msg.payload[..];
-                // eprintln!("{}",std::mem::size_of::<MessagePiece>());
-                let msg_piece = (&piece_msg.payload[..piece_msg.payload.len() - 8]) as *const [u8]
-                    as *const MessagePiece;
-                let msg_piece = unsafe { &*msg_piece };
-                assert_eq!(msg_piece.index() as usize, piece_index);
-                assert_eq!(msg_piece.begin() as usize, block_idx * PIECE_BLOCK_MAX);
-                assert_eq!(
-                    msg_piece.block().len(),
-                    block_size,
-                    "on iteration {} ",
-                    block_idx
-                );
-                eprintln!(
-                    "msg_piece:\n\
-                     index: {}\n\
-                     begin: {}\n\
-                     block.len: {}",
-                    msg_piece.index(),
-                    msg_piece.begin(),
-                    msg_piece.block().len()
-                );
-                all_blocks.extend(msg_piece.block());
+                {
+                    Ok(assembler) => assembler,
+                    Err(err) => {
+                        info!("peer {peer}: {err:#}");
+                        continue;
+                    }
+                };
+                info!("piece {piece_index}: {}", stats.progress());
+                match assembler.finish(expected_hash, verify, &mut stats) {
+                    Ok(all_blocks) => {
+                        if let Some(cache) = &mut peer_cache {
+                            cache.succeeded(peer);
+                        }
+                        break all_blocks;
+                    }
+                    Err(err) => {
+                        warn!("peer {peer}: {err:#}, banning it");
+                        peers.banned += 1;
+                        if let Some(cache) = &mut peer_cache {
+                            cache.ban(peer);
+                        }
+                    }
+                }
+            };
+            if !verify {
+                warn!("piece {piece_index}: not hash-checked, verify policy {verify_policy:?}");
             }
-            assert_eq!(all_blocks.len(), piece_size);
-            // eprintln!("piece_size: {}", piece_size);
-            // eprintln!("all_blocks.len: {:#?}", all_blocks.len());
-
-            let mut hasher = Sha1::new();
-            hasher.update(&all_blocks);
-            let hash: [u8; 20] = hasher
-                .finalize()
-                .try_into()
-                .context("received data' sha1 hash should be equal to piece_hash")?;
-            assert_eq!(hash, torrent.info.pieces.0[piece_index]);
+            info!("piece {piece_index}: {}", stats.progress());
 
-            std::fs::create_dir_all("./tmp")?;
-
-            tokio::fs::write(&output, all_blocks)
+            let output = plan.output;
+            // in place, so the allocated blocks are reused
+            let mut file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(&output)
+                .await
+                .context("open downloaded piece")?;
+            file.write_all(&all_blocks)
                 .await
+                .and(file.set_len(all_blocks.len() as u64).await)
                 .context("write out downloaded piece")?;
-            println!("Piece {piece_index} downloaded to {}.", output.display());
+            if final_check && !verify {
+                let written = tokio::fs::read(&output)
+                    .await
+                    .context("read back downloaded piece")?;
+                if piece::sha1(&written) != *expected_hash {
+                    anyhow::bail!(
+                        "final check: {} doesn't match the piece hash",
+                        output.display()
+                    );
+                }
+            }
+            if let Some(mut piece_map) = piece_map {
+                piece_map.insert(piece_index, verify || final_check, verify_policy)?;
+                piece_map.flush()?;
+            }
+            let summary = stats.summary(format!("Piece {piece_index}"), Some(output), peers);
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string(&summary).context("serialize summary")?
+                );
+            } else {
+                println!("{summary}");
+            }
         }
     }
     Ok(())
@@ -0,0 +1,113 @@
+//! Fetching the info dict of a magnet link from a peer with `ut_metadata` (BEP 9).
+
+use crate::download::PeerStream;
+use crate::extension::{self, BencodeDict, UtMetadataMsg, METADATA_PIECE_SIZE};
+use crate::info_hash::InfoHash;
+use crate::peer::{write_deadline, MessagePayload};
+use crate::peer_session::{PeerSession, UT_METADATA_ID};
+use crate::piece;
+use crate::stats::TransferStats;
+use crate::torrent::Info;
+use anyhow::{bail, Context};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+
+/// The largest info dict we accept, far above any real torrent's but a bound on what a
+/// peer can make us allocate.
+pub const MAX_METADATA_SIZE: usize = 16 << 20;
+
+/// How long a peer gets to send the whole info dict.
+pub const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Asks the peer for the info dict in [`METADATA_PIECE_SIZE`] pieces, checks the whole
+/// against `info_hash` and parses it.
+pub async fn fetch(
+    stream: &mut PeerStream,
+    session: &PeerSession,
+    info_hash: &InfoHash,
+    stats: &mut TransferStats,
+) -> anyhow::Result<Info> {
+    let Some(v1) = info_hash.v1() else {
+        bail!("checking metadata against a v2 info hash isn't supported yet");
+    };
+    let Some(their_id) = session.extension_id("ut_metadata") else {
+        bail!("the peer doesn't offer ut_metadata");
+    };
+    let size = session
+        .metadata_size()
+        .context("the peer doesn't say how large the metadata is")?;
+    if size == 0 || size > MAX_METADATA_SIZE {
+        bail!("the peer says the metadata is {size} bytes");
+    }
+    let npieces = extension::metadata_piece_count(size);
+    for piece in 0..npieces {
+        let request = MessagePayload::Extended {
+            id: their_id,
+            payload: UtMetadataMsg::request(piece as u32).to_bencode(),
+        };
+        write_deadline(stream.send(request))
+            .await
+            .with_context(|| format!("request metadata piece {piece}"))?;
+    }
+
+    let mut metadata = vec![0; size];
+    let mut received = vec![false; npieces];
+    let receive = async {
+        while received.contains(&false) {
+            let message = stream
+                .next()
+                .await
+                .context("peer closed the connection")?
+                .context("peer message was invalid")?;
+            stats.record_wire(4 + message.len());
+            let MessagePayload::Extended {
+                id: UT_METADATA_ID,
+                payload,
+            } = &message
+            else {
+                continue;
+            };
+            let (msg, data) = UtMetadataMsg::from_bencode_prefix(payload)?;
+            let piece = msg.piece as usize;
+            match msg.msg_type {
+                UtMetadataMsg::DATA => {}
+                UtMetadataMsg::REJECT => bail!("the peer refused metadata piece {piece}"),
+                UtMetadataMsg::REQUEST => {
+                    // we only ask for metadata we don't have
+                    let reject = MessagePayload::Extended {
+                        id: their_id,
+                        payload: UtMetadataMsg::reject(msg.piece).to_bencode(),
+                    };
+                    write_deadline(stream.send(reject))
+                        .await
+                        .context("reject metadata request")?;
+                    continue;
+                }
+                other => bail!("unknown ut_metadata message type {other}"),
+            }
+            if piece >= npieces {
+                bail!("got metadata piece {piece} of {npieces}");
+            }
+            let start = piece * METADATA_PIECE_SIZE;
+            let end = size.min(start + METADATA_PIECE_SIZE);
+            if data.len() != end - start {
+                bail!(
+                    "metadata piece {piece} is {} bytes instead of {}",
+                    data.len(),
+                    end - start
+                );
+            }
+            metadata[start..end].copy_from_slice(data);
+            received[piece] = true;
+        }
+        anyhow::Ok(())
+    };
+    tokio::time::timeout(METADATA_TIMEOUT, receive)
+        .await
+        .context("the peer took too long to send the metadata")??;
+
+    if piece::sha1(&metadata) != *v1 {
+        bail!("the metadata doesn't match the info hash");
+    }
+    serde_bencode::from_bytes(&metadata).context("parse metadata")
+}
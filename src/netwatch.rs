@@ -0,0 +1,129 @@
+//! Noticing that the network changed under us, e.g. when a laptop resumes from sleep or
+//! switches from Wi-Fi to Ethernet.
+//!
+//! Nothing tells a socket that the address it was bound to went away, so every connection
+//! lingers until its next write times out and the tracker keeps handing out the old address.
+//! Many connections dying within a few seconds of each other is the giveaway; a single one
+//! is just a peer leaving.
+
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Connections lost at about the same time that mean the network changed, by default.
+pub const DEFAULT_FAILURE_BURST: usize = 5;
+
+/// Counts lost connections, and tells when enough were lost at once to blame the network.
+#[derive(Debug, Clone)]
+pub struct FailureBurst {
+    threshold: usize,
+    window: Duration,
+    recent: VecDeque<Instant>,
+}
+
+/// Why networking is restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkChange {
+    /// `failures` connections were lost within `window`.
+    FailureBurst { failures: usize, window: Duration },
+    /// Asked for with SIGHUP.
+    Requested,
+}
+
+impl FailureBurst {
+    /// Blames the network once `threshold` connections were lost within `window`.
+    pub fn new(threshold: usize, window: Duration) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// A connection was lost at `now`. Returns the network change once this completes a
+    /// burst; counting then starts over.
+    pub fn lost(&mut self, now: Instant) -> Option<NetworkChange> {
+        while self
+            .recent
+            .front()
+            .is_some_and(|&at| now.duration_since(at) > self.window)
+        {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+        if self.recent.len() < self.threshold {
+            return None;
+        }
+        self.recent.clear();
+        Some(NetworkChange::FailureBurst {
+            failures: self.threshold,
+            window: self.window,
+        })
+    }
+
+    /// Forgets the connections lost so far, e.g. after networking was restarted.
+    pub fn reset(&mut self) {
+        self.recent.clear();
+    }
+}
+
+/// Whether `err` means the connection itself broke, as opposed to the peer hanging up or
+/// misbehaving.
+pub fn is_connection_lost(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.downcast_ref::<std::io::Error>().is_some_and(|err| {
+            matches!(
+                err.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::NetworkDown
+                    | ErrorKind::NetworkUnreachable
+                    | ErrorKind::HostUnreachable
+                    | ErrorKind::AddrNotAvailable
+            )
+        })
+    })
+}
+
+/// Waits for SIGHUP, which asks for networking to be restarted; never fires off Unix.
+pub async fn requested(hangups: &mut Option<HangupSignal>) {
+    match hangups {
+        #[cfg(unix)]
+        Some(hangups) => {
+            hangups.recv().await;
+        }
+        _ => std::future::pending().await,
+    }
+}
+
+#[cfg(unix)]
+pub type HangupSignal = tokio::signal::unix::Signal;
+#[cfg(not(unix))]
+pub type HangupSignal = std::convert::Infallible;
+
+/// Listens for SIGHUP, if the platform has it.
+pub fn hangups() -> Option<HangupSignal> {
+    #[cfg(unix)]
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => return Some(hangups),
+        Err(err) => warn!("can't listen for SIGHUP: {err}"),
+    }
+    None
+}
+
+impl Display for NetworkChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkChange::FailureBurst { failures, window } => write!(
+                f,
+                "{failures} connections lost within {}s",
+                window.as_secs_f64()
+            ),
+            NetworkChange::Requested => write!(f, "asked to by SIGHUP"),
+        }
+    }
+}
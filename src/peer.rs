@@ -1,14 +1,20 @@
-use crate::common::AsBytes;
-use bytes::{Buf, BufMut, BytesMut};
+use crate::limits::Limits;
+use crate::wire_log::{self, Direction};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use serde::{
-    de::{Error, Visitor},
+    de::{Error, SeqAccess, Visitor},
     Deserialize, Serialize, Serializer,
 };
+use serde_bencode::value::Value as BencodeValue;
 use std::{
     fmt::Formatter,
-    net::{Ipv4Addr, SocketAddrV4},
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
 };
+use tokio::time::Instant;
 use tokio_util::codec::{Decoder, Encoder};
+use tracing::warn;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
@@ -22,33 +28,66 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    /// The UDP port of the sender's DHT node (BEP 5).
+    Port = 9,
+    /// A message of an extension (BEP 10); the first payload byte says which.
+    Extended = 20,
 }
 
-#[derive(Debug, Clone)]
-pub struct Message {
-    pub tag: MessageTag,
-    pub payload: Vec<u8>,
+/// A peer wire message with its payload parsed, as [`MessageFramer`] reads and writes them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessagePayload {
+    /// A frame of length 0, which tells the other end we are still there.
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    /// The bits as sent; only with the torrent's piece count can they be checked and made
+    /// into a [`Bitfield`].
+    Bitfield(Vec<u8>),
+    Request(MessageRequest),
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Cancel(MessageRequest),
+    /// The UDP port of the sender's DHT node (BEP 5).
+    Port(u16),
+    /// A message of an extension (BEP 10) with the extended message `id` it was sent with.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// A message we don't speak, like those of the fast extension, passed through as is.
+    Raw {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
-pub struct MessageFramer {}
+pub struct MessageFramer {
+    /// The remote end of the connection, for the wire log.
+    peer: SocketAddr,
+    /// The largest frame we accept, to avoid a denial of service attack where the peer
+    /// makes us run out of memory.
+    max_inbound: usize,
+    /// The largest frame we send, since the other end won't accept larger ones.
+    max_outbound: usize,
+    /// When we last encoded a frame, to know when a keep-alive is due.
+    last_write: Instant,
+}
 
-#[derive(Debug)]
-#[repr(C)]
+/// The payload of a `Request` or `Cancel` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MessageRequest {
     index: [u8; 4],
     begin: [u8; 4],
     length: [u8; 4],
 }
 
-#[derive(Debug)]
-#[repr(C)]
-pub struct MessagePiece {
-    index: [u8; 4],
-    begin: [u8; 4],
-    block: [u8],
-}
-
-#[repr(C)]
 #[derive(Debug, PartialEq)]
 pub struct Handshake {
     /// length of the protocol string (BitTorrent protocol) which is 19 (1 byte)
@@ -63,40 +102,144 @@ pub struct Handshake {
     pub peer_id: [u8; 20],
 }
 
+/// The set of pieces a peer has, in the wire layout of the `Bitfield` message.
+///
+/// The high bit of the first byte corresponds to piece index 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    npieces: usize,
+}
+
+/// The `peers` of a tracker response: compact IPv4 peers, or any peers in the dictionary
+/// model.
 #[derive(Debug, Clone)]
-pub struct Peers(pub Vec<SocketAddrV4>);
+pub struct Peers(pub Vec<SocketAddr>);
 pub struct PeersVisitor;
 
+/// The `peers6` of a tracker response: 18 bytes per peer, IPv6 address then port.
+#[derive(Debug, Clone)]
+pub struct Peers6(pub Vec<SocketAddrV6>);
+struct Peers6Visitor;
+
 impl<'de> Visitor<'de> for PeersVisitor {
     type Value = Peers;
 
     fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
         formatter.write_str(
             "6 bytes, the first 4 bytes are the peer's IP address \
-            and the last 2 bytes are the peer's port number.",
+            and the last 2 bytes are the peer's port number, \
+            or a list of dictionaries with the peer's ip and port.",
         )
     }
 
+    /// The dictionary model, from trackers that ignore `compact=1`.
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut peers = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        let mut skipped = Vec::new();
+        while let Some(entry) = seq.next_element::<BencodeValue>()? {
+            match Peers::from_dict(&entry) {
+                Ok(peer) => peers.push(peer),
+                Err(reason) => skipped.push(reason),
+            }
+        }
+        if let Some(reason) = skipped.first() {
+            warn!(
+                "skipped {} of {} peer(s) the tracker listed, e.g. {reason}",
+                skipped.len(),
+                skipped.len() + peers.len()
+            );
+        }
+        Ok(Peers(peers))
+    }
+
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: Error,
     {
-        if v.len() % 6 != 0 {
-            Err(E::custom(format!("length is {}", v.len())))
+        Peers::from_compact(v).map_err(E::custom)
+    }
+}
+
+impl Peers {
+    /// Parses the compact representation: 6 bytes per peer, IP address then port.
+    pub fn from_compact(v: &[u8]) -> Result<Self, String> {
+        if !v.len().is_multiple_of(6) {
+            Err(format!("length is {}", v.len()))
         } else {
             // TODO: use array_chunks when stable
             Ok(Peers(
                 v.chunks_exact(6)
                     .map(|slice_6| {
-                        SocketAddrV4::new(
+                        SocketAddr::V4(SocketAddrV4::new(
                             Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
                             u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                        )
+                        ))
                     })
                     .collect(),
             ))
         }
     }
+
+    /// Parses one entry of the dictionary model: `ip`, `port` and a `peer id` we ignore.
+    ///
+    /// Hostnames aren't resolved, as this runs while the response is decoded.
+    fn from_dict(entry: &BencodeValue) -> Result<SocketAddr, String> {
+        let BencodeValue::Dict(entry) = entry else {
+            return Err("an entry that isn't a dictionary".to_string());
+        };
+        let ip = match entry.get(b"ip".as_slice()) {
+            Some(BencodeValue::Bytes(ip)) => String::from_utf8_lossy(ip),
+            _ => return Err("an entry without an ip".to_string()),
+        };
+        let ip = match ip.parse::<IpAddr>() {
+            Ok(ip) => ip.to_canonical(),
+            Err(_) => return Err(format!("unresolved hostname {ip:?}")),
+        };
+        let port = match entry.get(b"port".as_slice()) {
+            Some(&BencodeValue::Int(port)) => {
+                u16::try_from(port).map_err(|_| format!("{ip} with port {port}"))?
+            }
+            _ => return Err(format!("{ip} without a port")),
+        };
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+impl<'de> Visitor<'de> for Peers6Visitor {
+    type Value = Peers6;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str(
+            "18 bytes, the first 16 bytes are the peer's IPv6 address \
+            and the last 2 bytes are the peer's port number.",
+        )
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        if !v.len().is_multiple_of(18) {
+            return Err(E::custom(format!("length is {}", v.len())));
+        }
+        Ok(Peers6(
+            v.chunks_exact(18)
+                .map(|slice_18| {
+                    let ip: [u8; 16] = slice_18[..16].try_into().expect("16 bytes");
+                    SocketAddrV6::new(
+                        Ipv6Addr::from(ip),
+                        u16::from_be_bytes([slice_18[16], slice_18[17]]),
+                        0,
+                        0,
+                    )
+                })
+                .collect(),
+        ))
+    }
 }
 
 impl<'de> Deserialize<'de> for Peers {
@@ -108,6 +251,7 @@ impl<'de> Deserialize<'de> for Peers {
     }
 }
 
+/// In the compact representation, so IPv6 peers belong in [`Peers6`].
 impl Serialize for Peers {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -115,6 +259,11 @@ impl Serialize for Peers {
     {
         let mut single_slice = Vec::with_capacity(6 * self.0.len());
         for peer in &self.0 {
+            let SocketAddr::V4(peer) = peer else {
+                return Err(serde::ser::Error::custom(format!(
+                    "IPv6 peer {peer} in the IPv4 peer list"
+                )));
+            };
             single_slice.extend(peer.ip().octets());
             single_slice.extend(peer.port().to_be_bytes());
         }
@@ -122,34 +271,262 @@ impl Serialize for Peers {
     }
 }
 
+impl<'de> Deserialize<'de> for Peers6 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(Peers6Visitor)
+    }
+}
+
+impl Serialize for Peers6 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut single_slice = Vec::with_capacity(18 * self.0.len());
+        for peer in &self.0 {
+            single_slice.extend(peer.ip().octets());
+            single_slice.extend(peer.port().to_be_bytes());
+        }
+        serializer.serialize_bytes(&single_slice)
+    }
+}
+
+/// Writes to a peer that take longer than this are given up on.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// After this long without writing to a peer we send it a keep-alive, well within the two
+/// minutes after which peers commonly drop a silent connection.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// A peer we receive nothing from for this long, not even a keep-alive, is given up on.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Fails `write` with [`std::io::ErrorKind::TimedOut`] if it doesn't finish within
+/// [`WRITE_TIMEOUT`].
+///
+/// A peer that stopped reading, e.g. behind a dead NAT mapping, lets its receive window
+/// fill up and would leave our write hanging forever.
+pub async fn write_deadline<T>(
+    write: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(WRITE_TIMEOUT, write)
+        .await
+        .unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "peer stopped reading",
+            ))
+        })
+}
+
 impl Handshake {
-    const MEM_SIZE: usize = std::mem::size_of::<Self>();
-    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+    /// The size of a handshake on the wire.
+    pub const LEN: usize = 68;
+
+    /// With `extension_protocol`, the reserved bit that says we speak BEP 10 is set.
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], extension_protocol: bool) -> Self {
+        let mut reserved = [0; 8];
+        if extension_protocol {
+            reserved[5] |= 0x10;
+        }
         Self {
             length: 19,
             bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
     }
-    // pub fn as_bytes_mut(&mut self) -> &mut [u8; Self::MEM_SIZE] {
-    //      let self_as_bytes = self as *mut Self as *mut [u8; Self::MEM_SIZE];
-    //      // Safety: Handshake is a POD with repr(c)
-    //      unsafe { &mut *self_as_bytes }
-    //  }
+
+    /// Whether the sender speaks the extension protocol (BEP 10).
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    /// The handshake as sent on the wire.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[0] = self.length;
+        bytes[1..20].copy_from_slice(&self.bittorrent);
+        bytes[20..28].copy_from_slice(&self.reserved);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    /// A handshake as received, as long as it is one of the BitTorrent protocol: anything
+    /// else doesn't say what the rest of the bytes mean.
+    pub fn from_bytes(bytes: &[u8; Self::LEN]) -> Result<Self, String> {
+        if bytes[0] != 19 || bytes[1..20] != *b"BitTorrent protocol" {
+            return Err("not a BitTorrent protocol handshake".into());
+        }
+        Ok(Self {
+            length: 19,
+            bittorrent: *b"BitTorrent protocol",
+            reserved: bytes[20..28].try_into().expect("8 bytes"),
+            info_hash: bytes[28..48].try_into().expect("20 bytes"),
+            peer_id: bytes[48..].try_into().expect("20 bytes"),
+        })
+    }
 }
 
-impl AsBytes for Handshake {}
+impl Bitfield {
+    /// A bitfield of `npieces` pieces, none of which are present.
+    pub fn new(npieces: usize) -> Self {
+        Self {
+            bits: vec![0; npieces.div_ceil(8)],
+            npieces,
+        }
+    }
+
+    /// A bitfield of `npieces` pieces, all of which are present.
+    pub fn full(npieces: usize) -> Self {
+        let mut bitfield = Self::new(npieces);
+        for index in 0..npieces {
+            bitfield.set_piece(index);
+        }
+        bitfield
+    }
+
+    /// A bitfield of `npieces` pieces from its wire representation, most significant bit first.
+    ///
+    /// The bytes must hold exactly `npieces` bits, rounded up to a byte, with the spare bits
+    /// at the end clear: a peer that gets either wrong would otherwise have us believe it
+    /// has pieces the torrent doesn't.
+    pub fn from_bytes(bits: &[u8], npieces: usize) -> Result<Self, String> {
+        let mut bitfield = Self::new(npieces);
+        if bits.len() != bitfield.bits.len() {
+            return Err(format!(
+                "{} bytes can't hold exactly {npieces} pieces",
+                bits.len()
+            ));
+        }
+        bitfield.bits.copy_from_slice(bits);
+        if (npieces..bits.len() * 8).any(|index| bits[index / 8] & (0x80 >> (index % 8)) != 0) {
+            return Err("spare bits at the end are set".into());
+        }
+        Ok(bitfield)
+    }
+
+    /// Like [`Bitfield::from_bytes`], but only requires the bytes to cover `npieces`:
+    /// anything past the last piece is ignored.
+    pub fn from_bytes_lenient(bits: &[u8], npieces: usize) -> Result<Self, String> {
+        let mut bitfield = Self::new(npieces);
+        let Some(bits) = bits.get(..bitfield.bits.len()) else {
+            return Err(format!("{} bytes can't hold {npieces} pieces", bits.len()));
+        };
+        bitfield.bits.copy_from_slice(bits);
+        if !npieces.is_multiple_of(8) {
+            // clear the spare bits so that counting doesn't see them
+            *bitfield.bits.last_mut().expect("a partial byte") &= 0xff << (8 - npieces % 8);
+        }
+        Ok(bitfield)
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        index < self.npieces && self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set_piece(&mut self, index: usize) {
+        assert!(index < self.npieces, "piece {index} out of range");
+        self.bits[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    /// The indices of the pieces present, in order.
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.npieces).filter(|&index| self.has_piece(index))
+    }
+
+    /// The indices of the pieces not present, in order.
+    pub fn missing_pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.npieces).filter(|&index| !self.has_piece(index))
+    }
+
+    /// The number of pieces present.
+    pub fn count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.npieces
+    }
+
+    /// Whether the torrent has no pieces to have.
+    pub fn is_empty(&self) -> bool {
+        self.npieces == 0
+    }
+
+    /// The payload of a `Bitfield` message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+/// A frame is larger than `--max-inbound-frame` or `--max-outbound-frame`, as raised for
+/// the torrent's bitfield.
+///
+/// Messages we send that can be split, like extension messages, have to be split before
+/// they get here; the rest can't be sent at all.
+#[derive(Debug, Clone, Copy, PartialEq, thiserror::Error)]
+#[error(
+    "{} of {len} bytes is larger than the {max} byte {direction}bound frame limit",
+    id.map_or_else(|| "frame".to_string(), |id| format!("{} message", MessageTag::name_of(id)))
+)]
+pub struct FrameTooLarge {
+    pub direction: Direction,
+    /// The message id, unless it hadn't arrived yet.
+    pub id: Option<u8>,
+    /// The length of the frame after its length prefix.
+    pub len: usize,
+    pub max: usize,
+}
+
+impl MessageFramer {
+    pub fn new(peer: SocketAddr, limits: &Limits) -> Self {
+        Self {
+            peer,
+            max_inbound: limits.max_inbound_frame,
+            max_outbound: limits.max_outbound_frame,
+            last_write: Instant::now(),
+        }
+    }
+
+    /// How long until we have written nothing for [`KEEP_ALIVE_INTERVAL`].
+    pub fn until_keep_alive(&self) -> Duration {
+        KEEP_ALIVE_INTERVAL.saturating_sub(self.last_write.elapsed())
+    }
+
+    /// Checks that `message` fits in an outbound frame.
+    pub fn check_outbound(&self, message: &MessagePayload) -> Result<(), FrameTooLarge> {
+        // a keep-alive is 0 bytes, which always fits
+        let id = message.id().unwrap_or_default();
+        check_outbound(id, message.len(), self.max_outbound)
+    }
+}
+
+/// Checks that a message `id` of `len` bytes, id included, fits in a frame of `max`.
+pub fn check_outbound(id: u8, len: usize, max: usize) -> Result<(), FrameTooLarge> {
+    if len > max {
+        return Err(FrameTooLarge {
+            direction: Direction::Out,
+            id: Some(id),
+            len,
+            max,
+        });
+    }
+    Ok(())
+}
 
 impl Decoder for MessageFramer {
-    type Item = Message;
+    type Item = MessagePayload;
     type Error = std::io::Error;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        const MAX: usize = 1 << 16;
-
-        // eprintln!("decode happened. src.len={}", src.len());
-
         if src.len() < 4 {
             // Not enough data to read length marker.
             return Ok(None);
@@ -159,30 +536,33 @@ impl Decoder for MessageFramer {
         let mut length_bytes = [0u8; 4];
         length_bytes.copy_from_slice(&src[..4]);
         let length = u32::from_be_bytes(length_bytes) as usize;
-
         if length == 0 {
-            // This is a heartbeat message, discard it
+            // A keep-alive, which readers see so that it counts as the peer being alive.
+            // Returning it rather than going on to the next frame keeps a flood of them
+            // from costing more than one frame's work per call.
             src.advance(4);
-            // And then try again in case the buffer has more message
-            return self.decode(src);
+            wire_log::frame(self.peer, Direction::In, None, &[]);
+            return Ok(Some(MessagePayload::KeepAlive));
         }
 
         // Check that the length is not too large to avoid a denial of
         // service attack where the server runs out of memory.
-        if length > MAX {
+        if length > self.max_inbound {
+            let too_large = FrameTooLarge {
+                direction: Direction::In,
+                id: src.get(4).copied(),
+                len: length,
+                max: self.max_inbound,
+            };
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", length),
+                too_large,
             ));
         }
 
-        if src.len() < 5 {
-            // Not enough data to read tag marker.
-            return Ok(None);
-        }
-
         if src.len() < 4 + length {
-            // The full string has not yet arrived.
+            // The full frame has not yet arrived, which includes a frame whose id
+            // hasn't.
             //
             // We reserve more space in the buffer. This is not strictly
             // necessary, but is a good idea performance-wise.
@@ -193,59 +573,153 @@ impl Decoder for MessageFramer {
             return Ok(None);
         }
 
-        // eprintln!("length: {length}");
+        // The whole frame is buffered and `length >= 1` here, so it has an id. Splitting
+        // it off lets a piece keep its block without copying it.
+        let mut frame = src.split_to(4 + length);
+        frame.advance(4);
+        let id = frame.get_u8();
+        let payload = frame.freeze();
+        wire_log::frame(self.peer, Direction::In, Some(id), &payload);
 
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let tag = src[4];
-        let data = src[5..5 + length - 1].to_vec();
-        src.advance(4 + length);
-
-        Ok(Some(Message {
-            tag: tag
-                .try_into()
-                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?,
-            payload: data,
-        }))
+        MessagePayload::parse(id, payload)
+            .map(Some)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
     }
 }
 
-impl Encoder<Message> for MessageFramer {
+impl Encoder<MessagePayload> for MessageFramer {
     type Error = std::io::Error;
 
-    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        const MAX: usize = 8 * 1024 * 1024;
-
+    fn encode(&mut self, item: MessagePayload, dst: &mut BytesMut) -> Result<(), Self::Error> {
         // Don't send a message if it is longer than the other end will
         // accept.
-        if item.len() > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", item.len()),
-            ));
-        }
+        self.check_outbound(&item)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
 
-        // Convert the length into a byte array.
         // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_be_bytes(item.len() as u32);
-
-        // Reserve space in the buffer.
         dst.reserve(4 + item.len());
-
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.tag as u8);
-        dst.extend_from_slice(item.payload.as_slice());
+        dst.put_u32(item.len() as u32);
+        let id = item.id();
+        if let Some(id) = id {
+            dst.put_u8(id);
+        }
+        let start = dst.len();
+        item.put_payload(dst);
+        wire_log::frame(self.peer, Direction::Out, id, &dst[start..]);
+        self.last_write = Instant::now();
         Ok(())
     }
 }
 
-impl Message {
-    pub fn new(tag: MessageTag, payload: Vec<u8>) -> Self {
-        Self { tag, payload }
+impl MessagePayload {
+    /// The message id, which only keep-alives don't have.
+    pub fn id(&self) -> Option<u8> {
+        let tag = match self {
+            Self::KeepAlive => return None,
+            Self::Raw { id, .. } => return Some(*id),
+            Self::Choke => MessageTag::Choke,
+            Self::Unchoke => MessageTag::Unchoke,
+            Self::Interested => MessageTag::Interested,
+            Self::NotInterested => MessageTag::NotInterested,
+            Self::Have(_) => MessageTag::Have,
+            Self::Bitfield(_) => MessageTag::Bitfield,
+            Self::Request(_) => MessageTag::Request,
+            Self::Piece { .. } => MessageTag::Piece,
+            Self::Cancel(_) => MessageTag::Cancel,
+            Self::Port(_) => MessageTag::Port,
+            Self::Extended { .. } => MessageTag::Extended,
+        };
+        Some(tag as u8)
     }
+
+    /// The length of the frame after its length prefix.
     pub fn len(&self) -> usize {
-        1 /* tag */ + self.payload.len()
+        let payload = match self {
+            Self::KeepAlive => return 0,
+            Self::Choke | Self::Unchoke | Self::Interested | Self::NotInterested => 0,
+            Self::Have(_) => 4,
+            Self::Bitfield(bits) => bits.len(),
+            Self::Request(_) | Self::Cancel(_) => 12,
+            Self::Piece { block, .. } => 8 + block.len(),
+            Self::Port(_) => 2,
+            Self::Extended { payload, .. } => 1 + payload.len(),
+            Self::Raw { payload, .. } => payload.len(),
+        };
+        1 /* id */ + payload
+    }
+
+    /// Whether nothing follows the length prefix, as for a keep-alive.
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Self::KeepAlive)
+    }
+
+    /// Writes what follows the id.
+    fn put_payload(&self, dst: &mut BytesMut) {
+        match self {
+            Self::KeepAlive
+            | Self::Choke
+            | Self::Unchoke
+            | Self::Interested
+            | Self::NotInterested => {}
+            Self::Have(index) => dst.put_u32(*index),
+            Self::Bitfield(bits) => dst.extend_from_slice(bits),
+            Self::Request(request) | Self::Cancel(request) => {
+                dst.extend_from_slice(&request.to_bytes())
+            }
+            Self::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.extend_from_slice(block);
+            }
+            Self::Port(port) => dst.put_u16(*port),
+            Self::Extended { id, payload } => {
+                dst.put_u8(*id);
+                dst.extend_from_slice(payload);
+            }
+            Self::Raw { payload, .. } => dst.extend_from_slice(payload),
+        }
+    }
+
+    /// Parses the `payload` of a message `id`, checking its length if it's one we speak.
+    fn parse(id: u8, mut payload: Bytes) -> Result<Self, String> {
+        let Ok(tag) = MessageTag::try_from(id) else {
+            return Ok(Self::Raw {
+                id,
+                payload: payload.to_vec(),
+            });
+        };
+        tag.check_payload_len(payload.len())?;
+        Ok(match tag {
+            MessageTag::Choke => Self::Choke,
+            MessageTag::Unchoke => Self::Unchoke,
+            MessageTag::Interested => Self::Interested,
+            MessageTag::NotInterested => Self::NotInterested,
+            MessageTag::Have => Self::Have(payload.get_u32()),
+            MessageTag::Bitfield => Self::Bitfield(payload.to_vec()),
+            MessageTag::Request | MessageTag::Cancel => {
+                let request =
+                    MessageRequest::from_bytes(&payload[..].try_into().expect("12 bytes"));
+                if tag == MessageTag::Request {
+                    Self::Request(request)
+                } else {
+                    Self::Cancel(request)
+                }
+            }
+            MessageTag::Piece => Self::Piece {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                block: payload,
+            },
+            MessageTag::Port => Self::Port(payload.get_u16()),
+            MessageTag::Extended => Self::Extended {
+                id: payload.get_u8(),
+                payload: payload.to_vec(),
+            },
+        })
     }
 }
 
@@ -257,6 +731,14 @@ impl MessageRequest {
             length: length.to_be_bytes(),
         }
     }
+    pub fn from_bytes(bytes: &[u8; 12]) -> Self {
+        let field = |at: usize| bytes[at..at + 4].try_into().expect("4 bytes");
+        Self {
+            index: field(0),
+            begin: field(4),
+            length: field(8),
+        }
+    }
     pub fn index(&self) -> u32 {
         u32::from_be_bytes(self.index)
     }
@@ -266,48 +748,61 @@ impl MessageRequest {
     pub fn length(&self) -> u32 {
         u32::from_be_bytes(self.length)
     }
-
-    // #[allow(dead_code)]
-    // pub fn as_bytes(&self) -> &[u8; std::mem::size_of::<Self>()] {
-    //     let self_as_bytes = self as *const Self as *const [u8; std::mem::size_of::<Self>()];
-    //     unsafe { &*self_as_bytes }
-    // }
-    //
-    // pub fn as_bytes_mut(&mut self) -> &mut [u8; std::mem::size_of::<Self>()] {
-    //     let self_as_bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-    //     // Safety: Handshake is a POD with repr(c)
-    //     unsafe { &mut *self_as_bytes }
-    // }
+    /// The payload of a `Request` or `Cancel` message: index, begin and length, big-endian.
+    pub fn to_bytes(self) -> [u8; 12] {
+        let mut bytes = [0; 12];
+        bytes[..4].copy_from_slice(&self.index);
+        bytes[4..8].copy_from_slice(&self.begin);
+        bytes[8..].copy_from_slice(&self.length);
+        bytes
+    }
 }
 
-impl MessagePiece {
-    // pub fn new(index: u32, begin: u32, block: [u8]) -> Self {
-    //     Self {
-    //         index: index.to_be_bytes(),
-    //         begin: begin.to_be_bytes(),
-    //         block,
-    //     }
-    // }
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
-    }
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
-    }
-    pub fn block(&self) -> &[u8] {
-        &self.block
+impl MessageTag {
+    /// The name of message `id` for logs, which may be one we don't speak.
+    pub fn name_of(id: u8) -> String {
+        MessageTag::try_from(id).map_or_else(|_| format!("Unknown({id})"), |tag| format!("{tag:?}"))
     }
-    
-    pub fn try_from_bytes(data: &[u8]) -> anyhow::Result<&Self> {
-        // MessagePiece {
-        //     index: [0,0,0,0],
-        //     begin: [0,0,0,0],
-        //     block: [0,0,0,0],
-        // }
-        todo!()
+
+    /// Checks that a payload of `len` bytes is well-formed for a message with this tag.
+    ///
+    /// Catching inconsistent frames here keeps them from exploding later,
+    /// when the payload is interpreted.
+    pub fn check_payload_len(self, len: usize) -> Result<(), String> {
+        let (min, max) = match self {
+            MessageTag::Choke
+            | MessageTag::Unchoke
+            | MessageTag::Interested
+            | MessageTag::NotInterested => (0, Some(0)),
+            // <index>
+            MessageTag::Have => (4, Some(4)),
+            MessageTag::Bitfield => (0, None),
+            // <index><begin><length>
+            MessageTag::Request | MessageTag::Cancel => (12, Some(12)),
+            // <listen-port>
+            MessageTag::Port => (2, Some(2)),
+            // <index><begin><block>
+            MessageTag::Piece => (8, None),
+            // <extended message id><payload>
+            MessageTag::Extended => (1, None),
+        };
+        match max {
+            Some(max) if min == max && len != max => Err(format!(
+                "{:?} message payload must be exactly {} bytes, got {}",
+                self, max, len
+            )),
+            Some(max) if len > max => Err(format!(
+                "{:?} message payload must be at most {} bytes, got {}",
+                self, max, len
+            )),
+            _ if len < min => Err(format!(
+                "{:?} message payload must be at least {} bytes, got {}",
+                self, min, len
+            )),
+            _ => Ok(()),
+        }
     }
 }
-impl AsBytes for MessageRequest {}
 
 impl TryFrom<u8> for MessageTag {
     type Error = String;
@@ -323,7 +818,435 @@ impl TryFrom<u8> for MessageTag {
             6 => Ok(MessageTag::Request),
             7 => Ok(MessageTag::Piece),
             8 => Ok(MessageTag::Cancel),
+            9 => Ok(MessageTag::Port),
+            20 => Ok(MessageTag::Extended),
             _ => Err(format!("Unknown message type: {}.", value)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn peers(bencode: &[u8]) -> Vec<SocketAddr> {
+        serde_bencode::from_bytes::<Peers>(bencode).unwrap().0
+    }
+
+    #[test]
+    fn compact_and_dictionary_peers_decode_alike() {
+        let compact = peers(b"12:\x0a\x00\x00\x01\x1a\xe1\xc0\xa8\x01\x02\xc8\xd5");
+        let dicts = peers(
+            b"ld2:ip8:10.0.0.17:peer id20:-XX0000-0123456789ab4:porti6881ee\
+              d2:ip11:192.168.1.24:porti51413eee",
+        );
+        assert_eq!(
+            compact,
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "192.168.1.2:51413".parse().unwrap()
+            ]
+        );
+        assert_eq!(dicts, compact);
+    }
+
+    #[test]
+    fn dictionary_peers_without_a_usable_address_are_skipped() {
+        let listed = peers(
+            b"ld2:ip19:tracker.example.org4:porti1ee\
+              d2:ip15:::ffff:10.0.0.34:porti3ee\
+              d2:ip10:2001:db8::4:porti4ee\
+              d4:porti5ee\
+              d2:ip8:10.0.0.64:porti70000ee\
+              i7e\
+              d2:ip8:10.0.0.84:porti8eee",
+        );
+        assert_eq!(
+            listed,
+            [
+                "10.0.0.3:3".parse().unwrap(),
+                "[2001:db8::]:4".parse().unwrap(),
+                "10.0.0.8:8".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_handshake_is_written_byte_for_byte() {
+        let handshake = Handshake::new([0xaa; 20], *b"-RB0010-0123456789ab", true);
+        let mut expected = vec![19];
+        expected.extend_from_slice(b"BitTorrent protocol");
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0]);
+        expected.extend_from_slice(&[0xaa; 20]);
+        expected.extend_from_slice(b"-RB0010-0123456789ab");
+        assert_eq!(handshake.to_bytes()[..], expected[..]);
+        assert_eq!(Handshake::from_bytes(&handshake.to_bytes()), Ok(handshake));
+    }
+
+    #[test]
+    fn a_handshake_of_another_protocol_is_refused() {
+        let mut bytes = Handshake::new([0; 20], [0; 20], false).to_bytes();
+        bytes[1..20].copy_from_slice(b"BitTorrent Protocol");
+        assert!(Handshake::from_bytes(&bytes).is_err());
+        let mut bytes = Handshake::new([0; 20], [0; 20], false).to_bytes();
+        bytes[0] = 18;
+        assert!(Handshake::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn a_request_is_index_begin_and_length_big_endian() {
+        let request = MessageRequest::new(0x0102_0304, 0x4000, 0x0000_4000);
+        assert_eq!(
+            request.to_bytes(),
+            [1, 2, 3, 4, 0, 0, 0x40, 0, 0, 0, 0x40, 0]
+        );
+        assert_eq!(MessageRequest::from_bytes(&request.to_bytes()), request);
+        assert_eq!(
+            (request.index(), request.begin(), request.length()),
+            (0x0102_0304, 0x4000, 0x4000)
+        );
+    }
+
+    fn framer() -> MessageFramer {
+        MessageFramer::new(([127, 0, 0, 1], 6881).into(), &Limits::default())
+    }
+
+    /// A frame of message `id` with `payload`, length prefix included.
+    fn frame(id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (1 + payload.len() as u32).to_be_bytes().to_vec();
+        frame.push(id);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Decodes every complete frame in `bytes`, stopping at the first error.
+    fn decode_all(bytes: &[u8]) -> Result<Vec<MessagePayload>, std::io::Error> {
+        let mut framer = framer();
+        let mut src = BytesMut::from(bytes);
+        let mut messages = Vec::new();
+        while let Some(message) = framer.decode(&mut src)? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    fn decode_err(bytes: &[u8]) -> String {
+        let err = decode_all(bytes).expect_err("frame is malformed");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        err.to_string()
+    }
+
+    #[test]
+    fn flag_messages_take_no_payload() {
+        for (id, message) in [
+            (0, MessagePayload::Choke),
+            (1, MessagePayload::Unchoke),
+            (2, MessagePayload::Interested),
+            (3, MessagePayload::NotInterested),
+        ] {
+            assert_eq!(decode_all(&frame(id, &[])).unwrap(), [message]);
+            let err = decode_err(&frame(id, &[0]));
+            assert!(err.contains("exactly 0 bytes, got 1"), "{err}");
+        }
+    }
+
+    #[test]
+    fn have_takes_exactly_an_index() {
+        assert_eq!(
+            decode_all(&frame(4, &[0, 0, 1, 2])).unwrap(),
+            [MessagePayload::Have(0x102)]
+        );
+        for len in [0, 3, 5] {
+            let err = decode_err(&frame(4, &vec![0; len]));
+            assert_eq!(
+                err,
+                format!("Have message payload must be exactly 4 bytes, got {len}")
+            );
+        }
+    }
+
+    #[test]
+    fn request_and_cancel_take_exactly_twelve_bytes() {
+        let request = MessageRequest::new(1, 1 << 14, 1 << 14);
+        assert_eq!(
+            decode_all(&frame(6, &request.to_bytes())).unwrap(),
+            [MessagePayload::Request(request)]
+        );
+        assert_eq!(
+            decode_all(&frame(8, &request.to_bytes())).unwrap(),
+            [MessagePayload::Cancel(request)]
+        );
+        for (id, name) in [(6, "Request"), (8, "Cancel")] {
+            for len in [0, 11, 13] {
+                let err = decode_err(&frame(id, &vec![0; len]));
+                assert_eq!(
+                    err,
+                    format!("{name} message payload must be exactly 12 bytes, got {len}")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn port_takes_exactly_two_bytes() {
+        assert_eq!(
+            decode_all(&frame(9, &[0x1a, 0xe1])).unwrap(),
+            [MessagePayload::Port(6881)]
+        );
+        for len in [0, 1, 3] {
+            let err = decode_err(&frame(9, &vec![0; len]));
+            assert!(
+                err.contains("Port message payload must be exactly 2"),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn piece_needs_at_least_index_and_begin() {
+        assert_eq!(
+            decode_all(&frame(7, &[0, 0, 0, 2, 0, 0, 0, 4])).unwrap(),
+            [MessagePayload::Piece {
+                index: 2,
+                begin: 4,
+                block: Bytes::new(),
+            }]
+        );
+        let err = decode_err(&frame(7, &[0; 7]));
+        assert_eq!(err, "Piece message payload must be at least 8 bytes, got 7");
+    }
+
+    #[test]
+    fn a_piece_round_trips_through_the_framer() {
+        let block: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let piece = MessagePayload::Piece {
+            index: 0x0102_0304,
+            begin: 1 << 14,
+            block: Bytes::from(block.clone()),
+        };
+        let mut dst = BytesMut::new();
+        framer().encode(piece, &mut dst).unwrap();
+        let mut payload = vec![0x01, 0x02, 0x03, 0x04, 0, 0, 0x40, 0];
+        payload.extend_from_slice(&block);
+        assert_eq!(dst[..], frame(7, &payload)[..]);
+        let [MessagePayload::Piece {
+            index,
+            begin,
+            block: decoded,
+        }] = &decode_all(&dst).unwrap()[..]
+        else {
+            panic!("not a single piece");
+        };
+        assert_eq!((*index, *begin), (0x0102_0304, 1 << 14));
+        assert_eq!(decoded[..], block[..]);
+    }
+
+    #[test]
+    fn extended_needs_its_message_id() {
+        assert_eq!(
+            decode_all(&frame(20, &[0, b'd', b'e'])).unwrap(),
+            [MessagePayload::Extended {
+                id: 0,
+                payload: b"de".to_vec(),
+            }]
+        );
+        let err = decode_err(&frame(20, &[]));
+        assert!(
+            err.contains("Extended message payload must be at least 1"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn bitfields_and_unknown_messages_take_any_payload() {
+        assert_eq!(
+            decode_all(&frame(5, &[])).unwrap(),
+            [MessagePayload::Bitfield(Vec::new())]
+        );
+        assert_eq!(
+            decode_all(&frame(13, &[1, 2, 3])).unwrap(),
+            [MessagePayload::Raw {
+                id: 13,
+                payload: vec![1, 2, 3],
+            }]
+        );
+    }
+
+    /// Inputs that once got past the length checks or read out of bounds.
+    #[test]
+    fn short_frame_regressions() {
+        // a length prefix alone, or one with the id still missing
+        assert_eq!(decode_all(&[0, 0, 0]).unwrap(), []);
+        assert_eq!(decode_all(&[0, 0, 0, 1]).unwrap(), []);
+        assert_eq!(decode_all(&[0, 0, 0, 5, 4]).unwrap(), []);
+        // a frame of length 1 whose tag wants a payload
+        for id in [4, 6, 7, 8, 9, 20] {
+            decode_err(&[0, 0, 0, 1, id]);
+        }
+        // a truncated have followed by the start of the next frame
+        decode_err(&[0, 0, 0, 4, 4, 0, 0, 0, 0, 0, 0, 1]);
+        // a length one past u32 arithmetic
+        decode_err(&[0xff, 0xff, 0xff, 0xff, 7]);
+    }
+
+    /// A stream as a peer sends it: keep-alives around and between a bitfield, an unchoke
+    /// and three pieces, with the messages it decodes to.
+    fn stream() -> (Vec<u8>, Vec<MessagePayload>) {
+        let keep_alive = [0u8; 4];
+        let mut bytes = keep_alive.to_vec();
+        let mut messages = vec![MessagePayload::KeepAlive];
+        bytes.extend(frame(5, &[0xff, 0xe0]));
+        messages.push(MessagePayload::Bitfield(vec![0xff, 0xe0]));
+        bytes.extend(frame(1, &[]));
+        messages.push(MessagePayload::Unchoke);
+        for index in 0..3u32 {
+            let block: Vec<u8> = (0..300).map(|i| (i as u32 * 7 + index) as u8).collect();
+            let mut payload = index.to_be_bytes().to_vec();
+            payload.extend((index * 300).to_be_bytes());
+            payload.extend_from_slice(&block);
+            bytes.extend(frame(7, &payload));
+            messages.push(MessagePayload::Piece {
+                index,
+                begin: index * 300,
+                block: Bytes::from(block),
+            });
+            bytes.extend(keep_alive);
+            messages.push(MessagePayload::KeepAlive);
+        }
+        (bytes, messages)
+    }
+
+    /// Decodes `bytes` arriving in reads that end at each of `splits`, asserting nothing
+    /// is left over.
+    fn decode_split(bytes: &[u8], splits: &[usize]) -> Vec<MessagePayload> {
+        let mut framer = framer();
+        let mut src = BytesMut::new();
+        let mut messages = Vec::new();
+        let mut start = 0;
+        for &end in splits.iter().chain([&bytes.len()]) {
+            src.extend_from_slice(&bytes[start..end]);
+            start = end;
+            while let Some(message) = framer.decode(&mut src).unwrap() {
+                messages.push(message);
+            }
+        }
+        assert!(src.is_empty(), "{} bytes left over", src.len());
+        messages
+    }
+
+    #[test]
+    fn a_stream_decodes_the_same_split_at_any_byte() {
+        let (bytes, expected) = stream();
+        for split in 0..=bytes.len() {
+            assert_eq!(decode_split(&bytes, &[split]), expected, "split at {split}");
+        }
+    }
+
+    #[test]
+    fn a_stream_decodes_the_same_split_at_any_two_bytes() {
+        let (bytes, expected) = stream();
+        for first in 0..=bytes.len() {
+            for second in first..=bytes.len() {
+                assert_eq!(
+                    decode_split(&bytes, &[first, second]),
+                    expected,
+                    "split at {first} and {second}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_stream_decodes_the_same_a_byte_at_a_time() {
+        let (bytes, expected) = stream();
+        let every: Vec<_> = (1..bytes.len()).collect();
+        assert_eq!(decode_split(&bytes, &every), expected);
+    }
+
+    /// Decodes `bytes` arriving in reads of `chunks` bytes each, then the rest, until the
+    /// first error, and tells whether there was one.
+    ///
+    /// Only that there was is compared: an oversized frame is named by its tag once the tag
+    /// is in, and only by its length before.
+    fn decode_chunks(bytes: &[u8], chunks: &[usize]) -> (Vec<MessagePayload>, bool) {
+        let mut framer = framer();
+        let mut src = BytesMut::new();
+        let mut messages = Vec::new();
+        let mut rest = bytes;
+        let reads = chunks.iter().copied().chain([bytes.len()]);
+        for len in reads {
+            let (read, after) = rest.split_at(len.min(rest.len()));
+            src.extend_from_slice(read);
+            rest = after;
+            loop {
+                match framer.decode(&mut src) {
+                    Ok(Some(message)) => messages.push(message),
+                    Ok(None) => break,
+                    Err(_) => return (messages, true),
+                }
+            }
+        }
+        (messages, false)
+    }
+
+    /// Frames of any id, known or not, with short payloads of any length.
+    fn frames() -> impl Strategy<Value = Vec<u8>> {
+        let message = (0..=22u8, proptest::collection::vec(any::<u8>(), 0..24));
+        proptest::collection::vec(message, 0..16).prop_map(|frames| {
+            frames
+                .into_iter()
+                .flat_map(|(id, payload)| {
+                    // id 22 stands in for a keep-alive
+                    if id == 22 {
+                        vec![0; 4]
+                    } else {
+                        frame(id, &payload)
+                    }
+                })
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn random_bytes_decode_the_same_however_they_are_read(
+            bytes in proptest::collection::vec(any::<u8>(), 0..256),
+            chunks in proptest::collection::vec(0..32usize, 0..32),
+        ) {
+            prop_assert_eq!(decode_chunks(&bytes, &chunks), decode_chunks(&bytes, &[]));
+        }
+
+        #[test]
+        fn framed_streams_decode_the_same_however_they_are_read(
+            bytes in frames(),
+            chunks in proptest::collection::vec(0..48usize, 0..32),
+        ) {
+            prop_assert_eq!(decode_chunks(&bytes, &chunks), decode_chunks(&bytes, &[]));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_keep_alive_is_a_bare_zero_length_and_counts_as_a_write() {
+        let mut framer = framer();
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(framer.until_keep_alive(), Duration::from_secs(30));
+        let mut dst = BytesMut::new();
+        framer.encode(MessagePayload::KeepAlive, &mut dst).unwrap();
+        assert_eq!(&dst[..], [0, 0, 0, 0]);
+        assert_eq!(framer.until_keep_alive(), KEEP_ALIVE_INTERVAL);
+        tokio::time::advance(KEEP_ALIVE_INTERVAL * 2).await;
+        assert_eq!(framer.until_keep_alive(), Duration::ZERO);
+    }
+
+    #[test]
+    fn keep_alives_decode_one_per_call() {
+        let mut framer = framer();
+        let mut src = BytesMut::from(&[0u8; 8][..]);
+        assert_eq!(
+            framer.decode(&mut src).unwrap(),
+            Some(MessagePayload::KeepAlive)
+        );
+        assert_eq!(src.len(), 4);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::perms;
+use crate::redact;
+use crate::sidecar;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Peers remembered per torrent, good and banned ones each.
+pub const MAX_CACHED_PEERS: usize = 200;
+
+/// How long a peer's score takes to halve while we don't hear from it.
+const SCORE_HALF_LIFE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The peers of one torrent worth trying again in the next session, and those never to
+/// try again, kept in a JSON file in the state directory.
+///
+/// Entries expire once they haven't been seen for the maximum age given to [`load`], and
+/// the scores of the others fade with time, so a peer that was good last week doesn't
+/// beat one that was good yesterday.
+///
+/// [`load`]: PeerCache::load
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerCache {
+    /// Hex-encoded info hash of the torrent the peers belong to.
+    pub info_hash: String,
+    pub peers: Vec<CachedPeer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedPeer {
+    pub addr: SocketAddr,
+    /// When we last connected to the peer or failed to, in seconds since the Unix epoch.
+    pub last_seen: u64,
+    /// Goes up with every session the peer served us, down with every failure.
+    pub score: f64,
+    /// Failures since the peer last worked.
+    pub failures: u32,
+    /// The peer sent data that failed its hash check.
+    #[serde(default)]
+    pub banned: bool,
+}
+
+/// Where downloads keep their peer caches, and how long a peer stays in one without being
+/// seen.
+#[derive(Debug, Clone)]
+pub struct PeerCacheConfig {
+    pub state_dir: PathBuf,
+    pub max_age: Duration,
+}
+
+/// The [`PeerCache`] of one torrent along with its file, which is saved after every change.
+///
+/// Failing to save only gets a warning, like failing to load.
+#[derive(Debug)]
+pub struct PeerCacheFile {
+    path: PathBuf,
+    cache: PeerCache,
+}
+
+/// The file the peer cache of the torrent with `info_hash` is kept in.
+pub fn path(state_dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    state_dir.join(format!("{}.peers.json", hex::encode(info_hash)))
+}
+
+/// Now, in seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl PeerCache {
+    /// Reads the peer cache at `path`, dropping entries older than `max_age` at `now` and
+    /// fading the scores of the rest.
+    ///
+    /// A missing file gives an empty cache, and so does an unreadable one or one of
+    /// another torrent, after a warning: the cache only saves time, it's never needed.
+    pub fn load(path: &Path, info_hash: &[u8; 20], now: u64, max_age: Duration) -> Self {
+        let info_hash = hex::encode(info_hash);
+        let empty = || Self {
+            info_hash: info_hash.clone(),
+            peers: Vec::new(),
+        };
+        let file = match std::fs::read(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return empty(),
+            Err(err) => {
+                warn!("can't read peer cache {}: {err}", path.display());
+                return empty();
+            }
+        };
+        let mut cache: Self = match serde_json::from_slice(&file) {
+            Ok(cache) => cache,
+            Err(err) => {
+                warn!("ignoring peer cache {}: {err}", path.display());
+                return empty();
+            }
+        };
+        if !cache.info_hash.eq_ignore_ascii_case(&info_hash) {
+            warn!(
+                "ignoring peer cache {}, it is for info hash {}",
+                path.display(),
+                redact::hex_hash(&cache.info_hash)
+            );
+            return empty();
+        }
+        cache.peers.retain_mut(|peer| {
+            let age = now.saturating_sub(peer.last_seen);
+            peer.score *= 0.5f64.powf(age as f64 / SCORE_HALF_LIFE.as_secs_f64());
+            age <= max_age.as_secs()
+        });
+        cache
+    }
+
+    /// Replaces the cache file at `path` atomically, keeping at most [`MAX_CACHED_PEERS`]
+    /// good and banned peers each.
+    pub fn save(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.peers.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+        let (mut good, mut banned) = (0, 0);
+        self.peers.retain(|peer| {
+            let count = if peer.banned { &mut banned } else { &mut good };
+            *count += 1;
+            *count <= MAX_CACHED_PEERS
+        });
+        if let Some(dir) = path.parent() {
+            perms::create_dir_all(dir)
+                .with_context(|| format!("create state directory {}", dir.display()))?;
+        }
+        let json = serde_json::to_vec(self).context("serialize peer cache")?;
+        sidecar::write_atomic(path, &json)
+            .with_context(|| format!("write peer cache {}", path.display()))
+    }
+
+    /// The peers to dial before the tracker answers, best first: those that aren't banned
+    /// and worked more often than they failed.
+    pub fn dial_order(&self) -> Vec<SocketAddr> {
+        let mut good: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|peer| !peer.banned && peer.score > 0.0)
+            .collect();
+        good.sort_by(|a, b| b.score.total_cmp(&a.score));
+        good.into_iter().map(|peer| peer.addr).collect()
+    }
+
+    pub fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.peers
+            .iter()
+            .any(|peer| peer.addr == addr && peer.banned)
+    }
+
+    /// Records that `addr` served us at `now`.
+    pub fn succeeded(&mut self, addr: SocketAddr, now: u64) {
+        let peer = self.entry(addr, now);
+        peer.score += 1.0;
+        peer.failures = 0;
+    }
+
+    /// Records that connecting to or downloading from `addr` failed at `now`.
+    pub fn failed(&mut self, addr: SocketAddr, now: u64) {
+        let peer = self.entry(addr, now);
+        peer.score -= 1.0;
+        peer.failures += 1;
+    }
+
+    /// Records that `addr` sent bad data, so it's never dialed again.
+    pub fn ban(&mut self, addr: SocketAddr, now: u64) {
+        self.entry(addr, now).banned = true;
+    }
+
+    fn entry(&mut self, addr: SocketAddr, now: u64) -> &mut CachedPeer {
+        let at = match self.peers.iter().position(|peer| peer.addr == addr) {
+            Some(at) => at,
+            None => {
+                self.peers.push(CachedPeer {
+                    addr,
+                    last_seen: now,
+                    score: 0.0,
+                    failures: 0,
+                    banned: false,
+                });
+                self.peers.len() - 1
+            }
+        };
+        let peer = &mut self.peers[at];
+        peer.last_seen = now;
+        peer
+    }
+}
+
+impl PeerCacheFile {
+    /// Loads the peer cache of the torrent with `info_hash` as `config` says, see
+    /// [`PeerCache::load`].
+    pub fn open(config: &PeerCacheConfig, info_hash: &[u8; 20]) -> Self {
+        let path = path(&config.state_dir, info_hash);
+        let cache = PeerCache::load(&path, info_hash, unix_now(), config.max_age);
+        Self { path, cache }
+    }
+
+    /// See [`PeerCache::dial_order`].
+    pub fn dial_order(&self) -> Vec<SocketAddr> {
+        self.cache.dial_order()
+    }
+
+    pub fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.cache.is_banned(addr)
+    }
+
+    /// Records that `addr` served us.
+    pub fn succeeded(&mut self, addr: SocketAddr) {
+        self.cache.succeeded(addr, unix_now());
+        self.save();
+    }
+
+    /// Records that connecting to `addr` failed.
+    pub fn failed(&mut self, addr: SocketAddr) {
+        self.cache.failed(addr, unix_now());
+        self.save();
+    }
+
+    /// Records that `addr` sent bad data.
+    pub fn ban(&mut self, addr: SocketAddr) {
+        self.cache.ban(addr, unix_now());
+        self.save();
+    }
+
+    fn save(&mut self) {
+        if let Err(err) = self.cache.save(&self.path) {
+            warn!("{err:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: [u8; 20] = [7; 20];
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn reload(path: &Path, now: u64) -> PeerCache {
+        PeerCache::load(path, &HASH, now, Duration::from_secs(7 * DAY))
+    }
+
+    #[test]
+    fn old_entries_expire_and_scores_fade() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = path(dir.path(), &HASH);
+        let mut cache = reload(&path, 0);
+        cache.succeeded(addr(1), 0);
+        cache.succeeded(addr(2), 7 * DAY);
+        cache.save(&path).unwrap();
+
+        let cache = reload(&path, 8 * DAY);
+        assert_eq!(cache.peers.len(), 1);
+        assert_eq!(cache.peers[0].addr, addr(2));
+        assert_eq!(cache.peers[0].score, 0.5);
+    }
+
+    #[test]
+    fn saving_keeps_the_best_peers_and_every_ban_up_to_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = path(dir.path(), &HASH);
+        let mut cache = reload(&path, 0);
+        for port in 0..2 * MAX_CACHED_PEERS as u16 {
+            cache.succeeded(addr(port), 0);
+            if port % 2 == 0 {
+                cache.succeeded(addr(port), 0);
+            }
+        }
+        cache.ban(addr(9999), 0);
+        cache.failed(addr(1), 0);
+        cache.save(&path).unwrap();
+
+        let cache = reload(&path, 0);
+        let order = cache.dial_order();
+        assert_eq!(order.len(), MAX_CACHED_PEERS);
+        assert!(order.iter().all(|peer| peer.port() % 2 == 0));
+        assert!(cache.is_banned(addr(9999)));
+        assert!(!order.contains(&addr(9999)));
+    }
+
+    #[test]
+    fn a_foreign_or_corrupt_cache_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = path(dir.path(), &HASH);
+        let mut foreign = PeerCache::load(&path, &[8; 20], 0, Duration::MAX);
+        foreign.succeeded(addr(1), 0);
+        foreign.save(&path).unwrap();
+        assert!(reload(&path, 0).peers.is_empty());
+
+        std::fs::write(&path, b"{\"info_hash\":").unwrap();
+        let cache = reload(&path, 0);
+        assert!(cache.peers.is_empty());
+        assert_eq!(cache.info_hash, hex::encode(HASH));
+    }
+}
@@ -0,0 +1,61 @@
+//! Our peer id, which trackers and peers know us by.
+//!
+//! Trackers and peers drop a second peer with an id they already know, so every session
+//! gets a fresh one: an Azureus-style client prefix, then random bytes.
+
+use std::str::FromStr;
+
+/// `-`, the client code (RB), the version (0.1.0 with a zero for the build), `-`.
+pub const CLIENT_PREFIX: [u8; 8] = *b"-RB0010-";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// [`CLIENT_PREFIX`] followed by 12 random bytes.
+    pub fn generate() -> Self {
+        let mut peer_id = [0; 20];
+        peer_id[..8].copy_from_slice(&CLIENT_PREFIX);
+        rand::Rng::fill(&mut rand::thread_rng(), &mut peer_id[8..]);
+        Self(peer_id)
+    }
+}
+
+/// Exactly 20 bytes, for `--peer-id`.
+impl FromStr for PeerId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.as_bytes()
+            .try_into()
+            .map(Self)
+            .map_err(|_| format!("a peer id is 20 bytes, not {}", s.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_ids_share_the_prefix_and_nothing_else() {
+        let (a, b) = (PeerId::generate(), PeerId::generate());
+        assert_eq!(a.0[..8], *b"-RB0010-");
+        assert_eq!(b.0[..8], *b"-RB0010-");
+        assert_ne!(a.0[8..], b.0[8..]);
+    }
+
+    #[test]
+    fn an_override_must_be_20_bytes() {
+        assert_eq!(
+            "-XX0000-0123456789ab".parse::<PeerId>(),
+            Ok(PeerId(*b"-XX0000-0123456789ab"))
+        );
+        assert_eq!(
+            "-XX0000-0123".parse::<PeerId>(),
+            Err("a peer id is 20 bytes, not 12".to_string())
+        );
+        // bytes, not characters
+        assert!("-XX0000-0123456789aé".parse::<PeerId>().is_err());
+    }
+}
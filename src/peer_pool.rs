@@ -0,0 +1,132 @@
+//! The peers a download can connect to: those the tracker gave us and those other peers
+//! told us about over PEX.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often the task of one peer may panic before the peer is given up on.
+pub const MAX_PEER_RESTARTS: u32 = 3;
+
+/// How long after its first panic a peer is dialed again; the wait doubles with each one.
+pub const PEER_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    /// Every peer ever added, so one learned twice is only dialed once.
+    known: HashSet<SocketAddr>,
+    /// Known peers not dialed yet, in the order they were learned.
+    untried: VecDeque<SocketAddr>,
+    /// Peers handed out by [`PeerPool::next_to_dial`].
+    tried: usize,
+    /// How often the task of each peer panicked.
+    panics: HashMap<SocketAddr, u32>,
+    /// Peers to dial again once their backoff is over, and when that is.
+    restarts: Vec<(Instant, SocketAddr)>,
+}
+
+impl PeerPool {
+    pub fn new(peers: impl IntoIterator<Item = SocketAddr>) -> Self {
+        let mut pool = Self::default();
+        pool.add(peers);
+        pool
+    }
+
+    /// Adds the peers not known yet, returning how many there were.
+    pub fn add(&mut self, peers: impl IntoIterator<Item = SocketAddr>) -> usize {
+        let before = self.untried.len();
+        for peer in peers {
+            if self.known.insert(peer) {
+                self.untried.push_back(peer);
+            }
+        }
+        self.untried.len() - before
+    }
+
+    /// The next peer to dial, if any is left: one not dialed yet, or else one whose task
+    /// panicked and whose backoff is over.
+    pub fn next_to_dial(&mut self) -> Option<SocketAddr> {
+        if let Some(peer) = self.untried.pop_front() {
+            self.tried += 1;
+            return Some(peer);
+        }
+        let now = Instant::now();
+        let at = self.restarts.iter().position(|&(due, _)| due <= now)?;
+        Some(self.restarts.swap_remove(at).1)
+    }
+
+    /// The task of `peer` panicked: it's dialed again after a backoff, unless that happened
+    /// [`MAX_PEER_RESTARTS`] times already.
+    ///
+    /// Returns the backoff, or `None` if the peer was given up on.
+    pub fn panicked(&mut self, peer: SocketAddr) -> Option<Duration> {
+        let panics = self.panics.entry(peer).or_default();
+        if *panics == MAX_PEER_RESTARTS {
+            return None;
+        }
+        let backoff = PEER_RESTART_BACKOFF * 2u32.pow(*panics);
+        *panics += 1;
+        self.restarts.push((Instant::now() + backoff, peer));
+        Some(backoff)
+    }
+
+    /// When the next peer whose task panicked is due to be dialed again, if any is.
+    pub fn next_restart(&self) -> Option<Instant> {
+        self.restarts.iter().map(|&(due, _)| due).min()
+    }
+
+    /// Whether any known peer wasn't dialed yet.
+    pub fn has_untried(&self) -> bool {
+        !self.untried.is_empty()
+    }
+
+    /// How many peers were dialed so far.
+    pub fn tried(&self) -> usize {
+        self.tried
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extension::{BencodeDict, UtPexMsg};
+
+    #[test]
+    fn peers_learned_over_pex_are_dialed_once() {
+        let tracker = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut pool = PeerPool::new([tracker]);
+        assert_eq!(pool.next_to_dial(), Some(tracker));
+
+        // 10.0.0.1:6881 again and 10.0.0.2:6882, twice
+        let payload =
+            b"d5:added18:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe2\x0a\x00\x00\x02\x1a\xe2e";
+        let pex = UtPexMsg::from_bencode(payload).unwrap();
+        assert_eq!(pool.add(pex.added_peers()), 1);
+        assert_eq!(pool.add(pex.added_peers()), 0);
+        assert_eq!(
+            pool.next_to_dial(),
+            Some(SocketAddr::from(([10, 0, 0, 2], 6882)))
+        );
+        assert!(!pool.has_untried());
+        assert_eq!(pool.tried(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_peer_whose_task_panics_is_dialed_again_after_a_growing_backoff() {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut pool = PeerPool::new([peer]);
+        assert_eq!(pool.next_to_dial(), Some(peer));
+        for restart in 0..MAX_PEER_RESTARTS {
+            let backoff = pool.panicked(peer).unwrap();
+            assert_eq!(backoff, PEER_RESTART_BACKOFF * 2u32.pow(restart));
+            assert_eq!(pool.next_restart(), Some(Instant::now() + backoff));
+            assert_eq!(pool.next_to_dial(), None);
+            tokio::time::advance(backoff).await;
+            assert_eq!(pool.next_to_dial(), Some(peer));
+        }
+        assert_eq!(pool.panicked(peer), None);
+        assert_eq!(pool.next_restart(), None);
+        assert_eq!(pool.tried(), 1);
+    }
+}
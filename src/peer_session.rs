@@ -0,0 +1,219 @@
+//! What we learn about a peer after the handshake: whether it speaks the extension protocol
+//! (BEP 10), and if so which extensions it supports under which message ids.
+
+use crate::download::PeerStream;
+use crate::extension::{BencodeDict, ExtendedHandshake, UtPexMsg};
+use crate::peer::{write_deadline, Bitfield, Handshake, MessagePayload};
+use crate::stats::TransferStats;
+use anyhow::{bail, Context};
+use futures_util::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::info;
+
+/// How long to wait for the peer's extended handshake before going on without extensions.
+pub const EXTENDED_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The extended message id of the extended handshake itself.
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// The extended message id we want `ut_metadata` messages sent to us with.
+pub const UT_METADATA_ID: u8 = 1;
+
+/// The extended message id we want `ut_pex` messages sent to us with.
+pub const UT_PEX_ID: u8 = 2;
+
+/// The extensions a download offers.
+pub const DOWNLOAD_EXTENSIONS: &[(&str, u8)] =
+    &[("ut_metadata", UT_METADATA_ID), ("ut_pex", UT_PEX_ID)];
+
+/// The extensions a download of a private torrent offers: its peers come from its trackers
+/// alone (BEP 27), so there is no `ut_pex`.
+pub const PRIVATE_DOWNLOAD_EXTENSIONS: &[(&str, u8)] = &[("ut_metadata", UT_METADATA_ID)];
+
+/// What a connected peer negotiated with us.
+#[derive(Debug, Clone)]
+pub struct PeerSession {
+    /// The peer's extended handshake, if both sides speak the extension protocol and it
+    /// sent one in time.
+    pub extensions: Option<ExtendedHandshake>,
+    /// Whether the peer chokes us, as of the last message read.
+    pub choked: bool,
+    /// The pieces the peer has, as of the last message read, if we know how many pieces
+    /// the torrent has.
+    has: Option<Bitfield>,
+    /// Peers the peer told us about with `ut_pex` since they were last taken.
+    pex_peers: Vec<SocketAddr>,
+    /// The port of the peer's DHT node, from a `port` message not taken yet.
+    dht_port: Option<u16>,
+}
+
+impl PeerSession {
+    /// A peer we don't exchange extended handshakes with, of a torrent with `npieces`
+    /// pieces if we have its metadata.
+    pub fn plain(npieces: Option<usize>) -> Self {
+        Self {
+            extensions: None,
+            choked: true,
+            has: npieces.map(Bitfield::new),
+            pex_peers: Vec::new(),
+            dht_port: None,
+        }
+    }
+
+    /// The message id the peer wants `extension` sent with, if it supports it.
+    pub fn extension_id(&self, extension: &str) -> Option<u8> {
+        self.extensions.as_ref()?.id_of(extension)
+    }
+
+    /// The size of the info dict, as the peer advertises it for `ut_metadata`.
+    pub fn metadata_size(&self) -> Option<usize> {
+        Some(self.extensions.as_ref()?.metadata_size? as usize)
+    }
+
+    /// How many outstanding requests the peer queues.
+    pub fn reqq(&self) -> Option<usize> {
+        Some(self.extensions.as_ref()?.reqq? as usize)
+    }
+
+    /// Whether the peer said it has piece `index`.
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.has.as_ref().is_some_and(|has| has.has_piece(index))
+    }
+
+    /// Keeps track of whether the peer chokes us, of the pieces it has, of the peers it
+    /// tells us about and of its DHT node.
+    ///
+    /// Fails on a bitfield or `have` that doesn't fit the torrent, which is all we could
+    /// ask the peer for otherwise.
+    pub fn observe(&mut self, message: &MessagePayload) -> anyhow::Result<()> {
+        match message {
+            MessagePayload::Choke => self.choked = true,
+            MessagePayload::Unchoke => self.choked = false,
+            MessagePayload::Bitfield(bits) => {
+                if let Some(has) = &mut self.has {
+                    *has = Bitfield::from_bytes(bits, has.len())
+                        .map_err(anyhow::Error::msg)
+                        .context("invalid bitfield")?;
+                }
+            }
+            &MessagePayload::Have(index) => {
+                if let Some(has) = &mut self.has {
+                    if index as usize >= has.len() {
+                        bail!("have for piece {index} of {}", has.len());
+                    }
+                    has.set_piece(index as usize);
+                }
+            }
+            &MessagePayload::Port(port) => self.dht_port = Some(port),
+            MessagePayload::Extended {
+                id: UT_PEX_ID,
+                payload,
+            } => {
+                // a malformed message only costs us the peers in it
+                if let Ok(pex) = UtPexMsg::from_bencode(payload) {
+                    self.pex_peers.extend(pex.added_peers());
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The port of the peer's DHT node, if it told us since the last call.
+    pub fn take_dht_port(&mut self) -> Option<u16> {
+        self.dht_port.take()
+    }
+
+    /// The peers learned over `ut_pex` since the last call.
+    pub fn take_pex_peers(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.pex_peers)
+    }
+}
+
+/// The extended handshake offering `extensions`, with their message ids, and our version.
+pub fn our_extended_handshake(extensions: &[(&str, u8)]) -> ExtendedHandshake {
+    ExtendedHandshake {
+        m: extensions
+            .iter()
+            .map(|&(name, id)| (name.to_string(), id))
+            .collect(),
+        v: Some(format!("rbittorrent {}", env!("CARGO_PKG_VERSION"))),
+        ..ExtendedHandshake::default()
+    }
+}
+
+/// Exchanges extended handshakes with `peer`, right after a handshake in which we set the
+/// extension bit and the peer answered with `theirs`, offering `extensions`.
+///
+/// A peer without the extension bit gets none. What the peer sends while we wait, like its
+/// bitfield or an unchoke, is kept track of as [`PeerSession::observe`] does; `npieces`
+/// is the torrent's piece count, if we have its metadata.
+pub async fn establish(
+    stream: &mut PeerStream,
+    peer: SocketAddr,
+    theirs: &Handshake,
+    extensions: &[(&str, u8)],
+    npieces: Option<usize>,
+    stats: &mut TransferStats,
+) -> anyhow::Result<PeerSession> {
+    let mut session = PeerSession::plain(npieces);
+    if !theirs.supports_extensions() {
+        return Ok(session);
+    }
+    let ours = MessagePayload::Extended {
+        id: EXTENDED_HANDSHAKE_ID,
+        payload: our_extended_handshake(extensions).to_bencode(),
+    };
+    write_deadline(stream.send(ours))
+        .await
+        .context("send extended handshake")?;
+
+    let wait = async {
+        loop {
+            let message = stream
+                .next()
+                .await
+                .context("peer closed the connection")?
+                .context("peer message was invalid")?;
+            stats.record_wire(4 + message.len());
+            session.observe(&message)?;
+            if let MessagePayload::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload,
+            } = message
+            {
+                return anyhow::Ok(ExtendedHandshake::from_bencode(&payload));
+            }
+        }
+    };
+    match tokio::time::timeout(EXTENDED_HANDSHAKE_TIMEOUT, wait).await {
+        Ok(Ok(Ok(extensions))) => session.extensions = Some(extensions),
+        Ok(Ok(Err(err))) => {
+            info!("peer {peer}: ignoring its extended handshake: {err:#}");
+        }
+        Ok(Err(err)) => return Err(err),
+        Err(_) => info!("peer {peer}: sent no extended handshake, going on without"),
+    }
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_have_adds_to_the_bitfield_and_must_fit_the_torrent() {
+        let mut session = PeerSession::plain(Some(10));
+        let mut bits = Bitfield::new(10);
+        bits.set_piece(0);
+        session
+            .observe(&MessagePayload::Bitfield(bits.as_bytes().to_vec()))
+            .unwrap();
+        assert!(!session.has_piece(9));
+        session.observe(&MessagePayload::Have(9)).unwrap();
+        assert!(session.has_piece(0) && session.has_piece(9));
+        let err = session.observe(&MessagePayload::Have(10)).unwrap_err();
+        assert_eq!(err.to_string(), "have for piece 10 of 10");
+    }
+}
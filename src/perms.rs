@@ -0,0 +1,132 @@
+//! The permissions of the files and directories we create, for `--file-mode` and
+//! `--dir-mode`.
+//!
+//! Modes are set on the open descriptor right after creating, before anything is written,
+//! and `open(2)` already creates the file with the mode less the umask, so there is no
+//! window in which a file is more open than asked for. Files and directories that exist
+//! already keep their mode.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::warn;
+
+static MODES: OnceLock<Modes> = OnceLock::new();
+
+/// What `--file-mode` and `--dir-mode` ask for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::Args)]
+pub struct Modes {
+    /// The permissions of the files we create, e.g. `0644`; Unix only.
+    #[arg(long = "file-mode", global = true, value_parser = parse_mode)]
+    pub file_mode: Option<u32>,
+    /// The permissions of the directories we create, e.g. `0755`; Unix only.
+    #[arg(long = "dir-mode", global = true, value_parser = parse_mode)]
+    pub dir_mode: Option<u32>,
+}
+
+/// Applies `modes` to everything created from now on.
+pub fn set_modes(modes: Modes) {
+    if !cfg!(unix) && modes != Modes::default() {
+        warn!("--file-mode and --dir-mode only apply on Unix, ignoring them");
+    }
+    // set once at startup, before anything is created
+    let _ = MODES.set(modes);
+}
+
+fn modes() -> Modes {
+    MODES.get().copied().unwrap_or_default()
+}
+
+/// Parses an octal mode like `0644` or `755`.
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("`{s}` is not an octal file mode like 0644")),
+    }
+}
+
+/// Opens the file at `path` for writing, creating it with `--file-mode` if it doesn't
+/// exist. An existing file is neither truncated nor changed.
+pub fn create_or_open(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if let Some(mode) = modes().file_mode {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    match options.open(path) {
+        Ok(file) => {
+            created_file(&file)?;
+            Ok(file)
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            OpenOptions::new().write(true).open(path)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Gives a file we just created `--file-mode`, if set, whatever the umask.
+pub fn created_file(file: &File) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = modes().file_mode {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = file;
+    Ok(())
+}
+
+/// Creates `path` and any missing parents, giving each directory it creates
+/// `--dir-mode` if set.
+pub fn create_dir_all(path: &Path) -> io::Result<()> {
+    if path.as_os_str().is_empty() || path.is_dir() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    if let Some(mode) = modes().dir_mode {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(mode);
+    }
+    match builder.create(path) {
+        // the umask may have taken bits away
+        Ok(()) => created_dir(path),
+        // someone else was quicker
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn created_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = modes().dir_mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_are_octal() {
+        assert_eq!(parse_mode("0644"), Ok(0o644));
+        assert_eq!(parse_mode("755"), Ok(0o755));
+        assert_eq!(parse_mode("0o2775"), Ok(0o2775));
+        for bad in ["", "0648", "rwxr-xr-x", "17777"] {
+            assert!(parse_mode(bad).is_err(), "{bad}");
+        }
+    }
+}
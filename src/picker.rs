@@ -0,0 +1,389 @@
+use rand::Rng;
+
+/// How eagerly a piece should be fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// The piece is not wanted at all.
+    Skip,
+    Normal,
+    High,
+}
+
+/// How many connected peers have each piece.
+#[derive(Debug, Clone)]
+pub struct Availability {
+    counts: Vec<u32>,
+}
+
+/// Everything a picker gets to look at when choosing the next piece for a peer.
+#[derive(Debug, Clone, Copy)]
+pub struct PickContext<'a> {
+    /// Pieces we already have.
+    pub have: &'a [bool],
+    /// Pieces the peer we are picking for has.
+    pub peer_has: &'a [bool],
+    /// Per-piece availability across the swarm.
+    pub availability: &'a Availability,
+    /// Pieces currently claimed by some peer connection.
+    pub in_flight: &'a [bool],
+    pub priorities: &'a [Priority],
+}
+
+/// Decides which piece a peer should download next.
+///
+/// Implementations prefer pieces nobody is working on. Only when every wanted piece the
+/// peer has is already claimed do they hand out a claimed one, which duplicates work
+/// but keeps the tail of a download from stalling on a single slow peer.
+pub trait PiecePicker {
+    fn pick(&mut self, ctx: &PickContext) -> Option<usize>;
+}
+
+/// Picks the wanted piece with the fewest sources, so rare pieces spread through the swarm first.
+#[derive(Debug, Default)]
+pub struct RarestFirst;
+
+/// Picks pieces in index order, which suits streaming a file while it downloads.
+#[derive(Debug, Default)]
+pub struct Sequential;
+
+/// Keeps the pieces right after the streaming position at [`Priority::High`].
+///
+/// The window follows the consumer as pieces are emitted, and grows while downloading is
+/// barely keeping up with consumption so the next pieces are asked for early enough.
+/// Pieces beyond the window are left to normal picking.
+#[derive(Debug, Clone)]
+pub struct ReadAhead {
+    /// The next piece the consumer needs.
+    position: usize,
+    /// How many pieces from `position` on are prioritized.
+    window: usize,
+    npieces: usize,
+}
+
+/// Picks a uniformly random wanted piece.
+#[derive(Debug)]
+pub struct RandomPicker<R> {
+    rng: R,
+}
+
+impl Availability {
+    pub fn new(npieces: usize) -> Self {
+        Self {
+            counts: vec![0; npieces],
+        }
+    }
+
+    pub fn count(&self, index: usize) -> u32 {
+        self.counts[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// A peer connected and announced the pieces in `has`.
+    pub fn add_peer(&mut self, has: &[bool]) {
+        for (count, &has) in self.counts.iter_mut().zip(has) {
+            *count += has as u32;
+        }
+    }
+
+    /// A peer whose pieces are `has` went away.
+    pub fn remove_peer(&mut self, has: &[bool]) {
+        for (count, &has) in self.counts.iter_mut().zip(has) {
+            *count -= has as u32;
+        }
+    }
+
+    /// A connected peer announced a new piece.
+    pub fn add_piece(&mut self, index: usize) {
+        self.counts[index] += 1;
+    }
+
+    /// How many pieces have each number of sources: element `n` counts the pieces `n`
+    /// peers have.
+    pub fn histogram(&self) -> Vec<usize> {
+        let max = self.counts.iter().copied().max().unwrap_or(0) as usize;
+        let mut histogram = vec![0; max + 1];
+        for &count in &self.counts {
+            histogram[count as usize] += 1;
+        }
+        histogram
+    }
+
+    /// How many complete copies of the torrent the peers hold between them: every piece
+    /// at least the integer part of times, plus the share of pieces beyond that.
+    ///
+    /// Below 1.0, some pieces have no source at all.
+    pub fn distributed_copies(&self) -> f64 {
+        let Some(&min) = self.counts.iter().min() else {
+            return 0.0;
+        };
+        let above = self.counts.iter().filter(|&&count| count > min).count();
+        min as f64 + above as f64 / self.counts.len() as f64
+    }
+}
+
+impl PickContext<'_> {
+    fn wanted(&self, index: usize) -> bool {
+        !self.have[index] && self.peer_has[index] && self.priorities[index] != Priority::Skip
+    }
+
+    /// Candidate pieces in index order: unclaimed ones, or if there are none, claimed ones.
+    fn candidates(&self) -> impl Iterator<Item = usize> + '_ {
+        let npieces = self.have.len();
+        let unclaimed = (0..npieces).any(|index| self.wanted(index) && !self.in_flight[index]);
+        (0..npieces).filter(move |&index| self.wanted(index) && self.in_flight[index] != unclaimed)
+    }
+}
+
+impl PiecePicker for RarestFirst {
+    fn pick(&mut self, ctx: &PickContext) -> Option<usize> {
+        ctx.candidates().min_by_key(|&index| {
+            (
+                std::cmp::Reverse(ctx.priorities[index]),
+                ctx.availability.count(index),
+            )
+        })
+    }
+}
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, ctx: &PickContext) -> Option<usize> {
+        ctx.candidates()
+            .min_by_key(|&index| std::cmp::Reverse(ctx.priorities[index]))
+    }
+}
+
+impl ReadAhead {
+    /// The window never shrinks below this many pieces.
+    const MIN_WINDOW: usize = 2;
+    /// Nor grows beyond this many.
+    const MAX_WINDOW: usize = 64;
+
+    pub fn new(npieces: usize, initial_window: usize) -> Self {
+        Self {
+            position: 0,
+            window: initial_window.clamp(Self::MIN_WINDOW, Self::MAX_WINDOW),
+            npieces,
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// The consumer is done with everything before `position`.
+    pub fn advance(&mut self, position: usize) {
+        self.position = self.position.max(position).min(self.npieces);
+    }
+
+    /// Resizes the window from the download rate and how fast the consumer reads, e.g. how
+    /// fast stdout drains, both in bytes per second.
+    ///
+    /// Less than 1.5x headroom doubles the window, more than 4x shrinks it by one piece.
+    pub fn adjust(&mut self, download_rate: f64, consume_rate: f64) {
+        if consume_rate <= 0.0 {
+            return;
+        }
+        let headroom = download_rate / consume_rate;
+        if headroom < 1.5 {
+            self.window = (self.window * 2).min(Self::MAX_WINDOW);
+        } else if headroom > 4.0 {
+            self.window = (self.window - 1).max(Self::MIN_WINDOW);
+        }
+    }
+
+    /// Raises the pieces in the window to [`Priority::High`] and lowers those that fell out
+    /// of it back to [`Priority::Normal`]; skipped pieces stay skipped.
+    pub fn apply(&self, priorities: &mut [Priority]) {
+        let window = self.position..(self.position + self.window).min(self.npieces);
+        for (index, priority) in priorities.iter_mut().enumerate() {
+            if *priority == Priority::Skip {
+                continue;
+            }
+            *priority = if window.contains(&index) {
+                Priority::High
+            } else {
+                Priority::Normal
+            };
+        }
+    }
+}
+
+impl<R: Rng> RandomPicker<R> {
+    pub fn new(rng: R) -> Self {
+        Self { rng }
+    }
+}
+
+impl<R: Rng> PiecePicker for RandomPicker<R> {
+    fn pick(&mut self, ctx: &PickContext) -> Option<usize> {
+        // reservoir sampling over the candidates of the highest priority present
+        let mut best = None;
+        let mut seen = 0;
+        for index in ctx.candidates() {
+            let priority = ctx.priorities[index];
+            match best {
+                Some((_, best_priority)) if priority < best_priority => continue,
+                Some((_, best_priority)) if priority > best_priority => seen = 0,
+                _ => {}
+            }
+            seen += 1;
+            if self.rng.gen_range(0..seen) == 0 {
+                best = Some((index, priority));
+            }
+        }
+        best.map(|(index, _)| index)
+    }
+}
+
+impl<P: PiecePicker + ?Sized> PiecePicker for Box<P> {
+    fn pick(&mut self, ctx: &PickContext) -> Option<usize> {
+        (**self).pick(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    /// A swarm of `npieces` pieces where we have none and the peer has them all.
+    struct Swarm {
+        have: Vec<bool>,
+        peer_has: Vec<bool>,
+        availability: Availability,
+        in_flight: Vec<bool>,
+        priorities: Vec<Priority>,
+    }
+
+    impl Swarm {
+        fn new(npieces: usize) -> Self {
+            Self {
+                have: vec![false; npieces],
+                peer_has: vec![true; npieces],
+                availability: Availability::new(npieces),
+                in_flight: vec![false; npieces],
+                priorities: vec![Priority::Normal; npieces],
+            }
+        }
+
+        /// Another peer with the pieces in `has` joins.
+        fn with_peer(mut self, has: &[usize]) -> Self {
+            let mut bits = vec![false; self.have.len()];
+            for &index in has {
+                bits[index] = true;
+            }
+            self.availability.add_peer(&bits);
+            self
+        }
+
+        fn pick(&self, picker: &mut impl PiecePicker) -> Option<usize> {
+            picker.pick(&PickContext {
+                have: &self.have,
+                peer_has: &self.peer_has,
+                availability: &self.availability,
+                in_flight: &self.in_flight,
+                priorities: &self.priorities,
+            })
+        }
+    }
+
+    #[test]
+    fn rarest_first_picks_the_piece_with_fewest_sources() {
+        let swarm = Swarm::new(4)
+            .with_peer(&[0, 1, 2, 3])
+            .with_peer(&[0, 1, 3])
+            .with_peer(&[0, 3]);
+        assert_eq!(swarm.pick(&mut RarestFirst), Some(2));
+    }
+
+    #[test]
+    fn rarest_first_prefers_priority_over_rarity() {
+        let mut swarm = Swarm::new(3).with_peer(&[0, 1]).with_peer(&[1]);
+        swarm.priorities[1] = Priority::High;
+        assert_eq!(swarm.pick(&mut RarestFirst), Some(1));
+    }
+
+    #[test]
+    fn sequential_picks_in_index_order() {
+        let mut swarm = Swarm::new(4);
+        swarm.have[0] = true;
+        swarm.peer_has[1] = false;
+        assert_eq!(swarm.pick(&mut Sequential), Some(2));
+        swarm.priorities[3] = Priority::High;
+        assert_eq!(swarm.pick(&mut Sequential), Some(3));
+    }
+
+    #[test]
+    fn skipped_pieces_and_pieces_we_have_are_never_picked() {
+        let mut swarm = Swarm::new(3);
+        swarm.have[0] = true;
+        swarm.priorities[1] = Priority::Skip;
+        swarm.peer_has[2] = false;
+        assert_eq!(swarm.pick(&mut RarestFirst), None);
+        assert_eq!(swarm.pick(&mut Sequential), None);
+        assert_eq!(
+            swarm.pick(&mut RandomPicker::new(StdRng::seed_from_u64(1))),
+            None
+        );
+    }
+
+    #[test]
+    fn claimed_pieces_are_only_picked_once_nothing_else_is_left() {
+        let mut swarm = Swarm::new(3);
+        swarm.in_flight[0] = true;
+        swarm.in_flight[1] = true;
+        assert_eq!(swarm.pick(&mut Sequential), Some(2));
+        swarm.in_flight[2] = true;
+        assert_eq!(swarm.pick(&mut Sequential), Some(0));
+    }
+
+    #[test]
+    fn random_picks_cover_every_candidate_of_the_top_priority() {
+        let mut swarm = Swarm::new(6);
+        swarm.priorities[4] = Priority::Skip;
+        let mut picker = RandomPicker::new(StdRng::seed_from_u64(7));
+        let mut seen = [false; 6];
+        for _ in 0..200 {
+            seen[swarm.pick(&mut picker).unwrap()] = true;
+        }
+        assert_eq!(seen, [true, true, true, true, false, true]);
+
+        swarm.priorities[2] = Priority::High;
+        for _ in 0..20 {
+            assert_eq!(swarm.pick(&mut picker), Some(2));
+        }
+    }
+
+    #[test]
+    fn boxed_pickers_pick_like_the_picker_they_hold() {
+        let swarm = Swarm::new(3).with_peer(&[0, 2]);
+        let mut boxed: Box<dyn PiecePicker> = Box::new(RarestFirst);
+        assert_eq!(swarm.pick(&mut boxed), Some(1));
+    }
+
+    #[test]
+    fn availability_tracks_peers_coming_and_going() {
+        let mut availability = Availability::new(3);
+        availability.add_peer(&[true, true, false]);
+        availability.add_peer(&[true, false, false]);
+        availability.add_piece(2);
+        assert_eq!(availability.histogram(), [0, 2, 1]);
+        assert_eq!(availability.distributed_copies(), 1.0 + 1.0 / 3.0);
+        availability.remove_peer(&[true, false, false]);
+        assert_eq!(
+            (0..3)
+                .map(|index| availability.count(index))
+                .collect::<Vec<_>>(),
+            [1, 1, 1]
+        );
+        assert_eq!(Availability::new(0).distributed_copies(), 0.0);
+    }
+}
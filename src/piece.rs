@@ -0,0 +1,346 @@
+use crate::layout;
+use crate::stats::TransferStats;
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+/// The share of pieces [`VerifyPolicy::Sampled`] checks unless told otherwise.
+pub const DEFAULT_SAMPLE_FRACTION: f64 = 0.05;
+
+/// Which downloaded pieces get their SHA-1 checked.
+///
+/// Anything but [`VerifyPolicy::Full`] is only sensible between machines you trust, e.g.
+/// when copying over your own LAN. Ordered from the safest to the weakest.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyPolicy {
+    /// Every piece.
+    #[default]
+    Full,
+    /// The first and last pieces and a random share of the others.
+    Sampled,
+    /// No piece at all.
+    None,
+}
+
+impl VerifyPolicy {
+    /// Which of `npieces` pieces to check, by index.
+    ///
+    /// [`VerifyPolicy::Sampled`] picks `fraction` of the pieces between the first and the
+    /// last one, rounded up, using `rng`.
+    pub fn selection(self, npieces: usize, fraction: f64, rng: &mut impl Rng) -> Vec<bool> {
+        match self {
+            VerifyPolicy::Full => vec![true; npieces],
+            VerifyPolicy::None => vec![false; npieces],
+            VerifyPolicy::Sampled => {
+                let mut selected = vec![false; npieces];
+                let inner = 1..npieces.saturating_sub(1);
+                let count = (inner.len() as f64 * fraction.clamp(0.0, 1.0)).ceil() as usize;
+                for index in inner.choose_multiple(rng, count) {
+                    selected[index] = true;
+                }
+                if let Some(first) = selected.first_mut() {
+                    *first = true;
+                }
+                if let Some(last) = selected.last_mut() {
+                    *last = true;
+                }
+                selected
+            }
+        }
+    }
+}
+
+/// Collects the blocks of a single piece as they arrive from a peer.
+///
+/// Every accepted block is published to the [`TransferStats`] right away as buffered bytes,
+/// so progress moves at block granularity instead of jumping once per piece.
+#[derive(Debug)]
+pub struct PieceAssembler {
+    index: usize,
+    block_size: usize,
+    data: Vec<u8>,
+    /// Which blocks of the piece have been received so far.
+    received: Vec<bool>,
+    received_bytes: usize,
+}
+
+impl PieceAssembler {
+    pub fn new(index: usize, piece_size: usize, block_size: usize) -> Self {
+        let nblocks = layout::block_count(piece_size, block_size);
+        Self {
+            index,
+            block_size,
+            data: vec![0; piece_size],
+            received: vec![false; nblocks],
+            received_bytes: 0,
+        }
+    }
+
+    /// Stores `block` at offset `begin` of the piece.
+    ///
+    /// Blocks that were already received are ignored, so a duplicate never counts twice.
+    pub fn add_block(
+        &mut self,
+        begin: usize,
+        block: &[u8],
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<()> {
+        if !begin.is_multiple_of(self.block_size) {
+            bail!(
+                "block of piece {} starts at {begin}, which is not a multiple of {}",
+                self.index,
+                self.block_size
+            );
+        }
+        let end = begin + block.len();
+        if end > self.data.len() {
+            bail!(
+                "block {begin}..{end} is out of bounds of piece {} of size {}",
+                self.index,
+                self.data.len()
+            );
+        }
+        let block_idx = begin / self.block_size;
+        if self.received[block_idx] {
+            stats.record_duplicate(block.len());
+            return Ok(());
+        }
+        self.data[begin..end].copy_from_slice(block);
+        self.received[block_idx] = true;
+        self.received_bytes += block.len();
+        stats.record_received(block.len());
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().all(|&received| received)
+    }
+
+    /// Checks the assembled piece against `expected_hash`, unless `verify` is false, and
+    /// hands out its data.
+    ///
+    /// On success the piece's bytes move from buffered to verified, otherwise they are discarded.
+    pub fn finish(
+        self,
+        expected_hash: &[u8; 20],
+        verify: bool,
+        stats: &mut TransferStats,
+    ) -> anyhow::Result<Vec<u8>> {
+        if !self.is_complete() {
+            stats.record_discarded(self.received_bytes);
+            return Err(anyhow!("piece {} is missing blocks", self.index));
+        }
+        if !verify {
+            stats.record_verified(self.index, self.received_bytes);
+            return Ok(self.data);
+        }
+        let hash = sha1(&self.data);
+        if &hash != expected_hash {
+            stats.record_hash_failure(self.received_bytes);
+            return Err(anyhow!(
+                "piece {} hash mismatch: expected {}, got {}",
+                self.index,
+                hex::encode(expected_hash),
+                hex::encode(hash)
+            ));
+        }
+        stats.record_verified(self.index, self.received_bytes);
+        Ok(self.data)
+    }
+}
+
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 1 << 14;
+
+    fn piece(len: usize) -> (Vec<u8>, [u8; 20]) {
+        let data = crate::create::fixture_data(len, 7);
+        let hash = sha1(&data);
+        (data, hash)
+    }
+
+    #[test]
+    fn counters_move_block_by_block_while_a_piece_is_paused_midway() {
+        let (data, hash) = piece(3 * BLOCK + 100);
+        let mut stats = TransferStats::new(data.len());
+        let mut assembler = PieceAssembler::new(0, data.len(), BLOCK);
+
+        assembler.add_block(0, &data[..BLOCK], &mut stats).unwrap();
+        assembler
+            .add_block(2 * BLOCK, &data[2 * BLOCK..3 * BLOCK], &mut stats)
+            .unwrap();
+        // the download stalls here, with two of four blocks in
+        assert!(!assembler.is_complete());
+        assert_eq!(stats.buffered, 2 * BLOCK);
+        assert_eq!(stats.verified, 0);
+        let progress = stats.progress();
+        assert_eq!(progress.verified_pct, 0.0);
+        let expected = (2 * BLOCK) as f64 * 100.0 / data.len() as f64;
+        assert!((progress.buffered_pct - expected).abs() < 1e-9);
+
+        assembler
+            .add_block(BLOCK, &data[BLOCK..2 * BLOCK], &mut stats)
+            .unwrap();
+        assembler
+            .add_block(3 * BLOCK, &data[3 * BLOCK..], &mut stats)
+            .unwrap();
+        assert!(assembler.is_complete());
+        assert_eq!(stats.buffered, data.len());
+
+        assert_eq!(assembler.finish(&hash, true, &mut stats).unwrap(), data);
+        assert_eq!(stats.buffered, 0);
+        assert_eq!(stats.verified, data.len());
+        assert_eq!(stats.left(), 0);
+        assert_eq!(stats.progress().verified_pct, 100.0);
+        assert_eq!(stats.progress().eta, Some(std::time::Duration::ZERO));
+    }
+
+    #[test]
+    fn duplicate_blocks_count_as_wasted_not_buffered() {
+        let (data, _) = piece(2 * BLOCK);
+        let mut stats = TransferStats::new(data.len());
+        let mut assembler = PieceAssembler::new(0, data.len(), BLOCK);
+        assembler.add_block(0, &data[..BLOCK], &mut stats).unwrap();
+        assembler.add_block(0, &data[..BLOCK], &mut stats).unwrap();
+        assert_eq!(stats.buffered, BLOCK);
+        let summary = stats.summary("Piece 0".into(), None, Default::default());
+        assert_eq!(summary.downloaded, 2 * BLOCK);
+        assert_eq!(summary.wasted, BLOCK);
+    }
+
+    #[test]
+    fn a_failed_hash_check_discards_the_buffered_bytes() {
+        let (data, mut hash) = piece(BLOCK + 1);
+        hash[0] ^= 0xff;
+        let mut stats = TransferStats::new(data.len());
+        let mut assembler = PieceAssembler::new(0, data.len(), BLOCK);
+        assembler.add_block(0, &data[..BLOCK], &mut stats).unwrap();
+        assembler
+            .add_block(BLOCK, &data[BLOCK..], &mut stats)
+            .unwrap();
+        let err = assembler.finish(&hash, true, &mut stats).unwrap_err();
+        assert!(err.to_string().contains("hash mismatch"), "{err}");
+        assert_eq!(stats.buffered, 0);
+        assert_eq!(stats.verified, 0);
+        let summary = stats.summary("Piece 0".into(), None, Default::default());
+        assert_eq!(summary.hash_failures, 1);
+        assert_eq!(summary.wasted, data.len());
+    }
+
+    #[test]
+    fn misplaced_blocks_are_rejected() {
+        let (data, _) = piece(2 * BLOCK);
+        let mut stats = TransferStats::new(data.len());
+        let mut assembler = PieceAssembler::new(0, data.len(), BLOCK);
+        assert!(assembler.add_block(1, &data[..10], &mut stats).is_err());
+        assert!(assembler
+            .add_block(BLOCK, &data[..BLOCK + 1], &mut stats)
+            .is_err());
+        assert_eq!(stats.buffered, 0);
+    }
+
+    #[test]
+    fn an_incomplete_piece_cannot_be_finished() {
+        let (data, hash) = piece(2 * BLOCK);
+        let mut stats = TransferStats::new(data.len());
+        let mut assembler = PieceAssembler::new(0, data.len(), BLOCK);
+        assembler.add_block(0, &data[..BLOCK], &mut stats).unwrap();
+        assert!(assembler.finish(&hash, true, &mut stats).is_err());
+        assert_eq!(stats.buffered, 0);
+    }
+
+    fn checked(selection: &[bool]) -> Vec<usize> {
+        (0..selection.len())
+            .filter(|&index| selection[index])
+            .collect()
+    }
+
+    #[test]
+    fn sampled_checks_the_ends_and_a_share_of_the_rest() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let selection = VerifyPolicy::Sampled.selection(102, DEFAULT_SAMPLE_FRACTION, &mut rng);
+        let sampled = checked(&selection);
+        // 5% of the 100 pieces in between, rounded up, and the first and last
+        assert_eq!(sampled.len(), 7, "{sampled:?}");
+        assert_eq!(sampled.first(), Some(&0));
+        assert_eq!(sampled.last(), Some(&101));
+        let again = VerifyPolicy::Sampled.selection(
+            102,
+            DEFAULT_SAMPLE_FRACTION,
+            &mut StdRng::seed_from_u64(7),
+        );
+        assert_eq!(again, selection);
+
+        // rounded up, so a single piece in between is still checked
+        let mut rng = StdRng::seed_from_u64(7);
+        assert_eq!(
+            checked(&VerifyPolicy::Sampled.selection(3, 0.01, &mut rng)),
+            [0, 1, 2]
+        );
+        assert_eq!(
+            checked(&VerifyPolicy::Sampled.selection(1, 0.0, &mut rng)),
+            [0]
+        );
+        assert!(VerifyPolicy::Sampled.selection(0, 0.5, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn full_catches_a_corrupt_piece_wherever_it_is() {
+        let npieces = 5;
+        let pieces: Vec<_> = (0..npieces).map(|_| piece(BLOCK)).collect();
+        let selection = VerifyPolicy::Full.selection(npieces, 0.0, &mut rand::thread_rng());
+        assert_eq!(checked(&selection), [0, 1, 2, 3, 4]);
+        for corrupt in 0..npieces {
+            let mut stats = TransferStats::new(npieces * BLOCK);
+            let caught: Vec<usize> = pieces
+                .iter()
+                .enumerate()
+                .filter(|&(index, (data, hash))| {
+                    let mut data = data.clone();
+                    if index == corrupt {
+                        data[BLOCK / 2] ^= 1;
+                    }
+                    let mut assembler = PieceAssembler::new(index, BLOCK, BLOCK);
+                    assembler.add_block(0, &data, &mut stats).unwrap();
+                    assembler
+                        .finish(hash, selection[index], &mut stats)
+                        .is_err()
+                })
+                .map(|(index, _)| index)
+                .collect();
+            assert_eq!(caught, [corrupt]);
+        }
+    }
+
+    #[test]
+    fn none_lets_a_corrupt_piece_through() {
+        let (mut data, hash) = piece(BLOCK);
+        data[0] ^= 1;
+        let selection = VerifyPolicy::None.selection(1, 1.0, &mut rand::thread_rng());
+        let mut stats = TransferStats::new(data.len());
+        let mut assembler = PieceAssembler::new(0, data.len(), BLOCK);
+        assembler.add_block(0, &data, &mut stats).unwrap();
+        assert_eq!(
+            assembler.finish(&hash, selection[0], &mut stats).unwrap(),
+            data
+        );
+        assert_eq!(stats.verified, data.len());
+    }
+}
@@ -0,0 +1,264 @@
+//! What the download and seed commands are going to do, worked out before they do any of it.
+//!
+//! Planning only reads: the torrent, piece maps and resume files. Everything that writes,
+//! listens or connects happens when the plan is carried out, so `--dry-run` can stop right
+//! after printing it.
+
+use crate::client;
+use crate::peer::Bitfield;
+use crate::piece::VerifyPolicy;
+use crate::redact;
+use crate::resume_import::{self, ResumeFormat};
+use crate::seed::PieceMap;
+use crate::stats::HumanBytes;
+use crate::torrent::{Metainfo, Torrent};
+use anyhow::bail;
+use clap::ValueEnum;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use tokio_util::sync::CancellationToken;
+
+/// How a dry run talks to the tracker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DryRunAnnounce {
+    /// Announce as the command would, to see which peers are available.
+    #[default]
+    Peers,
+    /// Announce with numwant=0, which only checks that the tracker answers.
+    Quiet,
+    /// Don't contact the tracker either.
+    Skip,
+}
+
+/// What `download_piece` is going to do.
+#[derive(Debug, Clone)]
+pub struct DownloadPiecePlan {
+    pub name: String,
+    pub info_hash: [u8; 20],
+    pub piece_index: usize,
+    pub piece_size: usize,
+    pub output: PathBuf,
+    pub verify_policy: VerifyPolicy,
+    /// Files and directories that will be created or replaced.
+    pub writes: Vec<PathBuf>,
+}
+
+/// What `seed` is going to do.
+#[derive(Debug, Clone)]
+pub struct SeedPlan {
+    pub name: String,
+    pub info_hash: [u8; 20],
+    pub data: PathBuf,
+    pub port: u16,
+    /// The pieces to seed, or `None` if they can only be known by re-checking the data.
+    pub have: Option<Bitfield>,
+    /// The bytes in the pieces missing from `have`, what we announce as `left`.
+    pub missing_bytes: Option<usize>,
+    pub have_source: HaveSource,
+    /// Files that will be created or replaced.
+    pub writes: Vec<PathBuf>,
+}
+
+/// Where a [`SeedPlan`] takes the pieces to seed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HaveSource {
+    /// No piece map was given, so the data is assumed complete.
+    Assumed,
+    PieceMap(PathBuf),
+    /// The piece map can't be trusted; the data is re-checked and the map rewritten.
+    Recheck(PathBuf),
+    /// Another client's resume file, spot-checked first if `verify` is set.
+    Import {
+        resume: PathBuf,
+        verify: bool,
+    },
+}
+
+impl DownloadPiecePlan {
+    pub fn new(
+        torrent: &Torrent,
+        piece_index: usize,
+        output: PathBuf,
+        pieces: Option<&Path>,
+        verify_policy: VerifyPolicy,
+    ) -> anyhow::Result<Self> {
+        let npieces = torrent.declared_pieces();
+        if piece_index >= npieces {
+            bail!("there is no piece {piece_index}, the torrent has {npieces}");
+        }
+        // before anything is announced or allocated
+        torrent.piece_hash(piece_index)?;
+        let mut writes = vec![output.clone()];
+        writes.extend(pieces.map(Path::to_path_buf));
+        Ok(Self {
+            name: torrent.info.name.decode(torrent.encoding.as_deref()),
+            info_hash: torrent.info_hash(),
+            piece_index,
+            piece_size: torrent.piece_size(piece_index),
+            output,
+            verify_policy,
+            writes,
+        })
+    }
+}
+
+impl SeedPlan {
+    pub fn new(
+        torrent: &Torrent,
+        data: Option<PathBuf>,
+        pieces: Option<PathBuf>,
+        import: Option<(PathBuf, ResumeFormat)>,
+        verify_imported: bool,
+        port: u16,
+    ) -> anyhow::Result<Self> {
+        let default_data = || {
+            PathBuf::from(
+                torrent
+                    .info
+                    .name
+                    .to_path_component(torrent.encoding.as_deref()),
+            )
+        };
+        let mut data = data;
+        let (have, have_source) = match (import, &pieces) {
+            (Some((resume, format)), _) => {
+                let imported = resume_import::import(&resume, format, torrent)?;
+                data.get_or_insert_with(|| imported.location.unwrap_or_else(default_data));
+                let source = HaveSource::Import {
+                    resume,
+                    verify: verify_imported,
+                };
+                (Some(imported.have), source)
+            }
+            (None, Some(pieces)) => match PieceMap::load(pieces, torrent)? {
+                Some(have) => (Some(have), HaveSource::PieceMap(pieces.clone())),
+                None => (None, HaveSource::Recheck(pieces.clone())),
+            },
+            (None, None) => (
+                Some(Bitfield::full(torrent.info.pieces.0.len())),
+                HaveSource::Assumed,
+            ),
+        };
+        let writes = match have_source {
+            HaveSource::Import { .. } | HaveSource::Recheck(_) => pieces.into_iter().collect(),
+            HaveSource::Assumed | HaveSource::PieceMap(_) => Vec::new(),
+        };
+        let missing_bytes = have.as_ref().map(|have| {
+            have.missing_pieces()
+                .map(|index| torrent.piece_size(index))
+                .sum()
+        });
+        Ok(Self {
+            name: torrent.info.name.decode(torrent.encoding.as_deref()),
+            info_hash: torrent.info_hash(),
+            data: data.unwrap_or_else(default_data),
+            port,
+            have,
+            missing_bytes,
+            have_source,
+            writes,
+        })
+    }
+
+    /// The pieces to seed: spot-checked first if they were imported, found by re-checking
+    /// the data if they couldn't be planned. The piece maps in `writes` are then rewritten
+    /// to match.
+    ///
+    /// Hashing stops early once `cancel` is, and the piece maps are only written after it
+    /// finished, so an interrupted check leaves them as they were.
+    pub async fn check_pieces(
+        &self,
+        torrent: &Torrent,
+        cancel: &CancellationToken,
+    ) -> anyhow::Result<Bitfield> {
+        let have = match (&self.have, &self.have_source) {
+            (Some(have), HaveSource::Import { verify: true, .. }) => {
+                client::check_data(torrent, &self.data, cancel, {
+                    let have = have.clone();
+                    move |torrent, data, cancel| {
+                        resume_import::verify_sample(torrent, data, &have, 16, cancel)
+                    }
+                })
+                .await?;
+                have.clone()
+            }
+            (Some(have), HaveSource::Import { .. }) => have.clone(),
+            (Some(have), _) => return Ok(have.clone()),
+            (None, _) => {
+                client::check_data(torrent, &self.data, cancel, resume_import::recheck).await?
+            }
+        };
+        for pieces in &self.writes {
+            PieceMap::from_bitfield(torrent, &have)?.save(pieces)?;
+        }
+        Ok(have)
+    }
+}
+
+impl Display for DownloadPiecePlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Torrent: {} ({})",
+            self.name,
+            redact::hash(&self.info_hash)
+        )?;
+        writeln!(
+            f,
+            "Fetch: piece {}, {}",
+            self.piece_index,
+            HumanBytes(self.piece_size as u64)
+        )?;
+        writeln!(f, "Verify policy: {:?}", self.verify_policy)?;
+        write_paths(f, "Writes", &self.writes)
+    }
+}
+
+impl Display for SeedPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Torrent: {} ({})",
+            self.name,
+            redact::hash(&self.info_hash)
+        )?;
+        writeln!(f, "Data: {}", self.data.display())?;
+        match &self.have_source {
+            HaveSource::Assumed => writeln!(f, "Pieces: all, no piece map given")?,
+            HaveSource::PieceMap(path) => writeln!(f, "Pieces: from {}", path.display())?,
+            HaveSource::Recheck(path) => writeln!(
+                f,
+                "Pieces: re-checked from the data, {} can't be trusted",
+                path.display()
+            )?,
+            HaveSource::Import { resume, verify } => writeln!(
+                f,
+                "Pieces: imported from {}{}",
+                resume.display(),
+                if *verify { ", spot-checked first" } else { "" }
+            )?,
+        }
+        if let (Some(have), Some(missing)) = (&self.have, self.missing_bytes) {
+            writeln!(
+                f,
+                "Present: {} of {} pieces, {} missing",
+                have.count(),
+                have.len(),
+                HumanBytes(missing as u64)
+            )?;
+        }
+        writeln!(f, "Listen: port {}", self.port)?;
+        write_paths(f, "Writes", &self.writes)
+    }
+}
+
+fn write_paths(f: &mut Formatter<'_>, label: &str, paths: &[PathBuf]) -> std::fmt::Result {
+    if paths.is_empty() {
+        return writeln!(f, "{label}: nothing");
+    }
+    writeln!(f, "{label}:")?;
+    for path in paths {
+        writeln!(f, "  {}", path.display())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,175 @@
+//! How the files we download into get their space, before any data arrives.
+
+use crate::perms;
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Zeros written at a time by [`Preallocation::Full`].
+const ZERO_CHUNK: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preallocation {
+    /// Only set the length; most filesystems allocate blocks as they are written.
+    #[default]
+    Sparse,
+    /// Write zeros, which finds a full disk up front on any filesystem, slowly.
+    Full,
+    /// Have the filesystem reserve the blocks without writing them, or fall back to sparse
+    /// where it can't.
+    Fallocate,
+}
+
+/// Grows `file` to `len` bytes with `strategy`, returning the strategy that was actually
+/// used.
+///
+/// Bytes already in the file are never touched, so allocating a partly downloaded file
+/// again doesn't zero what arrived. A file that is long enough already is left alone.
+pub fn allocate(file: &mut File, len: u64, strategy: Preallocation) -> io::Result<Preallocation> {
+    let current = file.metadata()?.len();
+    if current >= len {
+        return Ok(strategy);
+    }
+    match strategy {
+        Preallocation::Sparse => file.set_len(len)?,
+        Preallocation::Full => zero_fill(file, current, len)?,
+        Preallocation::Fallocate => {
+            if !fallocate(file, current, len)? {
+                warn!("the filesystem can't reserve space, allocating sparsely");
+                file.set_len(len)?;
+                return Ok(Preallocation::Sparse);
+            }
+        }
+    }
+    Ok(strategy)
+}
+
+/// Opens or creates the file at `path` and allocates it like [`allocate`], on a blocking
+/// thread since `full` may write gigabytes.
+pub async fn allocate_path(
+    path: PathBuf,
+    len: u64,
+    strategy: Preallocation,
+) -> anyhow::Result<Preallocation> {
+    tokio::task::spawn_blocking(move || {
+        let mut file =
+            perms::create_or_open(&path).with_context(|| format!("open {}", path.display()))?;
+        allocate(&mut file, len, strategy)
+            .with_context(|| format!("preallocate {} ({strategy:?})", path.display()))
+    })
+    .await
+    .context("preallocation task")?
+}
+
+fn zero_fill(file: &mut File, from: u64, to: u64) -> io::Result<()> {
+    let zeros = vec![0; ZERO_CHUNK];
+    file.seek(SeekFrom::Start(from))?;
+    let mut at = from;
+    while at < to {
+        let chunk = (to - at).min(ZERO_CHUNK as u64) as usize;
+        file.write_all(&zeros[..chunk])?;
+        at += chunk as u64;
+    }
+    // a full disk may only show when the data is flushed
+    file.sync_data()
+}
+
+/// Reserves `from..to` of `file`, returning `false` if the filesystem doesn't support it.
+///
+/// This is `fallocate(2)` rather than `posix_fallocate(3)`, which glibc emulates on such
+/// filesystems by writing a byte to every block.
+#[cfg(target_os = "linux")]
+fn fallocate(file: &File, from: u64, to: u64) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+    let (offset, len) = (from as libc::off_t, (to - from) as libc::off_t);
+    // SAFETY: the descriptor is open for as long as `file` is borrowed.
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, offset, len) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+/// There is no portable way to reserve space elsewhere, and Windows can only skip the
+/// zeroing with `SetFileValidData`, which needs privileges and exposes stale disk contents.
+#[cfg(not(target_os = "linux"))]
+fn fallocate(_file: &File, _from: u64, _to: u64) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEN: u64 = 4 << 20;
+
+    fn allocated(strategy: Preallocation, prefix: &[u8]) -> (File, Preallocation) {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(prefix).unwrap();
+        let used = allocate(&mut file, LEN, strategy).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), LEN);
+        (file, used)
+    }
+
+    /// The bytes the filesystem actually set aside for `file`.
+    #[cfg(unix)]
+    fn disk_usage(file: &File) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        file.metadata().unwrap().blocks() * 512
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_full_and_fallocate_take_the_space_up_front() {
+        let (sparse, used) = allocated(Preallocation::Sparse, b"");
+        assert_eq!(used, Preallocation::Sparse);
+        assert!(disk_usage(&sparse) < LEN, "{}", disk_usage(&sparse));
+
+        let (full, used) = allocated(Preallocation::Full, b"");
+        assert_eq!(used, Preallocation::Full);
+        assert!(disk_usage(&full) >= LEN, "{}", disk_usage(&full));
+
+        // whether the temp dir's filesystem can reserve space decides what we get
+        let (reserved, used) = allocated(Preallocation::Fallocate, b"");
+        match used {
+            Preallocation::Fallocate => assert!(disk_usage(&reserved) >= LEN),
+            other => assert_eq!(other, Preallocation::Sparse),
+        }
+    }
+
+    #[test]
+    fn allocating_again_keeps_the_data_already_there() {
+        for strategy in [
+            Preallocation::Sparse,
+            Preallocation::Full,
+            Preallocation::Fallocate,
+        ] {
+            let (mut file, _) = allocated(strategy, b"downloaded");
+            allocate(&mut file, LEN, Preallocation::Full).unwrap();
+            let mut start = [0; 10];
+            file.seek(SeekFrom::Start(0)).unwrap();
+            io::Read::read_exact(&mut file, &mut start).unwrap();
+            assert_eq!(&start, b"downloaded", "{strategy:?}");
+        }
+    }
+
+    /// `/dev/full` fails every write like a filesystem with no space left.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn a_full_disk_surfaces_when_zeroing() {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/full")
+            .unwrap();
+        let err = allocate(&mut file, LEN, Preallocation::Full).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSPC), "{err}");
+    }
+}
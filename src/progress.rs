@@ -0,0 +1,168 @@
+//! The progress of a download as people follow it: a status line redrawn in place on a
+//! terminal, or a line of `key=value` pairs every so often when stderr goes elsewhere,
+//! like a log file.
+
+use bittorrent_starter_rust::files::FileStatus;
+use bittorrent_starter_rust::stats::{HumanBytes, TransferStats};
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Stderr, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often the status line is redrawn at most.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a progress line is logged when stderr isn't a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The rate shown is that of the pieces verified over this long.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Clears the terminal line the cursor is on and goes back to its start.
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
+/// Whether the status line is on screen, so other output has to clear it first.
+static STATUS_LINE: AtomicBool = AtomicBool::new(false);
+
+/// Where log lines go: stderr, above the status line rather than into it.
+pub fn log_writer() -> Stderr {
+    clear_status_line();
+    std::io::stderr()
+}
+
+fn clear_status_line() {
+    if STATUS_LINE.swap(false, Ordering::Relaxed) {
+        eprint!("{CLEAR_LINE}");
+        let _ = std::io::stderr().flush();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Off,
+    /// A status line redrawn in place.
+    Line,
+    /// A line logged every [`LOG_INTERVAL`].
+    Log,
+}
+
+/// Reports how far a download of `npieces` pieces got, from the same [`TransferStats`] the
+/// tracker hears about.
+#[derive(Debug)]
+pub struct ProgressReporter {
+    mode: Mode,
+    npieces: usize,
+    /// When progress was last shown, if it was.
+    shown_at: Option<Instant>,
+    /// The verified bytes at each update over the last [`RATE_WINDOW`], oldest first.
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl ProgressReporter {
+    /// A reporter for `npieces` pieces, which shows nothing unless `enabled`.
+    pub fn new(enabled: bool, npieces: usize) -> Self {
+        let mode = if !enabled {
+            Mode::Off
+        } else if std::io::stderr().is_terminal() {
+            Mode::Line
+        } else {
+            Mode::Log
+        };
+        Self {
+            mode,
+            npieces,
+            shown_at: None,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Shows that `done` pieces are in, if it's time to, and how far along `files` are if
+    /// there is more than one; `peers` are connected.
+    pub fn update(
+        &mut self,
+        done: usize,
+        stats: &TransferStats,
+        files: &[FileStatus],
+        peers: usize,
+    ) {
+        let interval = match self.mode {
+            Mode::Off => return,
+            Mode::Line => REDRAW_INTERVAL,
+            Mode::Log => LOG_INTERVAL,
+        };
+        let now = Instant::now();
+        self.samples.push_back((now, stats.verified_payload()));
+        // the newest sample older than the window is where the window starts
+        while self
+            .samples
+            .get(1)
+            .is_some_and(|&(at, _)| now.duration_since(at) >= RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        // the last piece is always shown
+        if done < self.npieces
+            && self
+                .shown_at
+                .is_some_and(|shown| shown.elapsed() < interval)
+        {
+            return;
+        }
+        self.shown_at = Some(now);
+        let pct = stats.progress().verified_pct;
+        let rate = self.rate();
+        let eta = match stats.left() {
+            0 => "0".to_string(),
+            _ if rate == 0 => "?".to_string(),
+            left => (left as u64).div_ceil(rate).to_string(),
+        };
+        let npieces = self.npieces;
+        // a single file is as far along as the download
+        let files = if files.len() > 1 { files } else { &[] };
+        if self.mode == Mode::Line {
+            let files: String = files
+                .iter()
+                .map(|file| format!(" {:.0}%", file.percent))
+                .collect();
+            let files = if files.is_empty() {
+                files
+            } else {
+                format!(", files{files}")
+            };
+            eprint!(
+                "{CLEAR_LINE}{done}/{npieces} pieces, {pct:.1}%, {}/s, ETA {eta}s, {peers} peer(s){files}",
+                HumanBytes(rate)
+            );
+            let _ = std::io::stderr().flush();
+            STATUS_LINE.store(true, Ordering::Relaxed);
+            return;
+        }
+        let files: String = files
+            .iter()
+            .map(|file| format!(" file{}_pct={:.1}", file.index, file.percent))
+            .collect();
+        eprintln!(
+            "progress pieces={done}/{npieces} verified_pct={pct:.1} rate={rate} \
+             eta_secs={eta} peers={peers}{files}"
+        );
+    }
+
+    /// Bytes verified per second over the window, 0 until there are two samples.
+    fn rate(&self) -> u64 {
+        let (Some(&(first, from)), Some(&(last, to))) = (self.samples.front(), self.samples.back())
+        else {
+            return 0;
+        };
+        let secs = last.duration_since(first).as_secs_f64();
+        if secs > 0.0 {
+            ((to - from) as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
+
+    /// Takes the status line off the screen, e.g. before the download's result is printed.
+    pub fn clear(&self) {
+        clear_status_line();
+    }
+}
@@ -0,0 +1,141 @@
+//! Caps on how fast we download and upload, shared by every peer connection.
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What a limiter lets through at once after being idle, in seconds' worth of its rate.
+const BURST: f64 = 0.1;
+
+/// A token bucket any number of tasks draw bytes from.
+///
+/// A task that takes more than the bucket holds leaves it in debt and waits until the
+/// debt is paid off, so the rate stays at the cap however large each amount is, and tasks
+/// that come later wait behind it.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    /// `None` when there is no cap.
+    bucket: Option<Arc<Mutex<Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second.
+    rate: f64,
+    /// Bytes that may go right away; negative while in debt.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter of `bytes_per_sec`, which never holds anything up if it is 0.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        if bytes_per_sec == 0 {
+            return Self::default();
+        }
+        let rate = bytes_per_sec as f64;
+        Self {
+            bucket: Some(Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate * BURST,
+                refilled_at: Instant::now(),
+            }))),
+        }
+    }
+
+    /// Waits until `bytes` more fit the cap.
+    pub async fn acquire(&self, bytes: usize) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+        let wait = {
+            // the bucket is consistent after every statement, so poisoning is moot
+            let mut bucket = bucket.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * bucket.rate;
+            bucket.tokens = (bucket.tokens + refill).min(bucket.rate * BURST);
+            bucket.refilled_at = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Bytes per second `tasks` tasks drawing `block` bytes at a time from `limiter` get
+    /// through over `seconds` after the first.
+    async fn throughput(limiter: RateLimiter, tasks: usize, block: usize, seconds: u64) -> usize {
+        let through = Arc::new(AtomicUsize::new(0));
+        let workers: Vec<_> = (0..tasks)
+            .map(|_| {
+                let (limiter, through) = (limiter.clone(), Arc::clone(&through));
+                tokio::spawn(async move {
+                    loop {
+                        limiter.acquire(block).await;
+                        through.fetch_add(block, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let before = through.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(seconds)).await;
+        let after = through.load(Ordering::Relaxed);
+        workers.iter().for_each(|worker| worker.abort());
+        (after - before) / seconds as usize
+    }
+
+    fn assert_near(measured: usize, cap: usize) {
+        let ratio = measured as f64 / cap as f64;
+        assert!(
+            (0.9..=1.1).contains(&ratio),
+            "{measured} B/s against a cap of {cap}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn one_task_stays_within_the_cap() {
+        let cap = 1 << 20;
+        assert_near(
+            throughput(RateLimiter::new(cap), 1, 1 << 14, 1).await,
+            cap as usize,
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn many_tasks_share_the_cap() {
+        let cap = 500 << 10;
+        assert_near(
+            throughput(RateLimiter::new(cap), 8, 1 << 14, 1).await,
+            cap as usize,
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn amounts_larger_than_the_bucket_still_average_out_to_the_cap() {
+        // blocks of 0.64s each, so the count is only as fine as that over a longer run
+        let cap = 100 << 10;
+        assert_near(
+            throughput(RateLimiter::new(cap), 2, 1 << 16, 20).await,
+            cap as usize,
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_cap_never_waits() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(0);
+        for _ in 0..1000 {
+            limiter.acquire(1 << 20).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,103 @@
+//! Shortened and masked forms of identifiers for logs, which people paste into public bug
+//! reports.
+//!
+//! Announce URLs often carry a passkey, in the path or the query, that lets anyone announce
+//! as the user. Info hashes are shortened too, unless `--log-full-ids` asks for them whole.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How many hex characters of an info hash are logged by default.
+const SHORT_HASH_LEN: usize = 8;
+
+static FULL_IDS: AtomicBool = AtomicBool::new(false);
+
+/// Logs whole info hashes from now on, for `--log-full-ids`.
+pub fn set_full_ids(full: bool) {
+    FULL_IDS.store(full, Ordering::Relaxed);
+}
+
+/// An info hash, or any other hash, as hex for a log line.
+pub fn hash(hash: &[u8]) -> String {
+    hex_hash(&hex::encode(hash))
+}
+
+/// Like [`hash`], for one that is hex already.
+pub fn hex_hash(hex: &str) -> String {
+    if FULL_IDS.load(Ordering::Relaxed) || hex.len() <= SHORT_HASH_LEN {
+        return hex.to_string();
+    }
+    match hex.get(..SHORT_HASH_LEN) {
+        Some(prefix) => format!("{prefix}…"),
+        None => hex.to_string(),
+    }
+}
+
+/// `url` with the values of its query and anything in its path that looks like a passkey
+/// masked.
+pub fn url(url: &reqwest::Url) -> String {
+    let mut redacted = format!("{}://{}", url.scheme(), url.host_str().unwrap_or_default());
+    if let Some(port) = url.port() {
+        redacted.push_str(&format!(":{port}"));
+    }
+    for segment in url.path_segments().into_iter().flatten() {
+        redacted.push('/');
+        if segment.len() >= 16 && segment.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            redacted.push_str("<redacted>");
+        } else {
+            redacted.push_str(segment);
+        }
+    }
+    let mut separator = '?';
+    for (key, _) in url.query_pairs() {
+        redacted.push(separator);
+        redacted.push_str(&key);
+        redacted.push_str("=<redacted>");
+        separator = '&';
+    }
+    redacted
+}
+
+/// Like [`url`], for a URL that hasn't been parsed yet.
+pub fn url_str(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(url) => self::url(&url),
+        Err(_) => "<unparseable url>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passkeys_in_the_path_and_query_are_masked() {
+        let url = reqwest::Url::parse(
+            "https://tracker.example:8443/a1b2c3d4e5f6a7b8c9d0/announce?passkey=secret&uid=42",
+        )
+        .unwrap();
+        assert_eq!(
+            self::url(&url),
+            "https://tracker.example:8443/<redacted>/announce?passkey=<redacted>&uid=<redacted>"
+        );
+    }
+
+    #[test]
+    fn plain_urls_are_left_as_they_are() {
+        assert_eq!(
+            url_str("http://tracker.example/announce"),
+            "http://tracker.example/announce"
+        );
+        // short path segments aren't passkeys
+        assert_eq!(
+            url_str("udp://tracker.example:1337/v1/announce"),
+            "udp://tracker.example:1337/v1/announce"
+        );
+        assert_eq!(url_str("not a url"), "<unparseable url>");
+    }
+
+    #[test]
+    fn hashes_are_shortened_unless_they_are_short_already() {
+        assert_eq!(hash(&[0xab; 20]), "abababab…");
+        assert_eq!(hex_hash("abcd"), "abcd");
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// The `reqq` we assume for peers that don't advertise one in their extended handshake.
+///
+/// Deliberately low: a peer that silently drops requests beyond its queue looks exactly
+/// like a peer that is slow to answer them.
+pub const UNADVERTISED_REQQ: usize = 8;
+
+/// This many request timeouts within [`TIMEOUT_CLUSTER_WINDOW`] count as requests being
+/// dropped rather than one slow block.
+const TIMEOUT_CLUSTER: usize = 2;
+
+const TIMEOUT_CLUSTER_WINDOW: Duration = Duration::from_secs(30);
+
+/// How many requests we keep outstanding with one peer.
+///
+/// Never more than the peer's advertised `reqq`, since many clients drop what doesn't fit
+/// in their queue without telling us. When requests keep timing out on a peer the window
+/// is halved first; only once it is down to a single request is the peer to blame. Every
+/// full window of answered requests grows it by one again.
+#[derive(Debug, Clone)]
+pub struct RequestWindow {
+    /// The most the window may grow to.
+    limit: usize,
+    size: usize,
+    outstanding: usize,
+    /// Requests answered since the window last grew or shrank.
+    answered: usize,
+    recent_timeouts: VecDeque<Instant>,
+    /// How often the window shrank, for per-peer metrics.
+    pub shrinks: u64,
+}
+
+/// What a request timeout did to a [`RequestWindow`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutVerdict {
+    /// A lone timeout, which may just be a slow block.
+    Tolerated,
+    /// Timeouts cluster, so the peer is probably dropping requests; the window shrank.
+    Shrunk { from: usize, to: usize },
+    /// Even a single outstanding request times out; the peer itself is the problem.
+    Penalize,
+}
+
+impl RequestWindow {
+    /// A window of at most `max_depth` requests, or the peer's `reqq` if that is lower.
+    pub fn new(max_depth: usize, reqq: Option<usize>) -> Self {
+        let limit = max_depth.min(reqq.unwrap_or(UNADVERTISED_REQQ)).max(1);
+        Self {
+            limit,
+            size: limit,
+            outstanding: 0,
+            answered: 0,
+            recent_timeouts: VecDeque::new(),
+            shrinks: 0,
+        }
+    }
+
+    /// The number of requests that may be outstanding right now.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding
+    }
+
+    /// Whether another request may be sent.
+    pub fn has_room(&self) -> bool {
+        self.outstanding < self.size
+    }
+
+    pub fn sent(&mut self) {
+        debug_assert!(self.has_room(), "request sent beyond the window");
+        self.outstanding += 1;
+    }
+
+    /// A request was answered, or cancelled before it could be.
+    pub fn answered(&mut self) {
+        self.outstanding = self.outstanding.saturating_sub(1);
+        self.answered += 1;
+        if self.answered >= self.size && self.size < self.limit {
+            self.size += 1;
+            self.answered = 0;
+        }
+    }
+
+    /// A request timed out at `now`.
+    pub fn timed_out(&mut self, now: Instant) -> TimeoutVerdict {
+        self.outstanding = self.outstanding.saturating_sub(1);
+        self.answered = 0;
+        while self
+            .recent_timeouts
+            .front()
+            .is_some_and(|&at| now.duration_since(at) > TIMEOUT_CLUSTER_WINDOW)
+        {
+            self.recent_timeouts.pop_front();
+        }
+        self.recent_timeouts.push_back(now);
+        if self.recent_timeouts.len() < TIMEOUT_CLUSTER {
+            return TimeoutVerdict::Tolerated;
+        }
+        self.recent_timeouts.clear();
+        if self.size == 1 {
+            return TimeoutVerdict::Penalize;
+        }
+        let from = self.size;
+        self.size = (self.size / 2).max(1);
+        self.shrinks += 1;
+        TimeoutVerdict::Shrunk {
+            from,
+            to: self.size,
+        }
+    }
+}
+
+impl Display for TimeoutVerdict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeoutVerdict::Tolerated => write!(f, "request timed out"),
+            TimeoutVerdict::Shrunk { from, to } => write!(
+                f,
+                "requests keep timing out, request window shrank from {from} to {to}"
+            ),
+            TimeoutVerdict::Penalize => write!(f, "requests time out even one at a time"),
+        }
+    }
+}
@@ -0,0 +1,599 @@
+use crate::peer::Bitfield;
+use crate::redact;
+use crate::torrent::{Keys, Metainfo, Torrent};
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use rand::seq::IteratorRandom;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Transmission tracks progress in blocks of this size.
+const TRANSMISSION_BLOCK_SIZE: usize = 1 << 14;
+
+/// Pieces are read and hashed in chunks of at most this size, so cancellation isn't held
+/// up by huge pieces.
+pub(crate) const READ_CHUNK: usize = 1 << 20;
+
+/// The resume formats of other clients we can import.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ResumeFormat {
+    /// Transmission's `<name>.<hash>.resume` files.
+    Transmission,
+    /// qBittorrent's (i.e. libtorrent's) `<hash>.fastresume` files.
+    Qbittorrent,
+}
+
+/// What another client's resume file says about a torrent.
+#[derive(Debug)]
+pub struct ImportedResume {
+    /// The pieces the other client had verified.
+    pub have: Bitfield,
+    /// Where the other client keeps the data, if the file said.
+    pub location: Option<PathBuf>,
+    /// Which files the other client was told to download, by file index.
+    pub wanted_files: Vec<bool>,
+}
+
+type Dict = HashMap<Vec<u8>, Value>;
+
+/// Reads a resume file written by another client for `torrent`.
+///
+/// Fields we don't need are ignored; those we need but can't make sense of are reported
+/// as warnings and skipped.
+pub fn import(
+    path: &Path,
+    format: ResumeFormat,
+    torrent: &Torrent,
+) -> anyhow::Result<ImportedResume> {
+    let file =
+        std::fs::read(path).with_context(|| format!("read resume file {}", path.display()))?;
+    let Value::Dict(dict) = serde_bencode::from_bytes(&file).context("parse resume file")? else {
+        bail!("resume file is not a bencode dictionary");
+    };
+    let info_hash = torrent.info_hash();
+    let npieces = torrent.info.pieces.0.len();
+
+    let imported = match format {
+        ResumeFormat::Transmission => {
+            // the info hash is only in the file name
+            let stem = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            let hex_hash = hex::encode(info_hash);
+            let hash_in_name = stem.rsplit('.').next().unwrap_or_default();
+            if hash_in_name.len() >= 16 && hash_in_name.bytes().all(|b| b.is_ascii_hexdigit()) {
+                if !hex_hash.starts_with(hash_in_name) {
+                    bail!(
+                        "resume file is for info hash {}, but the torrent's is {}",
+                        redact::hex_hash(hash_in_name),
+                        redact::hex_hash(&hex_hash)
+                    );
+                }
+            } else {
+                warn!(
+                    "can't tell which torrent {} belongs to, assuming {}",
+                    path.display(),
+                    redact::hex_hash(&hex_hash)
+                );
+            }
+
+            let have = match dict_get(&dict, "progress") {
+                Some(Value::Dict(progress)) => transmission_progress(progress, torrent)?,
+                _ => {
+                    warn!("resume file has no progress, assuming no pieces");
+                    Bitfield::new(npieces)
+                }
+            };
+            let location = bytes_get(&dict, "destination").map(|destination| {
+                Path::new(&*String::from_utf8_lossy(destination)).join(
+                    torrent
+                        .info
+                        .name
+                        .to_path_component(torrent.encoding.as_deref()),
+                )
+            });
+            // Transmission keeps skipped files in a "dnd" list next to their priorities
+            let wanted_files = match dict_get(&dict, "dnd") {
+                Some(Value::List(dnd)) => ints(dnd).map(|dnd| dnd == 0).collect(),
+                _ => Vec::new(),
+            };
+            ImportedResume {
+                have,
+                location,
+                wanted_files,
+            }
+        }
+        ResumeFormat::Qbittorrent => {
+            match bytes_get(&dict, "info-hash") {
+                Some(hash) if hash != info_hash => bail!(
+                    "resume file is for info hash {}, but the torrent's is {}",
+                    redact::hash(hash),
+                    redact::hash(&info_hash)
+                ),
+                Some(_) => {}
+                None => warn!("resume file has no info hash, can't check it"),
+            }
+
+            let have = match bytes_get(&dict, "pieces") {
+                Some(pieces) if pieces.len() == npieces => {
+                    let mut have = Bitfield::new(npieces);
+                    for (index, _) in pieces.iter().enumerate().filter(|(_, b)| *b & 1 != 0) {
+                        have.set_piece(index);
+                    }
+                    have
+                }
+                Some(pieces) => {
+                    warn!(
+                        "resume file lists {} pieces, but the torrent has {npieces}, \
+                         assuming no pieces",
+                        pieces.len()
+                    );
+                    Bitfield::new(npieces)
+                }
+                None if matches!(dict_get(&dict, "seed_mode"), Some(Value::Int(1))) => {
+                    warn!(
+                        "resume file is in seed mode, which claims all pieces \
+                         without checking them, consider --verify-imported"
+                    );
+                    Bitfield::full(npieces)
+                }
+                None => {
+                    warn!("resume file has no pieces, assuming none");
+                    Bitfield::new(npieces)
+                }
+            };
+            let save_path =
+                bytes_get(&dict, "qBt-savePath").or_else(|| bytes_get(&dict, "save_path"));
+            let location = save_path.map(|save_path| {
+                Path::new(&*String::from_utf8_lossy(save_path)).join(
+                    torrent
+                        .info
+                        .name
+                        .to_path_component(torrent.encoding.as_deref()),
+                )
+            });
+            // priority 0 means "don't download" to libtorrent
+            let wanted_files = match dict_get(&dict, "file_priority") {
+                Some(Value::List(priorities)) => ints(priorities).map(|p| p != 0).collect(),
+                _ => Vec::new(),
+            };
+            ImportedResume {
+                have,
+                location,
+                wanted_files,
+            }
+        }
+    };
+
+    if imported.wanted_files.contains(&false) {
+        warn!("ignoring skipped files, every file is treated alike");
+    }
+    Ok(imported)
+}
+
+/// Transmission has stored progress as `have: "all"`, a piece bitfield and a block
+/// bitfield over the years, and each of those may also be the strings `all` or `none`.
+///
+/// The bitfields are read leniently: what a file has past the last piece doesn't make the
+/// progress before it any less true.
+fn transmission_progress(progress: &Dict, torrent: &Torrent) -> anyhow::Result<Bitfield> {
+    let npieces = torrent.info.pieces.0.len();
+    if bytes_get(progress, "have") == Some(b"all") {
+        return Ok(Bitfield::full(npieces));
+    }
+    match bytes_get(progress, "pieces") {
+        Some(b"all") => return Ok(Bitfield::full(npieces)),
+        Some(b"none") => return Ok(Bitfield::new(npieces)),
+        Some(bits) => match Bitfield::from_bytes_lenient(bits, npieces) {
+            Ok(have) => return Ok(have),
+            Err(err) => warn!("ignoring progress.pieces: {err}"),
+        },
+        None => {}
+    }
+    match bytes_get(progress, "blocks") {
+        Some(b"all") => Ok(Bitfield::full(npieces)),
+        Some(b"none") => Ok(Bitfield::new(npieces)),
+        Some(bits) => {
+            let length = torrent.info.keys.total_length();
+            let nblocks = length.div_ceil(TRANSMISSION_BLOCK_SIZE);
+            let blocks = Bitfield::from_bytes_lenient(bits, nblocks)
+                .map_err(anyhow::Error::msg)
+                .context("parse progress.blocks")?;
+            let plength = torrent.info.plength;
+            let mut have = Bitfield::new(npieces);
+            for index in 0..npieces {
+                let start = index * plength / TRANSMISSION_BLOCK_SIZE;
+                let end = ((index + 1) * plength)
+                    .min(length)
+                    .div_ceil(TRANSMISSION_BLOCK_SIZE);
+                if (start..end).all(|block| blocks.has_piece(block)) {
+                    have.set_piece(index);
+                }
+            }
+            Ok(have)
+        }
+        None => {
+            warn!("resume file has no usable progress, assuming no pieces");
+            Ok(Bitfield::new(npieces))
+        }
+    }
+}
+
+/// Hashes up to `samples` of the pieces in `have`, chosen at random, to catch resume data
+/// that doesn't match what's on disk.
+///
+/// This blocks on disk I/O, so it's meant for a blocking thread. It gives up soon after
+/// `cancel` fires, checking it between pieces and between [`READ_CHUNK`]s of a piece.
+pub fn verify_sample(
+    torrent: &Torrent,
+    data_path: &Path,
+    have: &Bitfield,
+    samples: usize,
+    cancel: &CancellationToken,
+) -> anyhow::Result<()> {
+    if let Keys::MultiFile { .. } = torrent.info.keys {
+        bail!("verifying multi-file torrents is not supported yet");
+    }
+    let mut file =
+        std::fs::File::open(data_path).with_context(|| format!("open {}", data_path.display()))?;
+    let sample = have
+        .pieces()
+        .choose_multiple(&mut rand::thread_rng(), samples);
+    let mut chunk = vec![0; READ_CHUNK.min(torrent.info.plength)];
+    for &index in &sample {
+        if !piece_matches(&mut file, torrent, index, &mut chunk, cancel)? {
+            bail!(
+                "piece {index} is claimed complete but doesn't match its hash, \
+                 the resume data can't be trusted"
+            );
+        }
+    }
+    info!("spot-checked {} imported piece(s)", sample.len());
+    Ok(())
+}
+
+/// Hashes every piece of the data at `data_path`, for when there is no progress record
+/// that can be trusted.
+///
+/// Pieces that are missing from a short or absent file simply aren't present. Blocks and
+/// cancels like [`verify_sample`].
+pub fn recheck(
+    torrent: &Torrent,
+    data_path: &Path,
+    cancel: &CancellationToken,
+) -> anyhow::Result<Bitfield> {
+    if let Keys::MultiFile { .. } = torrent.info.keys {
+        bail!("re-checking multi-file torrents is not supported yet");
+    }
+    let mut have = Bitfield::new(torrent.info.pieces.0.len());
+    let mut file = match std::fs::File::open(data_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(have),
+        Err(err) => return Err(err).with_context(|| format!("open {}", data_path.display())),
+    };
+    let mut chunk = vec![0; READ_CHUNK.min(torrent.info.plength)];
+    for index in 0..have.len() {
+        match piece_matches(&mut file, torrent, index, &mut chunk, cancel) {
+            Ok(true) => have.set_piece(index),
+            Ok(false) => {}
+            Err(err)
+                if err
+                    .downcast_ref::<std::io::Error>()
+                    .map(std::io::Error::kind)
+                    == Some(std::io::ErrorKind::UnexpectedEof) =>
+            {
+                break
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    info!(
+        "re-checked {}: {} of {} pieces present",
+        data_path.display(),
+        have.count(),
+        have.len()
+    );
+    Ok(have)
+}
+
+/// Whether piece `index` in `file` matches its hash, read in chunks the size of `chunk`.
+fn piece_matches(
+    file: &mut std::fs::File,
+    torrent: &Torrent,
+    index: usize,
+    chunk: &mut [u8],
+    cancel: &CancellationToken,
+) -> anyhow::Result<bool> {
+    let plength = torrent.info.plength;
+    file.seek(SeekFrom::Start((index * plength) as u64))
+        .with_context(|| format!("seek to piece {index}"))?;
+    let mut hasher = Sha1::new();
+    let mut remaining = torrent.piece_size(index);
+    while remaining > 0 {
+        if cancel.is_cancelled() {
+            bail!("verification cancelled");
+        }
+        let chunk = &mut chunk[..remaining.min(READ_CHUNK)];
+        file.read_exact(chunk)
+            .with_context(|| format!("read piece {index}"))?;
+        hasher.update(&*chunk);
+        remaining -= chunk.len();
+    }
+    Ok(hasher.finalize().as_slice() == torrent.piece_hash(index)?)
+}
+
+fn dict_get<'a>(dict: &'a Dict, key: &str) -> Option<&'a Value> {
+    dict.get(key.as_bytes())
+}
+
+fn bytes_get<'a>(dict: &'a Dict, key: &str) -> Option<&'a [u8]> {
+    match dict_get(dict, key)? {
+        Value::Bytes(bytes) => Some(bytes),
+        _ => None,
+    }
+}
+
+/// The integers of a list, with anything that isn't an integer read as 0.
+fn ints(values: &[Value]) -> impl Iterator<Item = i64> + '_ {
+    values.iter().map(|value| match value {
+        Value::Int(int) => *int,
+        _ => 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEN: usize = 100_000;
+    const PLENGTH: usize = 1 << 15;
+
+    /// A synthetic resume file in `dir`, as the other client would write it.
+    fn resume_file(dir: &Path, name: &str, entries: Vec<(&str, Value)>) -> PathBuf {
+        let dict = entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect();
+        let path = dir.join(name);
+        std::fs::write(&path, serde_bencode::to_bytes(&Value::Dict(dict)).unwrap()).unwrap();
+        path
+    }
+
+    fn bytes(bytes: &[u8]) -> Value {
+        Value::Bytes(bytes.to_vec())
+    }
+
+    fn dict(entries: Vec<(&str, Value)>) -> Value {
+        Value::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    fn transmission_name(torrent: &Torrent) -> String {
+        format!(
+            "fixture.bin.{}.resume",
+            &hex::encode(torrent.info_hash())[..16]
+        )
+    }
+
+    fn pieces(have: &Bitfield) -> Vec<usize> {
+        have.pieces().collect()
+    }
+
+    #[test]
+    fn transmission_pieces_location_and_skipped_files() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        let path = resume_file(
+            dir.path(),
+            &transmission_name(&torrent),
+            vec![
+                ("progress", dict(vec![("pieces", bytes(&[0b1010_1111]))])),
+                ("destination", bytes(b"/srv/torrents")),
+                ("dnd", Value::List(vec![Value::Int(0), Value::Int(1)])),
+                // fields we don't use
+                ("uploaded", Value::Int(123_456)),
+                ("speed-limit-up", dict(vec![("speed-Bps", Value::Int(0))])),
+                ("labels", Value::List(vec![bytes(b"linux")])),
+            ],
+        );
+        let imported = import(&path, ResumeFormat::Transmission, &torrent).unwrap();
+        // the bits past the fourth piece are ignored
+        assert_eq!(pieces(&imported.have), [0, 2]);
+        assert_eq!(
+            imported.location,
+            Some(PathBuf::from("/srv/torrents/fixture.bin"))
+        );
+        assert_eq!(imported.wanted_files, [true, false]);
+    }
+
+    #[test]
+    fn transmission_block_progress_counts_only_whole_pieces() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        // two 16 KiB blocks per piece, and a single one for the short last piece: blocks
+        // 0 and 1 complete piece 0, block 2 is half of piece 1, block 6 is all of piece 3
+        let path = resume_file(
+            dir.path(),
+            &transmission_name(&torrent),
+            vec![("progress", dict(vec![("blocks", bytes(&[0b1110_0010]))]))],
+        );
+        let imported = import(&path, ResumeFormat::Transmission, &torrent).unwrap();
+        assert_eq!(pieces(&imported.have), [0, 3]);
+        assert_eq!(imported.location, None);
+        assert!(imported.wanted_files.is_empty());
+    }
+
+    #[test]
+    fn transmission_all_and_none_shorthands() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        let name = transmission_name(&torrent);
+        for (progress, expected) in [
+            (dict(vec![("have", bytes(b"all"))]), vec![0, 1, 2, 3]),
+            (dict(vec![("pieces", bytes(b"all"))]), vec![0, 1, 2, 3]),
+            (dict(vec![("pieces", bytes(b"none"))]), vec![]),
+            (dict(vec![("blocks", bytes(b"all"))]), vec![0, 1, 2, 3]),
+            (dict(vec![]), vec![]),
+        ] {
+            let path = resume_file(dir.path(), &name, vec![("progress", progress)]);
+            let imported = import(&path, ResumeFormat::Transmission, &torrent).unwrap();
+            assert_eq!(pieces(&imported.have), expected);
+        }
+    }
+
+    #[test]
+    fn transmission_files_for_another_torrent_are_rejected() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        let path = resume_file(
+            dir.path(),
+            "fixture.bin.0123456789abcdef.resume",
+            vec![("progress", dict(vec![("have", bytes(b"all"))]))],
+        );
+        let err = import(&path, ResumeFormat::Transmission, &torrent).unwrap_err();
+        assert!(
+            err.to_string().contains("resume file is for info hash"),
+            "{err}"
+        );
+
+        // without a hash in the name there is nothing to check
+        let path = resume_file(
+            dir.path(),
+            "fixture.bin.resume",
+            vec![("progress", dict(vec![("have", bytes(b"all"))]))],
+        );
+        let imported = import(&path, ResumeFormat::Transmission, &torrent).unwrap();
+        assert_eq!(imported.have.count(), 4);
+    }
+
+    #[test]
+    fn qbittorrent_pieces_save_path_and_priorities() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        let path = resume_file(
+            dir.path(),
+            "fixture.fastresume",
+            vec![
+                ("file-format", bytes(b"libtorrent resume file")),
+                ("info-hash", bytes(&torrent.info_hash())),
+                ("pieces", bytes(&[1, 0, 1, 0])),
+                ("save_path", bytes(b"/srv/torrents")),
+                ("qBt-savePath", bytes(b"/srv/qbt")),
+                (
+                    "file_priority",
+                    Value::List(vec![Value::Int(4), Value::Int(0)]),
+                ),
+                ("qBt-tags", Value::List(vec![bytes(b"linux")])),
+                ("trackers", Value::List(vec![Value::List(vec![])])),
+            ],
+        );
+        let imported = import(&path, ResumeFormat::Qbittorrent, &torrent).unwrap();
+        assert_eq!(pieces(&imported.have), [0, 2]);
+        // qBittorrent's own save path wins over libtorrent's
+        assert_eq!(
+            imported.location,
+            Some(PathBuf::from("/srv/qbt/fixture.bin"))
+        );
+        assert_eq!(imported.wanted_files, [true, false]);
+    }
+
+    #[test]
+    fn qbittorrent_files_for_another_torrent_are_rejected() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        let path = resume_file(
+            dir.path(),
+            "fixture.fastresume",
+            vec![
+                ("info-hash", bytes(&[0xab; 20])),
+                ("pieces", bytes(&[1, 1, 1, 1])),
+            ],
+        );
+        let err = import(&path, ResumeFormat::Qbittorrent, &torrent).unwrap_err();
+        assert!(
+            err.to_string().contains("resume file is for info hash"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn qbittorrent_seed_mode_and_mismatched_pieces() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        let path = resume_file(
+            dir.path(),
+            "fixture.fastresume",
+            vec![("seed_mode", Value::Int(1))],
+        );
+        let imported = import(&path, ResumeFormat::Qbittorrent, &torrent).unwrap();
+        assert_eq!(pieces(&imported.have), [0, 1, 2, 3]);
+
+        let path = resume_file(
+            dir.path(),
+            "fixture.fastresume",
+            vec![("pieces", bytes(&[1, 1, 1]))],
+        );
+        let imported = import(&path, ResumeFormat::Qbittorrent, &torrent).unwrap();
+        assert_eq!(imported.have.count(), 0);
+    }
+
+    #[test]
+    fn files_that_are_not_dictionaries_are_rejected() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.fastresume");
+        std::fs::write(&path, b"li1ei2ee").unwrap();
+        assert!(import(&path, ResumeFormat::Qbittorrent, &torrent).is_err());
+        std::fs::write(&path, b"not bencode").unwrap();
+        assert!(import(&path, ResumeFormat::Qbittorrent, &torrent).is_err());
+    }
+
+    #[test]
+    fn verify_sample_catches_claimed_pieces_that_are_corrupt() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        let data_path = dir.path().join("fixture.bin");
+        let mut data = Torrent::fixture_data(LEN);
+        std::fs::write(&data_path, &data).unwrap();
+        let cancel = CancellationToken::new();
+        let all = Bitfield::full(4);
+        verify_sample(&torrent, &data_path, &all, 4, &cancel).unwrap();
+
+        data[PLENGTH + 10] ^= 0xff;
+        std::fs::write(&data_path, &data).unwrap();
+        let err = verify_sample(&torrent, &data_path, &all, 4, &cancel).unwrap_err();
+        assert!(err.to_string().contains("piece 1"), "{err}");
+        // a sample that avoids the bad piece can't notice it
+        let mut others = Bitfield::new(4);
+        for index in [0, 2, 3] {
+            others.set_piece(index);
+        }
+        verify_sample(&torrent, &data_path, &others, 4, &cancel).unwrap();
+    }
+
+    #[test]
+    fn recheck_finds_the_intact_pieces_of_a_short_file() {
+        let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+        let dir = tempfile::tempdir().unwrap();
+        let data_path = dir.path().join("fixture.bin");
+        let cancel = CancellationToken::new();
+        assert_eq!(recheck(&torrent, &data_path, &cancel).unwrap().count(), 0);
+
+        let mut data = Torrent::fixture_data(LEN);
+        data[10] ^= 0xff;
+        data.truncate(3 * PLENGTH);
+        std::fs::write(&data_path, &data).unwrap();
+        let have = recheck(&torrent, &data_path, &cancel).unwrap();
+        assert_eq!(pieces(&have), [1, 2]);
+    }
+}
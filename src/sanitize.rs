@@ -0,0 +1,36 @@
+/// File names Windows refuses to create, regardless of extension.
+pub const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns a name from a torrent into a single path component that is safe to create.
+///
+/// Separators, control characters and characters Windows rejects are replaced with `_`,
+/// as are names that would escape the download directory (`.`, `..`) or that Windows reserves.
+pub fn path_component(name: &str) -> String {
+    let mut component: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows silently drops trailing dots and spaces, which can make two names collide
+    while component.ends_with(['.', ' ']) {
+        component.pop();
+    }
+    if component.is_empty() || is_reserved(&component) {
+        component.insert(0, '_');
+    }
+    component
+}
+
+/// Whether Windows reserves `name`, e.g. `NUL` or `com1.txt`.
+pub fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default();
+    WINDOWS_RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
@@ -0,0 +1,275 @@
+use crate::redact;
+use crate::tracker::{self, TrackerClient};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// What a tracker knows about one torrent's swarm.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ScrapeStats {
+    /// Peers that have the whole torrent, i.e. seeders.
+    #[serde(default)]
+    pub complete: u64,
+    /// How often the torrent was downloaded to completion, a.k.a. snatches.
+    #[serde(default)]
+    pub downloaded: u64,
+    /// Peers still downloading, i.e. leechers.
+    #[serde(default)]
+    pub incomplete: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    /// Keyed by raw info hash.
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeStats>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+/// One torrent of one poll, as `--json` prints it.
+#[derive(Debug, Serialize)]
+struct ScrapeRow<'a> {
+    unix_time: u64,
+    info_hash: &'a str,
+    name: &'a str,
+    /// `None` if the tracker didn't answer for the torrent.
+    stats: Option<ScrapeStats>,
+}
+
+/// A torrent to scrape and the tracker to ask.
+#[derive(Debug, Clone)]
+pub struct ScrapeTarget {
+    pub name: String,
+    pub info_hash: [u8; 20],
+    pub announce: String,
+}
+
+/// Derives the scrape URL from an announce URL, per the convention of BEP 48.
+///
+/// Only trackers whose announce path ends in a segment starting with `announce` support
+/// scraping.
+pub fn scrape_url(announce: &str) -> anyhow::Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(announce).context("parse tracker announce url")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        bail!("can't scrape {} trackers", url.scheme());
+    }
+    let path = url.path().to_string();
+    let (dir, last) = path.rsplit_once('/').unwrap_or(("", &path));
+    let Some(rest) = last.strip_prefix("announce") else {
+        bail!(
+            "tracker {} does not support scraping",
+            redact::url_str(announce)
+        );
+    };
+    url.set_path(&format!("{dir}/scrape{rest}"));
+    Ok(url)
+}
+
+/// Asks the tracker behind `url` about all of `info_hashes` at once.
+///
+/// Trackers may leave out torrents they don't know or limit how many they answer for, so
+/// the result can lack some of the requested hashes.
+pub async fn scrape(
+    client: &TrackerClient,
+    url: &reqwest::Url,
+    info_hashes: &[[u8; 20]],
+) -> anyhow::Result<HashMap<[u8; 20], ScrapeStats>> {
+    let mut url = url.clone();
+    let mut query = url.query().map(str::to_string).unwrap_or_default();
+    for info_hash in info_hashes {
+        if !query.is_empty() {
+            query.push('&');
+        }
+        query.push_str("info_hash=");
+        query.push_str(&tracker::urlencode_bytes(info_hash));
+    }
+    url.set_query(Some(&query));
+
+    let response = client.get(&url).await.context("fetch scrape")?;
+    let response: ScrapeResponse =
+        serde_bencode::from_bytes(&response).context("parse scrape response")?;
+    if let Some(reason) = response.failure_reason {
+        bail!("tracker refused scrape: {reason}");
+    }
+    Ok(response
+        .files
+        .into_iter()
+        .filter_map(|(info_hash, stats)| Some((info_hash.as_slice().try_into().ok()?, stats)))
+        .collect())
+}
+
+/// Scrapes `targets`, once or every `interval`, printing a row per torrent and poll, or
+/// with `json` a JSON object per line.
+///
+/// With `csv`, the rows are also appended to that file as
+/// `unix_time,info_hash,name,seeders,leechers,snatches`. Scraping once fails if a tracker
+/// couldn't be scraped, after printing what the others said.
+pub async fn watch(
+    client: &TrackerClient,
+    targets: &[ScrapeTarget],
+    interval: Option<Duration>,
+    csv: Option<&Path>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut trackers: BTreeMap<&str, Vec<&ScrapeTarget>> = BTreeMap::new();
+    for target in targets {
+        trackers.entry(&target.announce).or_default().push(target);
+    }
+    let mut csv = match csv {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("open {}", path.display()))?;
+            let mut csv = std::io::BufWriter::new(file);
+            if csv.get_ref().metadata().map(|meta| meta.len()).unwrap_or(0) == 0 {
+                writeln!(csv, "unix_time,info_hash,name,seeders,leechers,snatches")
+                    .context("write csv header")?;
+            }
+            Some(csv)
+        }
+        None => None,
+    };
+    let mut last: HashMap<[u8; 20], ScrapeStats> = HashMap::new();
+
+    if !json {
+        println!(
+            "{:<8}  {:<40}  {:>8}  {:>8}  {:>10}  name",
+            "time", "info hash", "seeders", "leechers", "snatches"
+        );
+    }
+    loop {
+        let mut failed = 0;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let time = format!(
+            "{:02}:{:02}:{:02}",
+            now / 3600 % 24,
+            now / 60 % 60,
+            now % 60
+        );
+        for (announce, targets) in &trackers {
+            let hashes: Vec<_> = targets.iter().map(|target| target.info_hash).collect();
+            let stats = match scrape_url(announce) {
+                Ok(url) => scrape(client, &url, &hashes).await,
+                Err(err) => Err(err),
+            };
+            let stats = match stats {
+                Ok(stats) => stats,
+                Err(err) => {
+                    warn!("scrape {}: {err:#}", redact::url_str(announce));
+                    failed += 1;
+                    HashMap::new()
+                }
+            };
+            for target in targets {
+                let info_hash = hex::encode(target.info_hash);
+                if json {
+                    let row = ScrapeRow {
+                        unix_time: now,
+                        info_hash: &info_hash,
+                        name: &target.name,
+                        stats: stats.get(&target.info_hash).copied(),
+                    };
+                    println!(
+                        "{}",
+                        serde_json::to_string(&row).context("serialize scrape row")?
+                    );
+                }
+                let Some(stats) = stats.get(&target.info_hash) else {
+                    if json {
+                        continue;
+                    }
+                    println!(
+                        "{time:<8}  {info_hash:<40}  {:>8}  {:>8}  {:>10}  {}",
+                        "-", "-", "-", target.name
+                    );
+                    continue;
+                };
+                let snatched = match last.insert(target.info_hash, *stats) {
+                    Some(previous) if stats.downloaded > previous.downloaded => {
+                        format!("(+{})", stats.downloaded - previous.downloaded)
+                    }
+                    _ => String::new(),
+                };
+                if !json {
+                    println!(
+                        "{time:<8}  {info_hash:<40}  {:>8}  {:>8}  {:>10}  {} {snatched}",
+                        stats.complete, stats.incomplete, stats.downloaded, target.name
+                    );
+                }
+                if let Some(csv) = &mut csv {
+                    writeln!(
+                        csv,
+                        "{now},{info_hash},{},{},{},{}",
+                        csv_field(&target.name),
+                        stats.complete,
+                        stats.incomplete,
+                        stats.downloaded
+                    )
+                    .context("write csv row")?;
+                }
+            }
+        }
+        if let Some(csv) = &mut csv {
+            csv.flush().context("write csv")?;
+        }
+        let Some(interval) = interval else {
+            if failed > 0 {
+                bail!(
+                    "{failed} of {} tracker(s) couldn't be scraped",
+                    trackers.len()
+                );
+            }
+            return Ok(());
+        };
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Quotes `field` for a CSV row if it needs it.
+pub fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrape_urls_replace_the_announce_segment() {
+        let url = |announce| scrape_url(announce).map(|url| url.to_string());
+        assert_eq!(
+            url("http://t.example/announce").unwrap(),
+            "http://t.example/scrape"
+        );
+        assert_eq!(
+            url("https://t.example/x/announce.php?passkey=abc").unwrap(),
+            "https://t.example/x/scrape.php?passkey=abc"
+        );
+        assert!(url("http://t.example/a").is_err());
+        assert!(url("http://t.example/announce/x").is_err());
+        assert!(url("udp://t.example:80/announce").is_err());
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("plain name"), "plain name");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
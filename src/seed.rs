@@ -0,0 +1,1251 @@
+use crate::add_seed;
+use crate::admission::Admission;
+use crate::choker::{Candidate, Choker, RECHOKE_INTERVAL};
+use crate::extension::{self, BencodeDict, ExtendedHandshake, UtMetadataMsg, UtPexMsg};
+use crate::files::{DataReader, FileMapper};
+use crate::info_hash::InfoHash;
+use crate::limits::Limits;
+use crate::netwatch::{self, FailureBurst, NetworkChange};
+use crate::peer::{
+    write_deadline, Bitfield, Handshake, MessageFramer, MessagePayload, MessageRequest,
+    IDLE_TIMEOUT, KEEP_ALIVE_INTERVAL,
+};
+use crate::peer_session::{self, UT_METADATA_ID, UT_PEX_ID};
+use crate::piece::{self, VerifyPolicy};
+use crate::prealloc::Preallocation;
+use crate::rate_limit::RateLimiter;
+use crate::redact;
+use crate::sidecar::{self, Debounce};
+use crate::stats::{HumanBytes, Transferred};
+use crate::torrent::Torrent;
+use anyhow::{bail, Context};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// The extensions we offer peers that speak the extension protocol.
+const SEED_EXTENSIONS: &[(&str, u8)] = &[("ut_metadata", UT_METADATA_ID), ("ut_pex", UT_PEX_ID)];
+
+/// The extensions we offer for a private torrent, whose peers come from its trackers alone
+/// (BEP 27).
+const PRIVATE_SEED_EXTENSIONS: &[(&str, u8)] = &[("ut_metadata", UT_METADATA_ID)];
+
+/// How often peers that want PEX hear which peers joined and left, at most once a minute
+/// as BEP 11 asks.
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Blocks served to one peer before the scheduler moves on to the next.
+const BLOCKS_PER_TURN: usize = 4;
+
+/// Messages buffered for one peer's socket, which bounds how far a slow reader lags.
+const OUTBOX_CAPACITY: usize = 8;
+
+/// How long to pause accepting after the listener itself failed.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How often the upload scheduler is restarted after panicking before seeding gives up.
+const MAX_UPLOADER_RESTARTS: u32 = 5;
+
+/// How long to wait before the first restart of the upload scheduler; doubles every time.
+const UPLOADER_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long a peer that connected has to send its handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A peer broke the protocol, rather than just going away.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct ProtocolViolation(&'static str);
+
+/// How often a [`PieceMapWriter`] writes its piece map at most.
+const PIECE_MAP_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The piece map format [`PieceMap::save`] writes; files with any other are not trusted.
+const PIECE_MAP_VERSION: u32 = 1;
+
+/// Which pieces of a torrent are present on disk, as stored in a piece map file.
+///
+/// The file is JSON, e.g. `{"version": 1, "checksum": "8a1f...", "info_hash": "d69f...",
+/// "have": [0, 1, 5]}`, where the checksum is the SHA-1 of the map without the first two
+/// fields. It is replaced atomically, and a file with the wrong version or checksum is
+/// ignored rather than trusted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PieceMap {
+    /// Hex-encoded info hash of the torrent the map belongs to.
+    pub info_hash: String,
+    /// Indices of the pieces that are present.
+    pub have: Vec<usize>,
+    /// The weakest verification policy any of the pieces was stored under.
+    #[serde(default)]
+    pub verify_policy: VerifyPolicy,
+    /// Those of `have` whose hash was never checked.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unverified: Vec<usize>,
+    /// How the downloaded data was allocated, so a resumed download allocates it the same
+    /// way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preallocation: Option<Preallocation>,
+}
+
+/// A piece map as stored in its file.
+#[derive(Debug, Serialize, Deserialize)]
+struct PieceMapFile<M> {
+    version: u32,
+    /// Hex-encoded SHA-1 of the JSON of `map`.
+    checksum: String,
+    #[serde(flatten)]
+    map: M,
+}
+
+/// A piece map file that can't be trusted, because it's torn or was written by something
+/// else. Its pieces have to be re-checked.
+#[derive(Debug, thiserror::Error)]
+#[error("{path} is not a usable piece map: {reason}")]
+pub struct UntrustedPieceMap {
+    path: String,
+    reason: String,
+}
+
+/// Keeps a piece map file up to date as pieces arrive, without writing it for every one.
+#[derive(Debug)]
+pub struct PieceMapWriter {
+    path: PathBuf,
+    map: PieceMap,
+    debounce: Debounce,
+}
+
+/// Serves the pieces we have of a torrent to whoever connects.
+///
+/// Peers only queue their requests; a single upload scheduler reads the blocks from disk
+/// and serves the queues round-robin, so a peer pipelining hundreds of requests can't
+/// starve the others.
+#[derive(Debug)]
+pub struct Seeder {
+    torrent: Torrent,
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    /// Where each file of the torrent is on disk.
+    data: FileMapper,
+    have: Bitfield,
+    limits: Limits,
+    /// The encoded info dict served over `ut_metadata`, if it still hashes to the torrent's
+    /// info hash.
+    metadata: Option<Vec<u8>>,
+    uploads: Mutex<UploadQueues>,
+    admission: Mutex<Admission>,
+    /// Block bytes sent to all peers so far.
+    uploaded: AtomicU64,
+    /// The upload cap, which every block served waits for.
+    rate: RateLimiter,
+    /// Signalled when a request was queued or an outbox drained.
+    work: Notify,
+    /// Signalled when networking was restarted, so the tracker learns our new address.
+    network_changed: Notify,
+}
+
+/// The request queues of all connected peers.
+#[derive(Debug)]
+struct UploadQueues {
+    peers: Vec<PeerUploads>,
+    /// Where the next round-robin turn starts looking for work.
+    cursor: usize,
+    /// Which peers are unchoked.
+    choker: Choker,
+    /// When the current round of the choker started, which peers' rates are measured over.
+    rechoked_at: Instant,
+}
+
+#[derive(Debug)]
+struct PeerUploads {
+    addr: SocketAddr,
+    requests: VecDeque<MessageRequest>,
+    /// Messages waiting to be written to the peer's socket.
+    outbox: mpsc::Sender<MessagePayload>,
+    /// Block bytes served to this peer so far.
+    served: u64,
+    /// `served` when the current round of the choker started.
+    served_at_rechoke: u64,
+    /// Whether the peer told us it wants to download.
+    interested: bool,
+    /// Whether we choke the peer; its requests are dropped while we do.
+    choked: bool,
+    /// The pieces the peer has, as told by its `Bitfield` and `Have` messages.
+    has: Bitfield,
+    /// Where the peer takes connections, from the port in its extended handshake.
+    listen: Option<SocketAddr>,
+    /// The message id the peer wants `ut_pex` messages sent with, if it does.
+    pex_id: Option<u8>,
+    /// The message id the peer wants `ut_metadata` messages sent with, if it does.
+    metadata_id: Option<u8>,
+    /// The peers it heard about from us over PEX and didn't hear dropped since.
+    pex_sent: HashSet<SocketAddr>,
+}
+
+impl PieceMap {
+    /// Reads a piece map file and turns it into the bitfield of `torrent`.
+    ///
+    /// A torn or foreign file is reported and yields `None`, so the caller can fall back to
+    /// re-checking the data.
+    pub fn load(path: &Path, torrent: &Torrent) -> anyhow::Result<Option<Bitfield>> {
+        let map = match Self::read(path, torrent) {
+            Ok(map) => map,
+            Err(err) if err.is::<UntrustedPieceMap>() => {
+                warn!("{err}");
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+        if !map.unverified.is_empty() {
+            warn!(
+                "{} piece(s) in {} were stored without a hash check \
+                 (verify policy {:?})",
+                map.unverified.len(),
+                path.display(),
+                map.verify_policy
+            );
+        }
+        let mut bitfield = Bitfield::new(torrent.info.pieces.0.len());
+        for index in map.have {
+            if index >= bitfield.len() {
+                bail!(
+                    "piece map claims piece {index}, but the torrent only has {}",
+                    bitfield.len()
+                );
+            }
+            bitfield.set_piece(index);
+        }
+        Ok(Some(bitfield))
+    }
+
+    /// Reads a piece map file of `torrent`, or starts an empty one if there is none yet or
+    /// it can't be trusted.
+    pub fn open(path: &Path, torrent: &Torrent) -> anyhow::Result<Self> {
+        let empty = || Self::from_bitfield(torrent, &Bitfield::new(torrent.info.pieces.0.len()));
+        if !path.exists() {
+            return empty();
+        }
+        match Self::read(path, torrent) {
+            Err(err) if err.is::<UntrustedPieceMap>() => {
+                warn!("{err}, starting over");
+                empty()
+            }
+            result => result,
+        }
+    }
+
+    fn read(path: &Path, torrent: &Torrent) -> anyhow::Result<Self> {
+        let file =
+            std::fs::read(path).with_context(|| format!("read piece map {}", path.display()))?;
+        let untrusted = |reason: String| UntrustedPieceMap {
+            path: path.display().to_string(),
+            reason,
+        };
+        let stored: PieceMapFile<PieceMap> = serde_json::from_slice(&file)
+            .map_err(|err| untrusted(format!("unreadable ({err})")))?;
+        if stored.version != PIECE_MAP_VERSION {
+            return Err(untrusted(format!("unknown format version {}", stored.version)).into());
+        }
+        if !stored
+            .checksum
+            .eq_ignore_ascii_case(&stored.map.checksum()?)
+        {
+            return Err(untrusted("checksum mismatch".to_string()).into());
+        }
+        let map = stored.map;
+        let info_hash = hex::encode(torrent.info_hash());
+        if !map.info_hash.eq_ignore_ascii_case(&info_hash) {
+            bail!(
+                "piece map is for info hash {}, but the torrent's is {}",
+                redact::hex_hash(&map.info_hash),
+                redact::hex_hash(&info_hash)
+            );
+        }
+        Ok(map)
+    }
+
+    fn checksum(&self) -> anyhow::Result<String> {
+        let json = serde_json::to_vec(self).context("serialize piece map")?;
+        Ok(hex::encode(piece::sha1(&json)))
+    }
+
+    /// Records that piece `index` is present, stored under `policy`.
+    pub fn insert(&mut self, index: usize, verified: bool, policy: VerifyPolicy) {
+        if !self.have.contains(&index) {
+            self.have.push(index);
+            self.have.sort_unstable();
+        }
+        self.unverified.retain(|&unverified| unverified != index);
+        if !verified {
+            self.unverified.push(index);
+            self.unverified.sort_unstable();
+        }
+        self.verify_policy = self.verify_policy.max(policy);
+    }
+
+    /// The piece map of the pieces of `torrent` that are set in `have`.
+    pub fn from_bitfield(torrent: &Torrent, have: &Bitfield) -> anyhow::Result<Self> {
+        Ok(Self {
+            info_hash: hex::encode(torrent.info_hash()),
+            have: have.pieces().collect(),
+            verify_policy: VerifyPolicy::Full,
+            unverified: Vec::new(),
+            preallocation: None,
+        })
+    }
+
+    /// Replaces the piece map file at `path` atomically.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let stored = PieceMapFile {
+            version: PIECE_MAP_VERSION,
+            checksum: self.checksum()?,
+            map: self,
+        };
+        let json = serde_json::to_vec(&stored).context("serialize piece map")?;
+        sidecar::write_atomic(path, &json)
+            .with_context(|| format!("write piece map {}", path.display()))
+    }
+}
+
+impl PieceMapWriter {
+    /// Opens the piece map file at `path` like [`PieceMap::open`].
+    pub fn open(path: PathBuf, torrent: &Torrent) -> anyhow::Result<Self> {
+        Ok(Self {
+            map: PieceMap::open(&path, torrent)?,
+            path,
+            debounce: Debounce::new(PIECE_MAP_SAVE_INTERVAL),
+        })
+    }
+
+    /// Records that piece `index` is present, writing the file if it's been a while.
+    pub fn insert(
+        &mut self,
+        index: usize,
+        verified: bool,
+        policy: VerifyPolicy,
+    ) -> anyhow::Result<()> {
+        self.map.insert(index, verified, policy);
+        if self.debounce.changed(Instant::now()) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// How the downloaded data was allocated, if that was recorded.
+    pub fn preallocation(&self) -> Option<Preallocation> {
+        self.map.preallocation
+    }
+
+    /// Records how the downloaded data was allocated.
+    pub fn preallocated(&mut self, strategy: Preallocation) -> anyhow::Result<()> {
+        if self.map.preallocation == Some(strategy) {
+            return Ok(());
+        }
+        self.map.preallocation = Some(strategy);
+        if self.debounce.changed(Instant::now()) {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes any pieces not written yet, e.g. when a download finishes or stops.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        if self.debounce.is_dirty() {
+            self.map.save(&self.path)?;
+            self.debounce.written(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+/// Binds the port we accept peers on, explaining the usual reasons this fails.
+///
+/// This has to succeed before we tell a tracker about the port.
+pub async fn bind_listener(port: u16) -> anyhow::Result<TcpListener> {
+    TcpListener::bind(("0.0.0.0", port))
+        .await
+        .map_err(|err| bind_error(port, err))
+}
+
+fn bind_error(port: u16, err: std::io::Error) -> anyhow::Error {
+    let hint = match err.kind() {
+        std::io::ErrorKind::PermissionDenied if port < 1024 => {
+            format!(
+                "port {port} requires elevated privileges, pass --port to choose one above 1023"
+            )
+        }
+        std::io::ErrorKind::AddrInUse => {
+            format!(
+                "port {port} is already in use by another process, pass --port to choose another"
+            )
+        }
+        _ => format!("listen on port {port}"),
+    };
+    anyhow::Error::new(err).context(hint)
+}
+
+impl Seeder {
+    pub fn new(
+        torrent: Torrent,
+        peer_id: [u8; 20],
+        data_path: PathBuf,
+        have: Bitfield,
+        limits: Limits,
+    ) -> anyhow::Result<Self> {
+        // a multi-file torrent's data may be with or without its name folder
+        let data = add_seed::locate(&torrent, &data_path);
+        // every peer gets our bitfield, and sends theirs
+        let limits = limits.for_pieces(have.len());
+        // fields we don't know are lost when parsing, and a peer checks what we send
+        let metadata = serde_bencode::to_bytes(&torrent.info)
+            .ok()
+            .filter(|info| piece::sha1(info) == torrent.info_hash());
+        if metadata.is_none() {
+            info!("the info dict doesn't encode back to its hash, not serving ut_metadata");
+        }
+        Ok(Self {
+            info_hash: torrent.identity(),
+            torrent,
+            peer_id,
+            data,
+            have,
+            limits,
+            metadata,
+            uploads: Mutex::new(UploadQueues {
+                peers: Vec::new(),
+                cursor: 0,
+                choker: Choker::new(limits.upload_slots),
+                rechoked_at: Instant::now(),
+            }),
+            admission: Mutex::new(Admission::new()),
+            uploaded: AtomicU64::new(0),
+            rate: RateLimiter::new(limits.max_up),
+            work: Notify::new(),
+            network_changed: Notify::new(),
+        })
+    }
+
+    pub fn torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    /// The extensions we offer peers of this torrent.
+    fn extensions(&self) -> Vec<(&'static str, u8)> {
+        let offered = if self.torrent.is_private() {
+            PRIVATE_SEED_EXTENSIONS
+        } else {
+            SEED_EXTENSIONS
+        };
+        offered
+            .iter()
+            .copied()
+            .filter(|&(name, _)| name != "ut_metadata" || self.metadata.is_some())
+            .collect()
+    }
+
+    /// The number of peers currently connected to us.
+    pub fn connected_peers(&self) -> usize {
+        self.uploads().peers.len()
+    }
+
+    /// The number of bytes of the torrent we don't have, as announced in `left`.
+    pub fn missing_bytes(&self) -> usize {
+        self.have
+            .missing_pieces()
+            .map(|index| self.torrent.piece_size(index))
+            .sum()
+    }
+
+    /// Waits until networking was restarted after the network changed.
+    pub async fn network_changed(&self) {
+        self.network_changed.notified().await;
+    }
+
+    /// The block bytes sent to peers since seeding started.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// The block bytes served to each connected peer so far, to see how fairly the upload
+    /// is shared.
+    pub fn served(&self) -> Vec<(SocketAddr, u64)> {
+        let uploads = self.uploads();
+        uploads
+            .peers
+            .iter()
+            .map(|peer| (peer.addr, peer.served))
+            .collect()
+    }
+
+    /// What to announce; seeding doesn't download anything.
+    pub fn transferred(&self) -> Transferred {
+        Transferred {
+            uploaded: self.uploaded(),
+            downloaded: 0,
+            left: self.missing_bytes(),
+        }
+    }
+
+    /// Accepts peers forever, serving each of them on its own task.
+    ///
+    /// Peer tasks are supervised: one that panics is cleaned up like one that failed, and
+    /// its address is put on cool-down. The upload scheduler is restarted if it panics, up
+    /// to [`MAX_UPLOADER_RESTARTS`] times.
+    ///
+    /// A burst of lost connections, as `burst` defines it, or SIGHUP restarts networking:
+    /// see [`Seeder::restart_networking`].
+    pub async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        mut burst: FailureBurst,
+    ) -> anyhow::Result<()> {
+        let reader = DataReader::open(self.data.clone()).await?;
+        let mut uploader = tokio::spawn(Arc::clone(&self).run_uploads(reader));
+        let mut uploader_restarts = 0;
+        // while the scheduler is down, when it's due to be restarted
+        let mut uploader_restart_at = None;
+        let mut peers = JoinSet::new();
+        let mut peer_tasks = HashMap::new();
+        let mut hangups = netwatch::hangups();
+        let mut pex =
+            tokio::time::interval_at(tokio::time::Instant::now() + PEX_INTERVAL, PEX_INTERVAL);
+        let private = self.torrent.is_private();
+        let mut rechoke = tokio::time::interval(RECHOKE_INTERVAL);
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, addr) = match accepted {
+                        Ok(accepted) => accepted,
+                        Err(err) => {
+                            // e.g. out of file descriptors, which may well pass
+                            warn!("accept failed: {err}");
+                            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    if let Err(rejection) = self.admission().admit(addr.ip()) {
+                        info!("peer {addr}: refused, {rejection}");
+                        continue;
+                    }
+                    let seeder = Arc::clone(&self);
+                    let serve = async move { seeder.serve_peer(stream, addr).await };
+                    let task = peers.spawn(serve.instrument(info_span!("peer", %addr)));
+                    peer_tasks.insert(task.id(), addr);
+                }
+                Some(joined) = peers.join_next_with_id() => {
+                    let (id, result) = match joined {
+                        Ok((id, result)) => (id, result),
+                        Err(err) => (err.id(), Err(task_failure(err))),
+                    };
+                    if let Some(addr) = peer_tasks.remove(&id) {
+                        let lost = result.as_ref().is_err_and(netwatch::is_connection_lost);
+                        self.peer_finished(addr, result);
+                        if let Some(change) = lost.then(|| burst.lost(Instant::now())).flatten() {
+                            self.restart_networking(change, &mut peers, &mut burst);
+                        }
+                    }
+                }
+                _ = pex.tick(), if !private => self.send_pex(),
+                _ = rechoke.tick() => self.rechoke(true),
+                () = netwatch::requested(&mut hangups) => {
+                    self.restart_networking(NetworkChange::Requested, &mut peers, &mut burst);
+                }
+                joined = &mut uploader, if uploader_restart_at.is_none() => {
+                    let err = match joined {
+                        Ok(never) => match never {},
+                        Err(err) => task_failure(err),
+                    };
+                    if uploader_restarts == MAX_UPLOADER_RESTARTS {
+                        return Err(err.context("upload scheduler keeps failing"));
+                    }
+                    let backoff = UPLOADER_RESTART_BACKOFF * 2u32.pow(uploader_restarts);
+                    uploader_restarts += 1;
+                    warn!("upload scheduler: {err:#}, restarting in {backoff:?}");
+                    // peers keep being accepted and reaped meanwhile
+                    uploader_restart_at = Some(tokio::time::Instant::now() + backoff);
+                }
+                () = tokio::time::sleep_until(
+                    uploader_restart_at.unwrap_or_else(tokio::time::Instant::now)
+                ), if uploader_restart_at.is_some() => {
+                    uploader_restart_at = None;
+                    uploader = self.restart_uploads().await?;
+                }
+            }
+        }
+    }
+
+    /// Cleans up after a peer task, however it ended.
+    fn peer_finished(&self, addr: SocketAddr, result: anyhow::Result<()>) {
+        let (served, has) = self.unregister(addr).unwrap_or_default();
+        // its slot may go to another peer
+        self.rechoke(false);
+        info!(
+            "peer {addr}: served {}, it had {has} of {} pieces",
+            HumanBytes(served),
+            self.have.len()
+        );
+        let violated = result.as_ref().is_err_and(|err| {
+            is_protocol_violation(err) || err.downcast_ref::<TaskPanicked>().is_some()
+        });
+        let mut admission = self.admission();
+        admission.release(addr.ip(), violated);
+        if let Err(err) = result {
+            info!("peer {addr}: {err:#}");
+        }
+        if violated {
+            info!("peer {addr}: cooling down, {}", admission.counters);
+        }
+    }
+
+    /// Drops every peer connection, which is likely dead after the network changed, and has
+    /// the tracker told our new address right away; peers find us there again.
+    ///
+    /// The listener is bound to every address, so it takes connections on the new one
+    /// as it is.
+    fn restart_networking(
+        &self,
+        change: NetworkChange,
+        peers: &mut JoinSet<anyhow::Result<()>>,
+        burst: &mut FailureBurst,
+    ) {
+        info!(
+            "network_changed, {change}, dropping {} peer connection(s)",
+            peers.len()
+        );
+        // cancelled tasks come back through the join set and are cleaned up there
+        peers.abort_all();
+        burst.reset();
+        self.network_changed.notify_one();
+    }
+
+    async fn restart_uploads(self: &Arc<Self>) -> anyhow::Result<JoinHandle<Never>> {
+        let reader = DataReader::open(self.data.clone()).await?;
+        // blocks the dead scheduler took off the queues are lost, peers time them out
+        self.work.notify_one();
+        Ok(tokio::spawn(Arc::clone(self).run_uploads(reader)))
+    }
+
+    async fn serve_peer(&self, mut stream: TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
+        let mut theirs = [0; Handshake::LEN];
+        // a peer that never says anything would hold on to its admission slot for good
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut theirs))
+            .await
+            .map_err(|_| ProtocolViolation("peer sent no handshake in time"))?
+            .context("read handshake")?;
+        let handshake = Handshake::from_bytes(&theirs)
+            .map_err(|_| ProtocolViolation("peer does not speak the BitTorrent protocol"))?;
+        if !self.info_hash.matches_wire(&handshake.info_hash) {
+            bail!(
+                "peer asked for unknown info hash {}",
+                redact::hash(&handshake.info_hash)
+            );
+        }
+        // a hybrid torrent answers to whichever of its hashes the peer used
+        let extensions = handshake.supports_extensions();
+        let reply = Handshake::new(handshake.info_hash, self.peer_id, extensions);
+        write_deadline(stream.write_all(&reply.to_bytes()))
+            .await
+            .context("write handshake")?;
+
+        let (mut sink, mut stream) =
+            tokio_util::codec::Framed::new(stream, MessageFramer::new(addr, &self.limits)).split();
+        write_deadline(sink.send(MessagePayload::Bitfield(self.have.as_bytes().to_vec())))
+            .await
+            .context("send bitfield")?;
+        if extensions {
+            let mut handshake = peer_session::our_extended_handshake(&self.extensions());
+            handshake.metadata_size = self.metadata.as_ref().map(|info| info.len() as u32);
+            let ours = MessagePayload::Extended {
+                id: 0,
+                payload: handshake.to_bencode(),
+            };
+            write_deadline(sink.send(ours))
+                .await
+                .context("send extended handshake")?;
+        }
+
+        let (outbox, mut outbox_rx) = mpsc::channel(OUTBOX_CAPACITY);
+        self.register(addr, outbox.clone());
+
+        let writer = async {
+            loop {
+                // a peer we have had nothing to send for a while gets a keep-alive
+                let message =
+                    match tokio::time::timeout(KEEP_ALIVE_INTERVAL, outbox_rx.recv()).await {
+                        Ok(Some(message)) => message,
+                        Ok(None) => return Ok(()),
+                        Err(_) => MessagePayload::KeepAlive,
+                    };
+                write_deadline(sink.send(message))
+                    .await
+                    .context("write to peer")?;
+                self.work.notify_one();
+            }
+        };
+        let reader = async {
+            loop {
+                let Ok(message) = tokio::time::timeout(IDLE_TIMEOUT, stream.next()).await else {
+                    bail!("peer sent nothing for {}s", IDLE_TIMEOUT.as_secs());
+                };
+                let Some(message) = message else {
+                    return Ok(());
+                };
+                let message = message.context("peer message was invalid")?;
+                match message {
+                    MessagePayload::Interested => self.peer_interested(addr, true),
+                    MessagePayload::NotInterested => self.peer_interested(addr, false),
+                    MessagePayload::Bitfield(_) | MessagePayload::Have(_) => {
+                        self.peer_has(addr, &message)?
+                    }
+                    // peers it knows are of no use to a seed, so its PEX messages aren't read
+                    MessagePayload::Extended { id: 0, payload } => {
+                        self.peer_extensions(addr, &payload);
+                    }
+                    MessagePayload::Extended {
+                        id: UT_METADATA_ID,
+                        payload,
+                    } => {
+                        if let Some(reply) = self.metadata_reply(addr, &payload) {
+                            outbox.send(reply).await.context("queue metadata piece")?;
+                        }
+                    }
+                    MessagePayload::Request(request) => self.enqueue(addr, request),
+                    MessagePayload::Cancel(request) => self.cancel(addr, request),
+                    _ => {}
+                }
+            }
+        };
+        tokio::select! {
+            result = writer => result,
+            result = reader => result,
+        }
+    }
+
+    fn register(&self, addr: SocketAddr, outbox: mpsc::Sender<MessagePayload>) {
+        self.uploads().peers.push(PeerUploads {
+            addr,
+            requests: VecDeque::new(),
+            outbox,
+            served: 0,
+            served_at_rechoke: 0,
+            interested: false,
+            choked: true,
+            has: Bitfield::new(self.have.len()),
+            listen: None,
+            pex_id: None,
+            metadata_id: None,
+            pex_sent: HashSet::new(),
+        });
+    }
+
+    /// Forgets a disconnected peer, returning how many bytes it was served and how many
+    /// pieces it had.
+    fn unregister(&self, addr: SocketAddr) -> Option<(u64, usize)> {
+        let mut uploads = self.uploads();
+        let at = uploads.peers.iter().position(|peer| peer.addr == addr)?;
+        let peer = uploads.peers.remove(at);
+        Some((peer.served, peer.has.count()))
+    }
+
+    /// Records whether a peer wants to download, which may well change who is unchoked.
+    fn peer_interested(&self, addr: SocketAddr, interested: bool) {
+        {
+            let mut uploads = self.uploads();
+            let Some(peer) = uploads.peers.iter_mut().find(|peer| peer.addr == addr) else {
+                return;
+            };
+            if peer.interested == interested {
+                return;
+            }
+            peer.interested = interested;
+        }
+        self.rechoke(false);
+    }
+
+    /// Picks the peers we upload to anew and tells those whose state changed. Requests a
+    /// peer had queued are dropped when it is choked.
+    ///
+    /// Rates are measured from the start of the round, which a `new_round` ends; rechoking
+    /// as peers come and go doesn't.
+    fn rechoke(&self, new_round: bool) {
+        let now = Instant::now();
+        let messages: Vec<_> = {
+            let mut uploads = self.uploads();
+            let round = now.duration_since(uploads.rechoked_at).as_secs_f64();
+            let candidates: Vec<_> = uploads
+                .peers
+                .iter()
+                .map(|peer| Candidate {
+                    addr: peer.addr,
+                    interested: peer.interested,
+                    choked: peer.choked,
+                    rate: if round > 0.0 {
+                        (peer.served - peer.served_at_rechoke) as f64 / round
+                    } else {
+                        0.0
+                    },
+                })
+                .collect();
+            let unchoked = uploads.choker.unchoked(now, &candidates);
+            if new_round {
+                uploads.rechoked_at = now;
+            }
+            uploads
+                .peers
+                .iter_mut()
+                .filter_map(|peer| {
+                    if new_round {
+                        peer.served_at_rechoke = peer.served;
+                    }
+                    let choked = !unchoked.contains(&peer.addr);
+                    if choked == peer.choked {
+                        return None;
+                    }
+                    peer.choked = choked;
+                    let message = if choked {
+                        peer.requests.clear();
+                        MessagePayload::Choke
+                    } else {
+                        MessagePayload::Unchoke
+                    };
+                    Some((peer.addr, peer.outbox.clone(), message))
+                })
+                .collect()
+        };
+        for (addr, outbox, message) in messages {
+            let state = if message == MessagePayload::Choke {
+                "choked"
+            } else {
+                "unchoked"
+            };
+            debug!("peer {addr}: {state}");
+            // a full outbox mustn't hold up accepting peers; the message may then overtake
+            // one queued by an earlier round, which the next round puts right
+            if let Err(mpsc::error::TrySendError::Full(message)) = outbox.try_send(message) {
+                tokio::spawn(async move {
+                    let _ = outbox.send(message).await;
+                });
+            }
+        }
+    }
+
+    /// Records the pieces a peer says it has, from its `Bitfield` or `Have` message.
+    fn peer_has(
+        &self,
+        addr: SocketAddr,
+        message: &MessagePayload,
+    ) -> Result<(), ProtocolViolation> {
+        let npieces = self.have.len();
+        let mut uploads = self.uploads();
+        let Some(peer) = uploads.peers.iter_mut().find(|peer| peer.addr == addr) else {
+            return Ok(());
+        };
+        let index = match message {
+            MessagePayload::Bitfield(bits) => {
+                peer.has = Bitfield::from_bytes(bits, npieces)
+                    .map_err(|_| ProtocolViolation("bitfield doesn't match the torrent"))?;
+                return Ok(());
+            }
+            &MessagePayload::Have(index) => index as usize,
+            _ => return Ok(()),
+        };
+        if index >= npieces {
+            return Err(ProtocolViolation(
+                "have for a piece the torrent doesn't have",
+            ));
+        }
+        peer.has.set_piece(index);
+        Ok(())
+    }
+
+    /// Records what the peer's extended handshake says about PEX.
+    fn peer_extensions(&self, addr: SocketAddr, payload: &[u8]) {
+        let extensions = match ExtendedHandshake::from_bencode(payload) {
+            Ok(extensions) => extensions,
+            Err(err) => {
+                info!("peer {addr}: ignoring its extended handshake: {err:#}");
+                return;
+            }
+        };
+        let mut uploads = self.uploads();
+        if let Some(peer) = uploads.peers.iter_mut().find(|peer| peer.addr == addr) {
+            peer.listen = extensions.p.map(|port| SocketAddr::new(addr.ip(), port));
+            if !self.torrent.is_private() {
+                peer.pex_id = extensions.id_of("ut_pex");
+            }
+            peer.metadata_id = extensions.id_of("ut_metadata");
+        }
+    }
+
+    /// The answer to a `ut_metadata` message from the peer at `addr`: the metadata piece it
+    /// asked for, or a reject if there is no such piece.
+    ///
+    /// Nothing answers other messages, or peers that didn't say they speak `ut_metadata`.
+    fn metadata_reply(&self, addr: SocketAddr, payload: &[u8]) -> Option<MessagePayload> {
+        let metadata = self.metadata.as_deref()?;
+        let msg = match UtMetadataMsg::from_bencode(payload) {
+            Ok(msg) => msg,
+            Err(err) => {
+                info!("peer {addr}: ignoring its ut_metadata message: {err:#}");
+                return None;
+            }
+        };
+        if msg.msg_type != UtMetadataMsg::REQUEST {
+            return None;
+        }
+        let id = self
+            .uploads()
+            .peers
+            .iter()
+            .find(|peer| peer.addr == addr)?
+            .metadata_id?;
+        Some(MessagePayload::Extended {
+            id,
+            payload: extension::metadata_response(metadata, msg.piece),
+        })
+    }
+
+    /// Tells every peer that wants PEX which of the other peers joined or left since it was
+    /// last told, at most [`MAX_PEX_PEERS`] of each; the rest follow next time.
+    ///
+    /// Only peers that told us their listen port can be passed on.
+    ///
+    /// [`MAX_PEX_PEERS`]: crate::extension::MAX_PEX_PEERS
+    fn send_pex(&self) {
+        let messages: Vec<_> = {
+            let mut uploads = self.uploads();
+            let listening: Vec<_> = uploads
+                .peers
+                .iter()
+                .filter_map(|peer| peer.listen)
+                .collect();
+            uploads
+                .peers
+                .iter_mut()
+                .filter_map(|peer| {
+                    let id = peer.pex_id?;
+                    let others: HashSet<_> = listening
+                        .iter()
+                        .copied()
+                        .filter(|&other| Some(other) != peer.listen)
+                        .collect();
+                    let added: Vec<_> = others.difference(&peer.pex_sent).copied().collect();
+                    let dropped: Vec<_> = peer.pex_sent.difference(&others).copied().collect();
+                    if added.is_empty() && dropped.is_empty() {
+                        return None;
+                    }
+                    let pex = UtPexMsg::batches(&added, &dropped).swap_remove(0);
+                    peer.pex_sent.extend(pex.added_peers());
+                    for dropped in pex.dropped_peers() {
+                        peer.pex_sent.remove(&dropped);
+                    }
+                    let message = MessagePayload::Extended {
+                        id,
+                        payload: pex.to_bencode(),
+                    };
+                    Some((peer.outbox.clone(), message))
+                })
+                .collect()
+        };
+        // a full outbox mustn't hold up accepting peers
+        for (outbox, message) in messages {
+            tokio::spawn(async move {
+                // a peer that went away doesn't need to know
+                let _ = outbox.send(message).await;
+            });
+        }
+    }
+
+    fn enqueue(&self, addr: SocketAddr, request: MessageRequest) {
+        let (index, begin, length) = (request.index(), request.begin(), request.length());
+        if let Err(reason) = self.check_request(index, begin, length) {
+            // there is no way to reject a request without the fast extension,
+            // the peer will time it out
+            debug!("peer {addr}: ignoring request {index}/{begin}/{length}: {reason}");
+            return;
+        }
+        let mut uploads = self.uploads();
+        let Some(peer) = uploads.peers.iter_mut().find(|peer| peer.addr == addr) else {
+            return;
+        };
+        if peer.choked {
+            debug!("peer {addr}: ignoring request {index}/{begin}/{length}: it is choked");
+            return;
+        }
+        if peer.requests.len() >= self.limits.max_queued_requests {
+            debug!(
+                "peer {addr}: ignoring request {index}/{begin}/{length}: \
+                 more than {} requests queued",
+                self.limits.max_queued_requests
+            );
+            return;
+        }
+        peer.requests.push_back(request);
+        self.work.notify_one();
+    }
+
+    fn cancel(&self, addr: SocketAddr, request: MessageRequest) {
+        let mut uploads = self.uploads();
+        if let Some(peer) = uploads.peers.iter_mut().find(|peer| peer.addr == addr) {
+            peer.requests.retain(|queued| *queued != request);
+        }
+    }
+
+    fn uploads(&self) -> MutexGuard<'_, UploadQueues> {
+        // a panicking peer task can't leave the queues half-updated, so poisoning is moot
+        self.uploads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn admission(&self) -> MutexGuard<'_, Admission> {
+        self.admission
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Serves queued requests forever, a few blocks per peer at a time.
+    async fn run_uploads(self: Arc<Self>, mut reader: DataReader) -> Never {
+        loop {
+            let Some((addr, outbox, requests)) = self.next_turn() else {
+                self.work.notified().await;
+                continue;
+            };
+            for request in requests {
+                let (index, begin) = (request.index(), request.begin());
+                let read =
+                    reader.read_block(index as usize, begin as usize, request.length() as usize);
+                let block = match read.await {
+                    Ok(block) => block,
+                    Err(err) => {
+                        warn!("peer {addr}: can't serve block {index}/{begin}: {err:#}");
+                        continue;
+                    }
+                };
+                self.rate.acquire(block.len()).await;
+                if outbox
+                    .send(MessagePayload::Piece {
+                        index,
+                        begin,
+                        block: block.into(),
+                    })
+                    .await
+                    .is_err()
+                {
+                    // disconnected, its queue is gone as well
+                    break;
+                }
+                self.uploaded
+                    .fetch_add(request.length() as u64, Ordering::Relaxed);
+                let mut uploads = self.uploads();
+                if let Some(peer) = uploads.peers.iter_mut().find(|peer| peer.addr == addr) {
+                    peer.served += request.length() as u64;
+                }
+            }
+        }
+    }
+
+    /// Takes up to [`BLOCKS_PER_TURN`] requests from the next peer in line that has room
+    /// in its outbox.
+    fn next_turn(
+        &self,
+    ) -> Option<(
+        SocketAddr,
+        mpsc::Sender<MessagePayload>,
+        Vec<MessageRequest>,
+    )> {
+        let mut uploads = self.uploads();
+        let npeers = uploads.peers.len();
+        for offset in 0..npeers {
+            let at = (uploads.cursor + offset) % npeers;
+            let peer = &mut uploads.peers[at];
+            let take = BLOCKS_PER_TURN
+                .min(peer.outbox.capacity())
+                .min(peer.requests.len());
+            if take == 0 {
+                continue;
+            }
+            let turn = (
+                peer.addr,
+                peer.outbox.clone(),
+                peer.requests.drain(..take).collect(),
+            );
+            uploads.cursor = at + 1;
+            return Some(turn);
+        }
+        None
+    }
+
+    fn check_request(&self, index: u32, begin: u32, length: u32) -> Result<(), String> {
+        let index = index as usize;
+        if !self.have.has_piece(index) {
+            return Err("we don't have that piece".into());
+        }
+        let max = self.limits.max_request_length;
+        if length == 0 || length > max {
+            return Err(format!("length must be in 1..={max}"));
+        }
+        if begin as usize + length as usize > self.torrent.piece_size(index) {
+            return Err("block extends past the end of the piece".into());
+        }
+        Ok(())
+    }
+}
+
+/// A supervised task panicked, or was cancelled.
+#[derive(Debug, thiserror::Error)]
+#[error("task panicked: {0}")]
+struct TaskPanicked(String);
+
+/// What [`Seeder::run_uploads`] returns, which is never.
+enum Never {}
+
+fn task_failure(err: JoinError) -> anyhow::Error {
+    if !err.is_panic() {
+        return anyhow::anyhow!("task was cancelled");
+    }
+    let panic = err.into_panic();
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    TaskPanicked(message).into()
+}
+
+/// Whether a peer connection ended because the peer misbehaved, as opposed to an I/O
+/// failure or a plain disconnect.
+fn is_protocol_violation(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<ProtocolViolation>()
+            || cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|err| err.kind() == std::io::ErrorKind::InvalidData)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_piece_map_remembers_how_the_data_was_allocated() {
+        let torrent = Torrent::fixture_single_file(100, 32);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bin.pieces");
+        let mut writer = PieceMapWriter::open(path.clone(), &torrent).unwrap();
+        assert_eq!(writer.preallocation(), None);
+        writer.preallocated(Preallocation::Full).unwrap();
+        writer.flush().unwrap();
+
+        let reopened = PieceMapWriter::open(path, &torrent).unwrap();
+        assert_eq!(reopened.preallocation(), Some(Preallocation::Full));
+    }
+
+    #[tokio::test]
+    async fn a_port_in_use_suggests_another() {
+        let taken = TcpListener::bind(("0.0.0.0", 0)).await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let err = bind_listener(port).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "port {port} is already in use by another process, pass --port to choose another"
+            )
+        );
+        assert!(err.root_cause().is::<std::io::Error>());
+    }
+
+    #[test]
+    fn a_privileged_port_asks_for_privileges_or_another_port() {
+        let denied = || std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            bind_error(443, denied()).to_string(),
+            "port 443 requires elevated privileges, pass --port to choose one above 1023"
+        );
+        // above 1023 privileges are not what's missing
+        assert_eq!(
+            bind_error(6881, denied()).to_string(),
+            "listen on port 6881"
+        );
+    }
+
+    #[test]
+    fn a_piece_map_remembers_the_weakest_policy_and_the_unchecked_pieces() {
+        let torrent = Torrent::fixture_single_file(4 * 16384, 16384);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.pieces");
+        let mut map = PieceMap::open(&path, &torrent).unwrap();
+        map.insert(0, true, VerifyPolicy::Full);
+        map.insert(2, false, VerifyPolicy::Sampled);
+        map.insert(3, true, VerifyPolicy::Sampled);
+        map.save(&path).unwrap();
+
+        let map = PieceMap::open(&path, &torrent).unwrap();
+        assert_eq!(map.have, [0, 2, 3]);
+        assert_eq!(map.unverified, [2]);
+        assert_eq!(map.verify_policy, VerifyPolicy::Sampled);
+
+        // checking a piece later takes it off the unchecked list, the policy stays
+        let mut map = map;
+        map.insert(2, true, VerifyPolicy::Full);
+        assert!(map.unverified.is_empty());
+        assert_eq!(map.verify_policy, VerifyPolicy::Sampled);
+    }
+
+    #[test]
+    fn a_torn_or_tampered_piece_map_is_not_trusted() {
+        let torrent = Torrent::fixture_single_file(4 * 16384, 16384);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.pieces");
+        let mut have = Bitfield::new(4);
+        have.set_piece(1);
+        have.set_piece(3);
+        PieceMap::from_bitfield(&torrent, &have)
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        let good = std::fs::read(&path).unwrap();
+        let loaded = PieceMap::load(&path, &torrent).unwrap().unwrap();
+        assert_eq!(loaded.pieces().collect::<Vec<_>>(), [1, 3]);
+
+        // a crash mid-write, at every length it could have stopped at
+        for len in 0..good.len() {
+            std::fs::write(&path, &good[..len]).unwrap();
+            assert!(PieceMap::load(&path, &torrent).unwrap().is_none(), "{len}");
+            let map = PieceMap::open(&path, &torrent).unwrap();
+            assert!(map.have.is_empty(), "{len}");
+        }
+
+        let json = String::from_utf8(good).unwrap();
+        let tampered = json.replace("[1,3]", "[0,1,3]");
+        assert_ne!(tampered, json);
+        let other_version = json.replace("\"version\":1", "\"version\":2");
+        assert_ne!(other_version, json);
+        for untrusted in [tampered, other_version] {
+            std::fs::write(&path, untrusted).unwrap();
+            assert!(PieceMap::load(&path, &torrent).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn a_piece_map_of_another_torrent_is_an_error() {
+        let torrent = Torrent::fixture_single_file(4 * 16384, 16384);
+        let other = Torrent::fixture_single_file(5 * 16384, 16384);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.pieces");
+        PieceMap::from_bitfield(&other, &Bitfield::new(5))
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        let err = PieceMap::load(&path, &torrent).unwrap_err();
+        assert!(err.to_string().contains("is for info hash"), "{err}");
+    }
+}
@@ -0,0 +1,196 @@
+//! When seeding has gone on long enough: after sharing a multiple of what we took, or after
+//! some time, whichever comes first.
+
+use crate::stats::HumanBytes;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// How often a running seed checks whether it reached its goal.
+pub const GOAL_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What `--seed-ratio` and `--seed-time` ask for; seeding stops at whichever is reached
+/// first, and never without either.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SeedGoal {
+    /// Stop once we uploaded this many times what we downloaded.
+    pub ratio: Option<f64>,
+    /// Stop after seeding this long.
+    pub time: Option<Duration>,
+}
+
+/// The goal that ended seeding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GoalReached {
+    Ratio { ratio: f64, uploaded: u64 },
+    Time { seeded: Duration },
+}
+
+/// Checks a [`SeedGoal`] against the running counters, reporting it reached only once.
+#[derive(Debug, Clone)]
+pub struct GoalTracker {
+    goal: SeedGoal,
+    started: Instant,
+    /// What the ratio is measured against when we downloaded nothing, e.g. for a torrent we
+    /// created: the size of the torrent, so ratio 2.0 means uploading it twice over.
+    fallback_size: u64,
+    reached: bool,
+}
+
+impl SeedGoal {
+    pub fn is_set(&self) -> bool {
+        self.ratio.is_some() || self.time.is_some()
+    }
+}
+
+impl GoalTracker {
+    /// Starts tracking `goal` for a torrent of `size` bytes, seeding since `started`.
+    pub fn new(goal: SeedGoal, size: u64, started: Instant) -> Self {
+        Self {
+            goal,
+            started,
+            fallback_size: size,
+            reached: false,
+        }
+    }
+
+    /// The goal reached at `now` with `uploaded` and `downloaded` bytes so far, the first
+    /// time one is; `None` before and ever after.
+    pub fn check(&mut self, now: Instant, uploaded: u64, downloaded: u64) -> Option<GoalReached> {
+        if self.reached {
+            return None;
+        }
+        let reached = self.reached_at(now, uploaded, downloaded);
+        self.reached = reached.is_some();
+        reached
+    }
+
+    fn reached_at(&self, now: Instant, uploaded: u64, downloaded: u64) -> Option<GoalReached> {
+        if let Some(target) = self.goal.ratio {
+            let base = if downloaded == 0 {
+                self.fallback_size
+            } else {
+                downloaded
+            };
+            // an empty torrent has nothing to share, only the time goal can end it
+            if base > 0 {
+                let ratio = uploaded as f64 / base as f64;
+                if ratio >= target {
+                    return Some(GoalReached::Ratio { ratio, uploaded });
+                }
+            }
+        }
+        let seeded = now.saturating_duration_since(self.started);
+        match self.goal.time {
+            Some(time) if seeded >= time => Some(GoalReached::Time { seeded }),
+            _ => None,
+        }
+    }
+}
+
+/// Parses `--seed-ratio`, which has to be a positive number.
+pub fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(ratio) if ratio.is_finite() && ratio > 0.0 => Ok(ratio),
+        _ => Err(format!("`{s}` is not a positive ratio")),
+    }
+}
+
+impl Display for GoalReached {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GoalReached::Ratio { ratio, uploaded } => {
+                write!(
+                    f,
+                    "ratio {ratio:.2} after uploading {}",
+                    HumanBytes(*uploaded)
+                )
+            }
+            GoalReached::Time { seeded } => {
+                write!(
+                    f,
+                    "seeded for {}",
+                    humantime::format_duration(Duration::from_secs(seeded.as_secs()))
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    #[test]
+    fn a_goal_is_reached_exactly_once() {
+        let started = Instant::now();
+        let goal = SeedGoal {
+            ratio: Some(2.0),
+            time: Some(Duration::from_secs(48 * 3600)),
+        };
+        let mut goals = GoalTracker::new(goal, 10 * MIB, started);
+        let mut reached = Vec::new();
+        // a MiB uploaded every interval, for far longer than either goal takes
+        for tick in 0..10_000u64 {
+            let now = started + GOAL_CHECK_INTERVAL * tick as u32;
+            reached.extend(goals.check(now, tick * MIB, 10 * MIB));
+        }
+        assert_eq!(
+            reached,
+            [GoalReached::Ratio {
+                ratio: 2.0,
+                uploaded: 20 * MIB
+            }]
+        );
+    }
+
+    #[test]
+    fn the_ratio_of_a_torrent_we_never_downloaded_is_against_its_size() {
+        let started = Instant::now();
+        let goal = SeedGoal {
+            ratio: Some(1.5),
+            time: None,
+        };
+        let mut goals = GoalTracker::new(goal, 4 * MIB, started);
+        assert_eq!(goals.check(started, 5 * MIB, 0), None);
+        assert_eq!(
+            goals.check(started, 6 * MIB, 0),
+            Some(GoalReached::Ratio {
+                ratio: 1.5,
+                uploaded: 6 * MIB
+            })
+        );
+    }
+
+    #[test]
+    fn an_empty_torrent_only_ends_on_the_time_goal() {
+        let started = Instant::now();
+        let goal = SeedGoal {
+            ratio: Some(1.0),
+            time: Some(Duration::from_secs(60)),
+        };
+        let mut goals = GoalTracker::new(goal, 0, started);
+        assert_eq!(goals.check(started + Duration::from_secs(59), 0, 0), None);
+        assert_eq!(
+            goals.check(started + Duration::from_secs(60), 0, 0),
+            Some(GoalReached::Time {
+                seeded: Duration::from_secs(60)
+            })
+        );
+        let mut unset = GoalTracker::new(SeedGoal::default(), 0, started);
+        assert!(!SeedGoal::default().is_set());
+        assert_eq!(
+            unset.check(started + Duration::from_secs(1 << 30), 1, 0),
+            None
+        );
+    }
+
+    #[test]
+    fn ratios_have_to_be_positive() {
+        assert_eq!(parse_ratio(" 2.5"), Ok(2.5));
+        for bad in ["0", "-1", "NaN", "inf", "two"] {
+            assert!(parse_ratio(bad).is_err(), "{bad}");
+        }
+    }
+}
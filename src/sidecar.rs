@@ -0,0 +1,104 @@
+use crate::perms;
+use anyhow::Context;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Replaces the file at `path` with `contents` so that a crash at any point leaves either
+/// the old file or the new one, never a mix.
+///
+/// The contents go to a temporary file in the same directory, which is synced and renamed
+/// over `path`; the directory is then synced so the rename itself survives a power loss.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut temp = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("create temporary file in {}", dir.display()))?;
+    // before the rename, so the file never shows up with the wrong mode
+    perms::created_file(temp.as_file())
+        .and_then(|()| temp.write_all(contents))
+        .and_then(|()| temp.as_file().sync_all())
+        .with_context(|| format!("write {}", temp.path().display()))?;
+    temp.persist(path)
+        .with_context(|| format!("replace {}", path.display()))?;
+    #[cfg(unix)]
+    std::fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("sync directory {}", dir.display()))?;
+    Ok(())
+}
+
+/// Decides when a frequently changing sidecar is due to be written: at most once per
+/// interval, so fast downloads aren't held up by fsyncs.
+#[derive(Debug, Clone)]
+pub struct Debounce {
+    interval: Duration,
+    last_write: Option<Instant>,
+    dirty: bool,
+}
+
+impl Debounce {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_write: None,
+            dirty: false,
+        }
+    }
+
+    /// Records a change at `now`, returning whether it should be written right away.
+    pub fn changed(&mut self, now: Instant) -> bool {
+        self.dirty = true;
+        self.last_write
+            .is_none_or(|last| now.duration_since(last) >= self.interval)
+    }
+
+    /// Whether there are changes that haven't been written yet.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn written(&mut self, now: Instant) {
+        self.dirty = false;
+        self.last_write = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_replaced_file_leaves_nothing_else_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        let names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["state.json"]);
+    }
+
+    #[test]
+    fn changes_are_written_at_most_once_per_interval() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut debounce = Debounce::new(Duration::from_secs(5));
+        assert!(!debounce.is_dirty());
+        // the first change is written right away
+        assert!(debounce.changed(at(0)));
+        debounce.written(at(0));
+        assert!(!debounce.is_dirty());
+        assert!(!debounce.changed(at(1)));
+        assert!(!debounce.changed(at(4)));
+        assert!(debounce.is_dirty());
+        assert!(debounce.changed(at(5)));
+        debounce.written(at(5));
+        assert!(!debounce.is_dirty());
+    }
+}
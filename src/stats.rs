@@ -0,0 +1,469 @@
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Time constant of the exponentially smoothed transfer rate.
+const RATE_SMOOTHING: Duration = Duration::from_secs(5);
+
+/// The peak rate is the highest average over windows of this length.
+const PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+/// Aggregated byte counters of a transfer.
+///
+/// Bytes move through two stages: a block that arrives from a peer is `buffered`,
+/// and once the piece it belongs to passes its hash check the whole piece becomes `verified`.
+///
+/// When only some files of a torrent are wanted, `total` and `verified` count just the
+/// bytes within them, so progress, the `left` we announce and the ETA all refer to the
+/// selection.
+#[derive(Debug, Clone)]
+pub struct TransferStats {
+    /// The number of payload bytes the transfer is expected to move.
+    pub total: usize,
+    /// Wanted bytes belonging to pieces whose hash has been checked.
+    pub verified: usize,
+    /// Bytes received but not yet verified.
+    pub buffered: usize,
+    rate: SmoothedRate,
+    started: Instant,
+    /// Every block byte received, including those that turned out useless.
+    downloaded: usize,
+    /// Every byte read off the wire, protocol messages included.
+    wire: usize,
+    /// The highest rate over a [`PEAK_WINDOW`] seen so far.
+    peak_rate: f64,
+    /// The current peak rate window and the bytes received in it.
+    window: (Instant, usize),
+    hash_failures: usize,
+    /// Bytes received in duplicate blocks or in pieces that were thrown away.
+    wasted: usize,
+    /// `Have` messages not sent because the peer already had the piece.
+    haves_suppressed: usize,
+    /// The wanted bytes of each piece, for a selective download.
+    wanted: Option<Vec<usize>>,
+    /// Bytes of every piece verified, wanted or not.
+    verified_payload: usize,
+}
+
+/// What an announce reports about a transfer so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transferred {
+    /// Payload bytes sent to peers.
+    pub uploaded: u64,
+    /// Payload bytes received and verified.
+    pub downloaded: usize,
+    /// Wanted bytes we don't have yet.
+    pub left: usize,
+}
+
+impl Transferred {
+    /// Nothing moved yet, with `left` bytes to go.
+    pub fn starting(left: usize) -> Self {
+        Self {
+            uploaded: 0,
+            downloaded: 0,
+            left,
+        }
+    }
+}
+
+/// The peers a transfer dealt with.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PeerCounts {
+    pub tried: usize,
+    pub connected: usize,
+    pub banned: usize,
+}
+
+/// What a finished transfer took, reported once at the end.
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    /// What was transferred, e.g. `Piece 3`.
+    pub what: String,
+    /// Where the data was written.
+    pub output: Option<PathBuf>,
+    pub elapsed_secs: f64,
+    /// Block bytes received, including wasted ones.
+    pub downloaded: usize,
+    /// The size of the data that was asked for.
+    pub payload: usize,
+    /// Bytes read off the wire, protocol messages included.
+    pub wire: usize,
+    /// Wire bytes beyond the payload, in percent of the payload.
+    pub overhead_pct: f64,
+    /// Average receive rate in bytes per second.
+    pub average_rate: f64,
+    /// The highest receive rate over a [`PEAK_WINDOW`], in bytes per second.
+    pub peak_rate: f64,
+    pub peers: PeerCounts,
+    pub hash_failures: usize,
+    pub wasted: usize,
+}
+
+/// A snapshot of a transfer, suitable for display.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub verified_pct: f64,
+    pub buffered_pct: f64,
+    /// Smoothed receive rate in bytes per second.
+    pub rate: f64,
+    pub eta: Option<Duration>,
+}
+
+/// How fast a consumer gets through the bytes handed to it, such as a player reading
+/// stdout; shared by whoever hands them over and the download that keeps ahead of it.
+#[derive(Debug, Clone, Default)]
+pub struct DrainRate(Arc<Mutex<Drained>>);
+
+#[derive(Debug, Default)]
+struct Drained {
+    bytes: usize,
+    /// The time spent handing them over.
+    busy: Duration,
+}
+
+impl DrainRate {
+    /// `len` bytes were handed over, which took `took`.
+    pub fn record(&self, len: usize, took: Duration) {
+        let mut drained = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        drained.bytes += len;
+        drained.busy += took;
+    }
+
+    /// Bytes per second while handing them over, infinite if that never took any time;
+    /// `None` before anything was.
+    pub fn bytes_per_sec(&self) -> Option<f64> {
+        let drained = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if drained.bytes == 0 {
+            return None;
+        }
+        Some(drained.bytes as f64 / drained.busy.as_secs_f64())
+    }
+}
+
+/// Exponentially weighted moving average of a byte rate.
+#[derive(Debug, Clone)]
+struct SmoothedRate {
+    last_sample: Instant,
+    bytes_per_sec: f64,
+}
+
+impl TransferStats {
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            verified: 0,
+            buffered: 0,
+            rate: SmoothedRate::new(),
+            started: Instant::now(),
+            downloaded: 0,
+            wire: 0,
+            peak_rate: 0.0,
+            window: (Instant::now(), 0),
+            hash_failures: 0,
+            wasted: 0,
+            haves_suppressed: 0,
+            wanted: None,
+            verified_payload: 0,
+        }
+    }
+
+    /// Stats of a download of the bytes in `wanted`, the part of each piece that lies
+    /// within the selected files.
+    pub fn selective(wanted: Vec<usize>) -> Self {
+        let total = wanted.iter().sum();
+        Self {
+            wanted: Some(wanted),
+            ..Self::new(total)
+        }
+    }
+
+    /// Wanted bytes not verified yet, the `left` of an announce.
+    pub fn left(&self) -> usize {
+        self.total.saturating_sub(self.verified)
+    }
+
+    /// Payload bytes verified so far, the `downloaded` of an announce.
+    ///
+    /// Unlike the summary's download count, this leaves out blocks that were thrown away.
+    pub fn verified_payload(&self) -> usize {
+        self.verified_payload
+    }
+
+    /// What to announce; downloads don't upload anything.
+    pub fn transferred(&self) -> Transferred {
+        Transferred {
+            uploaded: 0,
+            downloaded: self.verified_payload(),
+            left: self.left(),
+        }
+    }
+
+    /// A block of `len` bytes arrived and is waiting for its piece to complete.
+    pub fn record_received(&mut self, len: usize) {
+        self.buffered += len;
+        self.downloaded += len;
+        self.rate.update(len);
+        self.window.1 += len;
+        if self.window.0.elapsed() >= PEAK_WINDOW {
+            self.peak_rate = self.peak_rate.max(self.window_rate());
+            self.window = (Instant::now(), 0);
+        }
+    }
+
+    /// A block of `len` bytes arrived that we already had.
+    pub fn record_duplicate(&mut self, len: usize) {
+        self.downloaded += len;
+        self.wasted += len;
+    }
+
+    /// `len` bytes were read off the wire, whatever they carried.
+    pub fn record_wire(&mut self, len: usize) {
+        self.wire += len;
+    }
+
+    /// Piece `index` of `len` bytes passed its hash check.
+    pub fn record_verified(&mut self, index: usize, len: usize) {
+        self.buffered = self.buffered.saturating_sub(len);
+        self.verified += self
+            .wanted
+            .as_ref()
+            .map_or(len, |wanted| wanted.get(index).copied().unwrap_or(0));
+        self.verified_payload += len;
+    }
+
+    /// A piece of `len` bytes failed its hash check and its blocks were thrown away.
+    pub fn record_hash_failure(&mut self, len: usize) {
+        self.hash_failures += 1;
+        self.record_discarded(len);
+    }
+
+    /// `len` bytes of a piece were thrown away.
+    pub fn record_discarded(&mut self, len: usize) {
+        self.buffered = self.buffered.saturating_sub(len);
+        self.wasted += len;
+    }
+
+    /// A `Have` wasn't sent, as the peer has the piece itself.
+    pub fn record_have_suppressed(&mut self) {
+        self.haves_suppressed += 1;
+    }
+
+    /// How many `Have` messages were left out so far.
+    pub fn haves_suppressed(&self) -> usize {
+        self.haves_suppressed
+    }
+
+    fn window_rate(&self) -> f64 {
+        let secs = self.window.0.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            self.window.1 as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Sums the transfer up, normally once it's done.
+    pub fn summary(&self, what: String, output: Option<PathBuf>, peers: PeerCounts) -> Summary {
+        let elapsed_secs = self.started.elapsed().as_secs_f64();
+        Summary {
+            what,
+            output,
+            elapsed_secs,
+            downloaded: self.downloaded,
+            payload: self.total,
+            wire: self.wire,
+            overhead_pct: if self.total == 0 {
+                0.0
+            } else {
+                self.wire.saturating_sub(self.total) as f64 * 100.0 / self.total as f64
+            },
+            average_rate: if elapsed_secs > 0.0 {
+                self.downloaded as f64 / elapsed_secs
+            } else {
+                0.0
+            },
+            // a transfer shorter than a window still has a peak
+            peak_rate: self.peak_rate.max(self.window_rate()),
+            peers,
+            hash_failures: self.hash_failures,
+            wasted: self.wasted,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        let pct = |bytes: usize| {
+            if self.total == 0 {
+                100.0
+            } else {
+                bytes as f64 * 100.0 / self.total as f64
+            }
+        };
+        let rate = self.rate.bytes_per_sec;
+        // buffered bytes still have to be verified, but they no longer have to be fetched
+        let remaining = self.total.saturating_sub(self.verified + self.buffered);
+        let eta = if remaining == 0 {
+            Some(Duration::ZERO)
+        } else if rate > 0.0 {
+            Some(Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            None
+        };
+        Progress {
+            verified_pct: pct(self.verified),
+            buffered_pct: pct(self.buffered),
+            rate,
+            eta,
+        }
+    }
+}
+
+impl SmoothedRate {
+    fn new() -> Self {
+        Self {
+            last_sample: Instant::now(),
+            bytes_per_sec: 0.0,
+        }
+    }
+
+    fn update(&mut self, len: usize) {
+        let now = Instant::now();
+        let dt = now.duration_since(self.last_sample).as_secs_f64();
+        self.last_sample = now;
+        if dt <= 0.0 {
+            return;
+        }
+        let instant_rate = len as f64 / dt;
+        // weight the new sample by how much time it covers, so bursts of tiny intervals
+        // don't swing the estimate around
+        let alpha = 1.0 - (-dt / RATE_SMOOTHING.as_secs_f64()).exp();
+        self.bytes_per_sec += alpha * (instant_rate - self.bytes_per_sec);
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.1}% verified", self.verified_pct)?;
+        if self.buffered_pct > 0.0 {
+            write!(f, " (+{:.1}% buffered)", self.buffered_pct)?;
+        }
+        write!(f, ", {}/s", HumanBytes(self.rate as u64))?;
+        match self.eta {
+            Some(eta) => write!(f, ", ETA {}s", eta.as_secs()),
+            None => write!(f, ", ETA unknown"),
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.output {
+            Some(output) => writeln!(f, "{} downloaded to {}.", self.what, output.display())?,
+            None => writeln!(f, "{} downloaded.", self.what)?,
+        }
+        writeln!(f, "  elapsed        {:.1}s", self.elapsed_secs)?;
+        writeln!(
+            f,
+            "  downloaded     {} for {} of payload ({:.1}% overhead)",
+            HumanBytes(self.wire as u64),
+            HumanBytes(self.payload as u64),
+            self.overhead_pct
+        )?;
+        writeln!(
+            f,
+            "  rate           {}/s average, {}/s peak",
+            HumanBytes(self.average_rate as u64),
+            HumanBytes(self.peak_rate as u64)
+        )?;
+        writeln!(
+            f,
+            "  peers          {} tried, {} connected, {} banned",
+            self.peers.tried, self.peers.connected, self.peers.banned
+        )?;
+        write!(
+            f,
+            "  hash failures  {}, {} wasted",
+            self.hash_failures,
+            HumanBytes(self.wasted as u64)
+        )
+    }
+}
+
+/// Renders a byte count with a binary unit suffix, e.g. `1.50 MiB`.
+pub struct HumanBytes(pub u64);
+
+impl Display for HumanBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} {}", self.0, UNITS[0])
+        } else {
+            write!(f, "{:.2} {}", value, UNITS[unit])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_shows_buffered_bytes_only_while_there_are_any() {
+        let mut stats = TransferStats::new(1000);
+        stats.record_received(250);
+        let shown = stats.progress().to_string();
+        assert!(
+            shown.starts_with("0.0% verified (+25.0% buffered)"),
+            "{shown}"
+        );
+        stats.record_verified(0, 250);
+        let shown = stats.progress().to_string();
+        assert!(shown.starts_with("25.0% verified, "), "{shown}");
+    }
+
+    #[test]
+    fn a_drain_rate_counts_only_the_time_spent_handing_over() {
+        let drain = DrainRate::default();
+        assert_eq!(drain.bytes_per_sec(), None);
+        drain.record(1000, Duration::ZERO);
+        assert_eq!(drain.bytes_per_sec(), Some(f64::INFINITY));
+        drain.clone().record(3000, Duration::from_secs(2));
+        assert_eq!(drain.bytes_per_sec(), Some(2000.0));
+    }
+
+    #[test]
+    fn selective_downloads_count_only_wanted_bytes() {
+        let mut stats = TransferStats::selective(vec![100, 0, 40]);
+        assert_eq!(stats.total, 140);
+        stats.record_received(100);
+        stats.record_verified(1, 100);
+        assert_eq!(stats.verified, 0);
+        assert_eq!(stats.verified_payload(), 100);
+        stats.record_received(100);
+        stats.record_verified(2, 100);
+        assert_eq!(stats.left(), 100);
+        assert_eq!(
+            stats.transferred(),
+            Transferred {
+                uploaded: 0,
+                downloaded: 200,
+                left: 100
+            }
+        );
+    }
+
+    #[test]
+    fn human_bytes_picks_a_binary_unit() {
+        assert_eq!(HumanBytes(1023).to_string(), "1023 B");
+        assert_eq!(HumanBytes(1536).to_string(), "1.50 KiB");
+        assert_eq!(HumanBytes(3 << 30).to_string(), "3.00 GiB");
+    }
+}
@@ -0,0 +1,56 @@
+//! The data of a download written to stdout in order while it downloads, for a player
+//! reading from a pipe.
+
+use anyhow::Context;
+use bittorrent_starter_rust::stats::DrainRate;
+use std::io::Write;
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// The pieces waiting for stdout before the download has to wait for it too.
+const BACKLOG: usize = 8;
+
+/// Writes what it's given to stdout on a thread of its own, timing how fast stdout takes
+/// it.
+#[derive(Debug)]
+pub struct StdoutStream {
+    sender: SyncSender<Vec<u8>>,
+    writer: JoinHandle<std::io::Result<()>>,
+}
+
+impl StdoutStream {
+    /// Starts the writer, which records in `drain` how fast stdout drains.
+    pub fn start(drain: DrainRate) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(BACKLOG);
+        let writer = std::thread::spawn(move || {
+            let mut stdout = std::io::stdout().lock();
+            for data in receiver {
+                let started = Instant::now();
+                stdout.write_all(&data)?;
+                stdout.flush()?;
+                drain.record(data.len(), started.elapsed());
+            }
+            Ok(())
+        });
+        Self { sender, writer }
+    }
+
+    /// Queues `data` for stdout, waiting while [`BACKLOG`] pieces are ahead of it.
+    ///
+    /// Once stdout is gone, e.g. the player quit, the data is dropped; [`finish`] tells why.
+    ///
+    /// [`finish`]: StdoutStream::finish
+    pub fn send(&self, data: &[u8]) {
+        let _ = self.sender.send(data.to_vec());
+    }
+
+    /// Waits for everything queued to be written.
+    pub fn finish(self) -> anyhow::Result<()> {
+        drop(self.sender);
+        self.writer
+            .join()
+            .map_err(|_| anyhow::anyhow!("the stdout writer panicked"))?
+            .context("write to stdout")
+    }
+}
@@ -1,24 +1,342 @@
+use crate::bstring::BencodeString;
 use crate::hashes;
-use anyhow::Context;
+use crate::info_hash::InfoHash;
+use crate::layout;
+use crate::redact;
+use crate::torrent_ref::TorrentRef;
+use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use std::path::PathBuf;
+use std::sync::OnceLock;
 
 /// Metainfo files (also known as .torrent files) are bencoded dictionaries
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Torrent {
     /// The URL of the tracker.
     pub announce: String,
+    /// Tiers of backup trackers (BEP 12).
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    /// When the torrent was created, in seconds since the UNIX epoch.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    /// Name and version of the program that created the torrent.
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    /// The character encoding of the strings in `info` when they aren't UTF-8, e.g. `GBK`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
     pub info: Info,
+    /// The hash of `info`, set by [`Torrent::from_bytes`] or on first use.
+    #[serde(skip)]
+    info_hash: OnceLock<[u8; 20]>,
+}
+
+/// Announce URLs are redacted, they often carry a passkey.
+impl std::fmt::Debug for Torrent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let announce_list = self.announce_list.as_ref().map(|tiers| {
+            tiers
+                .iter()
+                .map(|tier| tier.iter().map(|url| redact::url_str(url)).collect())
+                .collect::<Vec<Vec<_>>>()
+        });
+        f.debug_struct("Torrent")
+            .field("announce", &redact::url_str(&self.announce))
+            .field("announce_list", &announce_list)
+            .field("creation_date", &self.creation_date)
+            .field("created_by", &self.created_by)
+            .field("encoding", &self.encoding)
+            .field("info", &self.info)
+            .finish()
+    }
 }
 
 impl Torrent {
-    pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
-        let info_encoded = serde_bencode::to_bytes(&self.info).context("re-encode info dict")?;
+    /// A torrent of `info` with `announce` as its tracker and nothing else, e.g. for an info
+    /// dict fetched from peers.
+    pub fn new(announce: String, info: Info) -> Self {
+        Self {
+            announce,
+            announce_list: None,
+            creation_date: None,
+            created_by: None,
+            encoding: None,
+            info,
+            info_hash: OnceLock::new(),
+        }
+    }
+
+    /// Parses a torrent file, hashing its info dict as it is in `bytes`, which keeps the
+    /// info hash right even if the dict has keys we don't read or encodes unusually.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let torrent: Self = serde_bencode::from_bytes(bytes).context("parse torrent file")?;
+        let info_hash = TorrentRef::parse(bytes)?.info_hash();
+        Ok(Self {
+            info_hash: OnceLock::from(info_hash),
+            ..torrent
+        })
+    }
+
+    /// Private torrents (BEP 27) may only get peers from their trackers.
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    /// The size of piece `index`: the piece length, except for the last piece, which holds
+    /// whatever is left, and pieces past the end, which are empty.
+    pub fn piece_size(&self, index: usize) -> usize {
+        layout::piece_size(self.info.keys.total_length(), self.info.plength, index)
+    }
+
+    /// Where the byte at `offset` into the torrent's data lies, with pieces split into
+    /// blocks of `block_size`.
+    pub fn locate(&self, offset: usize, block_size: usize) -> anyhow::Result<ByteLocation> {
+        let lengths = self.file_lengths();
+        let total: usize = lengths.iter().sum();
+        if offset >= total {
+            bail!("offset {offset} is past the end of the torrent's {total} bytes");
+        }
+        let mut file_start = 0;
+        let mut file = 0;
+        for (index, &length) in lengths.iter().enumerate() {
+            if offset < file_start + length {
+                file = index;
+                break;
+            }
+            file_start += length;
+        }
+        let piece_offset = offset % self.info.plength;
+        Ok(ByteLocation {
+            offset,
+            file,
+            file_offset: offset - file_start,
+            piece: offset / self.info.plength,
+            piece_offset,
+            block: piece_offset / block_size,
+            block_offset: piece_offset % block_size,
+        })
+    }
+
+    /// The offset into the torrent's data of the byte at `offset` into file `file`.
+    pub fn file_offset_to_absolute(&self, file: usize, offset: usize) -> anyhow::Result<usize> {
+        let lengths = self.file_lengths();
+        let Some(&length) = lengths.get(file) else {
+            bail!("there is no file {file}, the torrent has {}", lengths.len());
+        };
+        if offset >= length {
+            bail!("offset {offset} is past the end of file {file}'s {length} bytes");
+        }
+        Ok(lengths[..file].iter().sum::<usize>() + offset)
+    }
+
+    /// The path of file `index` relative to the download directory, e.g. `name/dir/file`.
+    pub fn file_path(&self, index: usize) -> Option<PathBuf> {
+        let encoding = self.encoding.as_deref();
+        let mut path = PathBuf::from(self.info.name.to_path_component(encoding));
+        match &self.info.keys {
+            Keys::SingleFile { .. } if index == 0 => {}
+            Keys::SingleFile { .. } => return None,
+            Keys::MultiFile { files } => path.extend(
+                files
+                    .get(index)?
+                    .path
+                    .iter()
+                    .map(|component| component.to_path_component(encoding)),
+            ),
+        }
+        Some(path)
+    }
+
+    /// Which torrent this is; we only read v1 metainfo so far.
+    pub fn identity(&self) -> InfoHash {
+        InfoHash::V1(self.info_hash())
+    }
+
+    /// The SHA-1 of the info dict as parsed by [`Torrent::from_bytes`], or else of its
+    /// encoding; computed once, so later changes to `info` don't change it.
+    pub fn info_hash(&self) -> [u8; 20] {
+        *self.info_hash.get_or_init(|| {
+            // strings, integers, lists and dicts always encode
+            let info_encoded = serde_bencode::to_bytes(&self.info).expect("info dict encodes");
+            Sha1::digest(&info_encoded).into()
+        })
+    }
+}
+
+/// What `info --json` shows of a torrent, shaped for scripts rather than like the bencode
+/// [`Torrent`] serializes to.
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentSummary {
+    pub announce: String,
+    pub name: String,
+    /// The v1 info hash in hex, or the v2 one if there is no v1 hash.
+    pub info_hash: String,
+    pub length: usize,
+    pub piece_length: usize,
+    pub piece_count: usize,
+    /// The SHA-1 of each piece in hex.
+    pub pieces: Vec<String>,
+    /// The files of a multi-file torrent; `null` for a single-file one.
+    pub files: Option<Vec<FileSummary>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileSummary {
+    /// The directories and the file name, below the torrent's own directory.
+    pub path: Vec<String>,
+    pub length: usize,
+}
+
+impl TorrentSummary {
+    pub fn new(torrent: &Torrent, identity: &InfoHash) -> Self {
+        let encoding = torrent.encoding.as_deref();
+        let files = match &torrent.info.keys {
+            Keys::SingleFile { .. } => None,
+            Keys::MultiFile { files } => Some(
+                files
+                    .iter()
+                    .map(|file| FileSummary {
+                        path: file
+                            .path
+                            .iter()
+                            .map(|component| component.decode(encoding))
+                            .collect(),
+                        length: file.length,
+                    })
+                    .collect(),
+            ),
+        };
+        let info_hash = match (identity.v1(), identity.v2()) {
+            (Some(v1), _) => hex::encode(v1),
+            (None, Some(v2)) => hex::encode(v2),
+            (None, None) => unreachable!("an info hash is v1, v2 or both"),
+        };
+        Self {
+            announce: torrent.announce.clone(),
+            name: torrent.info.name.decode(encoding),
+            info_hash,
+            length: torrent.info.keys.total_length(),
+            piece_length: torrent.info.plength,
+            piece_count: torrent.info.pieces.0.len(),
+            pieces: torrent.info.pieces.0.iter().map(hex::encode).collect(),
+            files,
+        }
+    }
+}
+
+/// What validation and the piece math need to know of a torrent, so a parsed [`Torrent`]
+/// and a borrowed [`TorrentRef`](crate::torrent_ref::TorrentRef) share them.
+pub trait Metainfo {
+    fn piece_length(&self) -> usize;
+
+    /// The hash of piece `index`, if the hashes go that far.
+    fn hash(&self, index: usize) -> Option<&[u8; 20]>;
+
+    fn hash_count(&self) -> usize;
+
+    /// The lengths of the files in the torrent, in order; a single file for single-file ones.
+    fn file_lengths(&self) -> Vec<usize>;
+
+    /// The length of the first file whose path has no components, if any.
+    fn file_without_path(&self) -> Option<usize>;
+
+    /// Checks that the metainfo is internally consistent, i.e. that it can actually be downloaded.
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.piece_length() == 0 {
+            bail!("piece length is zero");
+        }
+        // single-file torrents always have their one file
+        if self.file_lengths().is_empty() {
+            bail!("multi-file torrent without files");
+        }
+        if let Some(length) = self.file_without_path() {
+            bail!("file of length {length} has an empty path");
+        }
+        let npieces = self.declared_pieces();
+        let nhashes = self.hash_count();
+        if npieces != nhashes {
+            bail!("{npieces} pieces declared by length/piece-length but {nhashes} hashes present");
+        }
+        Ok(())
+    }
+
+    /// The number of pieces the data's length and the piece length make, which
+    /// [`validate`](Self::validate) checks against the number of piece hashes.
+    fn declared_pieces(&self) -> usize {
+        match self.piece_length() {
+            0 => 0,
+            plength => self.file_lengths().iter().sum::<usize>().div_ceil(plength),
+        }
+    }
+
+    /// The hash of piece `index`.
+    ///
+    /// Fails instead of panicking if the hashes stop short of a piece that the length
+    /// declares, for torrents that were never validated.
+    fn piece_hash(&self, index: usize) -> Result<&[u8; 20], hashes::MissingHashes> {
+        self.hash(index).ok_or(hashes::MissingHashes {
+            declared: self.declared_pieces().max(index + 1),
+            present: self.hash_count(),
+        })
+    }
+}
+
+impl Metainfo for Torrent {
+    fn piece_length(&self) -> usize {
+        self.info.plength
+    }
+
+    fn hash(&self, index: usize) -> Option<&[u8; 20]> {
+        self.info.pieces.0.get(index)
+    }
+
+    fn hash_count(&self) -> usize {
+        self.info.pieces.0.len()
+    }
 
-        let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        Ok(hasher.finalize().into())
+    fn file_lengths(&self) -> Vec<usize> {
+        match &self.info.keys {
+            Keys::SingleFile { length } => vec![*length],
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).collect(),
+        }
     }
+
+    fn file_without_path(&self) -> Option<usize> {
+        match &self.info.keys {
+            Keys::SingleFile { .. } => None,
+            Keys::MultiFile { files } => files
+                .iter()
+                .find(|file| file.path.is_empty())
+                .map(|file| file.length),
+        }
+    }
+}
+
+/// Where one byte of a torrent's data lies, see [`Torrent::locate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteLocation {
+    /// The offset into the torrent's data, i.e. into all files one after another.
+    pub offset: usize,
+    pub file: usize,
+    pub file_offset: usize,
+    pub piece: usize,
+    pub piece_offset: usize,
+    pub block: usize,
+    pub block_offset: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,7 +345,7 @@ pub struct Info {
     ///
     /// In the single file case, the name key is the name of a file,
     /// In the multiple file case, it's the name of a directory.
-    pub name: String,
+    pub name: BencodeString,
 
     /// The number of bytes in each piece the file is split into.
     ///
@@ -39,6 +357,10 @@ pub struct Info {
     /// Each entry of `pieces` is the SHA1 hash of the piece at the corresponding index.
     pub pieces: hashes::Hashes,
 
+    /// If set to 1, peers may only be obtained from the trackers in the metainfo.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
     #[serde(flatten)]
     pub keys: Keys,
 }
@@ -60,12 +382,11 @@ pub enum Keys {
 }
 
 impl Keys {
-    pub fn length(&self) -> usize {
+    /// The length of the torrent's data, i.e. of all its files one after another.
+    pub fn total_length(&self) -> usize {
         match self {
             Keys::SingleFile { length } => *length,
-            Keys::MultiFile { .. } => {
-                todo!()
-            }
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
         }
     }
 }
@@ -73,8 +394,248 @@ impl Keys {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TorrentFile {
     /// The length of the file in bytes.
-    length: usize,
+    pub length: usize,
     /// Subdirectory names for this file, the last of which is the actual file name
     /// (a zero length list is an error case).
-    path: Vec<String>,
+    pub path: Vec<BencodeString>,
+    /// File attributes (BEP 47), `p` marks a padding file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl TorrentFile {
+    pub fn new(length: usize, path: Vec<BencodeString>, attr: Option<String>) -> Self {
+        Self { length, path, attr }
+    }
+
+    /// Whether this is a BEP 47 padding file, which only exists to align the next file.
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{fixture_data, TorrentBuilder};
+
+    #[test]
+    fn a_hash_missing_from_the_table_is_an_error_not_a_panic() {
+        let torrent = Torrent::fixture_truncated_pieces(100_000, 1 << 15);
+        assert!(torrent.piece_hash(2).is_ok());
+        let err = torrent.piece_hash(3).unwrap_err();
+        assert_eq!(
+            err,
+            hashes::MissingHashes {
+                declared: 4,
+                present: 3
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "torrent metadata inconsistent: 4 pieces declared by length/piece-length but only 3 \
+             hashes present"
+        );
+        assert_eq!(
+            torrent.validate().unwrap_err().to_string(),
+            "4 pieces declared by length/piece-length but 3 hashes present"
+        );
+    }
+
+    #[test]
+    fn the_sample_torrent_hashes_to_its_known_info_hash() {
+        let torrent = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        assert_eq!(
+            hex::encode(torrent.info_hash()),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+        // re-encoding gives the same bytes for an info dict of only the keys we know
+        let rebuilt = Torrent::new(torrent.announce.clone(), torrent.info.clone());
+        assert_eq!(rebuilt.info_hash(), torrent.info_hash());
+    }
+
+    #[test]
+    fn keys_we_do_not_know_like_source_count_toward_the_hash() {
+        let info = [
+            &b"d6:lengthi3e4:name1:x12:piece lengthi16384e6:pieces20:"[..],
+            &[0xab; 20],
+            b"6:source3:abce",
+        ]
+        .concat();
+        let bytes = [&b"d8:announce15:http://tracker/4:info"[..], &info, b"e"].concat();
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(torrent.info_hash(), <[u8; 20]>::from(Sha1::digest(&info)));
+        let rebuilt = Torrent::new(torrent.announce.clone(), torrent.info.clone());
+        assert_ne!(rebuilt.info_hash(), torrent.info_hash());
+    }
+
+    const BLOCK: usize = 1 << 14;
+
+    /// The size of every piece of `torrent`, and of the one past its end.
+    fn piece_sizes(torrent: &Torrent) -> Vec<usize> {
+        (0..=torrent.declared_pieces())
+            .map(|index| torrent.piece_size(index))
+            .collect()
+    }
+
+    /// The bytes the blocks of every piece of `torrent` add up to.
+    fn block_lengths(torrent: &Torrent) -> usize {
+        (0..torrent.declared_pieces())
+            .flat_map(|index| layout::block_layout(torrent.piece_size(index), BLOCK))
+            .map(|(_, length)| length as usize)
+            .sum()
+    }
+
+    #[test]
+    fn a_length_that_is_a_multiple_of_the_piece_length_has_no_short_piece() {
+        let torrent = Torrent::fixture_single_file(4 * 2 * BLOCK, 2 * BLOCK);
+        assert_eq!(torrent.declared_pieces(), 4);
+        assert_eq!(
+            piece_sizes(&torrent),
+            [2 * BLOCK, 2 * BLOCK, 2 * BLOCK, 2 * BLOCK, 0]
+        );
+        assert_eq!(layout::block_count(torrent.piece_size(3), BLOCK), 2);
+        assert_eq!(block_lengths(&torrent), 4 * 2 * BLOCK);
+    }
+
+    #[test]
+    fn a_final_piece_smaller_than_a_block_is_a_single_short_block() {
+        let torrent = Torrent::fixture_single_file(2 * 2 * BLOCK + 100, 2 * BLOCK);
+        assert_eq!(torrent.declared_pieces(), 3);
+        assert_eq!(piece_sizes(&torrent), [2 * BLOCK, 2 * BLOCK, 100, 0]);
+        let last: Vec<_> = layout::block_layout(torrent.piece_size(2), BLOCK).collect();
+        assert_eq!(last, [(0, 100)]);
+        // a torrent smaller than one block is that one piece
+        let tiny = Torrent::fixture_single_file(10, 2 * BLOCK);
+        assert_eq!(piece_sizes(&tiny), [10, 0]);
+    }
+
+    #[test]
+    fn a_piece_length_off_the_block_size_ends_every_piece_in_a_short_block() {
+        let plength = 20_000;
+        let torrent = Torrent::fixture_single_file(3 * plength + 5_000, plength);
+        assert_eq!(piece_sizes(&torrent), [plength, plength, plength, 5_000, 0]);
+        let first: Vec<_> = layout::block_layout(torrent.piece_size(0), BLOCK).collect();
+        assert_eq!(
+            first,
+            [(0, BLOCK as u32), (BLOCK as u32, 20_000 - BLOCK as u32)]
+        );
+        assert_eq!(block_lengths(&torrent), 3 * plength + 5_000);
+    }
+
+    #[test]
+    fn the_pieces_of_a_multi_file_torrent_span_all_of_its_files() {
+        assert_eq!(piece_sizes(&torrent()), [64, 64, 52, 0]);
+    }
+
+    /// Files of 100, 0, 50 and 30 bytes in 64-byte pieces.
+    fn torrent() -> Torrent {
+        let files = [("a", 100), ("empty", 0), ("b", 50), ("c", 30)]
+            .iter()
+            .map(|&(name, length)| (vec![name.to_string()], length))
+            .collect();
+        TorrentBuilder::multi_file("t", files, 64)
+            .build(fixture_data(180, 1).as_slice())
+            .unwrap()
+    }
+
+    fn at(offset: usize, file: usize, file_offset: usize, piece_offset: usize) -> ByteLocation {
+        ByteLocation {
+            offset,
+            file,
+            file_offset,
+            piece: offset / 64,
+            piece_offset,
+            block: piece_offset / 16,
+            block_offset: piece_offset % 16,
+        }
+    }
+
+    #[test]
+    fn bytes_on_piece_and_file_boundaries() {
+        let torrent = torrent();
+        let cases = [
+            (0, at(0, 0, 0, 0)),
+            // the last byte of a piece and of a block, then the first of the next ones
+            (63, at(63, 0, 63, 63)),
+            (64, at(64, 0, 64, 0)),
+            (99, at(99, 0, 99, 35)),
+            // the empty file holds no byte, the next one starts where it is
+            (100, at(100, 2, 0, 36)),
+            (149, at(149, 2, 49, 21)),
+            (150, at(150, 3, 0, 22)),
+            // the last byte of the torrent, in its short last piece
+            (179, at(179, 3, 29, 51)),
+        ];
+        for (offset, expected) in cases {
+            assert_eq!(torrent.locate(offset, 16).unwrap(), expected, "{offset}");
+        }
+        let err = torrent.locate(180, 16).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "offset 180 is past the end of the torrent's 180 bytes"
+        );
+    }
+
+    #[test]
+    fn file_offsets_become_torrent_offsets() {
+        let torrent = torrent();
+        assert_eq!(torrent.file_offset_to_absolute(0, 0).unwrap(), 0);
+        assert_eq!(torrent.file_offset_to_absolute(2, 0).unwrap(), 100);
+        assert_eq!(torrent.file_offset_to_absolute(3, 29).unwrap(), 179);
+        for (file, offset) in [(0, 100), (1, 0), (3, 30), (4, 0)] {
+            assert!(
+                torrent.file_offset_to_absolute(file, offset).is_err(),
+                "{file}:{offset}"
+            );
+        }
+    }
+
+    #[test]
+    fn a_single_file_torrent_is_file_0() {
+        let torrent = Torrent::fixture_single_file(100, 32);
+        assert_eq!(
+            torrent.locate(99, 16).unwrap(),
+            ByteLocation {
+                offset: 99,
+                file: 0,
+                file_offset: 99,
+                piece: 3,
+                piece_offset: 3,
+                block: 0,
+                block_offset: 3,
+            }
+        );
+        assert_eq!(torrent.file_offset_to_absolute(0, 99).unwrap(), 99);
+    }
+
+    #[test]
+    fn debug_output_hides_passkeys_and_the_piece_hashes() {
+        let mut torrent = Torrent::fixture_single_file(1000 << 14, 1 << 14);
+        torrent.announce = "https://tracker.example/announce?passkey=hunter2".to_string();
+        torrent.announce_list = Some(vec![vec![
+            "udp://tracker.example:6969/0123456789abcdef0123/announce".to_string(),
+        ]]);
+        let debug = format!("{torrent:?}");
+        assert!(!debug.contains("hunter2"), "{debug}");
+        assert!(!debug.contains("0123456789abcdef0123"), "{debug}");
+        assert!(
+            debug.contains("https://tracker.example/announce?passkey=<redacted>"),
+            "{debug}"
+        );
+        assert!(
+            debug.contains("udp://tracker.example:6969/<redacted>/announce"),
+            "{debug}"
+        );
+        let first = hex::encode(torrent.info.pieces.0[0]);
+        assert!(
+            debug.contains(&format!(
+                "Hashes {{ count: 1000, first: \"{}…\" }}",
+                &first[..8]
+            )),
+            "{debug}"
+        );
+        assert!(!debug.contains(&first), "{debug}");
+        assert!(debug.len() < 1000, "{} bytes of debug output", debug.len());
+    }
 }
@@ -0,0 +1,376 @@
+//! Parsing a torrent without copying its byte strings out of the file.
+//!
+//! serde_bencode copies every byte string it reads, even from a slice, so a parsed
+//! [`Torrent`] owns a copy of its piece hashes, which are most of a large torrent. This walks
+//! the bencode by hand instead and keeps slices of the buffer, including the info dict
+//! itself, so the info hash doesn't need a re-encoding either.
+
+use crate::bstring;
+use crate::torrent::{Metainfo, Torrent};
+use anyhow::{bail, Context};
+use sha1::{Digest, Sha1};
+
+/// How deeply lists and dicts may nest, so a hostile file can't overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+/// A torrent borrowing its strings and piece hashes from the buffer it was parsed from.
+///
+/// Unknown keys are ignored, as they are when parsing a [`Torrent`].
+#[derive(Debug, Clone)]
+pub struct TorrentRef<'a> {
+    pub announce: &'a str,
+    /// Tiers of backup trackers (BEP 12).
+    pub announce_list: Option<Vec<Vec<&'a str>>>,
+    pub creation_date: Option<i64>,
+    pub created_by: Option<&'a str>,
+    pub encoding: Option<&'a str>,
+    pub info: InfoRef<'a>,
+    /// The info dict exactly as it appears in the file.
+    pub info_bytes: &'a [u8],
+}
+
+#[derive(Debug, Clone)]
+pub struct InfoRef<'a> {
+    pub name: &'a [u8],
+    pub plength: usize,
+    pub pieces: &'a [[u8; 20]],
+    pub private: Option<u8>,
+    pub keys: KeysRef<'a>,
+}
+
+#[derive(Debug, Clone)]
+pub enum KeysRef<'a> {
+    SingleFile { length: usize },
+    MultiFile { files: Vec<FileRef<'a>> },
+}
+
+#[derive(Debug, Clone)]
+pub struct FileRef<'a> {
+    pub length: usize,
+    pub path: Vec<&'a [u8]>,
+    pub attr: Option<&'a str>,
+}
+
+impl Torrent {
+    /// Parses a torrent that borrows from `bytes` instead of copying out of it.
+    pub fn from_bytes_borrowed(bytes: &[u8]) -> anyhow::Result<TorrentRef<'_>> {
+        TorrentRef::parse(bytes)
+    }
+}
+
+impl<'a> TorrentRef<'a> {
+    pub fn parse(bytes: &'a [u8]) -> anyhow::Result<Self> {
+        let mut parser = Parser {
+            input: bytes,
+            pos: 0,
+        };
+        let root = parser.value(0)?;
+        let root = root.as_dict("torrent")?;
+        let info = root.get("info").context("missing field `info`")?;
+        let info_bytes = info.as_dict("info")?.raw;
+        Ok(Self {
+            announce: root
+                .get("announce")
+                .context("missing field `announce`")?
+                .as_str("announce")?,
+            announce_list: root
+                .get("announce-list")
+                .map(|tiers| {
+                    tiers
+                        .as_list("announce-list")?
+                        .iter()
+                        .map(|tier| {
+                            tier.as_list("announce-list tier")?
+                                .iter()
+                                .map(|url| url.as_str("announce-list url"))
+                                .collect()
+                        })
+                        .collect()
+                })
+                .transpose()?,
+            creation_date: root
+                .get("creation date")
+                .map(|date| date.as_int("creation date"))
+                .transpose()?,
+            created_by: root
+                .get("created by")
+                .map(|by| by.as_str("created by"))
+                .transpose()?,
+            encoding: root
+                .get("encoding")
+                .map(|encoding| encoding.as_str("encoding"))
+                .transpose()?,
+            info: InfoRef::parse(info)?,
+            info_bytes,
+        })
+    }
+
+    /// Private torrents (BEP 27) may only get peers from their trackers.
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+
+    /// The SHA-1 of the info dict as it appears in the file.
+    pub fn info_hash(&self) -> [u8; 20] {
+        let mut hasher = Sha1::new();
+        hasher.update(self.info_bytes);
+        hasher.finalize().into()
+    }
+
+    /// The suggested name of the file or directory, as text.
+    pub fn name(&self) -> String {
+        bstring::decode(self.info.name, self.encoding)
+    }
+}
+
+impl<'a> InfoRef<'a> {
+    fn parse(info: &Value<'a>) -> anyhow::Result<Self> {
+        let info = info.as_dict("info")?;
+        let pieces = info
+            .get("pieces")
+            .context("missing field `pieces`")?
+            .as_bytes("pieces")?;
+        let (pieces, rest) = pieces.as_chunks::<20>();
+        if !rest.is_empty() {
+            bail!("pieces: length is {}", pieces.len() * 20 + rest.len());
+        }
+        let keys = match (info.get("length"), info.get("files")) {
+            (Some(length), None) => KeysRef::SingleFile {
+                length: length.as_usize("length")?,
+            },
+            (None, Some(files)) => KeysRef::MultiFile {
+                files: files
+                    .as_list("files")?
+                    .iter()
+                    .map(FileRef::parse)
+                    .collect::<anyhow::Result<_>>()?,
+            },
+            _ => bail!("info has to have either `length` or `files`"),
+        };
+        Ok(Self {
+            name: info
+                .get("name")
+                .context("missing field `name`")?
+                .as_bytes("name")?,
+            plength: info
+                .get("piece length")
+                .context("missing field `piece length`")?
+                .as_usize("piece length")?,
+            pieces,
+            private: info
+                .get("private")
+                .map(|private| {
+                    u8::try_from(private.as_int("private")?).context("private is out of range")
+                })
+                .transpose()?,
+            keys,
+        })
+    }
+}
+
+impl<'a> FileRef<'a> {
+    fn parse(file: &Value<'a>) -> anyhow::Result<Self> {
+        let file = file.as_dict("file")?;
+        Ok(Self {
+            length: file
+                .get("length")
+                .context("missing field `length`")?
+                .as_usize("length")?,
+            path: file
+                .get("path")
+                .context("missing field `path`")?
+                .as_list("path")?
+                .iter()
+                .map(|component| component.as_bytes("path"))
+                .collect::<anyhow::Result<_>>()?,
+            attr: file
+                .get("attr")
+                .map(|attr| attr.as_str("attr"))
+                .transpose()?,
+        })
+    }
+
+    /// Whether the file is a padding file (BEP 47).
+    pub fn is_padding(&self) -> bool {
+        self.attr.is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+impl Metainfo for TorrentRef<'_> {
+    fn piece_length(&self) -> usize {
+        self.info.plength
+    }
+
+    fn hash(&self, index: usize) -> Option<&[u8; 20]> {
+        self.info.pieces.get(index)
+    }
+
+    fn hash_count(&self) -> usize {
+        self.info.pieces.len()
+    }
+
+    fn file_lengths(&self) -> Vec<usize> {
+        match &self.info.keys {
+            KeysRef::SingleFile { length } => vec![*length],
+            KeysRef::MultiFile { files } => files.iter().map(|file| file.length).collect(),
+        }
+    }
+
+    fn file_without_path(&self) -> Option<usize> {
+        match &self.info.keys {
+            KeysRef::SingleFile { .. } => None,
+            KeysRef::MultiFile { files } => files
+                .iter()
+                .find(|file| file.path.is_empty())
+                .map(|file| file.length),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Value<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Value<'a>>),
+    Dict(Dict<'a>),
+}
+
+#[derive(Debug)]
+struct Dict<'a> {
+    entries: Vec<(&'a [u8], Value<'a>)>,
+    /// The whole dict as it appears in the input.
+    raw: &'a [u8],
+}
+
+impl<'a> Dict<'a> {
+    fn get(&self, key: &str) -> Option<&Value<'a>> {
+        self.entries
+            .iter()
+            .find(|(k, _)| *k == key.as_bytes())
+            .map(|(_, value)| value)
+    }
+}
+
+impl<'a> Value<'a> {
+    fn as_int(&self, what: &str) -> anyhow::Result<i64> {
+        match self {
+            Value::Int(int) => Ok(*int),
+            _ => bail!("{what}: expected an integer"),
+        }
+    }
+
+    fn as_usize(&self, what: &str) -> anyhow::Result<usize> {
+        usize::try_from(self.as_int(what)?).with_context(|| format!("{what} is out of range"))
+    }
+
+    fn as_bytes(&self, what: &str) -> anyhow::Result<&'a [u8]> {
+        match self {
+            Value::Bytes(bytes) => Ok(bytes),
+            _ => bail!("{what}: expected a byte string"),
+        }
+    }
+
+    fn as_str(&self, what: &str) -> anyhow::Result<&'a str> {
+        std::str::from_utf8(self.as_bytes(what)?).with_context(|| format!("{what} isn't UTF-8"))
+    }
+
+    fn as_list(&self, what: &str) -> anyhow::Result<&[Value<'a>]> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => bail!("{what}: expected a list"),
+        }
+    }
+
+    fn as_dict(&self, what: &str) -> anyhow::Result<&Dict<'a>> {
+        match self {
+            Value::Dict(dict) => Ok(dict),
+            _ => bail!("{what}: expected a dict"),
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self, depth: usize) -> anyhow::Result<Value<'a>> {
+        if depth > MAX_DEPTH {
+            bail!("nested more than {MAX_DEPTH} levels deep");
+        }
+        let start = self.pos;
+        match self.input.get(self.pos) {
+            None => bail!("unexpected end of data"),
+            Some(b'i') => {
+                self.pos += 1;
+                let digits = self.until(b'e')?;
+                let int = std::str::from_utf8(digits)
+                    .ok()
+                    .and_then(|digits| digits.parse().ok())
+                    .with_context(|| format!("invalid integer at {start}"))?;
+                Ok(Value::Int(int))
+            }
+            Some(b'l') => {
+                self.pos += 1;
+                let mut list = Vec::new();
+                while !self.end()? {
+                    list.push(self.value(depth + 1)?);
+                }
+                Ok(Value::List(list))
+            }
+            Some(b'd') => {
+                self.pos += 1;
+                let mut entries = Vec::new();
+                while !self.end()? {
+                    let key = self.bytes()?;
+                    entries.push((key, self.value(depth + 1)?));
+                }
+                Ok(Value::Dict(Dict {
+                    entries,
+                    raw: &self.input[start..self.pos],
+                }))
+            }
+            Some(b'0'..=b'9') => Ok(Value::Bytes(self.bytes()?)),
+            Some(&c) => bail!("invalid character {:?} at {start}", c as char),
+        }
+    }
+
+    /// Consumes the `e` ending a list or dict, if that's what comes next.
+    fn end(&mut self) -> anyhow::Result<bool> {
+        match self.input.get(self.pos) {
+            Some(b'e') => {
+                self.pos += 1;
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+            None => bail!("unexpected end of data"),
+        }
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let start = self.pos;
+        let len = std::str::from_utf8(self.until(b':')?)
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .with_context(|| format!("invalid byte string length at {start}"))?;
+        let bytes = self
+            .input
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..len))
+            .with_context(|| format!("byte string at {start} runs past the end"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// The input up to the next `delimiter`, which is consumed too.
+    fn until(&mut self, delimiter: u8) -> anyhow::Result<&'a [u8]> {
+        let rest = &self.input[self.pos..];
+        let len = rest
+            .iter()
+            .position(|&b| b == delimiter)
+            .context("unexpected end of data")?;
+        self.pos += len + 1;
+        Ok(&rest[..len])
+    }
+}
@@ -1,5 +1,101 @@
 use crate::peer;
+use crate::peer_id::PeerId;
+use crate::redact;
+use crate::torrent::Torrent;
+use crate::tracker_policy::TrackerPolicy;
+use crate::tracker_tls::{self, TrackerTls};
+use crate::ws_tracker;
+use anyhow::Context;
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value as BencodeValue;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// An identical tracker warning is reported at most once per this period.
+const WARNING_REPEAT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// The most peers we ever ask a tracker for.
+pub const MAX_NUMWANT: u32 = 200;
+
+/// How long to wait before announcing again after a failed announce.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fewer connected peers than this, with work left to do, counts as running dry.
+const LOW_PEER_THRESHOLD: usize = 4;
+
+/// How many times per session we announce ahead of the tracker's interval.
+const EARLY_ANNOUNCE_BUDGET: u32 = 5;
+
+/// The last warning reported and when, shared by every announce of the process.
+static LAST_WARNING: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+
+/// The tracker ids handed out to us, by announce URL.
+static TRACKER_IDS: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(Mutex::default);
+
+/// Random for every run of the process, like the key of most clients.
+static SESSION_KEY: LazyLock<String> = LazyLock::new(|| format!("{:08X}", rand::random::<u32>()));
+
+/// The `key` we announce with, the same for all announces of this process.
+///
+/// Private trackers use it to recognize us when our IP address changes.
+pub fn session_key() -> &'static str {
+    &SESSION_KEY
+}
+
+/// The tracker id `announce` gave us, which has to be echoed on every later announce.
+pub fn tracker_id(announce: &str) -> Option<String> {
+    TRACKER_IDS
+        .lock()
+        .expect("tracker id lock poisoned")
+        .get(announce)
+        .cloned()
+}
+
+/// Percent-encodes raw bytes for a query string, such as an info hash.
+///
+/// Only bytes outside the unreserved set of RFC 3986 are escaped; some trackers reject
+/// hashes with every byte escaped. serde_urlencoded can't do this, as it only takes UTF-8.
+pub fn urlencode_bytes(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(3 * bytes.len());
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// The `failure reason` of a tracker response, if the tracker refused.
+///
+/// A refusal carries none of the other keys, so it has to be looked for before the
+/// response is decoded as an answer.
+fn failure_reason(response: &[u8]) -> Option<String> {
+    let Ok(BencodeValue::Dict(response)) = serde_bencode::from_bytes(response) else {
+        return None;
+    };
+    match response.get(b"failure reason".as_slice()) {
+        Some(BencodeValue::Bytes(reason)) => Some(String::from_utf8_lossy(reason).into_owned()),
+        _ => None,
+    }
+}
+
+/// Remembers the tracker id in `response`, if any, for later announces to `announce`.
+pub fn remember_tracker_id(announce: &str, response: &TrackerResponse) {
+    if let Some(tracker_id) = &response.tracker_id {
+        TRACKER_IDS
+            .lock()
+            .expect("tracker id lock poisoned")
+            .insert(announce.to_string(), tracker_id.clone());
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackerRequest {
@@ -8,9 +104,9 @@ pub struct TrackerRequest {
     /// Note: this is NOT the hexadecimal representation, which is 40 bytes long
     #[serde(skip_serializing)]
     pub info_hash: [u8; 20],
-    /// A unique identifier for your client
-    /// A string of length 20 that you get to pick.
-    pub peer_id: String,
+    /// Our peer id, see [`PeerId`]; URL encoded like the info hash.
+    #[serde(skip_serializing)]
+    pub peer_id: [u8; 20],
     /// The port your client is listening on
     pub port: u16,
     /// The total amount uploaded so far
@@ -19,11 +115,62 @@ pub struct TrackerRequest {
     pub downloaded: usize,
     /// The number of bytes left to download
     pub left: usize,
+    /// Identifies us across IP changes, see [`session_key`].
+    pub key: String,
+    /// How many peers we'd like, see [`numwant`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<u32>,
     /// Whether the peer list should use the compact representation
 
     /// The compact representation is more commonly used in the wild,
     /// the non-compact representation is mostly supported for backward-compatibility.
     pub compact: u8,
+    /// The tracker id the tracker gave us last time, which it expects back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
+    /// Why we announce, if not just for peers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+}
+
+/// A change in our state the tracker is told about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// We joined the swarm, with the first announce of a download.
+    Started,
+    /// The last piece we wanted verified; sent once, not when starting with everything.
+    Completed,
+    /// We are leaving the swarm.
+    Stopped,
+}
+
+/// What the session looks like when it announces, to decide how many peers to ask for.
+#[derive(Debug, Clone, Copy)]
+pub struct SwarmNeed {
+    /// Peers we're connected to right now.
+    pub connected: usize,
+    /// How many peers we're willing to be connected to.
+    pub max_connections: usize,
+    /// Whether we have the whole torrent, or at least aren't downloading.
+    pub seeding: bool,
+    pub paused: bool,
+}
+
+/// How many peers to ask the tracker for, at most `max`.
+///
+/// None when paused or seeding with plenty of connections, a few when close to the
+/// connection cap and as many as allowed when we've run out of peers.
+pub fn numwant(need: SwarmNeed, max: u32) -> u32 {
+    let free = need.max_connections.saturating_sub(need.connected);
+    if need.paused || free == 0 || (need.seeding && need.connected >= need.max_connections / 2) {
+        return 0;
+    }
+    if need.connected < need.max_connections / 4 {
+        return max;
+    }
+    // ask for a few more than there's room for, some won't be reachable
+    (free + free / 2).min(max as usize) as u32
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,4 +181,603 @@ pub struct TrackerResponse {
     /// Each peer is represented using 6 bytes.
     /// The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
     pub peers: peer::Peers,
+    /// IPv6 peers, 18 bytes each: the address then the port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peers6: Option<peer::Peers6>,
+    /// Similar to failure reason, but the response still gets processed normally.
+    #[serde(rename = "warning message")]
+    pub warning_message: Option<String>,
+    /// The tracker asks not to be announced to more often than this, in seconds.
+    #[serde(
+        rename = "min interval",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub min_interval: Option<usize>,
+    /// To be sent back as `trackerid` on our next announces to this tracker.
+    #[serde(
+        rename = "tracker id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tracker_id: Option<String>,
+    /// Seeders in the swarm, if the tracker says.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete: Option<usize>,
+    /// Leechers in the swarm, if the tracker says.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<usize>,
+}
+
+/// Where we stand in a swarm after an announce: how big it is and what we told the tracker
+/// about ourselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwarmPosition {
+    pub complete: Option<usize>,
+    pub incomplete: Option<usize>,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+}
+
+/// Announces to HTTP and websocket trackers.
+///
+/// Every tracker is checked against a [`TrackerPolicy`] first.
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    http: reqwest::Client,
+    /// The TLS setup of `wss://` trackers, the same as that of `http`.
+    ws_tls: native_tls::TlsConnector,
+    policy: TrackerPolicy,
+    /// The order to try the trackers of each torrent in, by info hash.
+    tiers: Arc<Mutex<HashMap<[u8; 20], TrackerTiers>>>,
+    peer_id: PeerId,
+}
+
+/// The trackers of a torrent in the order to try them (BEP 12): tier by tier, each tier
+/// shuffled once, and a tracker that answered moved to the front of its tier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerTiers(Vec<Vec<String>>);
+
+/// No tracker of a torrent answered an announce.
+#[derive(Debug, thiserror::Error)]
+pub struct AllTrackersFailed {
+    /// Each tracker tried, redacted, and why it failed.
+    pub attempts: Vec<(String, String)>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    #[error("unsupported tracker scheme `{0}`")]
+    UnsupportedScheme(String),
+    #[error("tracker not allowed: {0}")]
+    Forbidden(String),
+    #[error("url-encode tracker parameters")]
+    Encode(#[from] serde_urlencoded::ser::Error),
+    #[error("fetch tracker")]
+    Http(#[source] reqwest::Error),
+    #[error("TLS certificate check with {host} failed")]
+    Certificate {
+        host: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("parse tracker response")]
+    Parse(#[from] serde_bencode::Error),
+    /// The tracker's `failure reason`, verbatim.
+    #[error("tracker refused announce: {0}")]
+    Refused(String),
+    #[error("websocket announce")]
+    WebSocket(#[source] anyhow::Error),
+}
+
+/// Decides when to announce next.
+///
+/// Normally that's once per tracker interval, but a session that has run out of peers
+/// can ask for an early announce, which is granted no sooner than the tracker's minimum
+/// interval and only a few times per session so we don't hammer the tracker.
+#[derive(Debug, Clone)]
+pub struct AnnounceSchedule {
+    last_announce: Option<Instant>,
+    min_interval: Duration,
+    next_announce: Instant,
+    early_budget: u32,
+}
+
+impl TrackerClient {
+    /// A client announcing as `peer_id`.
+    pub fn new(policy: TrackerPolicy, tls: &TrackerTls, peer_id: PeerId) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .dns_resolver(std::sync::Arc::new(policy.resolver()))
+            .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= 10 {
+                    attempt.error("too many redirects")
+                } else if let Err(err) = policy.check_url(attempt.url()) {
+                    attempt.error(err)
+                } else {
+                    attempt.follow()
+                }
+            }));
+        let http = tls
+            .apply(http)?
+            .build()
+            .context("configure the tracker http client")?;
+        let ws_tls = tls.connector()?;
+        Ok(Self {
+            http,
+            ws_tls,
+            policy,
+            tiers: Arc::default(),
+            peer_id,
+        })
+    }
+
+    /// The peer id we announce as, which peers have to see in our handshakes too.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Announces `request` to the trackers of `torrent` tier by tier until one answers,
+    /// returning its answer and URL.
+    ///
+    /// The tracker id of each tracker replaces the one in `request`.
+    pub async fn announce_tiers(
+        &self,
+        torrent: &Torrent,
+        request: &TrackerRequest,
+    ) -> Result<(reqwest::Url, TrackerResponse), AllTrackersFailed> {
+        let tiers = self
+            .tiers()
+            .entry(request.info_hash)
+            .or_insert_with(|| {
+                TrackerTiers::new(&torrent.announce, torrent.announce_list.as_deref())
+            })
+            .clone();
+        let mut attempts = Vec::new();
+        for (tier, announce) in tiers.iter() {
+            let announced = match reqwest::Url::parse(announce) {
+                Ok(url) => {
+                    let request = TrackerRequest {
+                        trackerid: tracker_id(url.as_str()),
+                        ..request.clone()
+                    };
+                    self.announce(&request, &url)
+                        .await
+                        .map(|response| (url, response))
+                        .map_err(anyhow::Error::from)
+                }
+                Err(err) => Err(anyhow::Error::from(err).context("parse tracker announce url")),
+            };
+            match announced {
+                Ok(answer) => {
+                    if let Some(tiers) = self.tiers().get_mut(&request.info_hash) {
+                        tiers.answered(tier, announce);
+                    }
+                    return Ok(answer);
+                }
+                Err(err) => {
+                    let url = redact::url_str(announce);
+                    warn!("tracker {url}: {err:#}");
+                    attempts.push((url, format!("{err:#}")));
+                }
+            }
+        }
+        Err(AllTrackersFailed { attempts })
+    }
+
+    fn tiers(&self) -> std::sync::MutexGuard<'_, HashMap<[u8; 20], TrackerTiers>> {
+        // the order is only ever swapped around, poisoning can't leave it half-updated
+        self.tiers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fetches `url` from an HTTP tracker, e.g. to scrape it.
+    pub async fn get(&self, url: &reqwest::Url) -> Result<bytes::Bytes, TrackerError> {
+        self.policy.check_url(url)?;
+        // reqwest puts the URL in its errors, passkey and all
+        let response = self.http.get(url.clone()).send().await;
+        let response = match response {
+            Ok(response) => response.bytes().await,
+            Err(err) => Err(err),
+        };
+        response.map_err(|err| {
+            if tracker_tls::is_certificate_error(&err) {
+                TrackerError::Certificate {
+                    host: url.host_str().unwrap_or_default().to_string(),
+                    source: err.without_url(),
+                }
+            } else {
+                TrackerError::Http(err.without_url())
+            }
+        })
+    }
+
+    /// Sends `request` to the tracker at `url` and returns its answer.
+    ///
+    /// Warnings in the response are reported, once, and a tracker id is remembered for the
+    /// next announce to the same tracker.
+    pub async fn announce(
+        &self,
+        request: &TrackerRequest,
+        url: &reqwest::Url,
+    ) -> Result<TrackerResponse, TrackerError> {
+        info!("announcing to {}", redact::url(url));
+        self.policy.check_url(url)?;
+        let response = match url.scheme() {
+            "http" | "https" => self.announce_http(request, url).await?,
+            "ws" | "wss" => {
+                self.policy.check_resolved(url).await?;
+                ws_tracker::announce(url, request, &self.ws_tls)
+                    .await
+                    .map_err(TrackerError::WebSocket)?
+            }
+            scheme => return Err(TrackerError::UnsupportedScheme(scheme.to_string())),
+        };
+        if let Some(warning) = response.fresh_warning() {
+            warn!("tracker says: {warning}");
+        }
+        remember_tracker_id(url.as_str(), &response);
+        Ok(response)
+    }
+
+    async fn announce_http(
+        &self,
+        request: &TrackerRequest,
+        url: &reqwest::Url,
+    ) -> Result<TrackerResponse, TrackerError> {
+        // info_hash goes first, as with every other client; private trackers are picky
+        let mut query = url
+            .query()
+            .map(|query| format!("{query}&"))
+            .unwrap_or_default();
+        query.push_str("info_hash=");
+        query.push_str(&urlencode_bytes(&request.info_hash));
+        query.push_str("&peer_id=");
+        query.push_str(&urlencode_bytes(&request.peer_id));
+        query.push('&');
+        query.push_str(&serde_urlencoded::to_string(request)?);
+        let mut url = url.clone();
+        url.set_query(Some(&query));
+
+        let response = self.get(&url).await?;
+        if let Some(reason) = failure_reason(&response) {
+            return Err(TrackerError::Refused(reason));
+        }
+        Ok(serde_bencode::from_bytes(&response)?)
+    }
+}
+
+impl AnnounceSchedule {
+    /// A schedule whose first announce is due right away.
+    ///
+    /// Private trackers ban clients that announce more often than they're told to, so
+    /// for `private` torrents there are no early announces at all.
+    pub fn new(private: bool) -> Self {
+        Self {
+            last_announce: None,
+            min_interval: Duration::ZERO,
+            next_announce: Instant::now(),
+            early_budget: if private { 0 } else { EARLY_ANNOUNCE_BUDGET },
+        }
+    }
+
+    pub fn next_announce(&self) -> Instant {
+        self.next_announce
+    }
+
+    /// Records a successful announce, scheduling the next one after the tracker's interval.
+    pub fn announced(&mut self, response: &TrackerResponse) {
+        let now = Instant::now();
+        self.last_announce = Some(now);
+        self.min_interval = Duration::from_secs(response.min_interval.unwrap_or(0) as u64);
+        self.next_announce = now + Duration::from_secs(response.interval as u64);
+    }
+
+    /// Records a failed announce, which is retried after a short while.
+    pub fn failed(&mut self) {
+        self.next_announce = Instant::now() + RETRY_INTERVAL;
+    }
+
+    /// Moves the next announce forward if the session has run dry, returning when it is now due.
+    ///
+    /// Nothing changes while we have enough peers, have nothing left to download or have
+    /// used up the early announce budget.
+    pub fn request_early(&mut self, active_peers: usize, work_left: bool) -> Option<Instant> {
+        if active_peers >= LOW_PEER_THRESHOLD || !work_left || self.early_budget == 0 {
+            return None;
+        }
+        let earliest = self
+            .last_announce
+            .map_or_else(Instant::now, |last| last + self.min_interval)
+            .max(Instant::now());
+        if earliest >= self.next_announce {
+            return None;
+        }
+        self.early_budget -= 1;
+        self.next_announce = earliest;
+        info!(
+            "early reannounce with {active_peers} peer(s) left, in {}s, \
+             {} early announce(s) left",
+            earliest.saturating_duration_since(Instant::now()).as_secs(),
+            self.early_budget
+        );
+        Some(earliest)
+    }
+}
+
+impl TrackerTiers {
+    /// The tiers of `announce_list`, or just `announce` if there are none; `announce` is
+    /// only a fallback for clients that don't know about tiers.
+    pub fn new(announce: &str, announce_list: Option<&[Vec<String>]>) -> Self {
+        let mut tiers: Vec<Vec<String>> = announce_list
+            .unwrap_or_default()
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        if tiers.is_empty() {
+            tiers.push(vec![announce.to_string()]);
+        }
+        let mut rng = rand::thread_rng();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rng);
+        }
+        Self(tiers)
+    }
+
+    /// Every tracker with the index of its tier, in the order to try them.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &String)> {
+        self.0
+            .iter()
+            .enumerate()
+            .flat_map(|(index, tier)| tier.iter().map(move |url| (index, url)))
+    }
+
+    /// Moves `url` to the front of `tier`, after it answered.
+    pub fn answered(&mut self, tier: usize, url: &str) {
+        let Some(tier) = self.0.get_mut(tier) else {
+            return;
+        };
+        if let Some(at) = tier.iter().position(|tracker| tracker == url) {
+            let tracker = tier.remove(at);
+            tier.insert(0, tracker);
+        }
+    }
+}
+
+impl Display for AllTrackersFailed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "none of the {} tracker(s) answered", self.attempts.len())?;
+        for (url, reason) in &self.attempts {
+            write!(f, "\n  {url}: {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+impl TrackerRequest {
+    /// An announce of the torrent `info_hash` stands for, which is all a magnet link may
+    /// give us. There is no event or peer count, and the tracker id is up to the tracker it
+    /// goes to.
+    pub fn new(info_hash: [u8; 20], peer_id: PeerId, port: u16, left: usize) -> Self {
+        Self {
+            info_hash,
+            peer_id: peer_id.0,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            key: session_key().to_string(),
+            numwant: None,
+            compact: 1,
+            trackerid: None,
+            event: None,
+        }
+    }
+}
+
+impl TrackerResponse {
+    /// The peers of both `peers` and `peers6`, IPv4 ones first.
+    pub fn all_peers(&self) -> Vec<SocketAddr> {
+        let peers6 = self.peers6.iter().flat_map(|peers6| &peers6.0);
+        self.peers
+            .0
+            .iter()
+            .copied()
+            .chain(peers6.map(|&peer| SocketAddr::V6(peer)))
+            .collect()
+    }
+
+    /// Our place in the swarm after announcing `request` and getting this response.
+    pub fn position(&self, request: &TrackerRequest) -> SwarmPosition {
+        SwarmPosition {
+            complete: self.complete,
+            incomplete: self.incomplete,
+            uploaded: request.uploaded,
+            downloaded: request.downloaded,
+            left: request.left,
+        }
+    }
+
+    /// The tracker's warning, unless the very same warning was already reported recently.
+    ///
+    /// Trackers tend to repeat a warning on every announce, which would spam the logs.
+    pub fn fresh_warning(&self) -> Option<&str> {
+        let warning = self.warning_message.as_deref()?;
+        let mut last = LAST_WARNING.lock().expect("warning lock poisoned");
+        match &*last {
+            Some((last_warning, at))
+                if last_warning == warning && at.elapsed() < WARNING_REPEAT_INTERVAL =>
+            {
+                None
+            }
+            _ => {
+                *last = Some((warning.to_string(), Instant::now()));
+                Some(warning)
+            }
+        }
+    }
+}
+
+/// Trackers count whoever has nothing left as a seeder, so we are one of `complete` or
+/// `incomplete` ourselves.
+impl Display for SwarmPosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let count = |count: Option<usize>| count.map_or("?".to_string(), |n| n.to_string());
+        write!(
+            f,
+            "swarm_position complete={} incomplete={} us={} uploaded={} downloaded={} \
+             left={}",
+            count(self.complete),
+            count(self.incomplete),
+            if self.left == 0 { "seeder" } else { "leecher" },
+            self.uploaded,
+            self.downloaded,
+            self.left
+        )
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Event::Started => "started",
+            Event::Completed => "completed",
+            Event::Stopped => "stopped",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_refusal_is_recognized_before_the_response_is_decoded() {
+        let refusal = b"d14:failure reason22:torrent not registered15:warning message4:slowe";
+        assert_eq!(
+            failure_reason(refusal).as_deref(),
+            Some("torrent not registered")
+        );
+        assert!(serde_bencode::from_bytes::<TrackerResponse>(refusal).is_err());
+        let answer = serde_bencode::to_bytes(&TrackerResponse::fixture(&[])).unwrap();
+        assert_eq!(failure_reason(&answer), None);
+        assert_eq!(failure_reason(b"not bencode"), None);
+    }
+
+    #[test]
+    fn only_reserved_bytes_of_an_info_hash_are_escaped() {
+        // the info hash of sample.torrent
+        let info_hash = hex::decode("d69f91e6b2ae4c542468d1073a71d4ea13879a7f").unwrap();
+        assert_eq!(
+            urlencode_bytes(&info_hash),
+            "%D6%9F%91%E6%B2%AELT%24h%D1%07%3Aq%D4%EA%13%87%9A%7F"
+        );
+        assert_eq!(urlencode_bytes(b"aZ09-._~ /%"), "aZ09-._~%20%2F%25");
+    }
+
+    #[test]
+    fn a_warning_is_reported_once_until_it_changes() {
+        let mut response = TrackerResponse::fixture(&[]);
+        response.warning_message = Some("client is behind NAT (unit test)".to_string());
+        assert_eq!(
+            response.fresh_warning(),
+            Some("client is behind NAT (unit test)")
+        );
+        assert_eq!(response.fresh_warning(), None);
+        response.warning_message = Some("announce too frequent (unit test)".to_string());
+        assert!(response.fresh_warning().is_some());
+    }
+
+    #[test]
+    fn the_warning_key_is_decoded_alongside_peers() {
+        let response: TrackerResponse = serde_bencode::from_bytes(
+            b"d8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe115:warning message4:slowe",
+        )
+        .unwrap();
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+        assert_eq!(
+            response.all_peers(),
+            ["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+
+    fn need(connected: usize, seeding: bool, paused: bool) -> SwarmNeed {
+        SwarmNeed {
+            connected,
+            max_connections: 40,
+            seeding,
+            paused,
+        }
+    }
+
+    #[test]
+    fn numwant_asks_for_nothing_when_paused_full_or_a_well_connected_seed() {
+        assert_eq!(numwant(need(0, false, true), 200), 0);
+        assert_eq!(numwant(need(40, false, false), 200), 0);
+        assert_eq!(numwant(need(45, false, false), 200), 0);
+        assert_eq!(numwant(need(20, true, false), 200), 0);
+        assert_eq!(numwant(need(39, true, false), 200), 0);
+    }
+
+    #[test]
+    fn numwant_asks_for_the_most_when_starved() {
+        assert_eq!(numwant(need(0, false, false), 200), 200);
+        assert_eq!(numwant(need(9, false, false), 200), 200);
+        assert_eq!(numwant(need(0, false, false), 30), 30);
+        // a seed short of connections still looks for leechers
+        assert_eq!(numwant(need(0, true, false), 200), 200);
+    }
+
+    #[test]
+    fn numwant_asks_for_a_few_more_than_there_is_room_for_near_the_cap() {
+        assert_eq!(numwant(need(10, false, false), 200), 45);
+        assert_eq!(numwant(need(30, false, false), 200), 15);
+        assert_eq!(numwant(need(39, false, false), 200), 1);
+        assert_eq!(numwant(need(19, true, false), 200), 31);
+        assert_eq!(numwant(need(10, false, false), 20), 20);
+    }
+
+    fn answered(schedule: &mut AnnounceSchedule, min_interval: Option<usize>) {
+        let mut response = TrackerResponse::fixture(&[]);
+        response.min_interval = min_interval;
+        schedule.announced(&response);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn running_dry_moves_the_announce_up_to_the_minimum_interval() {
+        let mut schedule = AnnounceSchedule::new(false);
+        answered(&mut schedule, Some(120));
+        let start = Instant::now();
+        assert_eq!(schedule.next_announce(), start + Duration::from_secs(1800));
+
+        assert_eq!(schedule.request_early(LOW_PEER_THRESHOLD, true), None);
+        assert_eq!(schedule.request_early(0, false), None);
+        let early = schedule.request_early(0, true).unwrap();
+        assert_eq!(early, start + Duration::from_secs(120));
+        assert_eq!(schedule.next_announce(), early);
+        // already as early as it gets
+        assert_eq!(schedule.request_early(0, true), None);
+
+        tokio::time::advance(Duration::from_secs(600)).await;
+        answered(&mut schedule, None);
+        assert_eq!(schedule.request_early(1, true), Some(Instant::now()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn early_announces_run_out() {
+        let mut schedule = AnnounceSchedule::new(false);
+        for _ in 0..EARLY_ANNOUNCE_BUDGET {
+            answered(&mut schedule, None);
+            assert!(schedule.request_early(0, true).is_some());
+        }
+        answered(&mut schedule, None);
+        assert_eq!(schedule.request_early(0, true), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn private_torrents_never_announce_early() {
+        let mut schedule = AnnounceSchedule::new(true);
+        answered(&mut schedule, None);
+        assert_eq!(schedule.request_early(0, true), None);
+    }
 }
@@ -0,0 +1,252 @@
+use crate::tracker::TrackerError;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use url::Host;
+
+/// Which tracker URLs we're willing to contact.
+///
+/// Torrents from untrusted sources can point their trackers at services on our own network
+/// (cloud metadata endpoints, admin interfaces on localhost), so by default only public
+/// addresses are allowed. The check is repeated on every address a tracker's name resolves
+/// to, so DNS rebinding can't sneak a local address past it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TrackerPolicy {
+    /// Allow loopback, private and link-local trackers, e.g. for testing.
+    pub allow_local: bool,
+}
+
+impl TrackerPolicy {
+    /// Checks the scheme and, if it's an address or `localhost`, the host of `url`.
+    pub fn check_url(&self, url: &Url) -> Result<(), TrackerError> {
+        if !matches!(url.scheme(), "http" | "https" | "udp" | "ws" | "wss") {
+            return Err(TrackerError::Forbidden(format!(
+                "scheme `{}` is not allowed for trackers",
+                url.scheme()
+            )));
+        }
+        match url.host() {
+            Some(Host::Ipv4(ip)) => self.check_addr(ip.into()),
+            Some(Host::Ipv6(ip)) => self.check_addr(ip.into()),
+            Some(Host::Domain(domain)) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                if !self.allow_local && (domain == "localhost" || domain.ends_with(".localhost")) {
+                    return Err(TrackerError::Forbidden(format!(
+                        "{domain} is a local address, pass --allow-local-trackers to allow it"
+                    )));
+                }
+                Ok(())
+            }
+            None => Err(TrackerError::Forbidden(
+                "tracker url has no host".to_string(),
+            )),
+        }
+    }
+
+    /// Checks an address a tracker resolved to.
+    pub fn check_addr(&self, ip: IpAddr) -> Result<(), TrackerError> {
+        if self.allow_local || !is_local(ip) {
+            return Ok(());
+        }
+        Err(TrackerError::Forbidden(format!(
+            "{ip} is a local address, pass --allow-local-trackers to allow it"
+        )))
+    }
+
+    /// Resolves the host of `url` and checks every address it resolves to.
+    ///
+    /// For clients that resolve names themselves, like the websocket one. Unlike
+    /// [`TrackerPolicy::resolver`], that leaves a window in which the name could be
+    /// re-pointed.
+    pub async fn check_resolved(&self, url: &Url) -> Result<(), TrackerError> {
+        self.check_url(url)?;
+        let (Some(Host::Domain(domain)), false) = (url.host(), self.allow_local) else {
+            return Ok(());
+        };
+        let port = url.port_or_known_default().unwrap_or(0);
+        let addrs = tokio::net::lookup_host((domain, port))
+            .await
+            .map_err(|err| TrackerError::Forbidden(format!("resolve {domain}: {err}")))?;
+        for addr in addrs {
+            self.check_addr(addr.ip())?;
+        }
+        Ok(())
+    }
+
+    /// A resolver for reqwest that only hands out addresses this policy allows.
+    pub fn resolver(self) -> PolicyResolver {
+        PolicyResolver(self)
+    }
+}
+
+/// Resolves names with the system resolver, dropping addresses a [`TrackerPolicy`] forbids.
+#[derive(Debug)]
+pub struct PolicyResolver(TrackerPolicy);
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.0;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| policy.check_addr(addr.ip()).is_ok())
+                .collect();
+            if addrs.is_empty() {
+                return Err(TrackerError::Forbidden(format!(
+                    "{} only resolves to local addresses",
+                    name.as_str()
+                ))
+                .into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether `ip` belongs to this host or a private network rather than the internet.
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_local_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local_v4(ip),
+            None => is_local_v6(ip),
+        },
+    }
+}
+
+fn is_local_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // carrier-grade NAT, 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+}
+
+fn is_local_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link-local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRICT: TrackerPolicy = TrackerPolicy { allow_local: false };
+    const LAX: TrackerPolicy = TrackerPolicy { allow_local: true };
+
+    #[test]
+    fn urls() {
+        // url, allowed by default, allowed with --allow-local-trackers
+        let table = [
+            ("http://tracker.example/announce", true, true),
+            (
+                "https://tracker.example:8443/announce?passkey=x",
+                true,
+                true,
+            ),
+            ("udp://tracker.example:6969", true, true),
+            ("wss://tracker.example/ws", true, true),
+            ("http://93.184.216.34/announce", true, true),
+            ("http://[2606:2800:220:1::]/announce", true, true),
+            ("file:///etc/passwd", false, false),
+            ("ftp://tracker.example/announce", false, false),
+            ("gopher://tracker.example/", false, false),
+            ("http://169.254.169.254/latest/meta-data", false, true),
+            ("http://127.0.0.1:8080/admin", false, true),
+            ("http://localhost:6969/announce", false, true),
+            ("http://LOCALHOST./announce", false, true),
+            ("http://tracker.localhost/announce", false, true),
+            ("http://10.0.0.1/announce", false, true),
+            ("http://192.168.1.1/announce", false, true),
+            ("http://172.16.5.4/announce", false, true),
+            ("http://100.64.0.1/announce", false, true),
+            ("http://0.0.0.0/announce", false, true),
+            ("http://[::1]/announce", false, true),
+            ("http://[fd00::1]/announce", false, true),
+            ("http://[fe80::1]/announce", false, true),
+            ("http://[::ffff:127.0.0.1]/announce", false, true),
+            // numeric forms of 127.0.0.1 are normalised by the url parser
+            ("http://2130706433/announce", false, true),
+            ("http://0x7f.1/announce", false, true),
+        ];
+        for (url, strict, lax) in table {
+            let parsed = Url::parse(url).unwrap();
+            assert_eq!(STRICT.check_url(&parsed).is_ok(), strict, "{url}");
+            assert_eq!(
+                LAX.check_url(&parsed).is_ok(),
+                lax,
+                "{url} with local allowed"
+            );
+        }
+    }
+
+    #[test]
+    fn resolved_addresses() {
+        // address, public
+        let table = [
+            ("93.184.216.34", true),
+            ("8.8.8.8", true),
+            ("100.128.0.1", true),
+            ("172.32.0.1", true),
+            ("2606:2800:220:1::", true),
+            ("127.0.0.1", false),
+            ("127.255.255.254", false),
+            ("10.255.255.255", false),
+            ("172.31.255.255", false),
+            ("192.168.0.1", false),
+            ("169.254.169.254", false),
+            ("100.127.255.255", false),
+            ("255.255.255.255", false),
+            ("0.0.0.0", false),
+            ("::1", false),
+            ("::", false),
+            ("fc00::1", false),
+            ("fe80::1", false),
+            ("febf::1", false),
+            ("::ffff:10.0.0.1", false),
+            ("::ffff:93.184.216.34", true),
+        ];
+        for (ip, public) in table {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert_eq!(STRICT.check_addr(ip).is_ok(), public, "{ip}");
+            assert!(LAX.check_addr(ip).is_ok(), "{ip} with local allowed");
+        }
+    }
+
+    #[test]
+    fn violations_say_how_to_allow_local_trackers() {
+        let err = STRICT
+            .check_url(&Url::parse("http://127.0.0.1/announce").unwrap())
+            .unwrap_err();
+        assert!(matches!(err, TrackerError::Forbidden(_)));
+        assert_eq!(
+            err.to_string(),
+            "tracker not allowed: 127.0.0.1 is a local address, pass --allow-local-trackers to allow it"
+        );
+    }
+
+    #[tokio::test]
+    async fn names_are_checked_after_they_resolve() {
+        let name: Name = "localhost".parse().unwrap();
+        let err = STRICT.resolver().resolve(name).await.err().unwrap();
+        assert!(
+            err.to_string().contains("only resolves to local addresses"),
+            "{err}"
+        );
+
+        let name: Name = "localhost".parse().unwrap();
+        let addrs: Vec<_> = LAX.resolver().resolve(name).await.unwrap().collect();
+        assert!(
+            addrs.iter().all(|addr| addr.ip().is_loopback()),
+            "{addrs:?}"
+        );
+    }
+}
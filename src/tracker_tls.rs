@@ -0,0 +1,137 @@
+use anyhow::{bail, Context};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// How we check HTTPS and WSS trackers and prove who we are to them, for private trackers
+/// with self-signed certificates or client certificates.
+#[derive(Debug, Clone, Default, PartialEq, Eq, clap::Args)]
+pub struct TrackerTls {
+    /// Also trust the CA certificates in this PEM file for HTTPS and WSS trackers.
+    #[arg(long = "tracker-ca", global = true)]
+    pub ca: Option<PathBuf>,
+    /// Present the certificate in this PEM file to HTTPS and WSS trackers, with
+    /// --tracker-key.
+    #[arg(long = "tracker-cert", global = true, requires = "key")]
+    pub cert: Option<PathBuf>,
+    /// The PKCS#8 PEM private key of --tracker-cert.
+    #[arg(long = "tracker-key", global = true, requires = "cert")]
+    pub key: Option<PathBuf>,
+    /// Don't verify the certificates of HTTPS and WSS trackers at all. Anyone on the network
+    /// path can then impersonate the tracker and read the passkey.
+    #[arg(long = "tracker-insecure", global = true)]
+    pub insecure: bool,
+}
+
+impl TrackerTls {
+    /// Configures `builder` to use these certificates.
+    pub fn apply(
+        &self,
+        mut builder: reqwest::ClientBuilder,
+    ) -> anyhow::Result<reqwest::ClientBuilder> {
+        if let Some(ca) = &self.ca {
+            let pem = read(ca, "tracker CA")?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("parse tracker CA {}", ca.display()))?;
+            if certs.is_empty() {
+                bail!("tracker CA {} has no certificates", ca.display());
+            }
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                let identity = reqwest::Identity::from_pkcs8_pem(
+                    &read(cert, "tracker certificate")?,
+                    &read(key, "tracker key")?,
+                )
+                .with_context(|| format!("load tracker certificate {}", cert.display()))?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => bail!("--tracker-cert and --tracker-key go together"),
+        }
+        if self.insecure {
+            warn!(
+                "--tracker-insecure: tracker certificates are not verified, anyone \
+                 on the network path can impersonate the tracker and read its passkey"
+            );
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+
+    /// A connector for `wss://` trackers using these certificates, as [`apply`] does for
+    /// HTTPS ones.
+    ///
+    /// [`apply`]: TrackerTls::apply
+    pub fn connector(&self) -> anyhow::Result<native_tls::TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ca) = &self.ca {
+            let pem = read(ca, "tracker CA")?;
+            let certs = pem_certificates(&pem);
+            if certs.is_empty() {
+                bail!("tracker CA {} has no certificates", ca.display());
+            }
+            for cert in certs {
+                let cert = native_tls::Certificate::from_pem(cert.as_bytes())
+                    .with_context(|| format!("parse tracker CA {}", ca.display()))?;
+                builder.add_root_certificate(cert);
+            }
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                let identity = native_tls::Identity::from_pkcs8(
+                    &read(cert, "tracker certificate")?,
+                    &read(key, "tracker key")?,
+                )
+                .with_context(|| format!("load tracker certificate {}", cert.display()))?;
+                builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => bail!("--tracker-cert and --tracker-key go together"),
+        }
+        // `apply` already warned about it
+        builder.danger_accept_invalid_certs(self.insecure);
+        builder
+            .build()
+            .context("configure the tracker TLS connector")
+    }
+}
+
+/// The PEM blocks of the certificates in a bundle, one per certificate.
+fn pem_certificates(pem: &[u8]) -> Vec<String> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let pem = String::from_utf8_lossy(pem);
+    let mut certs = Vec::new();
+    let mut rest = pem.as_ref();
+    while let Some(start) = rest.find(BEGIN) {
+        let Some(len) = rest[start..].find(END) else {
+            break;
+        };
+        let end = start + len + END.len();
+        certs.push(rest[start..end].to_string());
+        rest = &rest[end..];
+    }
+    certs
+}
+
+fn read(path: &Path, what: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("read {what} {}", path.display()))
+}
+
+/// Whether `err` is a TLS certificate being rejected, by the tracker or by us.
+///
+/// The TLS backends don't expose typed errors through reqwest, so this goes by the
+/// messages along the source chain, which all mention the certificate.
+pub fn is_certificate_error(err: &reqwest::Error) -> bool {
+    let mut source: Option<&dyn std::error::Error> = Some(err);
+    while let Some(err) = source {
+        if err.to_string().to_ascii_lowercase().contains("certificate") {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
@@ -0,0 +1,286 @@
+//! A bencoded value of any shape, for input that has no struct to deserialize into.
+
+use serde_bencode::value::Value as BencodeValue;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BenCode {
+    Int(i64),
+    /// A byte string, which needn't be UTF-8.
+    Bytes(Vec<u8>),
+    List(Vec<BenCode>),
+    Dict(HashMap<Vec<u8>, BenCode>),
+}
+
+impl BenCode {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BenCode::Int(int) => Some(*int),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BenCode::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// The value under `key`, if this is a dict that has it.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&BenCode> {
+        match self {
+            BenCode::Dict(dict) => dict.get(key.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// The value as JSON, byte strings as text if they are UTF-8 and as `{"hex": "..."}`
+    /// otherwise.
+    ///
+    /// Dict keys come out sorted; as JSON keys have to be text, those that aren't UTF-8
+    /// are in hex.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            BenCode::Int(int) => (*int).into(),
+            BenCode::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) => text.into(),
+                Err(_) => serde_json::json!({ "hex": hex::encode(bytes) }),
+            },
+            BenCode::List(values) => values.iter().map(BenCode::to_json).collect(),
+            BenCode::Dict(dict) => dict
+                .iter()
+                .map(|(key, value)| (key_to_json(key), value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+
+    /// The value's bencoding, see [`BenCode::write_to`].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        self.write_to(&mut encoded)
+            .expect("writing to a Vec can't fail");
+        encoded
+    }
+
+    /// Writes the value's canonical bencoding, with dict keys sorted by their raw bytes.
+    ///
+    /// Other clients hash what we encode, like an info dict, so the same value has to give
+    /// the same bytes whatever order the dict happens to iterate in.
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            BenCode::Int(int) => write!(out, "i{int}e"),
+            BenCode::Bytes(bytes) => write_bytes(out, bytes),
+            BenCode::List(values) => {
+                out.write_all(b"l")?;
+                for value in values {
+                    value.write_to(out)?;
+                }
+                out.write_all(b"e")
+            }
+            BenCode::Dict(dict) => {
+                let mut entries: Vec<_> = dict.iter().collect();
+                entries.sort_unstable_by_key(|&(key, _)| key);
+                out.write_all(b"d")?;
+                for (key, value) in entries {
+                    write_bytes(out, key)?;
+                    value.write_to(out)?;
+                }
+                out.write_all(b"e")
+            }
+        }
+    }
+}
+
+fn key_to_json(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(text) => text.to_string(),
+        Err(_) => hex::encode(key),
+    }
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    write!(out, "{}:", bytes.len())?;
+    out.write_all(bytes)
+}
+
+/// The value for people to read, with a dict entry per line and lists of scalars on one.
+///
+/// Byte strings that aren't UTF-8 show as `<hex>`, shortened if they are long, like piece
+/// hashes; the JSON of [`BenCode::to_json`] has them in full.
+impl Display for BenCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.write_readable(f, 0)
+    }
+}
+
+/// Non-UTF-8 byte strings longer than this are shortened when displayed.
+const SHOWN_BYTES: usize = 20;
+
+impl BenCode {
+    fn write_readable(&self, f: &mut Formatter<'_>, indent: usize) -> std::fmt::Result {
+        let pad = "  ";
+        match self {
+            BenCode::Int(int) => write!(f, "{int}"),
+            BenCode::Bytes(bytes) => write_readable_bytes(f, bytes),
+            BenCode::List(values) if values.iter().all(BenCode::is_scalar) => {
+                f.write_str("[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    value.write_readable(f, indent)?;
+                }
+                f.write_str("]")
+            }
+            BenCode::List(values) => {
+                f.write_str("[\n")?;
+                for value in values {
+                    write!(f, "{}", pad.repeat(indent + 1))?;
+                    value.write_readable(f, indent + 1)?;
+                    f.write_str("\n")?;
+                }
+                write!(f, "{}]", pad.repeat(indent))
+            }
+            BenCode::Dict(dict) if dict.is_empty() => f.write_str("{}"),
+            BenCode::Dict(dict) => {
+                let mut entries: Vec<_> = dict.iter().collect();
+                entries.sort_unstable_by_key(|&(key, _)| key);
+                f.write_str("{\n")?;
+                for (key, value) in entries {
+                    write!(f, "{}", pad.repeat(indent + 1))?;
+                    write_readable_bytes(f, key)?;
+                    f.write_str(": ")?;
+                    value.write_readable(f, indent + 1)?;
+                    f.write_str("\n")?;
+                }
+                write!(f, "{}}}", pad.repeat(indent))
+            }
+        }
+    }
+
+    fn is_scalar(&self) -> bool {
+        matches!(self, BenCode::Int(_) | BenCode::Bytes(_))
+    }
+}
+
+fn write_readable_bytes(f: &mut Formatter<'_>, bytes: &[u8]) -> std::fmt::Result {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => write!(f, "{text:?}"),
+        Err(_) if bytes.len() > SHOWN_BYTES => {
+            write!(f, "<{}… {} bytes>", hex::encode(&bytes[..8]), bytes.len())
+        }
+        Err(_) => write!(f, "<{}>", hex::encode(bytes)),
+    }
+}
+
+impl From<BencodeValue> for BenCode {
+    fn from(value: BencodeValue) -> Self {
+        match value {
+            BencodeValue::Int(int) => BenCode::Int(int),
+            BencodeValue::Bytes(bytes) => BenCode::Bytes(bytes),
+            BencodeValue::List(values) => {
+                BenCode::List(values.into_iter().map(BenCode::from).collect())
+            }
+            BencodeValue::Dict(dict) => BenCode::Dict(
+                dict.into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<BenCode> for BencodeValue {
+    fn from(value: BenCode) -> Self {
+        match value {
+            BenCode::Int(int) => BencodeValue::Int(int),
+            BenCode::Bytes(bytes) => BencodeValue::Bytes(bytes),
+            BenCode::List(values) => {
+                BencodeValue::List(values.into_iter().map(BencodeValue::from).collect())
+            }
+            BenCode::Dict(dict) => BencodeValue::Dict(
+                dict.into_iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::de::decode_cmd;
+
+    fn round_trip(encoded: &[u8]) -> Vec<u8> {
+        BenCode::from(decode_cmd(encoded).unwrap()).encode()
+    }
+
+    #[test]
+    fn canonical_input_encodes_back_to_the_same_bytes() {
+        for encoded in [
+            &b"i0e"[..],
+            b"i-42e",
+            b"0:",
+            b"4:\x00\xff\x00\xff",
+            b"le",
+            b"li1el1:aee",
+            b"de",
+            b"d2:\x00\xffi1e1:a0:4:spamld3:fooi9eeee",
+            b"d8:announce3:url4:infod6:lengthi3e4:name1:x12:piece lengthi16384e6:pieces20:\
+              \x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\xff\xfe\xfd\xfcee",
+        ] {
+            assert_eq!(round_trip(encoded), encoded, "{}", encoded.escape_ascii());
+        }
+    }
+
+    #[test]
+    fn dict_keys_are_sorted_by_their_raw_bytes() {
+        assert_eq!(
+            round_trip(b"d1:bi2e1:ai1e2:\xff\x00i4e1:Ai3ee"),
+            b"d1:Ai3e1:ai1e1:bi2e2:\xff\x00i4ee"
+        );
+        let dict: HashMap<Vec<u8>, BenCode> = (0..64u8)
+            .rev()
+            .map(|key| (vec![key], BenCode::Int(key.into())))
+            .collect();
+        let encoded = BenCode::Dict(dict).encode();
+        let mut expected = b"d".to_vec();
+        for key in 0..64u8 {
+            expected.extend([b'1', b':', key, b'i']);
+            expected.extend(key.to_string().bytes());
+            expected.push(b'e');
+        }
+        expected.push(b'e');
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn binary_values_come_out_as_hex_in_json() {
+        let value = BenCode::from(decode_cmd(b"d4:name3:abc6:pieces4:\x00\xff\x10\x80e").unwrap());
+        assert_eq!(
+            value.to_json().to_string(),
+            r#"{"name":"abc","pieces":{"hex":"00ff1080"}}"#
+        );
+        assert_eq!(
+            value.to_string(),
+            "{\n  \"name\": \"abc\"\n  \"pieces\": <00ff1080>\n}"
+        );
+    }
+
+    #[test]
+    fn deeply_nested_lists_keep_their_shape() {
+        let depth = 64;
+        let encoded = [&b"l".repeat(depth)[..], b"i1e", &b"e".repeat(depth)].concat();
+        let value = BenCode::from(decode_cmd(&encoded).unwrap());
+        let json = value.to_json().to_string();
+        assert_eq!(json, format!("{}1{}", "[".repeat(depth), "]".repeat(depth)));
+        assert_eq!(value.encode(), encoded);
+        let shown = BenCode::from(decode_cmd(b"lli1e1:aeli2eee").unwrap()).to_string();
+        assert_eq!(shown, "[\n  [1, \"a\"]\n  [2]\n]");
+    }
+}
@@ -0,0 +1,142 @@
+use crate::peer::MessageTag;
+use anyhow::Context;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// The log is rotated once it grows past this many bytes.
+const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How many leading payload bytes are dumped per frame.
+const DUMP_BYTES: usize = 16;
+
+/// The process-wide wire log, if one was requested.
+static WIRE_LOG: Mutex<Option<WireLog>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        })
+    }
+}
+
+/// Dumps every peer wire frame to a dedicated file, one line per frame.
+///
+/// This is independent of what we print on the console: the frame dump is far too verbose
+/// for normal operation, but invaluable when debugging protocol issues.
+struct WireLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+/// Starts logging frames to `path`, appending to it if it exists.
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let file = open(path)?;
+    let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+    *WIRE_LOG.lock().expect("wire log lock poisoned") = Some(WireLog {
+        path: path.to_path_buf(),
+        file,
+        size,
+    });
+    Ok(())
+}
+
+/// Records one frame sent to or received from `peer`, unless [`init`] was never called.
+///
+/// `id` is `None` for keep-alives.
+pub fn frame(peer: SocketAddr, direction: Direction, id: Option<u8>, payload: &[u8]) {
+    let mut guard = WIRE_LOG.lock().expect("wire log lock poisoned");
+    let Some(log) = guard.as_mut() else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let tag = id.map_or_else(|| "KeepAlive".to_string(), MessageTag::name_of);
+    let dump = hex::encode(&payload[..payload.len().min(DUMP_BYTES)]);
+    let line = format!(
+        "{}.{:03} {peer} {direction} {tag} len={} {dump}\n",
+        now.as_secs(),
+        now.subsec_millis(),
+        payload.len()
+    );
+    if let Err(err) = log.write(line.as_bytes()) {
+        warn!("wire log disabled: {err:#}");
+        *guard = None;
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open wire log {}", path.display()))
+}
+
+impl WireLog {
+    fn write(&mut self, line: &[u8]) -> anyhow::Result<()> {
+        if self.size + line.len() as u64 > MAX_SIZE {
+            self.rotate()?;
+        }
+        self.file.write_all(line).context("write wire log")?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Moves the current log to `<path>.1`, replacing the previous one, and starts afresh.
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        std::fs::rename(&self.path, &rotated).context("rotate wire log")?;
+        self.file = open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_full_log_is_rotated_before_the_line_that_would_overflow_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wire.log");
+        std::fs::write(&path, "old\n").unwrap();
+        let mut log = WireLog {
+            file: open(&path).unwrap(),
+            path: path.clone(),
+            size: MAX_SIZE - 4,
+        };
+        log.write(b"fits").unwrap();
+        log.write(b"new\n").unwrap();
+
+        assert_eq!(
+            std::fs::read(dir.path().join("wire.log.1")).unwrap(),
+            b"old\nfits"
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), b"new\n");
+        assert_eq!(log.size, 4);
+    }
+
+    #[test]
+    fn directions_display_as_in_and_out() {
+        assert_eq!(Direction::In.to_string(), "in");
+        assert_eq!(Direction::Out.to_string(), "out");
+    }
+}
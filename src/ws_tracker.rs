@@ -0,0 +1,321 @@
+use crate::peer::Peers;
+use crate::tracker::{TrackerRequest, TrackerResponse};
+use anyhow::{bail, Context};
+use futures_util::{SinkExt, StreamExt};
+use native_tls::TlsConnector;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::Connector;
+use tracing::debug;
+
+/// How long to wait for the tracker to answer our announce.
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A message from a WebTorrent-style tracker.
+///
+/// Besides announce responses, these trackers relay WebRTC offers and answers between
+/// peers, which is all some of them do.
+#[derive(Debug, Deserialize)]
+struct WsTrackerMessage {
+    action: Option<String>,
+    info_hash: Option<String>,
+    interval: Option<usize>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<usize>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    offer: Option<serde_json::Value>,
+    complete: Option<usize>,
+    incomplete: Option<usize>,
+    peers: Option<WsPeers>,
+}
+
+/// Trackers that hand out plain peers use either the dictionary model or the compact one.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum WsPeers {
+    Dicts(Vec<WsPeer>),
+    Compact(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct WsPeer {
+    ip: String,
+    port: u16,
+}
+
+/// Announces over a `ws://` or `wss://` tracker using the JSON protocol of WebTorrent trackers.
+///
+/// We don't speak WebRTC, so no offers are sent along; only trackers that also return plain
+/// TCP peers are of use, and those that don't are reported as an error. `wss://` trackers
+/// are checked with `tls`.
+pub async fn announce(
+    url: &reqwest::Url,
+    request: &TrackerRequest,
+    tls: &TlsConnector,
+) -> anyhow::Result<TrackerResponse> {
+    let response = tokio::time::timeout(ANNOUNCE_TIMEOUT, exchange(url, request, tls))
+        .await
+        .context("timed out waiting for the tracker")??;
+
+    let Some(peers) = response.peers else {
+        bail!("tracker only brokers WebRTC connections, which this client doesn't support");
+    };
+    let peers = match peers {
+        WsPeers::Dicts(peers) => peers
+            .into_iter()
+            .filter_map(|peer| match peer.ip.parse::<IpAddr>() {
+                Ok(ip) => Some(SocketAddr::new(ip.to_canonical(), peer.port)),
+                Err(_) => {
+                    debug!("skipping peer with unsupported address {}", peer.ip);
+                    None
+                }
+            })
+            .collect(),
+        WsPeers::Compact(compact) => {
+            Peers::from_compact(&string_to_binary(&compact)?)
+                .map_err(anyhow::Error::msg)
+                .context("parse compact peers")?
+                .0
+        }
+    };
+
+    Ok(TrackerResponse {
+        interval: response.interval.unwrap_or(120),
+        peers: Peers(peers),
+        peers6: None,
+        warning_message: response.warning_message,
+        min_interval: response.min_interval,
+        tracker_id: None,
+        complete: response.complete,
+        incomplete: response.incomplete,
+    })
+}
+
+/// Connects to the tracker, sends our announce and waits for the answer to it.
+async fn exchange(
+    url: &reqwest::Url,
+    request: &TrackerRequest,
+    tls: &TlsConnector,
+) -> anyhow::Result<WsTrackerMessage> {
+    let (mut socket, _) = tokio_tungstenite::connect_async_tls_with_config(
+        url.as_str(),
+        None,
+        false,
+        Some(Connector::NativeTls(tls.clone())),
+    )
+    .await
+    .context("connect to websocket tracker")?;
+
+    let info_hash = binary_to_string(&request.info_hash);
+    let mut announce = json!({
+        "action": "announce",
+        "info_hash": info_hash,
+        "peer_id": binary_to_string(&request.peer_id),
+        "port": request.port,
+        "uploaded": request.uploaded,
+        "downloaded": request.downloaded,
+        "left": request.left,
+        "numwant": request.numwant.unwrap_or(50),
+        "offers": [],
+    });
+    if let Some(event) = request.event {
+        announce["event"] = json!(event);
+    }
+    socket
+        .send(WsMessage::Text(announce.to_string()))
+        .await
+        .context("send announce")?;
+
+    while let Some(message) = socket.next().await {
+        let text = match message.context("read tracker message")? {
+            WsMessage::Text(text) => text,
+            WsMessage::Close(_) => break,
+            _ => continue,
+        };
+        let message: WsTrackerMessage =
+            serde_json::from_str(&text).context("parse tracker message")?;
+        if message.action.as_deref() != Some("announce")
+            || message.info_hash.as_deref() != Some(info_hash.as_str())
+        {
+            continue;
+        }
+        if let Some(reason) = message.failure_reason {
+            bail!("tracker refused announce: {reason}");
+        }
+        if message.offer.is_some() {
+            // another peer trying to reach us over WebRTC
+            continue;
+        }
+        let _ = socket.close(None).await;
+        return Ok(message);
+    }
+    bail!("tracker closed the connection without answering the announce")
+}
+
+/// WebTorrent trackers carry binary values in JSON strings, one char per byte.
+fn binary_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+fn string_to_binary(s: &str) -> anyhow::Result<Vec<u8>> {
+    s.chars()
+        .map(|c| u8::try_from(c).with_context(|| format!("{c:?} is not a byte")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_id::PeerId;
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+
+    const INFO_HASH: [u8; 20] = [0xab; 20];
+
+    fn request() -> TrackerRequest {
+        TrackerRequest::new(INFO_HASH, PeerId::generate(), 6881, 1000)
+    }
+
+    fn tls() -> TlsConnector {
+        TlsConnector::new().unwrap()
+    }
+
+    async fn announce_to(url: &reqwest::Url, request: &TrackerRequest) -> TrackerResponse {
+        announce(url, request, &tls()).await.unwrap()
+    }
+
+    /// A tracker on loopback that answers our announce with `replies`, and hands out the
+    /// announce it got.
+    async fn tracker(replies: Vec<Value>) -> (reqwest::Url, oneshot::Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/announce", listener.local_addr().unwrap());
+        let (announced, announce) = oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let Some(Ok(WsMessage::Text(text))) = socket.next().await else {
+                panic!("no announce");
+            };
+            let _ = announced.send(serde_json::from_str(&text).unwrap());
+            for reply in replies {
+                socket
+                    .send(WsMessage::Text(reply.to_string()))
+                    .await
+                    .unwrap();
+            }
+            // wait for the client to hang up
+            while let Some(Ok(_)) = socket.next().await {}
+        });
+        (url.parse().unwrap(), announce)
+    }
+
+    fn answer(extra: Value) -> Value {
+        let mut answer = json!({
+            "action": "announce",
+            "info_hash": binary_to_string(&INFO_HASH),
+            "interval": 300,
+            "complete": 3,
+            "incomplete": 1,
+        });
+        answer
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        answer
+    }
+
+    #[tokio::test]
+    async fn the_announce_carries_binary_strings_and_no_offers() {
+        let (url, announce) = tracker(vec![answer(json!({ "peers": [] }))]).await;
+        let request = request();
+        announce_to(&url, &request).await;
+        let announce = announce.await.unwrap();
+        assert_eq!(announce["action"], "announce");
+        assert_eq!(announce["info_hash"], binary_to_string(&INFO_HASH));
+        assert_eq!(
+            string_to_binary(announce["peer_id"].as_str().unwrap()).unwrap(),
+            request.peer_id
+        );
+        assert_eq!(announce["port"], 6881);
+        assert_eq!(announce["left"], 1000);
+        assert_eq!(announce["offers"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn dictionary_peers_are_mapped_into_the_response() {
+        let peers = json!([
+            { "ip": "10.0.0.1", "port": 6881 },
+            { "ip": "::ffff:10.0.0.2", "port": 6882 },
+            { "ip": "not an address", "port": 1 },
+        ]);
+        let (url, _) = tracker(vec![answer(json!({ "peers": peers }))]).await;
+        let response = announce_to(&url, &request()).await;
+        assert_eq!(
+            response.peers.0,
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+        assert_eq!(response.interval, 300);
+        assert_eq!((response.complete, response.incomplete), (Some(3), Some(1)));
+    }
+
+    #[tokio::test]
+    async fn compact_peers_are_mapped_into_the_response() {
+        let compact = binary_to_string(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        let (url, _) = tracker(vec![answer(json!({ "peers": compact }))]).await;
+        let response = announce_to(&url, &request()).await;
+        assert_eq!(response.peers.0, ["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn offers_and_other_torrents_are_skipped() {
+        let other = json!({
+            "action": "announce",
+            "info_hash": binary_to_string(&[1; 20]),
+            "peers": [{ "ip": "10.0.0.9", "port": 1 }],
+        });
+        let offer = answer(json!({ "offer": { "type": "offer", "sdp": "" } }));
+        let ours = answer(json!({ "peers": [{ "ip": "10.0.0.1", "port": 6881 }] }));
+        let (url, _) = tracker(vec![other, offer, ours]).await;
+        let response = announce_to(&url, &request()).await;
+        assert_eq!(response.peers.0, ["10.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn a_failure_reason_is_an_error() {
+        let (url, _) = tracker(vec![answer(json!({ "failure reason": "unregistered" }))]).await;
+        let err = announce(&url, &request(), &tls()).await.unwrap_err();
+        assert_eq!(format!("{err:#}"), "tracker refused announce: unregistered");
+    }
+
+    #[tokio::test]
+    async fn a_webrtc_only_tracker_is_an_error() {
+        let (url, _) = tracker(vec![answer(json!({}))]).await;
+        let err = announce(&url, &request(), &tls()).await.unwrap_err();
+        assert!(err.to_string().contains("WebRTC"), "{err}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_tracker_that_never_upgrades_the_connection_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/announce", listener.local_addr().unwrap());
+        let silent = tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let err = announce(&url.parse().unwrap(), &request(), &tls())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{err}");
+        silent.abort();
+    }
+}
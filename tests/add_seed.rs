@@ -0,0 +1,149 @@
+//! Seeding a multi-file torrent from data that is already on disk, laid out with or without
+//! the torrent's name folder.
+
+mod common;
+
+use bittorrent_starter_rust::client::{DownloadOptions, Limits};
+use bittorrent_starter_rust::create::{fixture_data, TorrentBuilder};
+use bittorrent_starter_rust::peer::Bitfield;
+use bittorrent_starter_rust::seed::PieceMap;
+use bittorrent_starter_rust::torrent::{Metainfo, Torrent};
+use common::Seed;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tokio_util::sync::CancellationToken;
+
+const FILES: [(&[&str], usize); 3] = [
+    (&["a.txt"], 1000),
+    (&["Sub", "b.bin"], 40_000),
+    (&["c.bin"], 7),
+];
+
+/// A padded torrent of [`FILES`] named `album`, and the content of each file.
+fn album() -> (Torrent, Vec<Vec<u8>>) {
+    let files = FILES
+        .iter()
+        .map(|(path, len)| (path.iter().map(|c| c.to_string()).collect(), *len))
+        .collect();
+    let builder = TorrentBuilder::multi_file("album", files, 16384)
+        .creation_date(0)
+        .pad_files(true);
+    let data = fixture_data(builder.content_length(), 4);
+    let torrent = builder.build(data.as_slice()).unwrap();
+    let mut rest = data.as_slice();
+    let contents = FILES
+        .iter()
+        .map(|(_, len)| {
+            let (content, tail) = rest.split_at(*len);
+            rest = tail;
+            content.to_vec()
+        })
+        .collect();
+    (torrent, contents)
+}
+
+/// Writes the files of the album below `root`, with their folder names in lower case as
+/// another system might have.
+fn write_files(root: &Path, contents: &[Vec<u8>]) {
+    for ((path, _), content) in FILES.iter().zip(contents) {
+        let path = root.join(path.join("/").to_lowercase());
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+}
+
+/// Runs `add_seed` on the data at `data` and checks what it printed, returning the piece map
+/// it wrote.
+async fn add_seed(dir: &TempDir, torrent: &Torrent, data: &Path) -> PathBuf {
+    let torrent_path = common::torrent_file(dir.path(), torrent);
+    let pieces = dir.path().join("album.pieces");
+    let assert = common::run([
+        OsString::from("add_seed"),
+        "--data".into(),
+        data.into(),
+        "--pieces".into(),
+        pieces.clone().into(),
+        torrent_path.clone().into(),
+    ])
+    .await
+    .success();
+    let stdout = String::from_utf8(assert.get_output().stdout.clone()).unwrap();
+    let npieces = torrent.declared_pieces();
+    assert!(
+        stdout.contains(&format!("Matching: {npieces} of {npieces} pieces\n")),
+        "{stdout}"
+    );
+    assert!(
+        stdout.ends_with(&format!(
+            "Seed with: seed --data {} --pieces {} {}\n",
+            data.display(),
+            pieces.display(),
+            torrent_path.display()
+        )),
+        "{stdout}"
+    );
+    pieces
+}
+
+/// Seeds `torrent` from `data` with the pieces in `pieces`, downloads it from the seed and
+/// returns the downloaded files.
+async fn download_from_seed(torrent: &Torrent, data: PathBuf, pieces: &Path) -> Vec<Vec<u8>> {
+    let have = PieceMap::load(pieces, torrent).unwrap().unwrap();
+    assert_eq!(have, Bitfield::full(torrent.declared_pieces()));
+    let seed = Seed::serve(
+        torrent,
+        data,
+        have,
+        Limits::default(),
+        tempfile::tempdir().unwrap(),
+    )
+    .await;
+    let output = tempfile::tempdir().unwrap();
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        ..DownloadOptions::default()
+    };
+    common::client()
+        .download(
+            torrent,
+            output.path(),
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    FILES
+        .iter()
+        .map(|(path, _)| {
+            let path = path
+                .iter()
+                .fold(output.path().join("album"), |p, c| p.join(c));
+            std::fs::read(path).unwrap()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn data_in_the_name_folder_is_recorded_and_seeded() {
+    let (torrent, contents) = album();
+    let dir = tempfile::tempdir().unwrap();
+    let data = dir.path().join("downloads");
+    write_files(&data.join("album"), &contents);
+
+    let pieces = add_seed(&dir, &torrent, &data).await;
+    assert_eq!(download_from_seed(&torrent, data, &pieces).await, contents);
+}
+
+#[tokio::test]
+async fn data_without_the_name_folder_is_recorded_and_seeded() {
+    let (torrent, contents) = album();
+    let dir = tempfile::tempdir().unwrap();
+    // the files right in a folder named otherwise
+    let data = dir.path().join("Album (2019)");
+    write_files(&data, &contents);
+
+    let pieces = add_seed(&dir, &torrent, &data).await;
+    assert_eq!(download_from_seed(&torrent, data, &pieces).await, contents);
+}
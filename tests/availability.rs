@@ -0,0 +1,122 @@
+//! `availability` against mock peers announcing constructed bitfields.
+
+mod common;
+
+use bittorrent_starter_rust::peer::Handshake;
+use bittorrent_starter_rust::torrent::Torrent;
+use common::MockTracker;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const PLENGTH: usize = 16384;
+const NPIECES: usize = 10;
+
+fn frame(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (1 + payload.len() as u32).to_be_bytes().to_vec();
+    frame.push(id);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn have(index: u32) -> Vec<u8> {
+    frame(4, &index.to_be_bytes())
+}
+
+fn bitfield(bytes: [u8; 2]) -> Vec<u8> {
+    frame(5, &bytes)
+}
+
+const HAVE_ALL: [u8; 5] = [0, 0, 0, 1, 14];
+
+/// A peer that answers the handshake for `torrent`, sends `frames` and hangs up.
+async fn peer(torrent: &Torrent, frames: Vec<Vec<u8>>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = torrent.info_hash();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut theirs = [0; Handshake::LEN];
+        stream.read_exact(&mut theirs).await.unwrap();
+        let ours = Handshake::new(info_hash, *b"-MK0001-availability", false);
+        stream.write_all(&ours.to_bytes()).await.unwrap();
+        for frame in frames {
+            stream.write_all(&frame).await.unwrap();
+        }
+    });
+    addr
+}
+
+/// The swarm of the tests, of which piece 0 has three sources, pieces 1 to 4 and 7 two,
+/// and the others one.
+async fn swarm(torrent: &Torrent) -> MockTracker {
+    let peers = [
+        // pieces 0 to 4
+        peer(torrent, vec![bitfield([0b1111_1000, 0])]).await,
+        // everything, keep-alive first
+        peer(torrent, vec![vec![0; 4], HAVE_ALL.to_vec()]).await,
+        // pieces 7 and 0, one at a time after an empty bitfield
+        peer(torrent, vec![bitfield([0, 0]), have(7), have(0)]).await,
+        // a peer that says nothing
+        peer(torrent, Vec::new()).await,
+    ];
+    MockTracker::start(&peers).await
+}
+
+#[tokio::test]
+async fn the_report_counts_the_sources_of_each_piece() {
+    let torrent = Torrent::fixture_single_file(NPIECES * PLENGTH, PLENGTH);
+    let tracker = swarm(&torrent).await;
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+
+    let assert = common::run([
+        "--announce".into(),
+        tracker.url.clone().into(),
+        "availability".into(),
+        torrent_path.into_os_string(),
+    ])
+    .await
+    .success();
+    assert_eq!(
+        String::from_utf8_lossy(&assert.get_output().stdout),
+        "Peers: 3 of 4 reported their pieces
+Pieces: 10
+  no source: 0
+  1 source: 4
+  2+ sources: 6
+Distributed copies: 1.600
+"
+    );
+}
+
+#[tokio::test]
+async fn the_json_report_has_the_whole_histogram() {
+    let torrent = Torrent::fixture_single_file(NPIECES * PLENGTH, PLENGTH);
+    let tracker = swarm(&torrent).await;
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+
+    let assert = common::run([
+        "--announce".into(),
+        tracker.url.clone().into(),
+        "availability".into(),
+        "--json".into(),
+        "--sample".into(),
+        "4".into(),
+        torrent_path.into_os_string(),
+    ])
+    .await
+    .success();
+    let report: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert_eq!(
+        report,
+        serde_json::json!({
+            "peers_tried": 4,
+            "peers_reported": 3,
+            "pieces": 10,
+            "histogram": [0, 4, 5, 1],
+            "distributed_copies": 1.6,
+        })
+    );
+}
@@ -0,0 +1,418 @@
+//! The binary end to end, against a mock tracker and seed on loopback, to catch breakage
+//! in the glue between argument parsing and what each command does.
+
+mod common;
+
+use bittorrent_starter_rust::peer::Handshake;
+use bittorrent_starter_rust::torrent::Torrent;
+use common::{MockTracker, Seed};
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket};
+
+const PLENGTH: usize = 16384;
+const LEN: usize = 3 * PLENGTH + 100;
+
+fn stdout(assert: &assert_cmd::assert::Assert) -> String {
+    String::from_utf8(assert.get_output().stdout.clone()).unwrap()
+}
+
+fn stderr(assert: &assert_cmd::assert::Assert) -> String {
+    String::from_utf8_lossy(&assert.get_output().stderr).into_owned()
+}
+
+/// The fixture torrent written to a fresh directory.
+fn torrent_file() -> (TempDir, Torrent, PathBuf) {
+    let torrent = Torrent::fixture_single_file(LEN, PLENGTH);
+    let dir = tempfile::tempdir().unwrap();
+    let path = common::torrent_file(dir.path(), &torrent);
+    (dir, torrent, path)
+}
+
+#[tokio::test]
+async fn decode_prints_bencode_as_json() {
+    let assert = common::run(["decode", "5:hello"]).await.success();
+    assert_eq!(stdout(&assert), "\"hello\"\n");
+    let assert = common::run(["decode", "i-52e"]).await.success();
+    assert_eq!(stdout(&assert), "-52\n");
+    let assert = common::run(["decode", "--json", "d3:foo3:bar5:helloli1ei2eee"])
+        .await
+        .success();
+    assert_eq!(stdout(&assert), "{\"foo\":\"bar\",\"hello\":[1,2]}\n");
+
+    let assert = common::run(["decode", "i52"]).await.code(1);
+    assert!(stdout(&assert).is_empty());
+    assert!(
+        stderr(&assert).starts_with("Error: "),
+        "{}",
+        stderr(&assert)
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn decode_passes_binary_strings_through_as_hex() {
+    use std::os::unix::ffi::OsStringExt;
+    let encoded = OsString::from_vec(b"d4:spaml2:\xfe\xffli1eeee".to_vec());
+    let assert = common::run([OsString::from("decode"), "--json".into(), encoded])
+        .await
+        .success();
+    assert_eq!(stdout(&assert), "{\"spam\":[{\"hex\":\"feff\"},[1]]}\n");
+}
+
+#[tokio::test]
+async fn info_prints_the_torrent() {
+    let (_dir, torrent, path) = torrent_file();
+    let assert = common::run([OsString::from("info"), path.into()])
+        .await
+        .success();
+    let hashes: String = torrent
+        .info
+        .pieces
+        .0
+        .iter()
+        .map(|hash| format!("{}\n", hex::encode(hash)))
+        .collect();
+    assert_eq!(
+        stdout(&assert),
+        format!(
+            "Tracker URL: http://127.0.0.1:6969/announce
+Name: fixture.bin
+Length: {LEN}
+Info Hash: {}
+Piece Length: {PLENGTH}
+Piece Hashes:
+{hashes}",
+            hex::encode(torrent.info_hash())
+        )
+    );
+
+    let assert = common::run(["info", "/nonexistent/fixture.torrent"])
+        .await
+        .code(1);
+    assert!(stdout(&assert).is_empty());
+}
+
+#[tokio::test]
+async fn peers_lists_what_the_tracker_hands_out() {
+    let (_dir, _, path) = torrent_file();
+    let peers = [
+        "10.0.0.1:6881".parse().unwrap(),
+        "10.0.0.2:51413".parse().unwrap(),
+    ];
+    let tracker = MockTracker::start(&peers).await;
+    let assert = common::run([
+        OsString::from("--announce"),
+        tracker.url.clone().into(),
+        "peers".into(),
+        path.clone().into(),
+    ])
+    .await
+    .success();
+    assert_eq!(stdout(&assert), "10.0.0.1:6881\n10.0.0.2:51413\n");
+    assert_eq!(tracker.requests().len(), 1);
+
+    // --peer adds to the tracker's peers
+    let assert = common::run([
+        OsString::from("--announce"),
+        tracker.url.clone().into(),
+        "--peer".into(),
+        "10.0.0.3:1".into(),
+        "peers".into(),
+        path.into(),
+    ])
+    .await
+    .success();
+    assert!(
+        stdout(&assert).contains("10.0.0.3:1\n"),
+        "{}",
+        stdout(&assert)
+    );
+}
+
+#[tokio::test]
+async fn a_peer_id_given_is_announced_as_is() {
+    let (_dir, _, path) = torrent_file();
+    let tracker = MockTracker::start(&[]).await;
+    common::run([
+        OsString::from("--announce"),
+        tracker.url.clone().into(),
+        "--peer-id".into(),
+        "-XX0000-0123456789ab".into(),
+        "peers".into(),
+        path.clone().into(),
+    ])
+    .await
+    .success();
+    let request = &tracker.requests()[0];
+    assert!(
+        request.contains("&peer_id=-XX0000-0123456789ab&"),
+        "{request}"
+    );
+
+    let assert = common::run([
+        OsString::from("--peer-id"),
+        "-XX0000-0123".into(),
+        "peers".into(),
+        path.into(),
+    ])
+    .await
+    .code(2);
+    assert!(
+        stderr(&assert).contains("a peer id is 20 bytes, not 12"),
+        "{}",
+        stderr(&assert)
+    );
+}
+
+#[tokio::test]
+async fn a_tracker_that_cant_be_reached_is_a_failure() {
+    let (_dir, _, path) = torrent_file();
+    // nothing listens on a port we just let go of
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/announce", closed.local_addr().unwrap());
+    drop(closed);
+    let assert = common::run([
+        OsString::from("--announce"),
+        url.into(),
+        "peers".into(),
+        path.into(),
+    ])
+    .await
+    .code(1);
+    assert!(stdout(&assert).is_empty());
+}
+
+#[tokio::test]
+async fn handshake_reports_the_seeds_peer_id() {
+    let (_dir, torrent, path) = torrent_file();
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(LEN)).await;
+    let assert = common::run([
+        OsString::from("handshake"),
+        path.into(),
+        seed.addr.to_string().into(),
+    ])
+    .await
+    .success();
+    let stdout = stdout(&assert);
+    let mut lines = stdout.lines();
+    assert_eq!(
+        lines.next(),
+        Some(format!("Handshake with peer_ip: {}", seed.addr).as_str())
+    );
+    let peer_id = lines.next().unwrap().strip_prefix("Peer ID: ").unwrap();
+    assert_eq!(peer_id.len(), 40, "{peer_id}");
+    assert!(lines.next().unwrap().starts_with("Connect: "));
+    assert!(lines.next().unwrap().starts_with("Handshake: "));
+    assert!(
+        lines.next().unwrap().starts_with("First message: bitfield"),
+        "{stdout}"
+    );
+}
+
+#[tokio::test]
+async fn download_piece_writes_the_piece_to_the_output() {
+    let (dir, torrent, path) = torrent_file();
+    let data = Torrent::fixture_data(LEN);
+    let seed = Seed::start(&torrent, &data).await;
+    let tracker = MockTracker::start(&[seed.addr]).await;
+    let output = dir.path().join("piece.bin");
+    let assert = common::run([
+        OsString::from("--announce"),
+        tracker.url.clone().into(),
+        "download_piece".into(),
+        "-o".into(),
+        output.clone().into(),
+        path.clone().into(),
+        "3".into(),
+    ])
+    .await
+    .success();
+    assert!(
+        stdout(&assert).starts_with(&format!("Piece 3 downloaded to {}.\n", output.display())),
+        "{}",
+        stdout(&assert)
+    );
+    assert_eq!(std::fs::read(&output).unwrap(), data[3 * PLENGTH..]);
+
+    // a piece the torrent doesn't have
+    let assert = common::run([
+        OsString::from("--announce"),
+        tracker.url.clone().into(),
+        "download_piece".into(),
+        "-o".into(),
+        dir.path().join("other.bin").into(),
+        path.into(),
+        "4".into(),
+    ])
+    .await
+    .code(1);
+    assert!(stdout(&assert).is_empty());
+    assert!(!dir.path().join("other.bin").exists());
+}
+
+#[tokio::test]
+async fn bad_arguments_are_a_usage_error() {
+    let assert = common::run(["download_piece", "fixture.torrent", "0"])
+        .await
+        .code(2);
+    assert!(
+        stderr(&assert).contains("-o <OUTPUT>"),
+        "{}",
+        stderr(&assert)
+    );
+    let assert = common::run(["download-piece", "-o", "x", "fixture.torrent", "0"])
+        .await
+        .code(2);
+    assert!(
+        stderr(&assert).contains("unrecognized subcommand 'download-piece'"),
+        "{}",
+        stderr(&assert)
+    );
+}
+
+#[tokio::test]
+async fn seed_on_a_taken_port_fails_before_announcing() {
+    let (_dir, _, path) = torrent_file();
+    let data = common::data_file("fixture.bin", &Torrent::fixture_data(LEN));
+    let tracker = MockTracker::start(&[]).await;
+    let taken = TcpListener::bind("0.0.0.0:0").await.unwrap();
+    let port = taken.local_addr().unwrap().port();
+    let assert = common::run([
+        OsString::from("--announce"),
+        tracker.url.clone().into(),
+        "seed".into(),
+        "--data".into(),
+        data.1.into(),
+        "--port".into(),
+        port.to_string().into(),
+        path.into(),
+    ])
+    .await
+    .code(1);
+    assert!(
+        stderr(&assert).contains(&format!("port {port} is already in use")),
+        "{}",
+        stderr(&assert)
+    );
+    assert!(tracker.requests().is_empty());
+}
+
+#[tokio::test]
+async fn a_seed_whose_connections_all_reset_announces_again() {
+    let (_dir, torrent, path) = torrent_file();
+    let data = common::data_file("fixture.bin", &Torrent::fixture_data(LEN));
+    let tracker = MockTracker::start(&[]).await;
+    let port = {
+        let free = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        free.local_addr().unwrap().port()
+    };
+    let mut seed =
+        tokio::process::Command::new(assert_cmd::cargo::cargo_bin("bittorrent-starter-rust"))
+            .arg("--allow-local-trackers")
+            .arg("--announce")
+            .arg(&tracker.url)
+            .arg("seed")
+            .arg("--data")
+            .arg(&data.1)
+            .args(["--port", &port.to_string()])
+            .args(["--rebind-after", "3", "--rebind-window", "5s"])
+            .arg(path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+    let tracker = &tracker;
+    let announces = |count| async move {
+        while tracker.requests().len() < count {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(20), announces(1))
+        .await
+        .expect("the seed announced");
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let mut peers = Vec::new();
+    for last in 30..33 {
+        // a few connections per address are allowed
+        let socket = TcpSocket::new_v4().unwrap();
+        socket
+            .bind(SocketAddr::new(IpAddr::from([127, 0, 0, last]), 0))
+            .unwrap();
+        let mut stream = socket.connect(addr).await.unwrap();
+        let handshake = Handshake::new(torrent.info_hash(), [last; 20], false);
+        stream.write_all(&handshake.to_bytes()).await.unwrap();
+        let mut answer = [0; Handshake::LEN];
+        stream.read_exact(&mut answer).await.unwrap();
+        peers.push(stream);
+    }
+    for stream in peers {
+        // closing with a zero linger resets the connection, as a dead network does
+        stream.set_zero_linger().unwrap();
+    }
+    // the tracker's interval is half an hour away
+    tokio::time::timeout(Duration::from_secs(5), announces(2))
+        .await
+        .expect("the seed announced again within the window");
+    assert!(seed.try_wait().unwrap().is_none(), "the seed kept running");
+}
+
+#[tokio::test]
+async fn download_piece_bans_a_peer_with_bad_data_and_counts_it() {
+    let (dir, torrent, path) = torrent_file();
+    let poisoned = Seed::start(&torrent, &vec![0xbd; LEN]).await;
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(LEN)).await;
+    let tracker = MockTracker::start(&[poisoned.addr, seed.addr]).await;
+    let assert = common::run([
+        OsString::from("--announce"),
+        tracker.url.clone().into(),
+        "download_piece".into(),
+        "--json".into(),
+        "-o".into(),
+        dir.path().join("piece.bin").into(),
+        path.into(),
+        "0".into(),
+    ])
+    .await
+    .success();
+    let summary: serde_json::Value = serde_json::from_str(&stdout(&assert)).unwrap();
+    assert_eq!(
+        summary["peers"],
+        serde_json::json!({ "tried": 2, "connected": 2, "banned": 1 })
+    );
+    assert_eq!(summary["hash_failures"], 1);
+}
+
+#[tokio::test]
+async fn download_streams_the_data_to_stdout_in_order() {
+    let (dir, torrent, path) = torrent_file();
+    let data = Torrent::fixture_data(LEN);
+    let seed = Seed::start(&torrent, &data).await;
+    let tracker = MockTracker::start(&[seed.addr]).await;
+    let assert = common::run([
+        OsString::from("--announce"),
+        tracker.url.clone().into(),
+        "download".into(),
+        "--stream".into(),
+        "--readahead".into(),
+        "2".into(),
+        "-o".into(),
+        dir.path().join("out.bin").into(),
+        path.into(),
+    ])
+    .await
+    .success();
+    assert_eq!(assert.get_output().stdout, data);
+    assert!(
+        stderr(&assert).contains("1 tried, 1 connected"),
+        "{}",
+        stderr(&assert)
+    );
+}
@@ -0,0 +1,1245 @@
+//! The library API against a seed on loopback.
+
+mod common;
+
+use bittorrent_starter_rust::client::{self, Client, PeerId, TransferStats};
+use bittorrent_starter_rust::client::{
+    DownloadEvent, DownloadOptions, DrainRate, Limits, PickContext, PickOrder, PiecePicker,
+    Priority,
+};
+use bittorrent_starter_rust::create::{fixture_data, TorrentBuilder};
+use bittorrent_starter_rust::extension::{BencodeDict, ExtendedHandshake};
+use bittorrent_starter_rust::peer::{Bitfield, Handshake, MessageFramer, MessagePayload};
+use bittorrent_starter_rust::peer_pool::{MAX_PEER_RESTARTS, PEER_RESTART_BACKOFF};
+use bittorrent_starter_rust::peer_session;
+use bittorrent_starter_rust::picker::{RandomPicker, Sequential};
+use bittorrent_starter_rust::stats::PeerCounts;
+use bittorrent_starter_rust::torrent::{Metainfo, Torrent};
+use bittorrent_starter_rust::tracker::TrackerResponse;
+use common::{MockTracker, Seed};
+use futures_util::{SinkExt, StreamExt};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
+
+/// Picks the last wanted piece first, and remembers what it picked.
+struct Backwards(Arc<Mutex<Vec<usize>>>);
+
+impl PiecePicker for Backwards {
+    fn pick(&mut self, ctx: &PickContext) -> Option<usize> {
+        let index = (0..ctx.have.len()).rev().find(|&index| {
+            !ctx.have[index] && ctx.peer_has[index] && ctx.priorities[index] != Priority::Skip
+        })?;
+        self.0.lock().unwrap().push(index);
+        Some(index)
+    }
+}
+
+/// Picks what the picker it wraps does, and remembers each pick with the first piece that
+/// was missing when it was made.
+struct Recording<P>(P, Arc<Mutex<Vec<(usize, usize)>>>);
+
+impl<P: PiecePicker> PiecePicker for Recording<P> {
+    fn pick(&mut self, ctx: &PickContext) -> Option<usize> {
+        let index = self.0.pick(ctx)?;
+        let first_missing = ctx.have.iter().position(|&have| !have).unwrap();
+        self.1.lock().unwrap().push((index, first_missing));
+        Some(index)
+    }
+}
+
+#[tokio::test]
+async fn download_fetches_pieces_in_the_order_of_an_injected_picker() {
+    let torrent = Torrent::fixture_single_file(5 * 16384 + 10, 16384);
+    let data = Torrent::fixture_data(5 * 16384 + 10);
+    let seed = Seed::start(&torrent, &data).await;
+    let picks = Arc::new(Mutex::new(Vec::new()));
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        pick: PickOrder::Custom(Arc::new({
+            let picks = Arc::clone(&picks);
+            move || Box::new(Backwards(Arc::clone(&picks)))
+        })),
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+
+    common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(*picks.lock().unwrap(), [5, 4, 3, 2, 1, 0]);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+}
+
+#[tokio::test]
+async fn download_piece_fetches_pieces_of_any_size_a_builder_makes() {
+    // a piece length that isn't a multiple of the block size, and a short last piece
+    let (len, plength) = (3 * 20_000 + 123, 20_000);
+    let data = fixture_data(len, 5);
+    let torrent = TorrentBuilder::single_file("odd.bin", len, plength)
+        .creation_date(0)
+        .build(data.as_slice())
+        .unwrap();
+    let seed = Seed::start(&torrent, &data).await;
+
+    let client = common::client();
+    let mut stats = TransferStats::new(len);
+    let mut connection = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    let mut downloaded = Vec::new();
+    for index in 0..torrent.declared_pieces() {
+        let piece = client
+            .download_piece(&torrent, &mut connection, index, &mut stats)
+            .await
+            .unwrap()
+            .finish(torrent.piece_hash(index).unwrap(), true, &mut stats)
+            .unwrap();
+        downloaded.extend(piece);
+    }
+    assert_eq!(downloaded, data);
+    assert_eq!(stats.left(), 0);
+    let err = client
+        .download_piece(&torrent, &mut connection, 4, &mut stats)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("out of range"), "{err}");
+}
+
+#[tokio::test]
+async fn download_piece_fails_for_a_piece_the_peer_lacks_and_the_connection_goes_on() {
+    let len = 2 * 16384;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let data = Torrent::fixture_data(len);
+    let mut have = Bitfield::new(2);
+    have.set_piece(1);
+    let seed = Seed::start_with(&torrent, &data, have).await;
+
+    let client = common::client();
+    let mut stats = TransferStats::new(len);
+    let mut connection = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    let err = client
+        .download_piece(&torrent, &mut connection, 0, &mut stats)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("doesn't have piece 0"), "{err}");
+    let piece = client
+        .download_piece(&torrent, &mut connection, 1, &mut stats)
+        .await
+        .unwrap()
+        .finish(torrent.piece_hash(1).unwrap(), true, &mut stats)
+        .unwrap();
+    assert_eq!(piece, data[16384..]);
+}
+
+#[tokio::test]
+async fn download_writes_the_files_of_a_seed_and_keeps_them_on_a_second_run() {
+    let files = vec![
+        (vec!["a.txt".to_string()], 20_000),
+        (vec!["sub".to_string(), "b.bin".to_string()], 50_000),
+        (vec!["c".to_string()], 1),
+    ];
+    let builder = TorrentBuilder::multi_file("set", files, 32768).creation_date(0);
+    let data = fixture_data(builder.content_length(), 9);
+    let torrent = builder.build(data.as_slice()).unwrap();
+    let seed = Seed::start(&torrent, &data).await;
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        ..DownloadOptions::default()
+    };
+    let output = tempfile::tempdir().unwrap();
+    let (client, cancel) = (common::client(), CancellationToken::new());
+    let download = || client.download(&torrent, output.path(), &options, |_| {}, &cancel);
+
+    let outcome = download().await.unwrap();
+    assert_eq!(outcome.connected, 1);
+    assert_eq!(outcome.stats.left(), 0);
+    let read = |path: &str| std::fs::read(output.path().join("set").join(path)).unwrap();
+    assert_eq!(read("a.txt"), data[..20_000]);
+    assert_eq!(read("sub/b.bin"), data[20_000..70_000]);
+    assert_eq!(read("c"), data[70_000..]);
+
+    let outcome = download().await.unwrap();
+    assert_eq!(
+        outcome.connected, 0,
+        "pieces already there were fetched again"
+    );
+    assert_eq!(read("sub/b.bin"), data[20_000..70_000]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn running_out_of_peers_reannounces_before_the_interval() {
+    // a peer that hangs up on everyone, the only one the tracker knows of at first
+    let flaky = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let flaky_addr = flaky.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = flaky.accept().await {
+            drop(stream);
+        }
+    });
+    let torrent = Torrent::fixture_single_file(3 * 16384, 16384);
+    let data = Torrent::fixture_data(3 * 16384);
+    let seed = Seed::start(&torrent, &data).await;
+    let tracker = MockTracker::with_bodies(vec![
+        serde_bencode::to_bytes(&TrackerResponse::fixture(&[flaky_addr])).unwrap(),
+        serde_bencode::to_bytes(&TrackerResponse::fixture(&[seed.addr])).unwrap(),
+    ])
+    .await;
+    let mut torrent = torrent;
+    torrent.announce = tracker.url.clone();
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+
+    let start = Instant::now();
+    let mut reannounced = Vec::new();
+    common::client()
+        .download(
+            &torrent,
+            &output,
+            &DownloadOptions::default(),
+            |event| {
+                if let DownloadEvent::Reannounced { new_peers } = event {
+                    reannounced.push(new_peers);
+                }
+            },
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    assert_eq!(reannounced, [1]);
+    let requests = tracker.requests();
+    assert_eq!(requests.len(), 4, "{requests:#?}");
+    assert!(requests[0].contains("event=started"));
+    assert!(!requests[1].contains("event="), "{}", requests[1]);
+    assert!(requests[2].contains("event=completed"));
+    let interval = Duration::from_secs(TrackerResponse::fixture(&[]).interval as u64);
+    let early = tracker.request_times()[1] - start;
+    assert!(early < interval, "reannounced after {early:?}");
+}
+
+#[tokio::test]
+async fn a_seed_of_the_fixture_torrent_answers_with_the_fixture_handshake() {
+    let torrent = Torrent::fixture_single_file(100_000, 1 << 15);
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(100_000)).await;
+    let (theirs, _, _) = client::handshake(&torrent.identity(), PeerId::generate(), &seed.addr)
+        .await
+        .unwrap();
+    let fixture = Handshake::fixture();
+    assert_eq!(theirs.info_hash, fixture.info_hash);
+    assert_eq!(theirs.to_bytes()[..20], fixture.to_bytes()[..20]);
+    // a seed offers extensions, unlike the fixture
+    assert!(theirs.supports_extensions() && !fixture.supports_extensions());
+}
+
+/// `summary` with everything that depends on timing replaced by `*`.
+fn masked(summary: &str) -> String {
+    summary
+        .lines()
+        .map(|line| {
+            let line = line.trim_end();
+            if line.starts_with("  elapsed") || line.starts_with("  rate") {
+                let label = line.split_whitespace().next().unwrap();
+                format!("  {label:<15}*")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[tokio::test]
+async fn a_finished_download_sums_itself_up() {
+    let len = 3 * 16384 + 10;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(len)).await;
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+
+    let outcome = common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    let summary = outcome.stats.summary(
+        "fixture.bin".to_string(),
+        Some(output.clone()),
+        PeerCounts {
+            tried: outcome.tried,
+            connected: outcome.connected,
+            banned: outcome.banned,
+        },
+    );
+    assert_eq!(
+        masked(&summary.to_string()),
+        format!(
+            "fixture.bin downloaded to {}.
+  elapsed        *
+  downloaded     48.29 KiB for 48.01 KiB of payload (0.6% overhead)
+  rate           *
+  peers          1 tried, 1 connected, 0 banned
+  hash failures  0, 0 B wasted",
+            output.display()
+        )
+    );
+
+    let mut json = serde_json::to_value(&summary).unwrap();
+    for key in ["elapsed_secs", "average_rate", "peak_rate"] {
+        assert!(json[key].as_f64().unwrap() > 0.0, "{key}");
+        json[key] = "*".into();
+    }
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "what": "fixture.bin",
+            "output": output,
+            "elapsed_secs": "*",
+            "downloaded": len,
+            "payload": len,
+            // handshake, bitfield, extension handshake, unchoke and block headers
+            "wire": 49446,
+            "overhead_pct": (49446 - len) as f64 * 100.0 / len as f64,
+            "average_rate": "*",
+            "peak_rate": "*",
+            "peers": { "tried": 1, "connected": 1, "banned": 0 },
+            "hash_failures": 0,
+            "wasted": 0,
+        })
+    );
+}
+
+#[tokio::test]
+async fn a_peer_whose_piece_fails_its_hash_check_counts_as_banned() {
+    let len = 2 * 16384;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let data = Torrent::fixture_data(len);
+    let poisoned = Seed::start(&torrent, &vec![0xbd; len]).await;
+    let seed = Seed::start(&torrent, &data).await;
+    let options = DownloadOptions {
+        peers: vec![poisoned.addr, seed.addr],
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+
+    let outcome = common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        (outcome.tried, outcome.connected, outcome.banned),
+        (2, 2, 1)
+    );
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+}
+
+#[tokio::test]
+async fn a_downloaded_piece_sums_itself_up_like_a_download() {
+    let len = 3 * 16384 + 10;
+    let torrent = Torrent::fixture_single_file(len, 2 * 16384);
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(len)).await;
+    let client = common::client();
+    let mut stats = TransferStats::new(torrent.piece_size(1));
+    let mut connection = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    client
+        .download_piece(&torrent, &mut connection, 1, &mut stats)
+        .await
+        .unwrap()
+        .finish(torrent.piece_hash(1).unwrap(), true, &mut stats)
+        .unwrap();
+
+    let summary = stats.summary("Piece 1".to_string(), None, PeerCounts::default());
+    let summary = masked(&summary.to_string());
+    let (first, details) = summary.split_once('\n').unwrap();
+    assert_eq!(first, "Piece 1 downloaded.");
+    let lines: Vec<_> = details.lines().map(|line| &line[..15]).collect();
+    assert_eq!(
+        lines,
+        [
+            "  elapsed      ",
+            "  downloaded   ",
+            "  rate         ",
+            "  peers        ",
+            "  hash failures"
+        ]
+    );
+    assert!(summary.contains("16.01 KiB of payload"), "{summary}");
+}
+
+/// Connects to a peer that speaks the extension protocol for `torrent`, and returns the
+/// extensions we offered it.
+async fn offered_extensions(torrent: &Torrent) -> BTreeMap<String, u8> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let npieces = torrent.declared_pieces();
+    let info_hash = torrent.info_hash();
+    let peer = tokio::spawn(async move {
+        let (mut stream, from) = listener.accept().await.unwrap();
+        let mut theirs = [0; Handshake::LEN];
+        stream.read_exact(&mut theirs).await.unwrap();
+        let ours = Handshake::new(info_hash, *b"-XX0000-mockpeer0000", true);
+        stream.write_all(&ours.to_bytes()).await.unwrap();
+        let mut stream = Framed::new(stream, MessageFramer::new(from, &Default::default()));
+        let extended = peer_session::our_extended_handshake(&[("ut_pex", 1)]);
+        for message in [
+            MessagePayload::Extended {
+                id: 0,
+                payload: extended.to_bencode(),
+            },
+            MessagePayload::Bitfield(Bitfield::full(npieces).as_bytes().to_vec()),
+            MessagePayload::Unchoke,
+        ] {
+            stream.send(message).await.unwrap();
+        }
+        loop {
+            if let MessagePayload::Extended { id: 0, payload } =
+                stream.next().await.unwrap().unwrap()
+            {
+                return ExtendedHandshake::from_bencode(&payload).unwrap().m;
+            }
+        }
+    });
+    let mut stats = TransferStats::new(0);
+    common::client()
+        .connect(torrent, addr, &mut stats)
+        .await
+        .unwrap();
+    peer.await.unwrap()
+}
+
+#[tokio::test]
+async fn a_private_torrent_is_downloaded_without_peer_exchange() {
+    let len = 2 * 16384;
+    let builder = || TorrentBuilder::single_file("fixture.bin", len, 16384).creation_date(0);
+    let public = builder().build(fixture_data(len, 0).as_slice()).unwrap();
+    let private = builder()
+        .private(true)
+        .build(fixture_data(len, 0).as_slice())
+        .unwrap();
+    assert!(private.is_private());
+
+    let offered = offered_extensions(&public).await;
+    assert!(offered.contains_key("ut_pex"), "{offered:?}");
+    let offered = offered_extensions(&private).await;
+    assert!(!offered.contains_key("ut_pex"), "{offered:?}");
+    assert!(offered.contains_key("ut_metadata"), "{offered:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn readahead_fetches_the_pieces_after_the_first_missing_one_first() {
+    let npieces = 12;
+    let len = npieces * 16384;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let data = Torrent::fixture_data(len);
+    // a seed that takes a second per piece
+    let limits = Limits {
+        max_up: 16384,
+        ..Limits::default()
+    };
+    let seed = Seed::start_limited(&torrent, &data, Bitfield::full(npieces), limits).await;
+    let picks = Arc::new(Mutex::new(Vec::new()));
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        pick: PickOrder::Custom(Arc::new({
+            let picks = Arc::clone(&picks);
+            move || {
+                let random = RandomPicker::new(StdRng::seed_from_u64(7));
+                Box::new(Recording(random, Arc::clone(&picks)))
+            }
+        })),
+        readahead: Some(3),
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+
+    common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let picks = picks.lock().unwrap();
+    assert_eq!(picks.len(), npieces);
+    for &(index, first_missing) in picks.iter() {
+        assert!(
+            (first_missing..first_missing + 3).contains(&index),
+            "picked {index} while waiting for {first_missing}: {picks:?}"
+        );
+    }
+    // the random picker didn't just happen to go in order
+    assert!(picks
+        .iter()
+        .any(|&(index, first_missing)| index != first_missing));
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+}
+
+/// Downloads 12 pieces from a seed within `limits` with a read-ahead window of `window`,
+/// handing the output to a consumer that takes `took(len)` for `len` bytes, and returns
+/// the window after each piece.
+async fn readahead_windows(
+    limits: Limits,
+    window: usize,
+    took: impl Fn(usize) -> Duration,
+) -> Vec<usize> {
+    let npieces = 12;
+    let len = npieces * 16384;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let data = Torrent::fixture_data(len);
+    let seed = Seed::start_limited(&torrent, &data, Bitfield::full(npieces), limits).await;
+    let drain = DrainRate::default();
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        readahead: Some(window),
+        drain: Some(drain.clone()),
+        in_order: true,
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let (mut windows, mut consumed) = (Vec::new(), Vec::new());
+
+    common::client()
+        .download(
+            &torrent,
+            &dir.path().join("out.bin"),
+            &options,
+            |event| match event {
+                DownloadEvent::Piece(progress) => windows.push(progress.readahead.unwrap()),
+                DownloadEvent::InOrder(chunk) => {
+                    drain.record(chunk.len(), took(chunk.len()));
+                    consumed.extend_from_slice(chunk);
+                }
+                _ => {}
+            },
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(consumed, data);
+    windows
+}
+
+#[tokio::test]
+async fn the_readahead_window_grows_while_a_throttled_seed_barely_keeps_ahead() {
+    let limits = Limits {
+        max_up: 256 << 10,
+        ..Limits::default()
+    };
+    // a consumer that takes anything right away is always waiting for the download
+    let windows = readahead_windows(limits, 2, |_| Duration::ZERO).await;
+    assert!(
+        windows.windows(2).all(|pair| pair[0] <= pair[1]),
+        "{windows:?}"
+    );
+    assert!(*windows.last().unwrap() > 2, "{windows:?}");
+}
+
+#[tokio::test]
+async fn the_readahead_window_shrinks_while_the_download_outpaces_the_consumer() {
+    // 1 KiB/s
+    let took = |len: usize| Duration::from_secs_f64(len as f64 / 1024.0);
+    let windows = readahead_windows(Limits::default(), 16, took).await;
+    assert!(
+        windows.windows(2).all(|pair| pair[0] >= pair[1]),
+        "{windows:?}"
+    );
+    assert!(*windows.last().unwrap() < 16, "{windows:?}");
+}
+
+/// Panics while picking as long as `left` is above 0, counting it down, and picks in index
+/// order after that.
+struct Panicking(Arc<AtomicUsize>);
+
+impl PiecePicker for Panicking {
+    fn pick(&mut self, ctx: &PickContext) -> Option<usize> {
+        let counted = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            });
+        if counted.is_ok() {
+            panic!("injected panic picking a piece");
+        }
+        Sequential.pick(ctx)
+    }
+}
+
+fn panicking(left: &Arc<AtomicUsize>) -> PickOrder {
+    let left = Arc::clone(left);
+    PickOrder::Custom(Arc::new(move || Box::new(Panicking(Arc::clone(&left)))))
+}
+
+#[tokio::test]
+async fn a_peer_task_that_panics_leaves_the_download_to_the_other_peers() {
+    let len = 5 * 16384 + 10;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let data = Torrent::fixture_data(len);
+    let first = Seed::start(&torrent, &data).await;
+    let second = Seed::start(&torrent, &data).await;
+    let panics = Arc::new(AtomicUsize::new(1));
+    // the task of the first peer dialed panics before asking it for anything
+    let options = DownloadOptions {
+        peers: vec![first.addr, second.addr],
+        pick: panicking(&panics),
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+
+    let outcome = common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    assert_eq!(panics.load(Ordering::Relaxed), 0);
+    assert_eq!((outcome.tried, outcome.connected), (2, 2));
+    assert_eq!(first.seeder.uploaded(), 0);
+    assert_eq!(second.seeder.uploaded(), len as u64);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_peer_whose_task_keeps_panicking_is_given_up_on_after_its_restarts() {
+    let len = 2 * 16384;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(len)).await;
+    let panics = Arc::new(AtomicUsize::new(usize::MAX));
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        pick: panicking(&panics),
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let started = Instant::now();
+
+    let err = common::client()
+        .download(
+            &torrent,
+            &dir.path().join("out.bin"),
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap_err();
+
+    assert!(err.to_string().contains("none of the peers"), "{err:#}");
+    let restarts = MAX_PEER_RESTARTS as usize;
+    assert_eq!(usize::MAX - panics.load(Ordering::Relaxed), 1 + restarts);
+    // 1 + 2 + 4 seconds of backoff
+    let backoff = PEER_RESTART_BACKOFF * (2u32.pow(MAX_PEER_RESTARTS) - 1);
+    assert!(started.elapsed() >= backoff, "{:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn tight_limits_on_both_ends_still_carry_a_download() {
+    let len = 3 * 16384 + 100;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let data = Torrent::fixture_data(len);
+    // 4 KiB blocks in frames with no byte to spare, like a small embedded target
+    let frame = 4096 + 9;
+    let seed_limits = Limits {
+        max_request_length: 4096,
+        max_outbound_frame: frame,
+        max_queued_requests: 2,
+        ..Limits::default()
+    };
+    let client_limits = Limits {
+        block_size: 4096,
+        max_inbound_frame: frame,
+        pipeline_depth: 2,
+        ..Limits::default()
+    };
+    seed_limits.validate().unwrap();
+    client_limits.validate().unwrap();
+    let seed = Seed::start_limited(&torrent, &data, Bitfield::full(4), seed_limits).await;
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+
+    let client = Client::new(common::client().trackers().clone(), client_limits);
+    let outcome = client
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    assert_eq!(seed.seeder.uploaded(), len as u64);
+    // a block header per 4 KiB block, where 16 KiB blocks would need 4
+    let blocks = 3 * 4 + 1;
+    let summary = outcome
+        .stats
+        .summary("out.bin".to_string(), None, Default::default());
+    assert_eq!(
+        summary.wire - summary.payload,
+        49446 - 49162 + (blocks - 4) * 13
+    );
+}
+
+#[tokio::test]
+async fn files_complete_as_the_pieces_they_lie_in_arrive() {
+    // pieces 0..16384..32768..49152..60000, so b straddles three pieces and c two
+    let files = [("a", 10_000), ("b", 30_000), ("c", 20_000)];
+    let len = files.iter().map(|(_, length)| length).sum();
+    let data = fixture_data(len, 3);
+    let torrent = TorrentBuilder::multi_file(
+        "three",
+        files
+            .iter()
+            .map(|&(name, length)| (vec![name.to_string()], length))
+            .collect(),
+        16384,
+    )
+    .creation_date(0)
+    .build(data.as_slice())
+    .unwrap();
+    let seed = Seed::start(&torrent, &data).await;
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        pick: PickOrder::Sequential,
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out");
+
+    let mut events = Vec::new();
+    common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |event| {
+                events.push(match event {
+                    DownloadEvent::Piece(progress) => {
+                        let percents: Vec<_> = progress
+                            .files
+                            .status()
+                            .iter()
+                            .map(|file| format!("{:.0}", file.percent))
+                            .collect();
+                        format!("piece {} [{}]", progress.done, percents.join(" "))
+                    }
+                    DownloadEvent::FileCompleted(file) => {
+                        let path = file.path.strip_prefix(&output).unwrap();
+                        format!("file {} {}", file.index, path.display())
+                    }
+                    other => panic!("unexpected {other:?}"),
+                })
+            },
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        events,
+        [
+            "piece 1 [100 21 0]",
+            "file 0 three/a",
+            "piece 2 [100 76 0]",
+            "piece 3 [100 100 46]",
+            "file 1 three/b",
+            "piece 4 [100 100 100]",
+            "file 2 three/c",
+        ]
+    );
+    let mut offset = 0;
+    for (name, length) in files {
+        let written = std::fs::read(output.join("three").join(name)).unwrap();
+        assert_eq!(written, data[offset..offset + length], "{name}");
+        offset += length;
+    }
+}
+
+#[tokio::test]
+async fn announces_count_left_within_the_selected_files() {
+    // b is pieces 1 and 2, half of the torrent
+    let files = [("a", 16384), ("b", 32768), ("c", 16384)];
+    let data = fixture_data(65536, 5);
+    let builder = |announce: &str| {
+        TorrentBuilder::multi_file(
+            "half",
+            files
+                .iter()
+                .map(|&(name, length)| (vec![name.to_string()], length))
+                .collect(),
+            16384,
+        )
+        .announce(announce)
+        .creation_date(0)
+    };
+    let placeholder = builder("http://127.0.0.1:1/announce")
+        .build(data.as_slice())
+        .unwrap();
+    let seed = Seed::start(&placeholder, &data).await;
+    // an interval of 0 asks for an announce after every piece
+    let mut response = TrackerResponse::fixture(&[seed.addr]);
+    response.interval = 0;
+    let tracker = MockTracker::with_body(serde_bencode::to_bytes(&response).unwrap()).await;
+    let torrent = builder(&tracker.url).build(data.as_slice()).unwrap();
+    assert_eq!(torrent.info_hash(), placeholder.info_hash());
+    let options = DownloadOptions {
+        files: vec![1],
+        pick: PickOrder::Sequential,
+        ..DownloadOptions::default()
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out");
+    common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+    let announced: Vec<_> = tracker
+        .requests()
+        .iter()
+        .map(|request| {
+            let (_, query) = request.split_once('?').unwrap();
+            let param = |key: &str| {
+                query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+                    .unwrap_or("-")
+                    .to_string()
+            };
+            format!(
+                "{} left={} downloaded={}",
+                param("event"),
+                param("left"),
+                param("downloaded")
+            )
+        })
+        .collect();
+    assert_eq!(
+        announced,
+        [
+            "started left=32768 downloaded=0",
+            "- left=16384 downloaded=16384",
+            "completed left=0 downloaded=32768",
+            "stopped left=0 downloaded=32768",
+        ]
+    );
+    assert_eq!(
+        std::fs::read(output.join("half").join("b")).unwrap(),
+        data[16384..49152]
+    );
+    assert!(!output.join("half").join("a").exists());
+}
+
+#[tokio::test]
+async fn a_peer_announcing_its_dht_port_is_downloaded_from_as_usual() {
+    let torrent = Torrent::fixture_single_file(16384, 16384);
+    let data = Torrent::fixture_data(16384);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = torrent.info_hash();
+    tokio::spawn(async move {
+        let (mut stream, from) = listener.accept().await.unwrap();
+        let mut theirs = [0; Handshake::LEN];
+        stream.read_exact(&mut theirs).await.unwrap();
+        // the DHT bit of the reserved bytes
+        let mut ours = Handshake::new(info_hash, *b"-XX0000-dhtpeer00000", false).to_bytes();
+        ours[27] |= 1;
+        stream.write_all(&ours).await.unwrap();
+        let mut stream = Framed::new(stream, MessageFramer::new(from, &Default::default()));
+        stream.send(MessagePayload::Port(6881)).await.unwrap();
+        stream
+            .send(MessagePayload::Bitfield(
+                Bitfield::full(1).as_bytes().to_vec(),
+            ))
+            .await
+            .unwrap();
+        while let Some(Ok(message)) = stream.next().await {
+            let reply = match message {
+                MessagePayload::Interested => MessagePayload::Unchoke,
+                MessagePayload::Request(request) => MessagePayload::Piece {
+                    index: request.index(),
+                    begin: request.begin(),
+                    block: data[request.begin() as usize..][..request.length() as usize]
+                        .to_vec()
+                        .into(),
+                },
+                _ => continue,
+            };
+            stream.send(reply).await.unwrap();
+        }
+    });
+
+    let client = common::client();
+    let mut stats = TransferStats::new(16384);
+    let mut connection = client.connect(&torrent, addr, &mut stats).await.unwrap();
+    let piece = client
+        .download_piece(&torrent, &mut connection, 0, &mut stats)
+        .await
+        .unwrap()
+        .finish(torrent.piece_hash(0).unwrap(), true, &mut stats)
+        .unwrap();
+    assert_eq!(piece, Torrent::fixture_data(16384));
+    assert_eq!(connection.session.take_dht_port(), Some(6881));
+    assert_eq!(connection.session.take_dht_port(), None);
+}
+
+#[tokio::test]
+async fn haves_amid_the_blocks_make_more_pieces_requestable() {
+    const PLENGTH: usize = 32768;
+    let torrent = Torrent::fixture_single_file(3 * PLENGTH, PLENGTH);
+    let data = Torrent::fixture_data(3 * PLENGTH);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = torrent.info_hash();
+    tokio::spawn({
+        let data = data.clone();
+        async move {
+            let (mut stream, from) = listener.accept().await.unwrap();
+            let mut theirs = [0; Handshake::LEN];
+            stream.read_exact(&mut theirs).await.unwrap();
+            let ours = Handshake::new(info_hash, *b"-XX0000-leecher00000", false);
+            stream.write_all(&ours.to_bytes()).await.unwrap();
+            let mut stream = Framed::new(stream, MessageFramer::new(from, &Default::default()));
+            // still downloading: piece 0 only, and the others as they "complete"
+            let mut has = Bitfield::new(3);
+            has.set_piece(0);
+            stream
+                .send(MessagePayload::Bitfield(has.as_bytes().to_vec()))
+                .await
+                .unwrap();
+            let mut next_have = 1;
+            while let Some(Ok(message)) = stream.next().await {
+                match message {
+                    MessagePayload::Interested => {
+                        stream.send(MessagePayload::Unchoke).await.unwrap();
+                    }
+                    MessagePayload::Request(request) => {
+                        // a have between every request and its block
+                        if next_have < 3 {
+                            stream.send(MessagePayload::Have(next_have)).await.unwrap();
+                            next_have += 1;
+                        }
+                        let start = request.index() as usize * PLENGTH + request.begin() as usize;
+                        let block = data[start..][..request.length() as usize].to_vec();
+                        stream
+                            .send(MessagePayload::Piece {
+                                index: request.index(),
+                                begin: request.begin(),
+                                block: block.into(),
+                            })
+                            .await
+                            .unwrap();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+    let options = DownloadOptions {
+        peers: vec![addr],
+        pick: PickOrder::Sequential,
+        ..DownloadOptions::default()
+    };
+    let outcome = common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(outcome.connected, 1);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+}
+
+/// What an [`endgame_peer`] heard from us: the (index, begin) of the requests and cancels,
+/// and the pieces we told it we have.
+#[derive(Debug, Default)]
+struct Heard {
+    requests: Vec<(u32, u32)>,
+    cancels: Vec<(u32, u32)>,
+    haves: Vec<u32>,
+}
+
+/// A peer with the pieces in `has` of `torrent` that unchokes us, and sends the blocks we
+/// ask for only if `serves`.
+async fn endgame_peer(
+    torrent: &Torrent,
+    data: Vec<u8>,
+    has: Bitfield,
+    serves: bool,
+) -> (std::net::SocketAddr, tokio::task::JoinHandle<Heard>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = torrent.info_hash();
+    let plength = torrent.info.plength;
+    let peer = tokio::spawn(async move {
+        let (mut stream, from) = listener.accept().await.unwrap();
+        let mut theirs = [0; Handshake::LEN];
+        stream.read_exact(&mut theirs).await.unwrap();
+        let ours = Handshake::new(info_hash, *b"-XX0000-endgame00000", false);
+        stream.write_all(&ours.to_bytes()).await.unwrap();
+        let mut stream = Framed::new(stream, MessageFramer::new(from, &Default::default()));
+        stream
+            .send(MessagePayload::Bitfield(has.as_bytes().to_vec()))
+            .await
+            .unwrap();
+        let mut heard = Heard::default();
+        while let Some(Ok(message)) = stream.next().await {
+            match message {
+                MessagePayload::Interested => {
+                    stream.send(MessagePayload::Unchoke).await.unwrap();
+                }
+                MessagePayload::Request(request) => {
+                    heard.requests.push((request.index(), request.begin()));
+                    if !serves {
+                        continue;
+                    }
+                    let start = request.index() as usize * plength + request.begin() as usize;
+                    let block = data[start..][..request.length() as usize].to_vec();
+                    let piece = MessagePayload::Piece {
+                        index: request.index(),
+                        begin: request.begin(),
+                        block: block.into(),
+                    };
+                    if stream.send(piece).await.is_err() {
+                        break;
+                    }
+                }
+                MessagePayload::Cancel(request) => {
+                    heard.cancels.push((request.index(), request.begin()));
+                }
+                MessagePayload::Have(index) => heard.haves.push(index),
+                _ => {}
+            }
+        }
+        heard
+    });
+    (addr, peer)
+}
+
+#[tokio::test]
+async fn the_endgame_asks_every_peer_and_cancels_the_copies_that_lose() {
+    const PLENGTH: usize = 32768;
+    let torrent = Torrent::fixture_single_file(2 * PLENGTH, PLENGTH);
+    let data = Torrent::fixture_data(2 * PLENGTH);
+    let (stalled, stalled_peer) =
+        endgame_peer(&torrent, data.clone(), Bitfield::full(2), false).await;
+    let (serving, serving_peer) =
+        endgame_peer(&torrent, data.clone(), Bitfield::full(2), true).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+    let options = DownloadOptions {
+        peers: vec![stalled, serving],
+        endgame_threshold: 20,
+        ..DownloadOptions::default()
+    };
+    let outcome = tokio::time::timeout(
+        Duration::from_secs(10),
+        common::client().download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        ),
+    )
+    .await
+    .expect("the stalled peer held up the download")
+    .unwrap();
+    assert_eq!(outcome.connected, 2);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+
+    let every_block = vec![(0, 0), (0, 16384), (1, 0), (1, 16384)];
+    let mut stalled = stalled_peer.await.unwrap();
+    stalled.requests.sort();
+    stalled.cancels.sort();
+    assert_eq!(stalled.requests, every_block);
+    assert_eq!(stalled.cancels, every_block);
+    let mut serving = serving_peer.await.unwrap();
+    serving.requests.sort();
+    assert_eq!(serving.requests, every_block);
+    assert!(serving.cancels.is_empty());
+}
+
+#[tokio::test]
+async fn without_an_endgame_threshold_one_peer_is_asked_at_a_time() {
+    const PLENGTH: usize = 32768;
+    let torrent = Torrent::fixture_single_file(2 * PLENGTH, PLENGTH);
+    let data = Torrent::fixture_data(2 * PLENGTH);
+    let (serving, serving_peer) =
+        endgame_peer(&torrent, data.clone(), Bitfield::full(2), true).await;
+    let (idle, idle_peer) = endgame_peer(&torrent, data.clone(), Bitfield::full(2), true).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+    let options = DownloadOptions {
+        peers: vec![serving, idle],
+        ..DownloadOptions::default()
+    };
+    let outcome = common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(outcome.connected, 1);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    assert_eq!(serving_peer.await.unwrap().requests.len(), 4);
+    idle_peer.abort();
+}
+
+#[tokio::test]
+async fn a_finished_piece_is_announced_only_to_the_peers_that_lack_it() {
+    const PLENGTH: usize = 32768;
+    let torrent = Torrent::fixture_single_file(2 * PLENGTH, PLENGTH);
+    let data = Torrent::fixture_data(2 * PLENGTH);
+    let (both, both_peer) = endgame_peer(&torrent, data.clone(), Bitfield::full(2), true).await;
+    let mut first = Bitfield::new(2);
+    first.set_piece(0);
+    let (partial, partial_peer) = endgame_peer(&torrent, data.clone(), first, true).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+    let options = DownloadOptions {
+        peers: vec![both, partial],
+        endgame_threshold: 20,
+        ..DownloadOptions::default()
+    };
+    let outcome = common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(outcome.connected, 2);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+
+    assert!(both_peer.await.unwrap().haves.is_empty());
+    assert_eq!(partial_peer.await.unwrap().haves, [1]);
+    // piece 0 to both peers, piece 1 to the one that had it
+    assert_eq!(outcome.stats.haves_suppressed(), 3);
+}
+
+#[tokio::test]
+async fn the_completed_announce_counts_every_peer_of_the_endgame() {
+    const PLENGTH: usize = 32768;
+    // enough that the tracker is asked for fewer than it can give
+    const NPEERS: usize = client::MAX_PEERS / 4 + 1;
+    let mut torrent = Torrent::fixture_single_file(2 * PLENGTH, PLENGTH);
+    let data = Torrent::fixture_data(2 * PLENGTH);
+    let mut peers = Vec::new();
+    let mut tasks = Vec::new();
+    for _ in 0..NPEERS {
+        let (addr, task) = endgame_peer(&torrent, data.clone(), Bitfield::full(2), true).await;
+        peers.push(addr);
+        tasks.push(task);
+    }
+    let tracker = MockTracker::start(&peers).await;
+    torrent.announce = tracker.url.clone();
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+    let options = DownloadOptions {
+        endgame_threshold: 20,
+        ..DownloadOptions::default()
+    };
+    let outcome = common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(outcome.connected, NPEERS);
+    tasks.into_iter().for_each(|task| task.abort());
+
+    let requests = tracker.requests();
+    let completed = requests
+        .iter()
+        .find(|request| request.contains("event=completed"))
+        .expect("a completed announce");
+    let free = client::MAX_PEERS - NPEERS;
+    let numwant = format!("numwant={}&", free + free / 2);
+    assert!(completed.contains(&numwant), "{completed}");
+}
@@ -0,0 +1,245 @@
+//! Loopback stand-ins for the rest of the swarm: a seed serving a torrent's data, and a
+//! tracker handing out whatever peers it is told to.
+
+#![allow(dead_code)] // not every test binary uses every helper
+
+use bittorrent_starter_rust::client::{Client, Limits, PeerId, TrackerClient};
+use bittorrent_starter_rust::files::FileMapper;
+use bittorrent_starter_rust::netwatch::{FailureBurst, DEFAULT_FAILURE_BURST};
+use bittorrent_starter_rust::peer::Bitfield;
+use bittorrent_starter_rust::seed::Seeder;
+use bittorrent_starter_rust::torrent::{Keys, Metainfo, Torrent};
+use bittorrent_starter_rust::tracker::TrackerResponse;
+use bittorrent_starter_rust::tracker_policy::TrackerPolicy;
+use bittorrent_starter_rust::tracker_tls::TrackerTls;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// A client that may talk to trackers on loopback.
+pub fn client() -> Client {
+    let policy = TrackerPolicy { allow_local: true };
+    let trackers = TrackerClient::new(policy, &TrackerTls::default(), PeerId::generate())
+        .expect("tracker client");
+    Client::new(trackers, Limits::default())
+}
+
+/// Runs our binary with `args` and local trackers allowed, on a blocking thread so the
+/// test's runtime keeps serving mock peers and trackers meanwhile.
+pub async fn run<I, A>(args: I) -> assert_cmd::assert::Assert
+where
+    I: IntoIterator<Item = A>,
+    A: Into<OsString>,
+{
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    tokio::task::spawn_blocking(move || {
+        assert_cmd::Command::cargo_bin("bittorrent-starter-rust")
+            .expect("our binary")
+            .arg("--allow-local-trackers")
+            .args(args)
+            .timeout(Duration::from_secs(30))
+            .assert()
+    })
+    .await
+    .expect("run our binary")
+}
+
+/// Writes `torrent` to a `.torrent` file in `dir`.
+pub fn torrent_file(dir: &std::path::Path, torrent: &Torrent) -> PathBuf {
+    let path = dir.join("fixture.torrent");
+    std::fs::write(
+        &path,
+        serde_bencode::to_bytes(torrent).expect("encode torrent"),
+    )
+    .expect("write torrent");
+    path
+}
+
+/// Writes `data` to a file of a fresh temporary directory.
+pub fn data_file(name: &str, data: &[u8]) -> (TempDir, PathBuf) {
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let path = dir.path().join(name);
+    std::fs::write(&path, data).expect("write data");
+    (dir, path)
+}
+
+/// Writes the files of `torrent` with `data`, the torrent's data from start to end, below a
+/// fresh temporary directory, the name folder included; padding files are left out.
+pub fn data_dir(torrent: &Torrent, data: &[u8]) -> (TempDir, PathBuf) {
+    let dir = tempfile::tempdir().expect("temporary directory");
+    let mapper = FileMapper::new(torrent, dir.path());
+    for file in mapper.files().iter().filter(|file| !file.padding) {
+        std::fs::create_dir_all(file.path.parent().expect("a file in the name folder"))
+            .expect("create directory");
+        std::fs::write(&file.path, &data[file.offset..][..file.length]).expect("write data");
+    }
+    let path = dir.path().to_path_buf();
+    (dir, path)
+}
+
+/// A seed of a torrent on a loopback port, which stops when dropped.
+pub struct Seed {
+    pub addr: SocketAddr,
+    pub seeder: Arc<Seeder>,
+    task: JoinHandle<anyhow::Result<()>>,
+    _dir: TempDir,
+}
+
+impl Seed {
+    /// Seeds every piece of `torrent` from `data`, its files one after another.
+    pub async fn start(torrent: &Torrent, data: &[u8]) -> Self {
+        let have = Bitfield::full(torrent.declared_pieces());
+        Self::start_with(torrent, data, have).await
+    }
+
+    /// Seeds the pieces of `torrent` in `have` from `data`, its files one after another.
+    pub async fn start_with(torrent: &Torrent, data: &[u8], have: Bitfield) -> Self {
+        Self::start_limited(torrent, data, have, Limits::default()).await
+    }
+
+    /// Seeds the pieces of `torrent` in `have` from `data`, its files one after another,
+    /// within `limits`.
+    pub async fn start_limited(
+        torrent: &Torrent,
+        data: &[u8],
+        have: Bitfield,
+        limits: Limits,
+    ) -> Self {
+        let (dir, path) = match torrent.info.keys {
+            Keys::SingleFile { .. } => data_file("seed.bin", data),
+            Keys::MultiFile { .. } => data_dir(torrent, data),
+        };
+        Self::serve(torrent, path, have, limits, dir).await
+    }
+
+    /// Seeds the pieces of `torrent` in `have` from `data_path`, kept until the seed stops.
+    pub async fn serve(
+        torrent: &Torrent,
+        data_path: PathBuf,
+        have: Bitfield,
+        limits: Limits,
+        dir: TempDir,
+    ) -> Self {
+        let seeder = Seeder::new(
+            torrent.clone(),
+            PeerId::generate().0,
+            data_path,
+            have,
+            limits,
+        )
+        .expect("seeder");
+        let seeder = Arc::new(seeder);
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind seed");
+        let addr = listener.local_addr().expect("seed address");
+        let burst = FailureBurst::new(DEFAULT_FAILURE_BURST, Duration::from_secs(5));
+        let task = tokio::spawn(Arc::clone(&seeder).serve(listener, burst));
+        Self {
+            addr,
+            seeder,
+            task,
+            _dir: dir,
+        }
+    }
+}
+
+impl Drop for Seed {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// An HTTP tracker on a loopback port that answers announces with canned responses.
+pub struct MockTracker {
+    pub url: String,
+    /// The path and query of every request so far, and when it came in.
+    requests: Arc<Mutex<Vec<(String, Instant)>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockTracker {
+    /// A tracker handing out `peers`.
+    pub async fn start(peers: &[SocketAddr]) -> Self {
+        let body = serde_bencode::to_bytes(&TrackerResponse::fixture(peers)).expect("encode");
+        Self::with_body(body).await
+    }
+
+    /// A tracker answering with `body`, whatever that is.
+    pub async fn with_body(body: Vec<u8>) -> Self {
+        Self::with_bodies(vec![body]).await
+    }
+
+    /// A tracker answering the first request with the first of `bodies`, the second with
+    /// the second and so on, and every request after that with the last one.
+    pub async fn with_bodies(bodies: Vec<Vec<u8>>) -> Self {
+        assert!(!bodies.is_empty(), "a tracker needs something to answer");
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind tracker");
+        let url = format!(
+            "http://{}/announce",
+            listener.local_addr().expect("tracker address")
+        );
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let task = tokio::spawn({
+            let requests = Arc::clone(&requests);
+            async move {
+                loop {
+                    let Ok((mut stream, _)) = listener.accept().await else {
+                        return;
+                    };
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let line = String::from_utf8_lossy(&request);
+                    let target = line.split(' ').nth(1).unwrap_or_default().to_string();
+                    let body = {
+                        let mut requests = requests.lock().unwrap();
+                        requests.push((target, Instant::now()));
+                        &bodies[(requests.len() - 1).min(bodies.len() - 1)]
+                    };
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(body).await;
+                }
+            }
+        });
+        Self {
+            url,
+            requests,
+            task,
+        }
+    }
+
+    /// The path and query of every request so far.
+    pub fn requests(&self) -> Vec<String> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().map(|(target, _)| target.clone()).collect()
+    }
+
+    /// When each request so far came in, on the clock of the test's runtime.
+    pub fn request_times(&self) -> Vec<Instant> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().map(|&(_, at)| at).collect()
+    }
+}
+
+impl Drop for MockTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
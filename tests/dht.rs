@@ -0,0 +1,142 @@
+//! DHT lookups against a few KRPC nodes on loopback.
+
+use bittorrent_starter_rust::dht::{Dht, NodeId};
+use serde_bencode::value::Value;
+use std::net::{SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+
+/// What a mock node knows: other nodes to point to, and peers of the torrent.
+#[derive(Clone, Default)]
+struct Knows {
+    nodes: Vec<(NodeId, SocketAddrV4)>,
+    peers: Vec<SocketAddrV4>,
+}
+
+fn compact(addr: SocketAddrV4) -> Vec<u8> {
+    let mut compact = addr.ip().octets().to_vec();
+    compact.extend_from_slice(&addr.port().to_be_bytes());
+    compact
+}
+
+fn dict(entries: Vec<(&str, Value)>) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+/// A socket for a node, bound before it is told what it knows so nodes can point to
+/// each other.
+async fn socket() -> (UdpSocket, SocketAddrV4) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let SocketAddr::V4(addr) = socket.local_addr().unwrap() else {
+        unreachable!("bound to IPv4");
+    };
+    (socket, addr)
+}
+
+/// Answers every query on `socket` as the node `id` that knows `knows`; `get_peers` gets
+/// the peers as well as the nodes.
+fn serve(socket: UdpSocket, id: NodeId, knows: Knows) {
+    tokio::spawn(async move {
+        let mut buf = [0; 1500];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let Ok(Value::Dict(query)) = serde_bencode::from_bytes(&buf[..len]) else {
+                continue;
+            };
+            let Some(Value::Bytes(q)) = query.get(b"q".as_slice()) else {
+                continue;
+            };
+            let mut body = vec![("id", Value::Bytes(id.0.to_vec()))];
+            if q != b"ping" {
+                let nodes = knows
+                    .nodes
+                    .iter()
+                    .flat_map(|(id, addr)| [id.0.to_vec(), compact(*addr)].concat())
+                    .collect();
+                body.push(("nodes", Value::Bytes(nodes)));
+            }
+            if q == b"get_peers" && !knows.peers.is_empty() {
+                let values = knows
+                    .peers
+                    .iter()
+                    .map(|peer| Value::Bytes(compact(*peer)))
+                    .collect();
+                body.push(("values", Value::List(values)));
+            }
+            let reply = dict(vec![
+                ("t", query[b"t".as_slice()].clone()),
+                ("y", Value::Bytes(b"r".to_vec())),
+                ("r", dict(body)),
+            ]);
+            let datagram = serde_bencode::to_bytes(&reply).unwrap();
+            socket.send_to(&datagram, from).await.unwrap();
+        }
+    });
+}
+
+#[tokio::test]
+async fn a_lookup_follows_the_nodes_it_is_pointed_to_for_peers() {
+    let info_hash = [0xf0; 20];
+    // the router knows a node close to the torrent, which knows another one even closer
+    let ids = [NodeId([0x01; 20]), NodeId([0xe0; 20]), NodeId([0xf1; 20])];
+    let (router, router_addr) = socket().await;
+    let (near, near_addr) = socket().await;
+    let (nearest, nearest_addr) = socket().await;
+    let peers: [SocketAddrV4; 3] = [
+        "10.0.0.1:6881".parse().unwrap(),
+        "10.0.0.2:6882".parse().unwrap(),
+        "10.0.0.3:6883".parse().unwrap(),
+    ];
+    serve(
+        router,
+        ids[0],
+        Knows {
+            nodes: vec![(ids[1], near_addr)],
+            peers: Vec::new(),
+        },
+    );
+    serve(
+        near,
+        ids[1],
+        Knows {
+            nodes: vec![(ids[2], nearest_addr)],
+            peers: peers[..2].to_vec(),
+        },
+    );
+    serve(
+        nearest,
+        ids[2],
+        Knows {
+            nodes: Vec::new(),
+            peers: peers[1..].to_vec(),
+        },
+    );
+
+    let mut dht = Dht::bind().await.unwrap();
+    let start = dht.bootstrap(&[router_addr.to_string()]).await.unwrap();
+    assert!(start.iter().any(|node| node.id == ids[0]));
+
+    let found = dht.get_peers(info_hash, &start).await.unwrap();
+    let closest: Vec<_> = found.closest.iter().map(|node| node.id).collect();
+    assert_eq!(closest, [ids[2], ids[1], ids[0]]);
+    // each peer once, however many nodes know it
+    let mut got = found.peers.clone();
+    got.sort();
+    let want: Vec<SocketAddr> = peers.iter().copied().map(SocketAddr::V4).collect();
+    assert_eq!(got, want);
+    assert_eq!(found.answered, found.queried);
+}
+
+// the ping times out on the paused clock
+#[tokio::test(start_paused = true)]
+async fn bootstrapping_fails_when_no_router_answers() {
+    let (silent, silent_addr) = socket().await;
+    let mut dht = Dht::bind().await.unwrap();
+    let err = dht.bootstrap(&[silent_addr.to_string()]).await.unwrap_err();
+    assert_eq!(err.to_string(), "none of the bootstrap nodes answered");
+    drop(silent);
+}
@@ -0,0 +1,165 @@
+//! `--dry-run` plans without connecting to peers or touching the filesystem.
+
+mod common;
+
+use bittorrent_starter_rust::torrent::Torrent;
+use common::MockTracker;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Everything below `dir`.
+fn tree(dir: &Path) -> BTreeSet<PathBuf> {
+    let mut paths = BTreeSet::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                pending.push(path.clone());
+            }
+            paths.insert(path);
+        }
+    }
+    paths
+}
+
+/// A peer the tracker hands out, to see whether anyone connects to it.
+async fn peer() -> TcpListener {
+    TcpListener::bind("127.0.0.1:0").await.unwrap()
+}
+
+async fn assert_untouched(peer: &TcpListener) {
+    let accepted = tokio::time::timeout(Duration::from_millis(200), peer.accept()).await;
+    assert!(accepted.is_err(), "a dry run connected to a peer");
+}
+
+#[tokio::test]
+async fn download_piece_plans_without_writing_or_connecting() {
+    let torrent = Torrent::fixture_single_file(3 * 16384 + 10, 16384);
+    let peer = peer().await;
+    let peer_addr = peer.local_addr().unwrap();
+    let tracker = MockTracker::start(&[peer_addr]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+    let before = tree(dir.path());
+    let output = dir.path().join("out").join("piece.bin");
+    let pieces = dir.path().join("fixture.pieces");
+
+    let assert = common::run([
+        "--announce".into(),
+        tracker.url.clone().into(),
+        "download_piece".into(),
+        "--dry-run".into(),
+        "-o".into(),
+        output.clone().into_os_string(),
+        "--pieces".into(),
+        pieces.clone().into_os_string(),
+        torrent_path.into_os_string(),
+        "3".into(),
+    ])
+    .await
+    .success();
+
+    let stdout = String::from_utf8(assert.get_output().stdout.clone()).unwrap();
+    let hash = &hex::encode(torrent.info_hash())[..8];
+    assert_eq!(
+        stdout,
+        format!(
+            "Torrent: fixture.bin ({hash}…)
+Fetch: piece 3, 10 B
+Verify policy: Full
+Writes:
+  {}
+  {}
+Peers: 1 available
+  {peer_addr}
+",
+            output.display(),
+            pieces.display()
+        )
+    );
+    assert_eq!(tree(dir.path()), before);
+    assert_untouched(&peer).await;
+    assert_eq!(tracker.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn seed_plans_without_writing_or_connecting() {
+    let len = 2 * 16384;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let peer = peer().await;
+    let tracker = MockTracker::start(&[peer.local_addr().unwrap()]).await;
+    let (dir, data) = common::data_file("fixture.bin", &Torrent::fixture_data(len));
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+    let before = tree(dir.path());
+
+    let assert = common::run([
+        "--announce".into(),
+        tracker.url.clone().into(),
+        "seed".into(),
+        "--dry-run".into(),
+        "--dry-run-announce".into(),
+        "quiet".into(),
+        "--data".into(),
+        data.clone().into_os_string(),
+        "--port".into(),
+        "6999".into(),
+        torrent_path.into_os_string(),
+    ])
+    .await
+    .success();
+
+    let stdout = String::from_utf8(assert.get_output().stdout.clone()).unwrap();
+    let hash = &hex::encode(torrent.info_hash())[..8];
+    assert_eq!(
+        stdout,
+        format!(
+            "Torrent: fixture.bin ({hash}…)
+Data: {}
+Pieces: all, no piece map given
+Present: 2 of 2 pieces, 0 B missing
+Listen: port 6999
+Writes: nothing
+Peers: 1 available
+  {}
+",
+            data.display(),
+            peer.local_addr().unwrap()
+        )
+    );
+    assert_eq!(tree(dir.path()), before);
+    assert_untouched(&peer).await;
+    // a quiet announce asks for no peers
+    let requests = tracker.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].contains("numwant=0"), "{}", requests[0]);
+}
+
+#[tokio::test]
+async fn a_dry_run_may_skip_the_tracker_too() {
+    let torrent = Torrent::fixture_single_file(16384, 16384);
+    let tracker = MockTracker::start(&[]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+
+    let assert = common::run([
+        "--announce".into(),
+        tracker.url.clone().into(),
+        "download_piece".into(),
+        "--dry-run".into(),
+        "--dry-run-announce".into(),
+        "skip".into(),
+        "-o".into(),
+        dir.path().join("piece.bin").into_os_string(),
+        torrent_path.into_os_string(),
+        "0".into(),
+    ])
+    .await
+    .success();
+
+    let stdout = String::from_utf8(assert.get_output().stdout.clone()).unwrap();
+    assert!(stdout.ends_with("Peers: not announced\n"), "{stdout}");
+    assert!(tracker.requests().is_empty());
+}
@@ -0,0 +1,94 @@
+//! Timing handshakes with a peer on loopback.
+
+use bittorrent_starter_rust::client::{self, PeerId};
+use bittorrent_starter_rust::handshake::{self, FIRST_MESSAGE_TIMEOUT};
+use bittorrent_starter_rust::peer::{Bitfield, Handshake};
+use bittorrent_starter_rust::torrent::Torrent;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const PEER_ID: [u8; 20] = *b"-TR4050-0123456789ab";
+
+/// A peer that answers the handshake, with the fast extension and DHT, then waits
+/// `delay` before sending `first`, if anything.
+async fn slow_peer(torrent: &Torrent, delay: Duration, first: Option<Vec<u8>>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = torrent.info_hash();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut theirs = [0; Handshake::LEN];
+        stream.read_exact(&mut theirs).await.unwrap();
+        let mut ours = Handshake::new(info_hash, PEER_ID, true);
+        ours.reserved[7] |= 0x04 | 0x01;
+        stream.write_all(&ours.to_bytes()).await.unwrap();
+        tokio::time::sleep(delay).await;
+        if let Some(first) = first {
+            stream.write_all(&first).await.unwrap();
+        }
+        // hold the connection until the other end is done
+        let _ = stream.read(&mut [0; 1]).await;
+    });
+    addr
+}
+
+#[tokio::test]
+async fn the_gap_before_a_delayed_bitfield_is_measured() {
+    let torrent = Torrent::fixture_single_file(10 * 16384, 16384);
+    let bitfield = Bitfield::full(10);
+    let mut message = (1 + bitfield.as_bytes().len() as u32)
+        .to_be_bytes()
+        .to_vec();
+    message.push(5);
+    message.extend_from_slice(bitfield.as_bytes());
+    let delay = Duration::from_millis(300);
+    let peer = slow_peer(&torrent, delay, Some(message)).await;
+
+    let (_, mut stream, mut report) =
+        client::handshake(&torrent.identity(), PeerId::generate(), &peer)
+            .await
+            .unwrap();
+    report.first_message = handshake::first_message(&mut stream, FIRST_MESSAGE_TIMEOUT)
+        .await
+        .unwrap();
+
+    let first = report.first_message.as_ref().unwrap();
+    assert_eq!((first.name, first.length), ("bitfield", 3));
+    assert!(first.after_ms >= 300.0, "{first:?}");
+    assert!(first.after_ms < 300.0 + 1000.0, "{first:?}");
+    // loopback answers right away
+    assert!(report.handshake_ms < 300.0, "{report:?}");
+    assert_eq!(report.peer, peer);
+    assert_eq!(report.peer_id, hex::encode(PEER_ID));
+    assert_eq!(report.extensions, ["extension_protocol", "fast", "dht"]);
+
+    let json = serde_json::to_value(&report).unwrap();
+    assert_eq!(json["first_message"]["name"], "bitfield");
+    assert_eq!(json["extensions"][1], "fast");
+    let shown = report.to_string();
+    assert!(
+        shown.contains("First message: bitfield (3 bytes) after "),
+        "{shown}"
+    );
+}
+
+#[tokio::test]
+async fn a_peer_that_sends_nothing_has_no_first_message() {
+    let torrent = Torrent::fixture_single_file(16384, 16384);
+    let peer = slow_peer(&torrent, Duration::ZERO, None).await;
+
+    let (_, mut stream, mut report) =
+        client::handshake(&torrent.identity(), PeerId::generate(), &peer)
+            .await
+            .unwrap();
+    report.first_message = handshake::first_message(&mut stream, Duration::from_millis(100))
+        .await
+        .unwrap();
+
+    assert!(report.first_message.is_none());
+    assert!(report.to_string().contains("First message: none"));
+    let json = serde_json::to_value(&report).unwrap();
+    assert!(json.get("first_message").is_none(), "{json}");
+}
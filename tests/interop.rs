@@ -0,0 +1,427 @@
+//! Conformance checks against a locally installed reference client.
+//!
+//! Point `RB_INTEROP_TRANSMISSION` at a `transmission-daemon` binary and run
+//! `cargo test --features interop -- --ignored`; without it the checks are skipped.
+//! Both directions run on loopback against a generated fixture, with a throwaway tracker
+//! that hands each side the other's address:
+//!
+//! 1. transmission seeds, and we download every piece with `download_piece`;
+//! 2. we `seed`, and transmission downloads the whole torrent.
+//!
+//! A direction passes if the data arrives hash-valid and our logs show no protocol errors.
+
+#![cfg(feature = "interop")]
+
+use anyhow::{bail, Context};
+use bittorrent_starter_rust::create::{fixture_data, TorrentBuilder};
+use bittorrent_starter_rust::peer::Peers;
+use bittorrent_starter_rust::piece;
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::tracker::TrackerResponse;
+use serde_json::{json, Value};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::process::{Child, Command};
+
+/// The environment variable naming the `transmission-daemon` binary.
+const TRANSMISSION_ENV: &str = "RB_INTEROP_TRANSMISSION";
+
+const FIXTURE_NAME: &str = "interop.bin";
+const FIXTURE_LENGTH: usize = (1 << 20) + 12_345;
+const FIXTURE_PIECE_LENGTH: usize = 1 << 15;
+
+/// How long either client gets to finish a transfer.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// What in our own logs means we failed to speak the protocol.
+const PROTOCOL_ERRORS: &[&str] = &[
+    "peer message was invalid",
+    "protocol",
+    "panicked",
+    "hash mismatch",
+];
+
+/// A transmission-daemon, a tracker and the fixture torrent they share.
+struct Setup {
+    dir: TempDir,
+    tracker: Tracker,
+    torrent: Torrent,
+    torrent_path: PathBuf,
+    data: Vec<u8>,
+    transmission: Transmission,
+}
+
+impl Setup {
+    /// Starts transmission, or returns `None` if it isn't installed.
+    async fn start() -> anyhow::Result<Option<Self>> {
+        let Some(daemon) = std::env::var_os(TRANSMISSION_ENV).map(PathBuf::from) else {
+            eprintln!("interop: skipped, set {TRANSMISSION_ENV} to a transmission-daemon binary");
+            return Ok(None);
+        };
+        if !daemon.is_file() {
+            eprintln!("interop: skipped, {} does not exist", daemon.display());
+            return Ok(None);
+        }
+
+        let dir = tempfile::tempdir().context("create scratch directory")?;
+        let tracker = Tracker::start().await?;
+        let data = fixture_data(FIXTURE_LENGTH, 7);
+        let torrent =
+            TorrentBuilder::single_file(FIXTURE_NAME, FIXTURE_LENGTH, FIXTURE_PIECE_LENGTH)
+                .announce(&format!("http://{}/announce", tracker.addr))
+                .creation_date(0)
+                .build(data.as_slice())?;
+        let torrent_path = dir.path().join("interop.torrent");
+        std::fs::write(&torrent_path, serde_bencode::to_bytes(&torrent)?)
+            .context("write fixture torrent")?;
+        let transmission = Transmission::start(&daemon, dir.path()).await?;
+        Ok(Some(Self {
+            dir,
+            tracker,
+            torrent,
+            torrent_path,
+            data,
+            transmission,
+        }))
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs transmission-daemon, see the module docs"]
+async fn transmission_seeds_to_us() -> anyhow::Result<()> {
+    let Some(setup) = Setup::start().await? else {
+        return Ok(());
+    };
+    they_seed(
+        &setup.transmission,
+        &setup.tracker,
+        &setup.torrent,
+        &setup.torrent_path,
+        &setup.data,
+        setup.dir.path(),
+    )
+    .await
+}
+
+#[tokio::test]
+#[ignore = "needs transmission-daemon, see the module docs"]
+async fn we_seed_to_transmission() -> anyhow::Result<()> {
+    let Some(setup) = Setup::start().await? else {
+        return Ok(());
+    };
+    we_seed(
+        &setup.transmission,
+        &setup.tracker,
+        &setup.torrent_path,
+        &setup.data,
+        setup.dir.path(),
+    )
+    .await
+}
+
+/// Transmission seeds the fixture and we download every piece of it.
+async fn they_seed(
+    transmission: &Transmission,
+    tracker: &Tracker,
+    torrent: &Torrent,
+    torrent_path: &Path,
+    data: &[u8],
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let seed_dir = dir.join("transmission-seed");
+    std::fs::create_dir_all(&seed_dir)?;
+    std::fs::write(seed_dir.join(FIXTURE_NAME), data).context("write fixture data")?;
+    let id = transmission.add(torrent_path, &seed_dir).await?;
+    transmission.wait_complete(id).await?;
+    tracker.hand_out(SocketAddrV4::new(
+        Ipv4Addr::LOCALHOST,
+        transmission.peer_port,
+    ));
+
+    let out_dir = dir.join("pieces");
+    std::fs::create_dir_all(&out_dir)?;
+    for (index, expected) in torrent.info.pieces.0.iter().enumerate() {
+        let output = out_dir.join(format!("{index}"));
+        let result = ourselves()?
+            .arg("download_piece")
+            .arg("-o")
+            .arg(&output)
+            .arg(torrent_path)
+            .arg(index.to_string())
+            .stdout(Stdio::null())
+            .output()
+            .await
+            .context("run download_piece")?;
+        let log = String::from_utf8_lossy(&result.stderr);
+        check_log(&log).with_context(|| format!("download of piece {index}"))?;
+        if !result.status.success() {
+            bail!("download of piece {index} failed:\n{log}");
+        }
+        let piece = std::fs::read(&output).with_context(|| format!("read piece {index}"))?;
+        if piece::sha1(&piece) != *expected {
+            bail!("piece {index} doesn't match its hash");
+        }
+    }
+    transmission.remove(id).await
+}
+
+/// We seed the fixture and transmission downloads it.
+async fn we_seed(
+    transmission: &Transmission,
+    tracker: &Tracker,
+    torrent_path: &Path,
+    data: &[u8],
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let data_path = dir.join(FIXTURE_NAME);
+    std::fs::write(&data_path, data).context("write fixture data")?;
+    let port = free_port().await?;
+    let mut seeder = ourselves()?
+        .arg("seed")
+        .arg("--data")
+        .arg(&data_path)
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(torrent_path)
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("start our seeder")?;
+    // read the log as it comes, or the seeder blocks once the pipe is full
+    let mut stderr = seeder.stderr.take().context("seeder stderr")?;
+    let log = tokio::spawn(async move {
+        let mut log = Vec::new();
+        let _ = stderr.read_to_end(&mut log).await;
+        String::from_utf8_lossy(&log).into_owned()
+    });
+    tracker.hand_out(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+
+    let leech_dir = dir.join("transmission-leech");
+    std::fs::create_dir_all(&leech_dir)?;
+    let id = transmission.add(torrent_path, &leech_dir).await?;
+    let complete = transmission.wait_complete(id).await;
+    seeder.start_kill().context("stop our seeder")?;
+    seeder.wait().await?;
+    let log = log.await?;
+    complete.with_context(|| format!("our seeder's log:\n{log}"))?;
+    check_log(&log).context("our seeder")?;
+    let downloaded =
+        std::fs::read(leech_dir.join(FIXTURE_NAME)).context("read transmission's download")?;
+    if downloaded != data {
+        bail!("transmission's download differs from the fixture");
+    }
+    transmission.remove(id).await
+}
+
+/// Our own binary, with local trackers allowed.
+fn ourselves() -> anyhow::Result<Command> {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_bittorrent-starter-rust"));
+    command.arg("--allow-local-trackers").kill_on_drop(true);
+    Ok(command)
+}
+
+fn check_log(log: &str) -> anyhow::Result<()> {
+    let lower = log.to_lowercase();
+    if let Some(line) = lower
+        .lines()
+        .find(|line| PROTOCOL_ERRORS.iter().any(|error| line.contains(error)))
+    {
+        bail!("protocol error in our log: {line}");
+    }
+    Ok(())
+}
+
+async fn free_port() -> anyhow::Result<u16> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// A tracker that answers every announce with the same single peer.
+struct Tracker {
+    addr: SocketAddrV4,
+    peer: Arc<Mutex<Option<SocketAddrV4>>>,
+}
+
+impl Tracker {
+    async fn start() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .context("bind tracker")?;
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr()?.port());
+        let peer = Arc::new(Mutex::new(None));
+        let handed_out = Arc::clone(&peer);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let peer = *handed_out.lock().expect("tracker lock poisoned");
+                tokio::spawn(async move {
+                    // the request doesn't matter, only that it was read
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let response = TrackerResponse {
+                        interval: 5,
+                        peers: Peers(peer.into_iter().map(SocketAddr::V4).collect()),
+                        peers6: None,
+                        warning_message: None,
+                        min_interval: None,
+                        tracker_id: None,
+                        complete: None,
+                        incomplete: None,
+                    };
+                    let body =
+                        serde_bencode::to_bytes(&response).expect("tracker response encodes");
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+        Ok(Self { addr, peer })
+    }
+
+    /// From now on, hand out `peer` to whoever announces.
+    fn hand_out(&self, peer: SocketAddrV4) {
+        *self.peer.lock().expect("tracker lock poisoned") = Some(peer);
+    }
+}
+
+/// A transmission-daemon of our own, driven over its RPC interface.
+struct Transmission {
+    _process: Child,
+    rpc: String,
+    peer_port: u16,
+    http: reqwest::Client,
+    session_id: Mutex<String>,
+}
+
+impl Transmission {
+    async fn start(daemon: &Path, dir: &Path) -> anyhow::Result<Self> {
+        let config_dir = dir.join("transmission-config");
+        std::fs::create_dir_all(&config_dir)?;
+        let rpc_port = free_port().await?;
+        let peer_port = free_port().await?;
+        let process = Command::new(daemon)
+            .arg("--foreground")
+            .arg("--config-dir")
+            .arg(&config_dir)
+            .args(["--port", &rpc_port.to_string()])
+            .args(["--peerport", &peer_port.to_string()])
+            .args(["--no-auth", "--no-portmap", "--no-dht", "--no-lpd"])
+            .arg("--encryption-tolerated")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("start {}", daemon.display()))?;
+        let transmission = Self {
+            _process: process,
+            rpc: format!("http://127.0.0.1:{rpc_port}/transmission/rpc"),
+            peer_port,
+            http: reqwest::Client::new(),
+            session_id: Mutex::new(String::new()),
+        };
+        for _ in 0..50 {
+            if transmission.call("session-get", json!({})).await.is_ok() {
+                return Ok(transmission);
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        bail!("transmission-daemon did not come up");
+    }
+
+    /// Adds the torrent at `path`, with its data in `download_dir`, returning its id.
+    async fn add(&self, path: &Path, download_dir: &Path) -> anyhow::Result<i64> {
+        let added = self
+            .call(
+                "torrent-add",
+                json!({ "filename": path, "download-dir": download_dir, "paused": false }),
+            )
+            .await?;
+        let torrent = added
+            .get("torrent-added")
+            .or_else(|| added.get("torrent-duplicate"))
+            .context("transmission did not add the torrent")?;
+        torrent["id"].as_i64().context("torrent id")
+    }
+
+    async fn remove(&self, id: i64) -> anyhow::Result<()> {
+        self.call(
+            "torrent-remove",
+            json!({ "ids": [id], "delete-local-data": false }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Waits until transmission has all of torrent `id`, hash-checked.
+    async fn wait_complete(&self, id: i64) -> anyhow::Result<()> {
+        let poll = async {
+            loop {
+                let got = self
+                    .call(
+                        "torrent-get",
+                        json!({ "ids": [id], "fields": ["percentDone", "error", "errorString"] }),
+                    )
+                    .await?;
+                let torrent = &got["torrents"][0];
+                if torrent["error"].as_i64().unwrap_or(0) != 0 {
+                    bail!("transmission: {}", torrent["errorString"]);
+                }
+                if torrent["percentDone"].as_f64() == Some(1.0) {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        };
+        tokio::time::timeout(TRANSFER_TIMEOUT, poll)
+            .await
+            .context("transfer timed out")?
+    }
+
+    /// Calls an RPC method, doing the session id dance as needed.
+    async fn call(&self, method: &str, arguments: Value) -> anyhow::Result<Value> {
+        let body = json!({ "method": method, "arguments": arguments });
+        for _ in 0..2 {
+            let session_id = self
+                .session_id
+                .lock()
+                .expect("session lock poisoned")
+                .clone();
+            let response = self
+                .http
+                .post(&self.rpc)
+                .header("X-Transmission-Session-Id", session_id)
+                .json(&body)
+                .send()
+                .await?;
+            if response.status() == reqwest::StatusCode::CONFLICT {
+                let session_id = response
+                    .headers()
+                    .get("X-Transmission-Session-Id")
+                    .and_then(|id| id.to_str().ok())
+                    .context("transmission did not send a session id")?;
+                *self.session_id.lock().expect("session lock poisoned") = session_id.to_string();
+                continue;
+            }
+            let response: Value = response.error_for_status()?.json().await?;
+            if response["result"] != "success" {
+                bail!("transmission {method}: {}", response["result"]);
+            }
+            return Ok(response["arguments"].clone());
+        }
+        bail!("transmission keeps rejecting our session id");
+    }
+}
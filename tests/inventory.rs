@@ -0,0 +1,130 @@
+//! `inventory` over a fixture directory with a corrupt torrent among the good ones.
+
+mod common;
+
+use bittorrent_starter_rust::create::TorrentBuilder;
+use bittorrent_starter_rust::torrent::Torrent;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// `single.torrent`, `corrupt.torrent`, a text file, and `sub/multi.torrent`, whose name
+/// needs quoting in CSV.
+fn fixture_dir() -> (TempDir, Torrent, Torrent) {
+    let dir = tempfile::tempdir().unwrap();
+    let single = TorrentBuilder::single_file("single.bin", 40000, 16384)
+        .announce("http://tracker.example/announce")
+        .creation_date(0)
+        .build(&vec![1; 40000][..])
+        .unwrap();
+    let files = vec![
+        (vec!["a.txt".to_string()], 10),
+        (vec!["b.txt".to_string()], 20),
+    ];
+    let multi = TorrentBuilder::multi_file("multi, \"v2\"", files, 16384)
+        .announce("http://one.example/announce")
+        .announce_list(vec![
+            vec!["http://one.example/announce".to_string()],
+            vec!["udp://two.example:6969".to_string()],
+        ])
+        .creation_date(1_700_000_000)
+        .private(true)
+        .build(&[2; 30][..])
+        .unwrap();
+    write(&dir.path().join("single.torrent"), &single);
+    std::fs::write(dir.path().join("corrupt.torrent"), b"d8:announce").unwrap();
+    std::fs::write(dir.path().join("notes.txt"), b"not a torrent").unwrap();
+    std::fs::create_dir(dir.path().join("sub")).unwrap();
+    write(&dir.path().join("sub").join("multi.torrent"), &multi);
+    (dir, single, multi)
+}
+
+fn write(path: &Path, torrent: &Torrent) {
+    std::fs::write(path, serde_bencode::to_bytes(torrent).unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn a_corrupt_torrent_is_reported_and_the_rest_listed() {
+    let (dir, single, _) = fixture_dir();
+    let assert = common::run(["inventory".into(), dir.path().as_os_str().to_owned()])
+        .await
+        .success();
+    let output = assert.get_output();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "path,info_hash,name,total_size,piece_length,pieces,files,private,creation_date,trackers
+{},{},single.bin,40000,16384,3,1,false,1970-01-01T00:00:00Z,http://tracker.example/announce
+",
+            dir.path().join("single.torrent").display(),
+            hex::encode(single.info_hash())
+        )
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("corrupt.torrent"), "{stderr}");
+    assert!(
+        stderr.contains("listed 1 torrent(s), 1 could not be read"),
+        "{stderr}"
+    );
+}
+
+#[tokio::test]
+async fn a_recursive_json_inventory_lists_subdirectories() {
+    let (dir, single, multi) = fixture_dir();
+    let assert = common::run([
+        "inventory".into(),
+        "--recursive".into(),
+        "--format".into(),
+        "json".into(),
+        dir.path().as_os_str().to_owned(),
+    ])
+    .await
+    .success();
+    let listed: serde_json::Value = serde_json::from_slice(&assert.get_output().stdout).unwrap();
+    assert_eq!(
+        listed,
+        serde_json::json!([
+            {
+                "path": dir.path().join("single.torrent"),
+                "info_hash": hex::encode(single.info_hash()),
+                "name": "single.bin",
+                "total_size": 40000,
+                "piece_length": 16384,
+                "pieces": 3,
+                "files": 1,
+                "private": false,
+                "creation_date": "1970-01-01T00:00:00Z",
+                "trackers": ["http://tracker.example/announce"],
+            },
+            {
+                "path": dir.path().join("sub").join("multi.torrent"),
+                "info_hash": hex::encode(multi.info_hash()),
+                "name": "multi, \"v2\"",
+                "total_size": 30,
+                "piece_length": 16384,
+                "pieces": 1,
+                "files": 2,
+                "private": true,
+                "creation_date": "2023-11-14T22:13:20Z",
+                "trackers": ["http://one.example/announce", "udp://two.example:6969"],
+            },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn csv_fields_with_commas_and_quotes_are_quoted() {
+    let (dir, _, multi) = fixture_dir();
+    let assert = common::run(["inventory".into(), dir.path().join("sub").into_os_string()])
+        .await
+        .success();
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).into_owned();
+    let row = stdout.lines().nth(1).unwrap();
+    assert!(
+        row.ends_with(&format!(
+            ",{},\"multi, \"\"v2\"\"\",30,16384,1,2,true,2023-11-14T22:13:20Z,\
+             http://one.example/announce udp://two.example:6969",
+            hex::encode(multi.info_hash())
+        )),
+        "{row}"
+    );
+}
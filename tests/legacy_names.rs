@@ -0,0 +1,86 @@
+//! Torrents whose names aren't UTF-8, as written by old clients.
+
+mod common;
+
+use bittorrent_starter_rust::client::DownloadOptions;
+use bittorrent_starter_rust::create::fixture_data;
+use bittorrent_starter_rust::piece;
+use bittorrent_starter_rust::torrent::Torrent;
+use common::Seed;
+use tokio_util::sync::CancellationToken;
+
+/// `表示.txt` in Shift-JIS; the second byte of `表` is `\`.
+const SHIFT_JIS: &[u8] = b"\x95\x5c\x8e\xa6.txt";
+
+const PLENGTH: usize = 16384;
+
+/// A single-file torrent named [`SHIFT_JIS`] holding `data`, and its raw info dict.
+fn torrent(data: &[u8], encoding: Option<&str>) -> (Vec<u8>, Vec<u8>) {
+    let mut info = format!("d6:lengthi{}e4:name{}:", data.len(), SHIFT_JIS.len()).into_bytes();
+    info.extend_from_slice(SHIFT_JIS);
+    let pieces: Vec<u8> = data.chunks(PLENGTH).flat_map(piece::sha1).collect();
+    info.extend_from_slice(
+        format!("12:piece lengthi{PLENGTH}e6:pieces{}:", pieces.len()).as_bytes(),
+    );
+    info.extend_from_slice(&pieces);
+    info.push(b'e');
+
+    let mut torrent = b"d8:announce30:http://127.0.0.1:6969/announce".to_vec();
+    if let Some(encoding) = encoding {
+        torrent.extend_from_slice(format!("8:encoding{}:{encoding}", encoding.len()).as_bytes());
+    }
+    torrent.extend_from_slice(b"4:info");
+    torrent.extend_from_slice(&info);
+    torrent.push(b'e');
+    (torrent, info)
+}
+
+#[test]
+fn a_shift_jis_name_parses_and_keeps_the_info_hash() {
+    let data = fixture_data(3 * PLENGTH, 1);
+    let (bytes, info) = torrent(&data, Some("Shift_JIS"));
+    let torrent = Torrent::from_bytes(&bytes).unwrap();
+    assert_eq!(torrent.info_hash(), piece::sha1(&info));
+    assert_eq!(torrent.info.name.as_bytes(), SHIFT_JIS);
+    // re-encoding, as the info hash of a torrent we built ourselves is computed
+    assert_eq!(serde_bencode::to_bytes(&torrent.info).unwrap(), info);
+    assert_eq!(torrent.file_path(0).unwrap().to_str(), Some("表示.txt"));
+}
+
+#[test]
+fn without_an_encoding_the_name_is_still_a_single_safe_component() {
+    let data = fixture_data(PLENGTH, 1);
+    let (bytes, info) = torrent(&data, None);
+    let torrent = Torrent::from_bytes(&bytes).unwrap();
+    assert_eq!(torrent.info_hash(), piece::sha1(&info));
+    let path = torrent.file_path(0).unwrap();
+    assert_eq!(path.components().count(), 1);
+    let name = path.to_str().unwrap();
+    assert!(name.contains('\u{fffd}') && !name.contains('\\'), "{name}");
+}
+
+#[tokio::test]
+async fn a_shift_jis_named_torrent_downloads_to_its_decoded_name() {
+    let data = fixture_data(2 * PLENGTH + 5, 1);
+    let (bytes, _) = torrent(&data, Some("Shift_JIS"));
+    let torrent = Torrent::from_bytes(&bytes).unwrap();
+    let seed = Seed::start(&torrent, &data).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join(torrent.file_path(0).unwrap());
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        ..DownloadOptions::default()
+    };
+    common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(dir.path().join("表示.txt")).unwrap(), data);
+}
@@ -0,0 +1,171 @@
+//! The peer cache carrying good and banned peers from one session to the next.
+
+mod common;
+
+use bittorrent_starter_rust::client::{DownloadOptions, DownloadOutcome};
+use bittorrent_starter_rust::peer_cache::{self, PeerCache, PeerCacheConfig};
+use bittorrent_starter_rust::torrent::Torrent;
+use common::{MockTracker, Seed};
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const PLENGTH: usize = 16384;
+
+/// Downloads piece 0 of the torrent at `torrent_path` into `dir`, remembering peers in
+/// `dir/state`.
+async fn download_piece(
+    dir: &Path,
+    tracker: &MockTracker,
+    torrent_path: &Path,
+) -> assert_cmd::assert::Assert {
+    let args: [OsString; 9] = [
+        "--announce".into(),
+        tracker.url.clone().into(),
+        "--state-dir".into(),
+        dir.join("state").into(),
+        "download_piece".into(),
+        "-o".into(),
+        dir.join("piece.bin").into(),
+        torrent_path.into(),
+        "0".into(),
+    ];
+    common::run(args).await
+}
+
+fn cache(dir: &Path, torrent: &Torrent) -> PeerCache {
+    let info_hash = torrent.info_hash();
+    let path = peer_cache::path(&dir.join("state"), &info_hash);
+    PeerCache::load(
+        &path,
+        &info_hash,
+        peer_cache::unix_now(),
+        Duration::from_secs(3600),
+    )
+}
+
+#[tokio::test]
+async fn the_next_session_dials_the_cached_peer_before_announcing() {
+    let len = 2 * PLENGTH;
+    let torrent = Torrent::fixture_single_file(len, PLENGTH);
+    let data = Torrent::fixture_data(len);
+    let seed = Seed::start(&torrent, &data).await;
+    let tracker = MockTracker::start(&[seed.addr]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+
+    download_piece(dir.path(), &tracker, &torrent_path)
+        .await
+        .success();
+    assert_eq!(tracker.requests().len(), 1);
+    assert_eq!(cache(dir.path(), &torrent).dial_order(), [seed.addr]);
+
+    std::fs::remove_file(dir.path().join("piece.bin")).unwrap();
+    download_piece(dir.path(), &tracker, &torrent_path)
+        .await
+        .success();
+    assert_eq!(tracker.requests().len(), 1, "the second session announced");
+    assert_eq!(
+        std::fs::read(dir.path().join("piece.bin")).unwrap(),
+        data[..PLENGTH]
+    );
+}
+
+#[tokio::test]
+async fn a_peer_that_sent_bad_data_stays_banned_in_the_next_session() {
+    let len = 2 * PLENGTH;
+    let torrent = Torrent::fixture_single_file(len, PLENGTH);
+    let poison = vec![0xbd; len];
+    let seed = Seed::start(&torrent, &poison).await;
+    let tracker = MockTracker::start(&[seed.addr]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+
+    download_piece(dir.path(), &tracker, &torrent_path)
+        .await
+        .failure();
+    let cached = cache(dir.path(), &torrent);
+    assert!(cached.is_banned(seed.addr), "{cached:?}");
+    assert!(cached.dial_order().is_empty());
+
+    let assert = download_piece(dir.path(), &tracker, &torrent_path)
+        .await
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("the tracker knows no peers that aren't banned"),
+        "{stderr}"
+    );
+    assert_eq!(tracker.requests().len(), 2);
+    assert_eq!(seed.seeder.uploaded(), PLENGTH as u64);
+}
+
+/// Downloads all of `torrent` into `dir/name` from the peers its tracker knows, remembering
+/// peers in `dir/state`.
+async fn download(dir: &Path, torrent: &Torrent, name: &str) -> DownloadOutcome {
+    let options = DownloadOptions {
+        peer_cache: Some(PeerCacheConfig {
+            state_dir: dir.join("state"),
+            max_age: Duration::from_secs(3600),
+        }),
+        ..DownloadOptions::default()
+    };
+    common::client()
+        .download(
+            torrent,
+            &dir.join(name),
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn the_next_download_starts_with_the_peer_that_served_the_last() {
+    let len = 3 * PLENGTH;
+    let mut torrent = Torrent::fixture_single_file(len, PLENGTH);
+    let data = Torrent::fixture_data(len);
+    let seed = Seed::start(&torrent, &data).await;
+    let tracker = MockTracker::start(&[seed.addr]).await;
+    torrent.announce = tracker.url.clone();
+    let dir = tempfile::tempdir().unwrap();
+
+    download(dir.path(), &torrent, "first.bin").await;
+    assert_eq!(cache(dir.path(), &torrent).dial_order(), [seed.addr]);
+
+    // a tracker that knows no one, the seed can only come from the cache
+    let empty = MockTracker::start(&[]).await;
+    torrent.announce = empty.url.clone();
+    let outcome = download(dir.path(), &torrent, "second.bin").await;
+    assert_eq!(outcome.tried, 1);
+    assert_eq!(std::fs::read(dir.path().join("second.bin")).unwrap(), data);
+    let cached = cache(dir.path(), &torrent);
+    assert_eq!(cached.peers.len(), 1);
+    // once per session, not once per piece, faded by the second or so in between
+    assert!((1.9..=2.0).contains(&cached.peers[0].score), "{cached:?}");
+}
+
+#[tokio::test]
+async fn a_peer_that_sent_bad_data_to_a_download_is_not_dialed_by_the_next() {
+    let len = 3 * PLENGTH;
+    let mut torrent = Torrent::fixture_single_file(len, PLENGTH);
+    let data = Torrent::fixture_data(len);
+    let poisoned = Seed::start(&torrent, &vec![0xbd; len]).await;
+    let seed = Seed::start(&torrent, &data).await;
+    let tracker = MockTracker::start(&[poisoned.addr, seed.addr]).await;
+    torrent.announce = tracker.url.clone();
+    let dir = tempfile::tempdir().unwrap();
+
+    let outcome = download(dir.path(), &torrent, "first.bin").await;
+    assert_eq!(outcome.banned, 1);
+    assert!(cache(dir.path(), &torrent).is_banned(poisoned.addr));
+    let poisoned_uploads = poisoned.seeder.uploaded();
+
+    let outcome = download(dir.path(), &torrent, "second.bin").await;
+    assert_eq!((outcome.tried, outcome.banned), (1, 0));
+    assert_eq!(std::fs::read(dir.path().join("second.bin")).unwrap(), data);
+    assert_eq!(poisoned.seeder.uploaded(), poisoned_uploads);
+}
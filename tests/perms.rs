@@ -0,0 +1,133 @@
+//! `--file-mode` and `--dir-mode` on what a download creates.
+//!
+//! The modes are process-wide, so every test in here that creates files in-process uses
+//! the same ones.
+#![cfg(unix)]
+
+mod common;
+
+use bittorrent_starter_rust::create::TorrentBuilder;
+use bittorrent_starter_rust::files::{DataWriter, FileMapper};
+use bittorrent_starter_rust::perms::{self, Modes};
+use bittorrent_starter_rust::torrent::Torrent;
+use common::{MockTracker, Seed};
+use std::ffi::OsString;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const PLENGTH: usize = 16384;
+
+/// Wider than the usual umask of 022 allows, so the umask can't have decided them.
+const MODES: Modes = Modes {
+    file_mode: Some(0o664),
+    dir_mode: Some(0o775),
+};
+
+fn mode(path: &Path) -> u32 {
+    std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+fn set_mode(path: &Path, mode: u32) {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+}
+
+/// A torrent of `tree/a.bin` and `tree/sub/dir/b.bin`.
+fn tree() -> Torrent {
+    let files = vec![
+        (vec!["a.bin".to_string()], 100),
+        (
+            vec!["sub".to_string(), "dir".to_string(), "b.bin".to_string()],
+            200,
+        ),
+    ];
+    TorrentBuilder::multi_file("tree", files, PLENGTH)
+        .creation_date(0)
+        .build(&[7u8; 300][..])
+        .unwrap()
+}
+
+#[tokio::test]
+async fn created_files_and_directories_get_the_modes_asked_for() {
+    perms::set_modes(MODES);
+    let dir = tempfile::tempdir().unwrap();
+    DataWriter::create(FileMapper::new(&tree(), dir.path()))
+        .await
+        .unwrap();
+
+    let root = dir.path().join("tree");
+    for created in [&root, &root.join("sub"), &root.join("sub").join("dir")] {
+        assert_eq!(mode(created), 0o775, "{}", created.display());
+    }
+    for created in [
+        root.join("a.bin"),
+        root.join("sub").join("dir").join("b.bin"),
+    ] {
+        assert_eq!(mode(&created), 0o664, "{}", created.display());
+    }
+}
+
+#[tokio::test]
+async fn a_resumed_download_keeps_the_modes_it_finds() {
+    perms::set_modes(MODES);
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tree");
+    let sub = root.join("sub");
+    std::fs::create_dir_all(&sub).unwrap();
+    set_mode(&sub, 0o700);
+    std::fs::write(root.join("a.bin"), [7; 50]).unwrap();
+    set_mode(&root.join("a.bin"), 0o600);
+
+    DataWriter::create(FileMapper::new(&tree(), dir.path()))
+        .await
+        .unwrap();
+
+    assert_eq!(mode(&sub), 0o700);
+    assert_eq!(mode(&root.join("a.bin")), 0o600);
+    assert_eq!(std::fs::read(root.join("a.bin")).unwrap()[..50], [7; 50]);
+    // what didn't exist yet is created as asked
+    assert_eq!(mode(&sub.join("dir")), 0o775);
+    assert_eq!(mode(&sub.join("dir").join("b.bin")), 0o664);
+}
+
+async fn download_piece(output: &Path, file_mode: &str) -> assert_cmd::assert::Assert {
+    let len = 2 * PLENGTH;
+    let torrent = Torrent::fixture_single_file(len, PLENGTH);
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(len)).await;
+    let tracker = MockTracker::start(&[seed.addr]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+    let args: [OsString; 9] = [
+        "--announce".into(),
+        tracker.url.clone().into(),
+        "--file-mode".into(),
+        file_mode.into(),
+        "download_piece".into(),
+        "-o".into(),
+        output.into(),
+        torrent_path.into(),
+        "1".into(),
+    ];
+    common::run(args).await
+}
+
+#[tokio::test]
+async fn the_command_line_modes_reach_the_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("piece.bin");
+    download_piece(&output, "0664").await.success();
+    assert_eq!(mode(&output), 0o664);
+    assert_eq!(
+        std::fs::read(&output).unwrap(),
+        Torrent::fixture_data(2 * PLENGTH)[PLENGTH..]
+    );
+
+    let assert = download_piece(&dir.path().join("other.bin"), "0648")
+        .await
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("`0648` is not an octal file mode"),
+        "{stderr}"
+    );
+    assert!(!dir.path().join("other.bin").exists());
+}
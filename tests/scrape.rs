@@ -0,0 +1,133 @@
+//! Scraping a tracker on loopback, once and in watch mode.
+
+mod common;
+
+use bittorrent_starter_rust::scrape::{self, ScrapeTarget};
+use common::MockTracker;
+use std::time::Duration;
+
+const A: [u8; 20] = [0xaa; 20];
+const B: [u8; 20] = [0xbb; 20];
+
+/// A scrape response with `(info hash, seeders, leechers, snatches)` for each torrent.
+fn response(files: &[([u8; 20], u64, u64, u64)]) -> Vec<u8> {
+    let mut body = b"d5:filesd".to_vec();
+    for (info_hash, complete, incomplete, downloaded) in files {
+        body.extend_from_slice(b"20:");
+        body.extend_from_slice(info_hash);
+        body.extend_from_slice(
+            format!(
+                "d8:completei{complete}e10:downloadedi{downloaded}e10:incompletei{incomplete}ee"
+            )
+            .as_bytes(),
+        );
+    }
+    body.extend_from_slice(b"ee");
+    body
+}
+
+fn target(name: &str, info_hash: [u8; 20], tracker: &MockTracker) -> ScrapeTarget {
+    ScrapeTarget {
+        name: name.to_string(),
+        info_hash,
+        announce: tracker.url.clone(),
+    }
+}
+
+#[tokio::test]
+async fn one_request_asks_for_every_hash_and_missing_ones_are_left_out() {
+    let tracker = MockTracker::with_body(response(&[(B, 3, 1, 7)])).await;
+    let url = scrape::scrape_url(&tracker.url).unwrap();
+    let stats = scrape::scrape(common::client().trackers(), &url, &[A, B])
+        .await
+        .unwrap();
+
+    assert_eq!(stats.len(), 1);
+    let b = stats[&B];
+    assert_eq!((b.complete, b.incomplete, b.downloaded), (3, 1, 7));
+    let requests = tracker.requests();
+    assert_eq!(requests.len(), 1);
+    let expected = format!(
+        "/scrape?info_hash={}&info_hash={}",
+        "%AA".repeat(20),
+        "%BB".repeat(20)
+    );
+    assert_eq!(requests[0], expected);
+}
+
+#[tokio::test]
+async fn a_refused_scrape_is_an_error() {
+    let tracker = MockTracker::with_body(b"d14:failure reason9:forbiddene".to_vec()).await;
+    let url = scrape::scrape_url(&tracker.url).unwrap();
+    let err = scrape::scrape(common::client().trackers(), &url, &[A])
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "tracker refused scrape: forbidden");
+}
+
+#[tokio::test]
+async fn watching_appends_a_row_per_torrent_and_poll_to_the_csv() {
+    let tracker = MockTracker::with_bodies(vec![
+        response(&[(A, 1, 4, 0), (B, 9, 0, 20)]),
+        // the tracker forgot about B for a poll
+        response(&[(A, 2, 3, 1)]),
+        response(&[(A, 3, 2, 2), (B, 8, 1, 21)]),
+    ])
+    .await;
+    let dir = tempfile::tempdir().unwrap();
+    let csv = dir.path().join("swarm.csv");
+    let targets = [
+        target("a, the first", A, &tracker),
+        target("b", B, &tracker),
+    ];
+
+    let client = common::client();
+    let watching = scrape::watch(
+        client.trackers(),
+        &targets,
+        Some(Duration::from_millis(50)),
+        Some(&csv),
+        false,
+    );
+    // the header and the rows of three polls
+    let polled = async {
+        while std::fs::read_to_string(&csv).map_or(0, |csv| csv.lines().count()) < 6 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::select! {
+        result = watching => panic!("watching ended: {result:?}"),
+        () = polled => {}
+    }
+
+    let csv = std::fs::read_to_string(&csv).unwrap();
+    let rows: Vec<_> = csv
+        .lines()
+        .map(|line| line.split_once(',').unwrap().1)
+        .collect();
+    let (a, b) = (hex::encode(A), hex::encode(B));
+    assert_eq!(
+        rows,
+        [
+            "info_hash,name,seeders,leechers,snatches".to_string(),
+            format!("{a},\"a, the first\",1,4,0"),
+            format!("{b},b,9,0,20"),
+            format!("{a},\"a, the first\",2,3,1"),
+            format!("{a},\"a, the first\",3,2,2"),
+            format!("{b},b,8,1,21"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn scraping_once_fails_if_a_tracker_did_not_answer() {
+    let targets = [ScrapeTarget {
+        name: "x".to_string(),
+        info_hash: A,
+        announce: "http://127.0.0.1:1/announce".to_string(),
+    }];
+    let err = scrape::watch(common::client().trackers(), &targets, None, None, true)
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "1 of 1 tracker(s) couldn't be scraped");
+}
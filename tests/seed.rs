@@ -0,0 +1,584 @@
+//! Seeding to our own downloader over loopback.
+
+mod common;
+
+use bittorrent_starter_rust::admission::MAX_CONNECTIONS_PER_IP;
+use bittorrent_starter_rust::client::{DownloadOptions, Limits, PeerConnection, TransferStats};
+use bittorrent_starter_rust::create::TorrentBuilder;
+use bittorrent_starter_rust::extension::{self, BencodeDict, ExtendedHandshake};
+use bittorrent_starter_rust::peer::{
+    Bitfield, Handshake, MessageFramer, MessagePayload, MessageRequest, WRITE_TIMEOUT,
+};
+use bittorrent_starter_rust::seed::{PieceMap, HANDSHAKE_TIMEOUT};
+use bittorrent_starter_rust::torrent::{Metainfo, Torrent};
+use common::Seed;
+use futures_util::{SinkExt, StreamExt};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_util::codec::Framed;
+use tokio_util::sync::CancellationToken;
+
+const PLENGTH: usize = 16384;
+
+#[tokio::test]
+async fn a_partial_seed_serves_exactly_the_pieces_of_its_piece_map() {
+    let len = 6 * PLENGTH + 10;
+    let torrent = Torrent::fixture_single_file(len, PLENGTH);
+    let data = Torrent::fixture_data(len);
+    // only the even pieces made it to disk, the rest is still preallocated zeroes
+    let mut partial = vec![0; len];
+    let mut have = Bitfield::new(torrent.declared_pieces());
+    for index in (0..torrent.declared_pieces()).step_by(2) {
+        let range = index * PLENGTH..(len).min((index + 1) * PLENGTH);
+        partial[range.clone()].copy_from_slice(&data[range]);
+        have.set_piece(index);
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let map = dir.path().join("seed.bin.pieces");
+    PieceMap::from_bitfield(&torrent, &have)
+        .unwrap()
+        .save(&map)
+        .unwrap();
+    let have = PieceMap::load(&map, &torrent)
+        .unwrap()
+        .expect("trusted map");
+    let seed = Seed::start_with(&torrent, &partial, have).await;
+    assert_eq!(seed.seeder.missing_bytes(), 3 * PLENGTH);
+
+    let client = common::client();
+    let mut stats = TransferStats::new(len);
+    let mut connection = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    for index in 0..torrent.declared_pieces() {
+        assert_eq!(connection.session.has_piece(index), index % 2 == 0);
+        let piece = client
+            .download_piece(&torrent, &mut connection, index, &mut stats)
+            .await;
+        if index % 2 == 1 {
+            let err = piece.unwrap_err().to_string();
+            assert!(err.contains("doesn't have piece"), "{err}");
+            continue;
+        }
+        let piece = piece
+            .unwrap()
+            .finish(torrent.piece_hash(index).unwrap(), true, &mut stats)
+            .unwrap();
+        let start = index * PLENGTH;
+        assert_eq!(piece, data[start..start + piece.len()]);
+    }
+}
+
+#[tokio::test]
+async fn requests_for_pieces_the_seed_lacks_go_unanswered() {
+    let len = 2 * PLENGTH;
+    let torrent = Torrent::fixture_single_file(len, PLENGTH);
+    let data = Torrent::fixture_data(len);
+    let mut have = Bitfield::new(2);
+    have.set_piece(1);
+    let seed = Seed::start_with(&torrent, &data, have).await;
+
+    let client = common::client();
+    let mut stats = TransferStats::new(len);
+    let mut connection = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    for index in [0, 1] {
+        let request = MessageRequest::new(index, 0, 16);
+        connection
+            .stream
+            .send(MessagePayload::Request(request))
+            .await
+            .unwrap();
+    }
+    loop {
+        match connection.stream.next().await.unwrap().unwrap() {
+            MessagePayload::Piece { index, block, .. } => {
+                assert_eq!(index, 1, "the seed served a piece it doesn't have");
+                assert_eq!(block[..], data[PLENGTH..PLENGTH + 16]);
+                break;
+            }
+            MessagePayload::KeepAlive | MessagePayload::Have(_) => {}
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_download_gets_every_piece_of_the_seed_short_last_one_included() {
+    let len = 3 * PLENGTH + 100;
+    let torrent = Torrent::fixture_single_file(len, PLENGTH);
+    let data = Torrent::fixture_data(len);
+    let seed = Seed::start(&torrent, &data).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.bin");
+    let options = DownloadOptions {
+        peers: vec![seed.addr],
+        ..DownloadOptions::default()
+    };
+    let outcome = common::client()
+        .download(
+            &torrent,
+            &output,
+            &options,
+            |_| {},
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(outcome.connected, 1);
+    assert_eq!(std::fs::read(&output).unwrap(), data);
+    assert_eq!(seed.seeder.uploaded(), len as u64);
+}
+
+#[tokio::test]
+async fn requests_the_seed_cant_answer_are_ignored_and_later_ones_served() {
+    // pieces of two blocks, so a request longer than a block still fits in one
+    let plength = 2 * PLENGTH;
+    let len = 2 * plength + 100;
+    let torrent = Torrent::fixture_single_file(len, plength);
+    let data = Torrent::fixture_data(len);
+    let mut have = Bitfield::new(3);
+    have.set_piece(0);
+    have.set_piece(2);
+    let seed = Seed::start_with(&torrent, &data, have).await;
+
+    let client = common::client();
+    let mut stats = TransferStats::new(len);
+    let mut connection = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    let unanswerable = [
+        // a piece the seed lacks
+        MessageRequest::new(1, 0, PLENGTH as u32),
+        // longer than 16 KiB
+        MessageRequest::new(0, 0, PLENGTH as u32 + 1),
+        // past the end of a full piece, and of the short last one
+        MessageRequest::new(0, plength as u32 - 10, 20),
+        MessageRequest::new(2, 0, 101),
+    ];
+    let served = MessageRequest::new(2, 0, 100);
+    for request in unanswerable.into_iter().chain([served]) {
+        connection
+            .stream
+            .send(MessagePayload::Request(request))
+            .await
+            .unwrap();
+    }
+    loop {
+        let next = tokio::time::timeout(Duration::from_secs(5), connection.stream.next());
+        match next.await.unwrap().unwrap().unwrap() {
+            MessagePayload::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                assert_eq!((index, begin), (2, 0), "the seed answered a bad request");
+                assert_eq!(block[..], data[2 * plength..]);
+                break;
+            }
+            MessagePayload::KeepAlive | MessagePayload::Have(_) => {}
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+    let next = tokio::time::timeout(Duration::from_millis(200), connection.stream.next());
+    assert!(next.await.is_err(), "the seed sent more than was asked");
+    assert_eq!(seed.seeder.uploaded(), 100);
+}
+
+/// Keeps `depth` requests in flight until `stop` is set, cycling through the pieces, and
+/// returns how many blocks came in.
+async fn leech(
+    mut connection: PeerConnection,
+    npieces: u32,
+    depth: usize,
+    stop: &AtomicBool,
+) -> u64 {
+    let (mut requested, mut received) = (0, 0);
+    while !stop.load(Ordering::Relaxed) {
+        while requested - received < depth as u64 {
+            let request =
+                MessageRequest::new((requested % npieces as u64) as u32, 0, PLENGTH as u32);
+            connection
+                .stream
+                .send(MessagePayload::Request(request))
+                .await
+                .unwrap();
+            requested += 1;
+        }
+        if let MessagePayload::Piece { .. } = connection.stream.next().await.unwrap().unwrap() {
+            received += 1;
+        }
+    }
+    received
+}
+
+#[tokio::test]
+async fn a_greedy_leecher_does_not_starve_a_polite_one() {
+    let npieces = 16;
+    let torrent = Torrent::fixture_single_file(npieces * PLENGTH, PLENGTH);
+    let data = Torrent::fixture_data(npieces * PLENGTH);
+    // throttled, so the scheduler, not the sockets, decides who gets what
+    let limits = Limits {
+        max_up: 2 << 20,
+        ..Limits::default()
+    };
+    let have = Bitfield::full(npieces);
+    let seed = Seed::start_limited(&torrent, &data, have, limits).await;
+
+    let client = common::client();
+    let mut stats = TransferStats::new(data.len());
+    let greedy = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    let polite = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    let greedy_addr = greedy.stream.get_ref().local_addr().unwrap();
+    let polite_addr = polite.stream.get_ref().local_addr().unwrap();
+
+    let stop = AtomicBool::new(false);
+    let served = tokio::time::timeout(Duration::from_secs(20), async {
+        let greedy = async {
+            let queue = limits.max_queued_requests;
+            let mut connection = greedy;
+            // a whole queue's worth of requests, topped up as blocks arrive
+            let mut received = 0;
+            let mut requested = 0;
+            while received < 60 {
+                while requested - received < queue {
+                    let index = (requested % npieces) as u32;
+                    let request = MessageRequest::new(index, 0, PLENGTH as u32);
+                    connection
+                        .stream
+                        .send(MessagePayload::Request(request))
+                        .await
+                        .unwrap();
+                    requested += 1;
+                }
+                if let MessagePayload::Piece { .. } =
+                    connection.stream.next().await.unwrap().unwrap()
+                {
+                    received += 1;
+                }
+            }
+            let served = seed.seeder.served();
+            stop.store(true, Ordering::Relaxed);
+            served
+        };
+        let polite = leech(polite, npieces as u32, 8, &stop);
+        tokio::join!(greedy, polite).0
+    })
+    .await
+    .expect("both leechers are served");
+
+    let of = |addr| served.iter().find(|(peer, _)| *peer == addr).unwrap().1;
+    let (greedy, polite) = (of(greedy_addr), of(polite_addr));
+    assert!(polite > 0, "the polite leecher got nothing");
+    // an eighth of the greedy one's requests in flight still gets about as many turns
+    let ratio = greedy as f64 / polite as f64;
+    assert!(ratio <= 1.5, "served {greedy} vs {polite} bytes");
+}
+
+/// Connects to `seed` from the loopback address `from` and handshakes, returning the
+/// connection if the seed answered and `None` if it hung up instead.
+async fn handshake_from(from: [u8; 4], seed: SocketAddr, torrent: &Torrent) -> Option<TcpStream> {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::new(IpAddr::from(from), 0)).unwrap();
+    let mut stream = socket.connect(seed).await.unwrap();
+    let handshake = Handshake::new(torrent.info_hash(), [from[3]; 20], false);
+    // a refused connection may be closed before the handshake is even written
+    stream.write_all(&handshake.to_bytes()).await.ok()?;
+    let mut answer = [0; Handshake::LEN];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut answer));
+    read.await.expect("answer or hang-up").ok()?;
+    Some(stream)
+}
+
+#[tokio::test]
+async fn one_address_gets_a_few_connections_while_another_still_connects() {
+    let torrent = Torrent::fixture_single_file(PLENGTH, PLENGTH);
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(PLENGTH)).await;
+
+    let mut held = Vec::new();
+    for _ in 0..10 {
+        if let Some(stream) = handshake_from([127, 0, 0, 2], seed.addr, &torrent).await {
+            held.push(stream);
+        }
+    }
+    assert_eq!(held.len(), MAX_CONNECTIONS_PER_IP);
+    assert!(handshake_from([127, 0, 0, 3], seed.addr, &torrent)
+        .await
+        .is_some());
+}
+
+#[tokio::test]
+async fn rapid_reconnects_are_cut_down_to_a_burst() {
+    let torrent = Torrent::fixture_single_file(PLENGTH, PLENGTH);
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(PLENGTH)).await;
+
+    let started = std::time::Instant::now();
+    let mut answered = 0;
+    for _ in 0..200 {
+        // each connection is dropped before the next, so only the rate limit applies
+        if handshake_from([127, 0, 0, 4], seed.addr, &torrent)
+            .await
+            .is_some()
+        {
+            answered += 1;
+        }
+    }
+    // a burst of 40, then 20 a second; fewer when the seed hasn't noticed a hang-up yet and
+    // the address is still at its connection limit
+    let allowed = 40 + (20.0 * started.elapsed().as_secs_f64()).ceil() as usize;
+    assert!((1..=allowed).contains(&answered), "{answered} answered");
+    assert!(answered < 200);
+
+    // the limiter refills, and a well-behaved address is let in again
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(handshake_from([127, 0, 0, 5], seed.addr, &torrent)
+        .await
+        .is_some());
+}
+
+/// The extended handshake `seed` answers a peer that speaks the extension protocol with.
+async fn seed_extensions(seed: SocketAddr, torrent: &Torrent) -> ExtendedHandshake {
+    let mut stream = TcpStream::connect(seed).await.unwrap();
+    let ours = Handshake::new(torrent.info_hash(), *b"-XX0000-extensions00", true);
+    stream.write_all(&ours.to_bytes()).await.unwrap();
+    let mut theirs = [0; Handshake::LEN];
+    stream.read_exact(&mut theirs).await.unwrap();
+    let mut stream = Framed::new(stream, MessageFramer::new(seed, &Limits::default()));
+    loop {
+        if let MessagePayload::Extended { id: 0, payload } = stream.next().await.unwrap().unwrap() {
+            return ExtendedHandshake::from_bencode(&payload).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_private_torrent_is_seeded_without_peer_exchange() {
+    let data = Torrent::fixture_data(PLENGTH);
+    let builder = || TorrentBuilder::single_file("fixture.bin", PLENGTH, PLENGTH).creation_date(0);
+    let public = builder().build(data.as_slice()).unwrap();
+    let private = builder().private(true).build(data.as_slice()).unwrap();
+
+    let seed = Seed::start(&public, &data).await;
+    let offered = seed_extensions(seed.addr, &public).await;
+    assert!(offered.id_of("ut_pex").is_some(), "{offered:?}");
+    let seed = Seed::start(&private, &data).await;
+    let offered = seed_extensions(seed.addr, &private).await;
+    assert_eq!(offered.id_of("ut_pex"), None, "{offered:?}");
+}
+
+#[tokio::test]
+async fn the_seed_serves_a_large_info_dict_in_16_kib_pieces() {
+    // 2500 piece hashes make an info dict of four metadata pieces
+    let plength = 1024;
+    let len = 2500 * plength - 10;
+    let torrent = Torrent::fixture_single_file(len, plength);
+    let encoded = serde_bencode::to_bytes(&torrent.info).unwrap();
+    assert_eq!(extension::metadata_piece_count(encoded.len()), 4);
+    let data = Torrent::fixture_data(len);
+    let seed = Seed::start(&torrent, &data).await;
+
+    let offered = seed_extensions(seed.addr, &torrent).await;
+    assert!(offered.id_of("ut_metadata").is_some(), "{offered:?}");
+    assert_eq!(offered.metadata_size, Some(encoded.len() as u32));
+    let info = common::client()
+        .fetch_metadata(&torrent.identity(), &[seed.addr])
+        .await
+        .unwrap();
+    assert_eq!(serde_bencode::to_bytes(&info).unwrap(), encoded);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_peer_that_stops_reading_is_dropped_after_the_write_timeout() {
+    let npieces = 4;
+    let torrent = Torrent::fixture_single_file(npieces * PLENGTH, PLENGTH);
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(npieces * PLENGTH)).await;
+
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.set_recv_buffer_size(4096).unwrap();
+    let mut stream = socket.connect(seed.addr).await.unwrap();
+    let ours = Handshake::new(torrent.info_hash(), *b"-XX0000-stopsreading", false);
+    stream.write_all(&ours.to_bytes()).await.unwrap();
+    let mut theirs = [0; Handshake::LEN];
+    stream.read_exact(&mut theirs).await.unwrap();
+    let mut peer = Framed::new(stream, MessageFramer::new(seed.addr, &Limits::default()));
+    peer.send(MessagePayload::Interested).await.unwrap();
+    loop {
+        match peer.next().await.unwrap().unwrap() {
+            MessagePayload::Unchoke => break,
+            MessagePayload::Bitfield(_) | MessagePayload::KeepAlive => {}
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    // from here on the peer reads nothing, but keeps asking for more than the socket
+    // buffers hold
+    let started = tokio::time::Instant::now();
+    'flood: while !seed.seeder.served().is_empty() {
+        for index in 0..64 {
+            let request = MessageRequest::new(index % npieces as u32, 0, PLENGTH as u32);
+            if peer.send(MessagePayload::Request(request)).await.is_err() {
+                break 'flood;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    while !seed.seeder.served().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let stalled = started.elapsed();
+    assert!(
+        (WRITE_TIMEOUT..2 * WRITE_TIMEOUT).contains(&stalled),
+        "gave up after {stalled:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn waiting_for_the_upload_cap_does_not_count_against_the_write_timeout() {
+    // a minute's worth of uploads at the cap, twice the write timeout
+    let len = 64 * PLENGTH;
+    let torrent = Torrent::fixture_single_file(len, PLENGTH);
+    let data = Torrent::fixture_data(len);
+    let limits = Limits {
+        max_up: PLENGTH as u64,
+        ..Limits::default()
+    };
+    let seed = Seed::start_limited(&torrent, &data, Bitfield::full(64), limits).await;
+
+    let client = common::client();
+    let mut stats = TransferStats::new(len);
+    let mut connection = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    let started = tokio::time::Instant::now();
+    for index in 0..64 {
+        client
+            .download_piece(&torrent, &mut connection, index, &mut stats)
+            .await
+            .unwrap();
+    }
+    assert!(started.elapsed() > 2 * WRITE_TIMEOUT - Duration::from_secs(5));
+    let served: Vec<_> = seed
+        .seeder
+        .served()
+        .iter()
+        .map(|&(_, served)| served)
+        .collect();
+    assert_eq!(served, [len as u64]);
+}
+
+#[tokio::test]
+async fn connections_reset_at_once_restart_networking() {
+    let torrent = Torrent::fixture_single_file(2 * PLENGTH, PLENGTH);
+    let data = Torrent::fixture_data(2 * PLENGTH);
+    let seed = Seed::start(&torrent, &data).await;
+    let changed = seed.seeder.network_changed();
+    tokio::pin!(changed);
+
+    // the common seed blames the network after five lost connections in five seconds
+    let mut dying = Vec::new();
+    for last in 20..25 {
+        dying.push(
+            handshake_from([127, 0, 0, last], seed.addr, &torrent)
+                .await
+                .unwrap(),
+        );
+    }
+    let mut idle = handshake_from([127, 0, 0, 25], seed.addr, &torrent)
+        .await
+        .unwrap();
+    for stream in dying {
+        // closing with a zero linger resets the connection, as a dead network does
+        stream.set_zero_linger().unwrap();
+    }
+    tokio::time::timeout(Duration::from_secs(5), &mut changed)
+        .await
+        .expect("networking restarted within the window");
+
+    // the connection that was still fine is dropped too
+    let mut rest = Vec::new();
+    let read = tokio::time::timeout(Duration::from_secs(5), idle.read_to_end(&mut rest));
+    read.await.expect("dropped").ok();
+    // and the seed takes new connections as before
+    let mut stats = TransferStats::new(2 * PLENGTH);
+    let client = common::client();
+    let mut connection = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    let piece = client
+        .download_piece(&torrent, &mut connection, 1, &mut stats)
+        .await
+        .unwrap()
+        .finish(torrent.piece_hash(1).unwrap(), true, &mut stats)
+        .unwrap();
+    assert_eq!(piece, data[PLENGTH..]);
+}
+
+#[tokio::test]
+async fn requests_from_a_choked_peer_are_dropped() {
+    let torrent = Torrent::fixture_single_file(PLENGTH, PLENGTH);
+    let data = Torrent::fixture_data(PLENGTH);
+    let seed = Seed::start(&torrent, &data).await;
+    let stream = handshake_from([127, 0, 0, 1], seed.addr, &torrent)
+        .await
+        .unwrap();
+    let mut peer = Framed::new(stream, MessageFramer::new(seed.addr, &Limits::default()));
+    // not interested, so still choked
+    let early = MessageRequest::new(0, 0, 16);
+    peer.send(MessagePayload::Request(early)).await.unwrap();
+    peer.send(MessagePayload::Interested).await.unwrap();
+    loop {
+        match peer.next().await.unwrap().unwrap() {
+            MessagePayload::Unchoke => break,
+            MessagePayload::Bitfield(_) | MessagePayload::KeepAlive => {}
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+    let later = MessageRequest::new(0, 16, 16);
+    peer.send(MessagePayload::Request(later)).await.unwrap();
+    let next = tokio::time::timeout(Duration::from_secs(5), peer.next());
+    let MessagePayload::Piece { begin, block, .. } = next.await.unwrap().unwrap().unwrap() else {
+        panic!("no piece for the request made once unchoked");
+    };
+    assert_eq!(begin, 16, "the seed answered a request made while choked");
+    assert_eq!(block[..], data[16..32]);
+    let next = tokio::time::timeout(Duration::from_millis(200), peer.next());
+    assert!(next.await.is_err(), "the seed sent more than was asked");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_peer_that_never_handshakes_is_dropped_and_cools_down() {
+    let torrent = Torrent::fixture_single_file(PLENGTH, PLENGTH);
+    let seed = Seed::start(&torrent, &Torrent::fixture_data(PLENGTH)).await;
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.6:0".parse().unwrap()).unwrap();
+    let mut silent = socket.connect(seed.addr).await.unwrap();
+
+    let started = tokio::time::Instant::now();
+    let mut byte = [0; 1];
+    let read = silent.read(&mut byte).await;
+    assert!(matches!(read, Ok(0) | Err(_)), "the seed sent {read:?}");
+    assert!(started.elapsed() >= HANDSHAKE_TIMEOUT);
+    // the silence counts as breaking the protocol
+    assert!(handshake_from([127, 0, 0, 6], seed.addr, &torrent)
+        .await
+        .is_none());
+    assert!(handshake_from([127, 0, 0, 7], seed.addr, &torrent)
+        .await
+        .is_some());
+}
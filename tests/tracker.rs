@@ -0,0 +1,192 @@
+//! Announcing to a tracker on loopback.
+
+mod common;
+
+use bittorrent_starter_rust::client::{PeerId, Transferred};
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::tracker::{SwarmNeed, TrackerError, TrackerRequest, TrackerResponse};
+use common::MockTracker;
+use std::net::SocketAddr;
+
+fn need() -> SwarmNeed {
+    SwarmNeed {
+        connected: 0,
+        max_connections: 50,
+        seeding: false,
+        paused: false,
+    }
+}
+
+#[tokio::test]
+async fn a_warning_comes_with_the_peers_it_was_sent_with() {
+    let peers: Vec<SocketAddr> = vec!["10.1.2.3:6881".parse().unwrap()];
+    let mut response = TrackerResponse::fixture(&peers);
+    response.warning_message = Some("announce too frequent (mock)".to_string());
+    let tracker = MockTracker::with_body(serde_bencode::to_bytes(&response).unwrap()).await;
+    let mut torrent = Torrent::fixture_single_file(1000, 1 << 14);
+    torrent.announce = tracker.url.clone();
+
+    let client = common::client();
+    let answer = client
+        .announce(&torrent, 6881, Transferred::starting(1000), need(), None)
+        .await
+        .unwrap();
+
+    assert_eq!(answer.all_peers(), peers);
+    assert_eq!(
+        answer.warning_message.as_deref(),
+        Some("announce too frequent (mock)")
+    );
+    assert_eq!(tracker.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn a_refusal_is_an_error_naming_the_reason() {
+    let tracker = MockTracker::with_body(b"d14:failure reason12:unregisterede".to_vec()).await;
+    let url = tracker.url.parse().unwrap();
+    let request = TrackerRequest::new([1; 20], PeerId::generate(), 6881, 1000);
+
+    let err = common::client()
+        .trackers()
+        .announce(&request, &url)
+        .await
+        .unwrap_err();
+
+    assert_eq!(err.to_string(), "tracker refused announce: unregistered");
+}
+
+#[tokio::test]
+async fn numwant_follows_the_connection_count() {
+    let tracker = MockTracker::start(&[]).await;
+    let mut torrent = Torrent::fixture_single_file(1000, 1 << 14);
+    torrent.announce = tracker.url.clone();
+    let client = common::client();
+    let left = Transferred::starting(1000);
+    for (connected, seeding) in [(0, false), (30, false), (40, true)] {
+        let need = SwarmNeed {
+            connected,
+            seeding,
+            ..need()
+        };
+        client
+            .announce(&torrent, 6881, left, need, None)
+            .await
+            .unwrap();
+    }
+
+    let numwants: Vec<_> = tracker
+        .requests()
+        .iter()
+        .map(|request| {
+            let query = request.split_once('?').unwrap().1;
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("numwant="))
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    assert_eq!(numwants, ["200", "30", "0"]);
+}
+
+/// The query parameters of `request`, a request target, in order.
+fn query(request: &str) -> Vec<(String, String)> {
+    let (_, query) = request.split_once('?').unwrap();
+    query
+        .split('&')
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap();
+            (key.to_string(), value.to_string())
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn an_announce_keeps_the_url_query_and_puts_the_info_hash_first() {
+    let tracker = MockTracker::start(&[]).await;
+    let url = format!("{}?passkey=secret", tracker.url).parse().unwrap();
+    let peer_id = PeerId(*b"-RB0000-0123456789ab");
+    let mut request = TrackerRequest::new([0xab; 20], peer_id, 6881, 1000);
+    request.numwant = Some(20);
+
+    common::client()
+        .trackers()
+        .announce(&request, &url)
+        .await
+        .unwrap();
+
+    let sent = query(&tracker.requests()[0]);
+    let keys: Vec<_> = sent.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys[..3], ["passkey", "info_hash", "peer_id"], "{keys:?}");
+    let value = |key: &str| {
+        sent.iter()
+            .find(|(sent, _)| sent == key)
+            .map(|(_, value)| value.as_str())
+    };
+    assert_eq!(value("info_hash"), Some("%AB".repeat(20).as_str()));
+    assert_eq!(value("peer_id"), Some("-RB0000-0123456789ab"));
+    assert_eq!(value("port"), Some("6881"));
+    assert_eq!(value("left"), Some("1000"));
+    assert_eq!(value("compact"), Some("1"));
+    assert_eq!(value("numwant"), Some("20"));
+    assert!(value("key").is_some_and(|key| !key.is_empty()));
+    assert_eq!(value("event"), None);
+    assert_eq!(value("trackerid"), None);
+}
+
+#[tokio::test]
+async fn a_tracker_id_is_echoed_on_later_announces_to_that_tracker() {
+    let mut response = TrackerResponse::fixture(&[]);
+    response.tracker_id = Some("abc123".to_string());
+    let tracker = MockTracker::with_body(serde_bencode::to_bytes(&response).unwrap()).await;
+    let mut torrent = Torrent::fixture_single_file(1000, 1 << 14);
+    torrent.announce = tracker.url.clone();
+
+    let client = common::client();
+    for _ in 0..2 {
+        client
+            .announce(&torrent, 6881, Transferred::starting(1000), need(), None)
+            .await
+            .unwrap();
+    }
+
+    let trackerids: Vec<_> = tracker
+        .requests()
+        .iter()
+        .map(|request| {
+            query(request)
+                .into_iter()
+                .find(|(key, _)| key == "trackerid")
+                .map(|(_, value)| value)
+        })
+        .collect();
+    assert_eq!(trackerids, [None, Some("abc123".to_string())]);
+}
+
+#[tokio::test]
+async fn announce_errors_say_what_went_wrong() {
+    let request = TrackerRequest::new([1; 20], PeerId::generate(), 6881, 1000);
+    let trackers = common::client();
+    let trackers = trackers.trackers();
+
+    let garbage = MockTracker::with_body(b"<html>not bencode</html>".to_vec()).await;
+    let err = trackers
+        .announce(&request, &garbage.url.parse().unwrap())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, TrackerError::Parse(_)), "{err:?}");
+
+    let udp = "udp://127.0.0.1:6969/announce".parse().unwrap();
+    let err = trackers.announce(&request, &udp).await.unwrap_err();
+    assert!(matches!(err, TrackerError::UnsupportedScheme(ref scheme) if scheme == "udp"));
+
+    // nothing listens on a port that was just freed
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed = format!("http://{}/announce", listener.local_addr().unwrap());
+    drop(listener);
+    let err = trackers
+        .announce(&request, &closed.parse().unwrap())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, TrackerError::Http(_)), "{err:?}");
+}
@@ -0,0 +1,167 @@
+//! HTTPS and WSS trackers with a self-signed certificate, checked with `--tracker-ca`.
+
+use bittorrent_starter_rust::client::{PeerId, TrackerClient};
+use bittorrent_starter_rust::tracker::{TrackerError, TrackerRequest, TrackerResponse};
+use bittorrent_starter_rust::tracker_policy::TrackerPolicy;
+use bittorrent_starter_rust::tracker_tls::TrackerTls;
+use futures_util::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/tls");
+
+/// The self-signed certificate of the trackers, for `localhost` and `127.0.0.1`.
+fn certificate() -> PathBuf {
+    PathBuf::from(FIXTURES).join("tracker.pem")
+}
+
+fn peer() -> SocketAddr {
+    "10.0.0.1:6881".parse().unwrap()
+}
+
+/// Accepts TLS connections on a loopback port with the fixture certificate, handing each
+/// to `serve`.
+async fn tls_tracker<F, Fut>(serve: F) -> SocketAddr
+where
+    F: Fn(tokio_native_tls::TlsStream<tokio::net::TcpStream>) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    let identity = native_tls::Identity::from_pkcs8(
+        &std::fs::read(certificate()).unwrap(),
+        &std::fs::read(PathBuf::from(FIXTURES).join("tracker.key")).unwrap(),
+    )
+    .unwrap();
+    let acceptor =
+        tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            // a client that rejects our certificate fails the handshake
+            if let Ok(stream) = acceptor.accept(stream).await {
+                serve(stream).await;
+            }
+        }
+    });
+    addr
+}
+
+/// An HTTPS tracker handing out [`peer`].
+async fn https_tracker() -> reqwest::Url {
+    let addr = tls_tracker(|mut stream| async move {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        let body = serde_bencode::to_bytes(&TrackerResponse::fixture(&[peer()])).unwrap();
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        let _ = stream.write_all(head.as_bytes()).await;
+        let _ = stream.write_all(&body).await;
+        let _ = stream.shutdown().await;
+    })
+    .await;
+    format!("https://localhost:{}/announce", addr.port())
+        .parse()
+        .unwrap()
+}
+
+/// A WebTorrent-style tracker handing out [`peer`].
+async fn wss_tracker() -> reqwest::Url {
+    let addr = tls_tracker(|stream| async move {
+        let Ok(mut socket) = tokio_tungstenite::accept_async(stream).await else {
+            return;
+        };
+        let Some(Ok(Message::Text(announce))) = socket.next().await else {
+            return;
+        };
+        let announce: serde_json::Value = serde_json::from_str(&announce).unwrap();
+        let answer = serde_json::json!({
+            "action": "announce",
+            "info_hash": announce["info_hash"],
+            "interval": 120,
+            "peers": [{"ip": "10.0.0.1", "port": 6881}],
+        });
+        let _ = socket.send(Message::Text(answer.to_string())).await;
+    })
+    .await;
+    format!("wss://localhost:{}/announce", addr.port())
+        .parse()
+        .unwrap()
+}
+
+async fn announce(tls: TrackerTls, url: &reqwest::Url) -> Result<TrackerResponse, TrackerError> {
+    let policy = TrackerPolicy { allow_local: true };
+    let trackers = TrackerClient::new(policy, &tls, PeerId::generate()).unwrap();
+    let request = TrackerRequest::new([1; 20], PeerId::generate(), 6881, 1000);
+    trackers.announce(&request, url).await
+}
+
+fn trusting_the_tracker() -> TrackerTls {
+    TrackerTls {
+        ca: Some(certificate()),
+        ..TrackerTls::default()
+    }
+}
+
+#[tokio::test]
+async fn an_https_tracker_with_a_self_signed_certificate_needs_its_ca() {
+    let url = https_tracker().await;
+
+    let err = announce(TrackerTls::default(), &url).await.unwrap_err();
+    assert!(
+        matches!(&err, TrackerError::Certificate { host, .. } if host == "localhost"),
+        "{err:?}"
+    );
+    assert_eq!(
+        err.to_string(),
+        "TLS certificate check with localhost failed"
+    );
+
+    let response = announce(trusting_the_tracker(), &url).await.unwrap();
+    assert_eq!(response.all_peers(), [peer()]);
+
+    let insecure = TrackerTls {
+        insecure: true,
+        ..TrackerTls::default()
+    };
+    let response = announce(insecure, &url).await.unwrap();
+    assert_eq!(response.all_peers(), [peer()]);
+}
+
+#[tokio::test]
+async fn a_wss_tracker_with_a_self_signed_certificate_needs_its_ca() {
+    let url = wss_tracker().await;
+
+    let err = announce(TrackerTls::default(), &url).await.unwrap_err();
+    assert!(matches!(err, TrackerError::WebSocket(_)), "{err:?}");
+
+    let response = announce(trusting_the_tracker(), &url).await.unwrap();
+    assert_eq!(response.all_peers(), [peer()]);
+
+    let insecure = TrackerTls {
+        insecure: true,
+        ..TrackerTls::default()
+    };
+    let response = announce(insecure, &url).await.unwrap();
+    assert_eq!(response.all_peers(), [peer()]);
+}
+
+#[test]
+fn a_ca_file_without_certificates_is_an_error() {
+    let tls = TrackerTls {
+        ca: Some(PathBuf::from(FIXTURES).join("tracker.key")),
+        ..TrackerTls::default()
+    };
+    let err = tls.connector().unwrap_err();
+    assert!(err.to_string().contains("has no certificates"), "{err:#}");
+}
@@ -0,0 +1,64 @@
+//! A torrent whose piece hashes stop short of its length fails cleanly, never by panicking.
+
+mod common;
+
+use bittorrent_starter_rust::torrent::Torrent;
+use common::MockTracker;
+
+const PLENGTH: usize = 16384;
+const LEN: usize = 3 * PLENGTH + 10;
+
+const INCONSISTENT: &str =
+    "torrent metadata inconsistent: 4 pieces declared by length/piece-length but only 3 hashes \
+     present";
+
+#[tokio::test]
+async fn download_piece_of_a_piece_without_a_hash_fails_before_downloading() {
+    let torrent = Torrent::fixture_truncated_pieces(LEN, PLENGTH);
+    let tracker = MockTracker::start(&[]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+    let output = dir.path().join("piece.bin");
+
+    let assert = common::run([
+        "--announce".into(),
+        tracker.url.clone().into(),
+        "download_piece".into(),
+        "-o".into(),
+        output.clone().into_os_string(),
+        torrent_path.into_os_string(),
+        "3".into(),
+    ])
+    .await
+    .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(stderr.contains(INCONSISTENT), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+    assert!(!output.exists());
+    assert!(tracker.requests().is_empty());
+}
+
+#[tokio::test]
+async fn download_refuses_the_torrent_before_announcing() {
+    let torrent = Torrent::fixture_truncated_pieces(LEN, PLENGTH);
+    let tracker = MockTracker::start(&[]).await;
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = common::torrent_file(dir.path(), &torrent);
+
+    let assert = common::run([
+        "--announce".into(),
+        tracker.url.clone().into(),
+        "download".into(),
+        "-o".into(),
+        dir.path().join("out.bin").into_os_string(),
+        torrent_path.into_os_string(),
+    ])
+    .await
+    .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("4 pieces declared by length/piece-length but 3 hashes present"),
+        "{stderr}"
+    );
+    assert!(tracker.requests().is_empty());
+}
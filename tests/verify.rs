@@ -0,0 +1,251 @@
+//! Hash-checking data on disk against torrents built at test time.
+
+use bittorrent_starter_rust::add_seed::{self, FileCheck};
+use bittorrent_starter_rust::client;
+use bittorrent_starter_rust::create::{fixture_data, TorrentBuilder};
+use bittorrent_starter_rust::peer::Bitfield;
+use bittorrent_starter_rust::plan::SeedPlan;
+use bittorrent_starter_rust::resume_import::ResumeFormat;
+use bittorrent_starter_rust::seed::PieceMap;
+use bittorrent_starter_rust::torrent::{Keys, Metainfo, Torrent};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio_util::sync::CancellationToken;
+
+const FILES: [(&[&str], usize); 3] = [
+    (&["a.txt"], 1000),
+    (&["sub", "b.bin"], 40_000),
+    (&["c.bin"], 7),
+];
+
+/// A padded multi-file torrent of [`FILES`] and their content, written below a temporary
+/// directory's `album` folder.
+fn album() -> (Torrent, TempDir) {
+    let files = FILES
+        .iter()
+        .map(|(path, len)| (path.iter().map(|c| c.to_string()).collect(), *len))
+        .collect();
+    let builder = TorrentBuilder::multi_file("album", files, 16384)
+        .announce("http://127.0.0.1:6969/announce")
+        .creation_date(0)
+        .pad_files(true);
+    let data = fixture_data(builder.content_length(), 9);
+    let torrent = builder.build(data.as_slice()).unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut rest = data.as_slice();
+    for (path, len) in FILES {
+        let path = path.iter().fold(dir.path().join("album"), |p, c| p.join(c));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let (content, tail) = rest.split_at(len);
+        std::fs::write(path, content).unwrap();
+        rest = tail;
+    }
+    (torrent, dir)
+}
+
+/// A torrent of 1 GiB in 64 MiB pieces and its (sparse, all zero) data in a temporary
+/// directory: enough hashing that finishing it can't pass for cancelling it.
+fn large() -> (Torrent, TempDir, PathBuf) {
+    const PLENGTH: usize = 64 << 20;
+    const LEN: usize = 16 * PLENGTH;
+    let mut torrent = Torrent::fixture_single_file(1000, 1 << 14);
+    torrent.info.keys = Keys::SingleFile { length: LEN };
+    torrent.info.plength = PLENGTH;
+    torrent.info.pieces.0 = vec![[0xab; 20]; LEN / PLENGTH];
+    let dir = tempfile::tempdir().unwrap();
+    let data = dir.path().join("fixture.bin");
+    std::fs::File::create(&data)
+        .unwrap()
+        .set_len(LEN as u64)
+        .unwrap();
+    (torrent, dir, data)
+}
+
+/// Cancels `cancel` shortly after the check has started, returning how long the check
+/// took from then on to give up.
+async fn cancel_soon(
+    cancel: &CancellationToken,
+    check: impl std::future::Future<Output = anyhow::Result<()>>,
+) -> Duration {
+    let canceller = {
+        let cancel = cancel.clone();
+        async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            cancel.cancel();
+            Instant::now()
+        }
+    };
+    let (result, cancelled_at) = tokio::join!(check, canceller);
+    let err = result.unwrap_err();
+    assert!(err.to_string().contains("interrupted"), "{err:#}");
+    cancelled_at.elapsed()
+}
+
+async fn verify(torrent: &Torrent, data: &Path) -> add_seed::DataCheck {
+    let mapper = add_seed::locate(torrent, data);
+    client::check_data(
+        torrent,
+        data,
+        &CancellationToken::new(),
+        move |torrent, _, cancel| add_seed::check(torrent, &mapper, cancel),
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn intact_data_matches_every_piece_and_file() {
+    let (torrent, dir) = album();
+    let report = verify(&torrent, dir.path()).await;
+    assert_eq!(report.have.count(), torrent.declared_pieces());
+    assert_eq!(report.mismatches(), 0);
+    let checks: Vec<_> = report.files.iter().map(|file| file.check).collect();
+    assert_eq!(checks, [FileCheck::Matches; 3]);
+    // found just as well when pointed at the name folder itself
+    let report = verify(&torrent, &dir.path().join("album")).await;
+    assert_eq!(report.have.count(), torrent.declared_pieces());
+}
+
+#[tokio::test]
+async fn a_flipped_byte_fails_only_the_piece_it_is_in() {
+    let (torrent, dir) = album();
+    let b = dir.path().join("album/sub/b.bin");
+    let mut content = std::fs::read(&b).unwrap();
+    // b.bin starts on piece 1 thanks to the padding, so this is in piece 2
+    content[20_000] ^= 1;
+    std::fs::write(&b, content).unwrap();
+
+    let report = verify(&torrent, dir.path()).await;
+    assert_eq!(report.failed_pieces().collect::<Vec<_>>(), [2]);
+    let checks: Vec<_> = report.files.iter().map(|file| file.check).collect();
+    assert_eq!(
+        checks,
+        [
+            FileCheck::Matches,
+            FileCheck::Corrupt { bad_pieces: 1 },
+            FileCheck::Matches
+        ]
+    );
+}
+
+#[tokio::test]
+async fn missing_and_truncated_files_are_reported_as_such() {
+    let (torrent, dir) = album();
+    std::fs::remove_file(dir.path().join("album/a.txt")).unwrap();
+    std::fs::write(dir.path().join("album/c.bin"), b"abc").unwrap();
+
+    let report = verify(&torrent, dir.path()).await;
+    let checks: Vec<_> = report.files.iter().map(|file| file.check).collect();
+    assert_eq!(
+        checks,
+        [
+            FileCheck::Missing,
+            FileCheck::Matches,
+            FileCheck::WrongSize { actual: 3 }
+        ]
+    );
+    let missing: Vec<_> = report.missing.pieces().collect();
+    assert_eq!(missing, [0, torrent.declared_pieces() - 1]);
+    assert_eq!(report.failed_pieces().count(), 0);
+}
+
+#[tokio::test]
+async fn cancelling_a_recheck_returns_promptly_and_keeps_the_piece_map() {
+    let (torrent, dir, data) = large();
+    // a piece map that can't be trusted, so seeding re-checks the data first
+    let pieces = dir.path().join("fixture.pieces");
+    std::fs::write(&pieces, b"torn").unwrap();
+    let plan = SeedPlan::new(&torrent, Some(data), Some(pieces.clone()), None, false, 0).unwrap();
+    assert!(plan.have.is_none());
+
+    let cancel = CancellationToken::new();
+    let took = cancel_soon(&cancel, async {
+        plan.check_pieces(&torrent, &cancel).await.map(drop)
+    })
+    .await;
+    assert!(took < Duration::from_secs(1), "took {took:?} to give up");
+    assert_eq!(std::fs::read(&pieces).unwrap(), b"torn");
+}
+
+#[tokio::test]
+async fn cancelling_the_check_of_imported_pieces_writes_no_piece_map() {
+    let (torrent, dir, data) = large();
+    // seed mode claims every piece, so all 16 are candidates for the spot check
+    let resume = dir.path().join("fixture.fastresume");
+    std::fs::write(&resume, b"d9:seed_modei1ee").unwrap();
+    let pieces = dir.path().join("fixture.pieces");
+    let plan = SeedPlan::new(
+        &torrent,
+        Some(data),
+        Some(pieces.clone()),
+        Some((resume, ResumeFormat::Qbittorrent)),
+        true,
+        0,
+    )
+    .unwrap();
+
+    let cancel = CancellationToken::new();
+    let took = cancel_soon(&cancel, async {
+        plan.check_pieces(&torrent, &cancel).await.map(drop)
+    })
+    .await;
+    assert!(took < Duration::from_secs(1), "took {took:?} to give up");
+    assert!(!pieces.exists());
+}
+
+#[tokio::test]
+async fn cancelling_a_verify_returns_promptly() {
+    let (torrent, _dir, data) = large();
+    let mapper = add_seed::locate(&torrent, &data);
+    let cancel = CancellationToken::new();
+    let took = cancel_soon(&cancel, async {
+        client::check_data(&torrent, &data, &cancel, move |torrent, _, cancel| {
+            add_seed::check(torrent, &mapper, cancel)
+        })
+        .await
+        .map(drop)
+    })
+    .await;
+    assert!(took < Duration::from_secs(1), "took {took:?} to give up");
+}
+
+#[tokio::test]
+async fn a_torn_piece_map_falls_back_to_rechecking_the_data() {
+    let len = 5 * 16384 + 10;
+    let torrent = Torrent::fixture_single_file(len, 16384);
+    let mut data = Torrent::fixture_data(len);
+    // piece 2 is corrupt on disk, though the piece map claimed it
+    data[2 * 16384] ^= 1;
+    let dir = tempfile::tempdir().unwrap();
+    let data_path = dir.path().join("fixture.bin");
+    std::fs::write(&data_path, &data).unwrap();
+    let pieces = dir.path().join("fixture.pieces");
+    PieceMap::from_bitfield(&torrent, &Bitfield::full(6))
+        .unwrap()
+        .save(&pieces)
+        .unwrap();
+    // a crash halfway through rewriting it
+    let map = std::fs::read(&pieces).unwrap();
+    std::fs::write(&pieces, &map[..map.len() / 2]).unwrap();
+
+    let plan = SeedPlan::new(
+        &torrent,
+        Some(data_path),
+        Some(pieces.clone()),
+        None,
+        false,
+        0,
+    )
+    .unwrap();
+    assert!(plan.have.is_none(), "a torn piece map was trusted");
+    let have = plan
+        .check_pieces(&torrent, &CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(have.pieces().collect::<Vec<_>>(), [0, 1, 3, 4, 5]);
+    // and what the check found replaces it
+    let reloaded = PieceMap::load(&pieces, &torrent).unwrap().unwrap();
+    assert_eq!(reloaded.pieces().collect::<Vec<_>>(), [0, 1, 3, 4, 5]);
+}
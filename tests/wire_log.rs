@@ -0,0 +1,61 @@
+//! The wire log of a loopback exchange. It is process-wide, hence a test binary of its own.
+
+mod common;
+
+use bittorrent_starter_rust::client::TransferStats;
+use bittorrent_starter_rust::torrent::Torrent;
+use bittorrent_starter_rust::wire_log;
+use common::Seed;
+
+#[tokio::test]
+async fn the_wire_log_has_every_frame_of_a_piece_download() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("wire.log");
+    wire_log::init(&log).unwrap();
+
+    let torrent = Torrent::fixture_single_file(20_000, 1 << 15);
+    let data = Torrent::fixture_data(20_000);
+    let seed = Seed::start(&torrent, &data).await;
+    let client = common::client();
+    let mut stats = TransferStats::new(20_000);
+    let mut connection = client
+        .connect(&torrent, seed.addr, &mut stats)
+        .await
+        .unwrap();
+    client
+        .download_piece(&torrent, &mut connection, 0, &mut stats)
+        .await
+        .unwrap();
+
+    // our side of the connection, the seed logs its own side under our address
+    let seed_addr = seed.addr.to_string();
+    let log = std::fs::read_to_string(&log).unwrap();
+    let frames: Vec<_> = log
+        .lines()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .filter(|fields| fields[1] == seed_addr)
+        .map(|fields| (fields[2], fields[3], fields[4], fields[5]))
+        .collect();
+    let expected = [
+        ("out", "Extended"),
+        ("in", "Bitfield"),
+        ("in", "Extended"),
+        ("out", "Interested"),
+        ("in", "Unchoke"),
+        ("out", "Request"),
+        ("out", "Request"),
+        ("in", "Piece"),
+        ("in", "Piece"),
+    ];
+    let tags: Vec<_> = frames.iter().map(|&(dir, tag, ..)| (dir, tag)).collect();
+    assert_eq!(tags, expected, "{log}");
+    // the two requests, with their payloads dumped in full
+    assert_eq!(frames[5].2, "len=12");
+    assert_eq!(frames[5].3, "000000000000000000004000");
+    assert_eq!(frames[6].3, "000000000000400000000e20");
+    // the blocks, cut short after index, begin and the first bytes of data
+    assert_eq!(frames[7].2, "len=16392");
+    assert_eq!(frames[8].2, "len=3624");
+    let dump = format!("{}{}", "0".repeat(16), hex::encode(&data[..8]));
+    assert_eq!(frames[7].3, dump);
+}